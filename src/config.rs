@@ -1,73 +1,644 @@
-use crate::nullfs::{NullFs, NullFsPath, any_fs::AnyFs};
+use crate::nullfs::{NullFs, NullFsPath, any_fs::AnyFs, backend, hashing::HashAlgo};
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier, password_hash::SaltString};
 use eyre::{Context, ContextCompat};
 use indexmap::{IndexMap, IndexSet};
+use rand::Rng;
 use reqwest::Url;
+use rustls_pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::{
     collections::HashSet,
     path::{Path, PathBuf},
+    sync::{Arc, LazyLock, Mutex, RwLock},
+    time::Duration,
 };
 use uuid::Uuid;
 
+/// Prefix of the argon2 PHC strings accepted as `password`, other values are plaintext
+const ARGON2_PREFIX: &str = "$argon2";
+
+/// Node settings that are no longer read, along with what became of them, see
+/// [`obsolete_keys`]
+const OBSOLETE_KEYS: [(&str, &str); 1] = [(
+    "compressState",
+    "the snapshot states are sqlite databases now",
+)];
+
+/// Argon2 hashes already verified against a password, keyed by (hash, sha256 of the password),
+/// so that basic auth does not pay for argon2 on every request
+static VERIFIED: LazyLock<Mutex<HashSet<(String, String)>>> = LazyLock::new(Mutex::default);
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
 #[serde(rename_all = "camelCase")]
 pub struct User {
     pub name: String,
+    /// Plaintext or argon2 hash as printed by `nullfs hash-password`
     pub password: Option<String>,
 }
 
+impl User {
+    pub fn has_plaintext_password(&self) -> bool {
+        self.password
+            .as_ref()
+            .is_some_and(|password| !password.starts_with(ARGON2_PREFIX))
+    }
+
+    /// Whether `candidate` holds the credentials of this configured user
+    pub fn verify(&self, candidate: &User) -> bool {
+        if self.name != candidate.name {
+            return false;
+        }
+
+        match (&self.password, &candidate.password) {
+            (None, None) => true,
+            (Some(hash), Some(password)) if hash.starts_with(ARGON2_PREFIX) => {
+                verify_password(hash, password)
+            }
+            (Some(expected), Some(password)) => expected == password,
+            _ => false,
+        }
+    }
+}
+
+/// Argon2 hash of `password` in the PHC string format, with a random salt
+pub fn hash_password(password: &str) -> eyre::Result<String> {
+    let salt = SaltString::encode_b64(&rand::random::<[u8; 16]>())
+        .map_err(|e| eyre::eyre!("Encoding salt: {e}"))?;
+
+    Ok(Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| eyre::eyre!("Hashing password: {e}"))?
+        .to_string())
+}
+
+fn verify_password(hash: &str, password: &str) -> bool {
+    let key = (
+        hash.to_owned(),
+        format!("{:x}", Sha256::digest(password.as_bytes())),
+    );
+    if VERIFIED.lock().unwrap().contains(&key) {
+        return true;
+    }
+
+    let verified = match PasswordHash::new(hash) {
+        Ok(parsed) => Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok(),
+        Err(e) => {
+            tracing::error!("Malformed password hash: {e}");
+            false
+        }
+    };
+    if verified {
+        VERIFIED.lock().unwrap().insert(key);
+    }
+
+    verified
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct RelayNode {
     pub address: Url,
     pub auth: User,
+    /// Realm presented to this relay, defaults to the node realm
+    #[serde(default)]
+    pub realm: Option<String>,
+    /// Seconds after which a request to this relay is abandoned, unset requests never time out
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+    /// Seconds allowed to connect to this relay, defaults to 10
+    #[serde(default)]
+    pub connect_timeout_secs: Option<u64>,
+    /// PEM certificate trusted on top of the system ones when talking to an `https://` relay,
+    /// meant for self-signed setups
+    #[serde(default)]
+    pub ca_cert: Option<PathBuf>,
+}
+
+/// PEM files the node serves HTTPS with
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct TlsConfig {
+    /// Certificate chain, leaf first
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+impl TlsConfig {
+    pub fn server_config(&self) -> eyre::Result<rustls::ServerConfig> {
+        let certs = CertificateDer::pem_file_iter(&self.cert)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| eyre::eyre!("Reading certificate {}: {e}", self.cert.display()))?;
+        if certs.is_empty() {
+            eyre::bail!("No certificate found in {}", self.cert.display());
+        }
+        let key = PrivateKeyDer::from_pem_file(&self.key)
+            .map_err(|e| eyre::eyre!("Reading private key {}: {e}", self.key.display()))?;
+
+        rustls::ServerConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .wrap_err_with(|| {
+            format!(
+                "Pairing certificate {} with key {}",
+                self.cert.display(),
+                self.key.display()
+            )
+        })
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "camelCase", tag = "type")]
 pub enum StoreKind {
-    Local { root: PathBuf },
+    Local {
+        root: PathBuf,
+        /// Links are read through as the entries they point to, otherwise they are synced as
+        /// links the other nodes recreate
+        #[serde(default)]
+        follow_symlinks: bool,
+        /// Deleted entries are moved under [`crate::nullfs::local_fs::TRASH_DIR`] rather than
+        /// removed for good
+        #[serde(default)]
+        trash: bool,
+        /// Age past which the trashed entries are removed, defaults to 30
+        #[serde(default)]
+        trash_retention_days: Option<u64>,
+    },
+    /// S3 compatible bucket, `endpoint` targets other providers than AWS (minio, r2, ..)
+    S3 {
+        bucket: String,
+        #[serde(default)]
+        prefix: Option<String>,
+        #[serde(default)]
+        region: Option<String>,
+        #[serde(default)]
+        endpoint: Option<Url>,
+    },
+    /// Volatile volume starting empty, mostly meant for tests
+    Memory,
+    /// Store type without a built-in variant, built by the factory registered for `kind`
+    /// through [`crate::nullfs::backend::register_backend`], `options` holds the other fields
+    #[serde(skip)]
+    Custom {
+        kind: String,
+        options: serde_json::Map<String, serde_json::Value>,
+    },
+}
+
+impl StoreKind {
+    const BUILTIN: [&str; 3] = ["local", "s3", "memory"];
+
+    /// Value of the `type` field
+    pub fn kind(&self) -> &str {
+        match self {
+            StoreKind::Local { .. } => "local",
+            StoreKind::S3 { .. } => "s3",
+            StoreKind::Memory => "memory",
+            StoreKind::Custom { kind, .. } => kind,
+        }
+    }
+}
+
+/// (De)serializes a `store` entry, the types without a built-in variant are kept
+/// as [`StoreKind::Custom`] for the backend registry to resolve
+mod store_kind {
+    use super::StoreKind;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer, de::Error};
+    use serde_json::{Map, Value};
+
+    pub fn serialize<S: Serializer>(store: &StoreKind, serializer: S) -> Result<S::Ok, S::Error> {
+        match store {
+            StoreKind::Custom { kind, options } => {
+                let mut fields = options.clone();
+                fields.insert("type".to_owned(), Value::String(kind.clone()));
+                fields.serialize(serializer)
+            }
+            store => store.serialize(serializer),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<StoreKind, D::Error> {
+        let mut options = Map::deserialize(deserializer)?;
+        let Some(Value::String(kind)) = options.get("type").cloned() else {
+            return Err(D::Error::missing_field("type"));
+        };
+
+        if StoreKind::BUILTIN.contains(&kind.as_str()) {
+            return serde_json::from_value(Value::Object(options)).map_err(D::Error::custom);
+        }
+
+        options.remove("type");
+        Ok(StoreKind::Custom { kind, options })
+    }
+}
+
+/// How to pick a version when the relays of a volume disagree on a file content
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum TieBreak {
+    /// The relay holding the most recently modified version wins
+    Newest,
+    /// The first relay listed in `pullFrom` holding the file wins
+    Priority,
+    /// The version held by the most relays wins, the first listed in `pullFrom` among equals
+    Quorum,
+}
+
+/// Order in which the relays of a volume are tried on each sync cycle, the first one that
+/// answers serves the volume
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum RelayOrder {
+    /// Spreads the load of the volume over its relays
+    #[default]
+    Random,
+    /// The order of `pullFrom`, a backup relay only serves while the ones listed before it
+    /// are down
+    Priority,
+}
+
+/// Shell commands serving a volume from a point-in-time filesystem snapshot (zfs, btrfs, ..),
+/// both receive `NULLFS_VOLUME` and `NULLFS_ROOT`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct FsSnapshotHook {
+    /// Takes the snapshot, the last line of its output is the read-only snapshot mount
+    pub create: String,
+    /// Removes a replaced snapshot, its mount is given in `NULLFS_SNAPSHOT`
+    pub destroy: Option<String>,
+    /// Age under which the current snapshot is reused by other captures, defaults to 60
+    pub max_age_secs: Option<u64>,
+}
+
+/// What a user allowed on a volume can do with it, `Rw` implies `Ro`
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Access {
+    /// Pull, list and download
+    Ro,
+    /// Upload and delete as well
+    Rw,
+}
+
+/// `allow` entry standing for every configured user
+pub const ANY_USER: &str = "*";
+
+/// Entry of a volume `allow` list, a bare user name is granted [`Access::Ro`]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(from = "GrantEntry")]
+pub struct Grant {
+    pub user: String,
+    pub access: Access,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum GrantEntry {
+    Name(String),
+    Full { user: String, access: Access },
+}
+
+impl From<GrantEntry> for Grant {
+    fn from(entry: GrantEntry) -> Self {
+        match entry {
+            GrantEntry::Name(user) => Grant {
+                user,
+                access: Access::Ro,
+            },
+            GrantEntry::Full { user, access } => Grant { user, access },
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct VolumeItem {
-    pub allow: Vec<String>,
+    pub allow: Vec<Grant>,
     pub pull_from: Vec<String>,
+    #[serde(with = "store_kind")]
     pub store: StoreKind,
+    #[serde(default)]
+    pub tie_break: Option<TieBreak>,
+    #[serde(default)]
+    pub relay_order: RelayOrder,
+    #[serde(default)]
+    pub fs_snapshot: Option<FsSnapshotHook>,
+    /// Skip directories mounted from another device during capture
+    #[serde(default)]
+    pub skip_mounts: bool,
+    /// Only list again the directories whose mtime changed since the last capture, see
+    /// [`crate::nullfs::snapshot::Snapshot::incremental`]
+    #[serde(default)]
+    pub incremental_scan: bool,
+    /// Where conflicting files are set aside, relative to the volume root
+    #[serde(default)]
+    pub quarantine_dir: Option<String>,
+    /// Inserted before the extension of the copy receiving the remote version of a file
+    /// edited both locally and on a relay, `{node}` is replaced by the relay name
+    #[serde(default)]
+    pub conflict_suffix: Option<String>,
+    /// Previous versions kept of each file a relay overwrites, none when 0, see
+    /// [`crate::nullfs::quarantine::versions_dir`]
+    #[serde(default)]
+    pub keep_versions: usize,
+    /// Volumes are started by decreasing priority on each sync cycle, volumes sharing a
+    /// priority go in random order, every volume is still synced on every cycle
+    ///
+    /// Transfers also share the download rate by priority, see
+    /// [`crate::nullfs::throttle::RateLimiter`]
+    #[serde(default)]
+    pub priority: u32,
+    /// Let the users allowed [`Access::Rw`] write through `/v1/upload` and delete through
+    /// `/v1/file`, read-only for everyone otherwise
+    #[serde(default)]
+    pub writable: bool,
+    /// Serve `/v1/dir`, `/v1/download`, `/v1/hash` and `/v1/exists` to requests without
+    /// credentials
+    #[serde(default)]
+    pub anonymous: bool,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct NodeConfig {
     pub name: String,
+    /// Tenant namespace of this node, keeps peers of a shared relay apart
+    pub realm: Option<String>,
     pub address: String,
     pub port: u16,
     #[serde(default)]
     pub secure: bool,
+    /// Where the identity, stash, session key and states of the node are kept, created when
+    /// missing, defaults to the working directory
+    pub data_dir: Option<PathBuf>,
     pub refresh_secs: Option<u64>,
+    /// Volumes pulled and applied at the same time within a sync cycle, defaults to 4
+    pub sync_concurrency: Option<usize>,
+    /// Upper bound of a random delay added to each refresh, spreads the pulls of nodes sharing
+    /// a relay, defaults to 0
+    pub refresh_jitter_secs: Option<u64>,
+    /// Period at which applied commands are purged from the stash and the database vacuumed,
+    /// the last command applied to each path is kept to recognize its echo
+    pub stash_vacuum_secs: Option<u64>,
+    /// Modification time drift under which a file of unchanged size is not considered modified,
+    /// defaults to 0 (exact comparison)
+    pub mtime_tolerance_ms: Option<u64>,
+    /// Amount of large files hashed concurrently off the async runtime, defaults to the CPU count
+    pub hash_workers: Option<usize>,
+    /// Algorithm of the content hashes (`sha256` or `blake3`), defaults to sha256, a relay
+    /// and its pullers must use the same one
+    pub hash_algo: Option<HashAlgo>,
+    /// Age after which the state kept for a peer that stopped pulling is removed, defaults to 90
+    pub peer_state_max_age_days: Option<u64>,
+    /// Fold contiguous repetitions of the same pulled commands, and drop the ones later
+    /// commands undo, before applying them, defaults to true
+    pub collapse_commands: Option<bool>,
+    /// Age after which a pending command is checked against the relay before being applied,
+    /// the stale ones are dropped, unset commands never expire
+    pub command_ttl_secs: Option<u64>,
+    /// Failed attempts after which a pulled command is set aside for good, it is retried with
+    /// an exponential backoff until then, defaults to 5
+    pub max_command_attempts: Option<u32>,
+    /// Times a stash query refused because another connection holds the database lock is
+    /// retried before failing, defaults to 5
+    pub stash_busy_retries: Option<u32>,
+    /// Connections opened to the stash database, at least 1, defaults to 5
+    pub stash_pool_size: Option<u32>,
+    /// Pages of the stash database each connection keeps in memory, defaults to 100 000
+    /// (around 400 MB with the default 4 KiB pages)
+    pub stash_cache_pages: Option<u32>,
+    /// Cap of the download rate from the relays in bytes per second, shared by every transfer
+    /// of the node, unlimited when unset
+    pub max_download_bytes_per_sec: Option<u64>,
+    /// Size in bytes above which a pulled file is written as it downloads instead of being held
+    /// in memory, defaults to 8 MiB
+    pub stream_threshold_bytes: Option<u64>,
+    /// Size in bytes above which a pulled file differing from its local copy only gets the
+    /// chunks the copy lacks, defaults to 1 MiB
+    pub delta_threshold_bytes: Option<u64>,
+    /// How long a relay found alive, or down, is not probed again, defaults to 10
+    pub liveness_ttl_secs: Option<u64>,
+    /// Compress the file contents exchanged with the relays (zstd or gzip) when both ends
+    /// support it, already compressed formats are sent as is, defaults to true
+    pub compression: Option<bool>,
+    /// Keep the volumes paused through `/v1/volume/{name}/pause` paused accross restarts
+    #[serde(default)]
+    pub persist_paused: bool,
+    /// Serve the Merkle tree of the volumes and use the one of the relays to skip unchanged
+    /// subtrees when applying commands
+    #[serde(default)]
+    pub merkle: bool,
+    /// Log the pulled commands instead of applying them, the volumes are left untouched, also
+    /// set by the `--dry-run` flag
+    #[serde(default)]
+    pub dry_run: bool,
+    /// Content type served for the files of an extension (`svg: image/svg+xml`), consulted
+    /// before the built-in table
+    #[serde(default)]
+    pub mime_overrides: IndexMap<String, String>,
+    /// Origins (`https://app.example.com`) whose pages may call the `/v1` API from a browser,
+    /// no cross-origin request is allowed when empty
+    #[serde(default)]
+    pub cors_allowed_origins: Vec<String>,
+    /// Largest request body accepted by the `/v1` endpoints, defaults to 1 GiB which is also
+    /// the most `/v1/upload` takes
+    pub max_body_bytes: Option<u64>,
+    /// Time a `/v1/download` may take from the request to the last byte sent, unlimited when
+    /// unset
+    pub download_timeout_secs: Option<u64>,
+    /// Time the sync in progress and the requests in flight are given to finish on shutdown,
+    /// defaults to 30
+    pub shutdown_grace_secs: Option<u64>,
+    /// Serve HTTPS instead of plain HTTP
+    pub tls: Option<TlsConfig>,
     pub users: IndexSet<User>,
     pub relay_nodes: IndexMap<String, RelayNode>,
     pub volumes: IndexMap<String, VolumeItem>,
 }
 
+/// Keys of [`OBSOLETE_KEYS`] still set at the top level of a configuration
+pub fn obsolete_keys(content: &str) -> Vec<(&'static str, &'static str)> {
+    let Ok(serde_yaml::Value::Mapping(node)) = serde_yaml::from_str(content) else {
+        return vec![];
+    };
+
+    OBSOLETE_KEYS
+        .into_iter()
+        .filter(|(key, _)| node.contains_key(*key))
+        .collect()
+}
+
 impl NodeConfig {
     pub async fn load_from_file(path: &Path) -> eyre::Result<Self> {
         let content = tokio::fs::read_to_string(path)
             .await
             .wrap_err_with(|| format!("Loading configuration file at {}", path.display()))?;
+        let content = expand_env_vars(&content, |name| std::env::var(name).ok())
+            .wrap_err_with(|| format!("Expanding configuration file at {}", path.display()))?;
 
         let config = serde_yaml::from_str::<Self>(&content)
             .wrap_err_with(|| "Parsing configuration file".to_string())?;
+        for (key, reason) in obsolete_keys(&content) {
+            tracing::warn!("{key} is no longer used and can be removed, {reason}");
+        }
 
         config.validate()
     }
 
+    /// See [`NodeConfig::data_dir`]
+    pub fn data_dir(&self) -> &Path {
+        self.data_dir.as_deref().unwrap_or(Path::new("."))
+    }
+
+    pub fn create_data_dir(&self) -> eyre::Result<()> {
+        std::fs::create_dir_all(self.data_dir())
+            .wrap_err_with(|| format!("Creating data directory {}", self.data_dir().display()))
+    }
+
+    /// Pause between two sync cycles, `refreshSecs` (5 by default, at least 1) plus a random
+    /// delay up to `refreshJitterSecs`
+    pub fn refresh_delay(&self) -> Duration {
+        let refresh = Duration::from_secs(self.refresh_secs.unwrap_or(5).max(1));
+        let jitter_ms = self.refresh_jitter_secs.unwrap_or(0).saturating_mul(1000);
+        refresh + Duration::from_millis(rand::rng().random_range(0..=jitter_ms))
+    }
+
+    /// Loads a configuration and initializes every volume, a root that does not exist or
+    /// a bucket that cannot be configured fails here rather than in the middle of a sync
+    pub async fn load_checked(path: &Path) -> eyre::Result<Self> {
+        let config = Self::load_from_file(path).await?;
+        for name in config.volumes.keys() {
+            config
+                .get_initialized_fs_volume(name)
+                .await
+                .wrap_err_with(|| format!("Checking volume {name:?}"))?;
+        }
+
+        Ok(config)
+    }
+
+    /// Problems a valid configuration can still run into once started: local roots that
+    /// cannot be read and relays of `pullFrom` that are not declared, nothing is created
+    pub async fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        for (name, vol) in &self.volumes {
+            if let StoreKind::Local { root, .. } = &vol.store {
+                match tokio::fs::metadata(root).await {
+                    Ok(meta) if !meta.is_dir() => problems.push(format!(
+                        "Volume {name:?}: root {} is not a directory",
+                        root.display()
+                    )),
+                    Ok(_) => {
+                        if let Err(e) = tokio::fs::read_dir(root).await {
+                            problems.push(format!(
+                                "Volume {name:?}: root {} is not readable: {e}",
+                                root.display()
+                            ));
+                        }
+                    }
+                    Err(e) => problems.push(format!(
+                        "Volume {name:?}: root {} is not accessible: {e}",
+                        root.display()
+                    )),
+                }
+            }
+
+            for share in &vol.pull_from {
+                if let Err(e) = self.resolve_alias(share) {
+                    problems.push(format!("Volume {name:?}: {e}"));
+                }
+            }
+        }
+
+        problems
+    }
+
+    /// Replaces `current` with the configuration at `path` once it fully checks out,
+    /// on failure the node keeps running on `current` untouched
+    pub async fn reload(current: &mut Arc<NodeConfig>, path: &Path) -> eyre::Result<()> {
+        match Self::load_checked(path).await {
+            Ok(config) => {
+                *current = Arc::new(config);
+                tracing::info!("Configuration reloaded from {}", path.display());
+                Ok(())
+            }
+            Err(e) => {
+                tracing::error!(
+                    "Configuration reload from {} failed, keeping the previous one: {:?}",
+                    path.display(),
+                    e
+                );
+                Err(e)
+            }
+        }
+    }
+
+    /// Fields only read when the node starts, changing them takes a restart
+    fn restart_only_changes(&self, other: &NodeConfig) -> Vec<&'static str> {
+        [
+            ("name", self.name != other.name),
+            ("address", self.address != other.address),
+            ("port", self.port != other.port),
+            ("secure", self.secure != other.secure),
+            ("hashWorkers", self.hash_workers != other.hash_workers),
+            ("hashAlgo", self.hash_algo != other.hash_algo),
+            (
+                "peerStateMaxAgeDays",
+                self.peer_state_max_age_days != other.peer_state_max_age_days,
+            ),
+            ("persistPaused", self.persist_paused != other.persist_paused),
+            (
+                "stashPoolSize",
+                self.stash_pool_size != other.stash_pool_size,
+            ),
+            (
+                "stashCachePages",
+                self.stash_cache_pages != other.stash_cache_pages,
+            ),
+            (
+                "shutdownGraceSecs",
+                self.shutdown_grace_secs != other.shutdown_grace_secs,
+            ),
+            ("dataDir", self.data_dir != other.data_dir),
+            ("tls", self.tls != other.tls),
+            (
+                "corsAllowedOrigins",
+                self.cors_allowed_origins != other.cors_allowed_origins,
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(field, changed)| changed.then_some(field))
+        .collect()
+    }
+
     fn validate(self) -> eyre::Result<Self> {
         if self.name.trim().is_empty() {
             eyre::bail!("Node name cannot be empty");
         }
 
+        if let Some(tls) = &self.tls {
+            tls.server_config()?;
+        }
+
+        let realms = self
+            .relay_nodes
+            .values()
+            .filter_map(|relay| relay.realm.as_ref())
+            .chain(self.realm.as_ref());
+        for realm in realms {
+            if !is_safe_identifier(realm) {
+                eyre::bail!(
+                    "Realm {realm:?} is invalid, expected non-empty alphanumeric characters, '-' or '_'"
+                );
+            }
+        }
+
         for relay in self.relay_nodes.values() {
             if let Some(port) = relay.address.port() {
                 let host = relay
@@ -87,6 +658,10 @@ impl NodeConfig {
             }
         }
 
+        if self.stash_pool_size == Some(0) {
+            eyre::bail!("Stash pool size must be at least 1");
+        }
+
         let mut seen = HashSet::new();
         let mut duplicates = HashSet::new();
         for user in &self.users {
@@ -104,9 +679,46 @@ impl NodeConfig {
             );
         }
 
-        for (_, vol) in &self.volumes {
-            for uname in &vol.allow {
-                if self.resolve_user(uname).is_none() {
+        for (name, vol) in &self.volumes {
+            if !backend::is_registered(vol.store.kind()) {
+                eyre::bail!(
+                    "Volume {name:?} has an unknown store type {:?}",
+                    vol.store.kind()
+                );
+            }
+
+            if matches!(vol.store, StoreKind::S3 { .. })
+                && (vol.fs_snapshot.is_some() || vol.skip_mounts || vol.incremental_scan)
+            {
+                eyre::bail!(
+                    "Volume {name:?} is stored in a bucket, fsSnapshot, skipMounts and incrementalScan only apply to local volumes"
+                );
+            }
+
+            if let Some(dir) = &vol.quarantine_dir
+                && !dir
+                    .split('/')
+                    .all(|comp| !comp.is_empty() && comp != "." && comp != "..")
+            {
+                eyre::bail!(
+                    "Volume {name:?} quarantine directory {dir:?} must be a relative path inside the volume"
+                );
+            }
+
+            if let Some(suffix) = &vol.conflict_suffix
+                && suffix.trim().is_empty()
+            {
+                eyre::bail!("Volume {name:?} conflict suffix cannot be empty");
+            }
+
+            if vol.anonymous && vol.writable {
+                tracing::warn!(
+                    "Volume {name:?} is both anonymous and writable, anyone can read what the rw users write"
+                );
+            }
+
+            for Grant { user: uname, .. } in &vol.allow {
+                if uname != ANY_USER && self.resolve_user(uname).is_none() {
                     eyre::bail!(
                         "User {:?} is not defined, expected: {}",
                         uname,
@@ -120,13 +732,36 @@ impl NodeConfig {
             }
         }
 
+        for (ext, mime) in &self.mime_overrides {
+            if !mime.contains('/') || reqwest::header::HeaderValue::from_str(mime).is_err() {
+                eyre::bail!("Content type {mime:?} of extension {ext:?} is not valid");
+            }
+        }
+
+        for origin in &self.cors_allowed_origins {
+            let is_origin = reqwest::Url::parse(origin).is_ok_and(|url| {
+                matches!(url.scheme(), "http" | "https")
+                    && url.origin().ascii_serialization() == *origin
+            });
+            if !is_origin {
+                eyre::bail!(
+                    "CORS origin {origin:?} is not valid, expected a scheme and a host without path (https://app.example.com)"
+                );
+            }
+        }
+
         Ok(self)
     }
 
+    /// Resolves a relay alias, the relay inherits the node realm unless it overrides it
     pub fn resolve_alias(&self, value: &str) -> eyre::Result<RelayNode> {
         self.relay_nodes
             .get(value)
             .cloned()
+            .map(|mut relay| {
+                relay.realm = relay.realm.or_else(|| self.realm.clone());
+                relay
+            })
             .ok_or_else(|| eyre::eyre!("Unable to resolve relay node {value} from the value"))
     }
 
@@ -147,30 +782,65 @@ impl NodeConfig {
             .collect()
     }
 
+    /// Whether `user` can at least read `volume`, anonymous volumes can be read by anyone
     pub fn allow(&self, volume: &str, user: &User) -> bool {
-        if let Some(vol) = self.volumes.get(volume) {
-            for uname in &vol.allow {
-                let known_user = self.resolve_user(uname).with_context(|| {
-                    format!(
-                        "Expected to know user {uname:?}: invalid config passed through validation"
-                    )
-                }).unwrap();
+        self.allows_anonymous(volume) || self.access_level(volume, user).is_some()
+    }
 
-                if known_user.eq(user) {
-                    return true;
+    /// Whether `volume` is readable without credentials
+    pub fn allows_anonymous(&self, volume: &str) -> bool {
+        self.volumes.get(volume).is_some_and(|vol| vol.anonymous)
+    }
+
+    /// Highest access granted to `user` on `volume`, none when the credentials do not match,
+    /// `*` grants its access to every configured user, unknown users are skipped
+    pub fn access_level(&self, volume: &str, user: &User) -> Option<Access> {
+        let vol = self.volumes.get(volume)?;
+        let mut granted = None;
+        for Grant {
+            user: uname,
+            access,
+        } in &vol.allow
+        {
+            if uname == ANY_USER {
+                if self
+                    .resolve_user(&user.name)
+                    .is_some_and(|known| known.verify(user))
+                {
+                    granted = granted.max(Some(*access));
                 }
+                continue;
+            }
+
+            // validation rejects these, a grant slipping through it only denies
+            let Some(known_user) = self.resolve_user(uname) else {
+                tracing::warn!("Volume {volume:?} grants access to unknown user {uname:?}");
+                continue;
+            };
+
+            if known_user.verify(user) {
+                granted = granted.max(Some(*access));
             }
         }
 
-        false
+        granted
     }
 
     pub async fn get_initialized_fs_volume(
         &self,
         volume_name: &str,
+    ) -> eyre::Result<Option<AnyFs>> {
+        self.get_initialized_fs_volume_at(volume_name, None).await
+    }
+
+    /// Same as [`Self::get_initialized_fs_volume`], reads being served from `snapshot_root` if any
+    pub async fn get_initialized_fs_volume_at(
+        &self,
+        volume_name: &str,
+        snapshot_root: Option<PathBuf>,
     ) -> eyre::Result<Option<AnyFs>> {
         if let Some(volume) = self.volumes.get(volume_name) {
-            let mut fs = AnyFs::from_volume_item(volume_name, volume);
+            let mut fs = AnyFs::from_volume_item_at(volume_name, volume, snapshot_root)?;
             fs.init().await?;
             return Ok(Some(fs));
         }
@@ -179,6 +849,94 @@ impl NodeConfig {
     }
 }
 
+/// Configuration of a running node, swapped as a whole when reloaded
+///
+/// Readers keep the configuration they got for as long as they hold it, the next ones
+/// see the reloaded one
+#[derive(Debug)]
+pub struct LiveConfig {
+    current: RwLock<Arc<NodeConfig>>,
+}
+
+impl LiveConfig {
+    pub fn new(config: Arc<NodeConfig>) -> Self {
+        Self {
+            current: RwLock::new(config),
+        }
+    }
+
+    pub fn current(&self) -> Arc<NodeConfig> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Swaps in the configuration at `path` once it fully checks out, see [`NodeConfig::reload`]
+    pub async fn reload(&self, path: &Path) -> eyre::Result<()> {
+        let previous = self.current();
+        let mut config = previous.clone();
+        NodeConfig::reload(&mut config, path).await?;
+
+        // a reload never starts applying commands a dry run was reviewing
+        if previous.dry_run && !config.dry_run {
+            tracing::warn!("Still in dry run, leaving it takes a restart");
+            Arc::make_mut(&mut config).dry_run = true;
+        }
+
+        let ignored = previous.restart_only_changes(&config);
+        if !ignored.is_empty() {
+            tracing::warn!(
+                "Changes to {} only take effect after a restart",
+                ignored.join(", ")
+            );
+        }
+        *self.current.write().unwrap() = config;
+
+        Ok(())
+    }
+}
+
+/// Replaces the `${VAR}` and `${VAR:-default}` references of a configuration with the values
+/// given by `lookup`, an unknown variable without default is an error
+pub fn expand_env_vars(
+    content: &str,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> eyre::Result<String> {
+    let mut expanded = String::with_capacity(content.len());
+    let mut rest = content;
+    while let Some(start) = rest.find("${") {
+        expanded.push_str(&rest[..start]);
+        let reference = &rest[start + 2..];
+        let end = reference.find('}').with_context(|| {
+            let line = reference.lines().next().unwrap_or_default();
+            format!("Unclosed reference ${{{line}")
+        })?;
+
+        let (name, default) = match reference[..end].split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (&reference[..end], None),
+        };
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            eyre::bail!("Invalid environment variable name {name:?}");
+        }
+
+        let value = lookup(name)
+            .or_else(|| default.map(|default| default.to_owned()))
+            .with_context(|| format!("Environment variable {name} is not set"))?;
+        expanded.push_str(&value);
+        rest = &reference[end + 1..];
+    }
+    expanded.push_str(rest);
+
+    Ok(expanded)
+}
+
+/// Whether a value can safely be embedded in a file name
+pub fn is_safe_identifier(value: &str) -> bool {
+    !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct NodeIdentifier {
     pub uuid: String,
@@ -1,6 +1,8 @@
 use crate::{
-    config::{NodeConfig, NodeIdentifier},
-    nullfs::Synchronizer,
+    config::{LiveConfig, NodeConfig, NodeIdentifier},
+    nullfs::{
+        DEFAULT_SYNC_CONCURRENCY, EdgeNodes, Synchronizer, hashing, volume_state::VolumeStates,
+    },
 };
 use std::{path::PathBuf, sync::Arc};
 use tokio::signal;
@@ -16,15 +18,28 @@ mod tests;
 
 #[actix_web::main]
 async fn main() -> eyre::Result<()> {
-    let args = std::env::args().collect::<Vec<String>>();
+    let mut args = std::env::args().collect::<Vec<String>>();
+    let dry_run = args.iter().any(|arg| arg == "--dry-run");
+    args.retain(|arg| arg != "--dry-run");
 
     let pkg_name = env!("CARGO_PKG_NAME").replace("-", "_");
     let pkg_version = env!("CARGO_PKG_VERSION");
-    if args.len() < 2 {
-        eprintln!("{pkg_name} {pkg_version}");
-        eprintln!("Usage: {} <config-path>", args[0]);
-        std::process::exit(1);
-    }
+    let (sync_once, diff_only, config_arg) = match args.as_slice() {
+        [_, cmd, path] if cmd == "sync-once" => (true, false, path),
+        [_, cmd, path] if cmd == "diff" => (false, true, path),
+        [_, cmd, path] if cmd == "validate" => std::process::exit(validate(path).await),
+        [_, cmd] if cmd == "hash-password" => return print_password_hash(),
+        [_, path] => (false, false, path),
+        _ => {
+            eprintln!("{pkg_name} {pkg_version}");
+            eprintln!("Usage: {} [--dry-run] <config-path>", args[0]);
+            eprintln!("       {} sync-once [--dry-run] <config-path>", args[0]);
+            eprintln!("       {} diff <config-path>", args[0]);
+            eprintln!("       {} validate <config-path>", args[0]);
+            eprintln!("       {} hash-password", args[0]);
+            std::process::exit(1);
+        }
+    };
 
     if std::env::var("RUST_LOG").is_err() {
         let filter_str = format!("{pkg_name}=info");
@@ -36,25 +51,183 @@ async fn main() -> eyre::Result<()> {
         .with_env_filter(EnvFilter::from_default_env())
         .init();
 
-    let config_path = PathBuf::from(&args[1]);
-    let config = Arc::new(NodeConfig::load_from_file(&config_path).await?);
-    let identifier = Arc::new(NodeIdentifier::load_from_file(&PathBuf::from(format!(
-        ".id-{}",
-        config.name.trim()
-    )))?);
+    let config_path = PathBuf::from(config_arg);
+    let mut config = NodeConfig::load_from_file(&config_path).await?;
+    config.dry_run |= dry_run;
+    if config.dry_run {
+        tracing::warn!("Dry run, the pulled commands are logged and not applied");
+    }
+    let config = Arc::new(config);
+    for user in config
+        .users
+        .iter()
+        .filter(|user| user.has_plaintext_password())
+    {
+        tracing::warn!(
+            "User {:?} has a plaintext password, support for those will be removed, \
+             replace it with the output of `{pkg_name} hash-password`",
+            user.name
+        );
+    }
+    hashing::configure_workers(config.hash_workers);
+    hashing::configure_algo(config.hash_algo);
+    config.create_data_dir()?;
+    let identifier = Arc::new(NodeIdentifier::load_from_file(
+        &config
+            .data_dir()
+            .join(format!(".id-{}", config.name.trim())),
+    )?);
+
+    let states = Arc::new(VolumeStates::load(config.persist_paused.then(|| {
+        config
+            .data_dir()
+            .join(format!(".paused-{}.json", identifier.uuid))
+    }))?);
+
+    if diff_only {
+        let vol2relay = Synchronizer::prepare(&config, &identifier).await?;
+        std::process::exit(diff(&vol2relay).await);
+    }
+
+    if sync_once {
+        let mut vol2relay = Synchronizer::prepare(&config, &identifier).await?;
+        let concurrency = config.sync_concurrency.unwrap_or(DEFAULT_SYNC_CONCURRENCY);
+        let summary =
+            Synchronizer::sync_once(&mut vol2relay, identifier, &states, concurrency).await?;
+        for error in &summary.errors {
+            eprintln!("{error}");
+        }
+        println!("{} :: {}", config.name, summary);
+
+        std::process::exit(if summary.is_success() { 0 } else { 1 });
+    }
 
     let shutdown = CancellationToken::new();
     let shutdown_sync = shutdown.clone();
-    let sconfig = config.clone();
+    let live = Arc::new(LiveConfig::new(config));
+    let slive = live.clone();
     let sidentifier = identifier.clone();
     let shutdown_server = shutdown.clone();
 
-    let _ = tokio::spawn(async move { server::run(sconfig, sidentifier, shutdown_server).await });
-    let _ = tokio::spawn(async move { Synchronizer::run(config, identifier, shutdown_sync).await });
+    let sstates = states.clone();
+
+    let server =
+        tokio::spawn(
+            async move { server::run(slive, sidentifier, sstates, shutdown_server).await },
+        );
+    #[cfg(unix)]
+    tokio::spawn(reload_on_sighup(live.clone(), config_path));
+    let sync =
+        tokio::spawn(
+            async move { Synchronizer::run(live, identifier, states, shutdown_sync).await },
+        );
 
     signal::ctrl_c().await?;
     shutdown.cancel();
-    tracing::warn!("Shutting down everything...");
+    tracing::warn!(
+        "Shutting down, finishing the command being applied and the requests in flight..."
+    );
+
+    // both are bounded by `shutdownGraceSecs`
+    let (server, sync) = tokio::join!(server, sync);
+    for (task, result) in [("Server", server), ("Sync", sync)] {
+        match result {
+            Ok(Err(e)) => tracing::error!("{task} stopped with an error: {e}"),
+            Err(e) => tracing::error!("{task} did not complete: {e}"),
+            Ok(Ok(())) => {}
+        }
+    }
 
     Ok(())
 }
+
+/// Reloads the configuration each time the process receives a SIGHUP
+#[cfg(unix)]
+async fn reload_on_sighup(live: Arc<LiveConfig>, path: PathBuf) -> eyre::Result<()> {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut hangups = signal(SignalKind::hangup())?;
+    while hangups.recv().await.is_some() {
+        tracing::info!("Received SIGHUP, reloading {}", path.display());
+        // failures are logged and leave the current configuration in place
+        live.reload(&path).await.ok();
+    }
+
+    Ok(())
+}
+
+/// Reads a password from stdin and prints its hash, to be used as a user `password`
+fn print_password_hash() -> eyre::Result<()> {
+    eprint!("Password: ");
+    let mut password = String::new();
+    std::io::stdin().read_line(&mut password)?;
+    let password = password.trim_end_matches(['\r', '\n']);
+    if password.is_empty() {
+        eyre::bail!("Password cannot be empty");
+    }
+
+    println!("{}", config::hash_password(password)?);
+    Ok(())
+}
+
+/// Prints the paths where each volume differs from its relays, returns the exit code
+async fn diff(vol2relay: &[EdgeNodes]) -> i32 {
+    let mut diverging = 0;
+    let mut failed = false;
+    for (fs, share_node) in vol2relay.iter().flatten() {
+        let compared = async {
+            let root = fs.volume_root()?;
+            share_node.diverging_paths(fs, &root).await
+        };
+        match compared.await {
+            Ok(paths) => {
+                diverging += paths.len();
+                for path in paths {
+                    println!("{}: {path}", share_node.name);
+                }
+            }
+            Err(e) => {
+                eprintln!(
+                    "{}: could not compare @/{}: {e}",
+                    share_node.name,
+                    fs.get_volume_name()
+                );
+                failed = true;
+            }
+        }
+    }
+
+    match (failed, diverging) {
+        (false, 0) => 0,
+        (false, _) => 1,
+        (true, _) => 2,
+    }
+}
+
+/// Checks the configuration at `path` without starting anything, returns the exit code
+async fn validate(path: &str) -> i32 {
+    let config = match NodeConfig::load_from_file(&PathBuf::from(path)).await {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{path}: invalid configuration");
+            eprintln!("  - {e:#}");
+            return 1;
+        }
+    };
+
+    let problems = config.problems().await;
+    if problems.is_empty() {
+        println!(
+            "{path}: ok ({} volume(s), {} relay(s))",
+            config.volumes.len(),
+            config.relay_nodes.len()
+        );
+        return 0;
+    }
+
+    eprintln!("{path}: {} problem(s)", problems.len());
+    for problem in &problems {
+        eprintln!("  - {problem}");
+    }
+    1
+}
@@ -1,14 +1,18 @@
 use crate::{
-    config::{StoreKind, VolumeItem},
-    nullfs::{self, File, FileStat, NullFs, NullFsPath, local_fs::LocalVolume},
+    config::VolumeItem,
+    nullfs::{
+        self, ByteStream, DirListing, DirPage, File, FileStat, NullFs, NullFsPath,
+        backend::{self, SharedFs},
+    },
 };
 use async_trait::async_trait;
-use std::sync::Arc;
+use std::{ops::Range, path::PathBuf};
 
 #[derive(Clone, Debug)]
 pub struct AnyFs {
     pub volume_name: String,
-    pub fs_instance: Arc<tokio::sync::Mutex<dyn NullFs>>,
+    /// Only [`NullFs::init`] takes the write lock, every other call shares the volume
+    pub fs_instance: SharedFs,
 }
 
 impl AnyFs {}
@@ -16,62 +20,97 @@ impl AnyFs {}
 #[async_trait]
 impl NullFs for AnyFs {
     async fn init(&mut self) -> eyre::Result<()> {
-        let mut fs = self.fs_instance.lock().await;
+        let mut fs = self.fs_instance.write().await;
         fs.init().await
     }
 
     async fn dir(&self, dir: &NullFsPath) -> eyre::Result<Vec<nullfs::File>> {
-        let fs = self.fs_instance.lock().await;
+        let fs = self.fs_instance.read().await;
         fs.dir(dir).await
     }
 
+    async fn dir_page(&self, dir: &NullFsPath, page: &DirPage) -> eyre::Result<DirListing> {
+        let fs = self.fs_instance.read().await;
+        fs.dir_page(dir, page).await
+    }
+
     async fn mkdir(&self, path: &NullFsPath) -> eyre::Result<()> {
-        let fs = self.fs_instance.lock().await;
+        let fs = self.fs_instance.read().await;
         fs.mkdir(path).await
     }
 
     async fn copy(&self, o: &NullFsPath, d: &NullFsPath) -> eyre::Result<()> {
-        let fs = self.fs_instance.lock().await;
+        let fs = self.fs_instance.read().await;
         fs.copy(o, d).await
     }
 
     async fn rename(&self, o: &NullFsPath, d: &NullFsPath) -> eyre::Result<()> {
-        let fs = self.fs_instance.lock().await;
+        let fs = self.fs_instance.read().await;
         fs.rename(o, d).await
     }
 
     async fn stats(&self, path: &NullFsPath) -> eyre::Result<FileStat> {
-        let fs = self.fs_instance.lock().await;
+        let fs = self.fs_instance.read().await;
         fs.stats(path).await
     }
 
     async fn hash(&self, path: &NullFsPath) -> eyre::Result<String> {
-        let fs = self.fs_instance.lock().await;
+        let fs = self.fs_instance.read().await;
         fs.hash(path).await
     }
 
     async fn exists(&self, path: &NullFsPath) -> eyre::Result<bool> {
-        let fs = self.fs_instance.lock().await;
+        let fs = self.fs_instance.read().await;
         fs.exists(path).await
     }
 
+    async fn is_mount_point(&self, path: &NullFsPath) -> eyre::Result<bool> {
+        let fs = self.fs_instance.read().await;
+        fs.is_mount_point(path).await
+    }
+
+    async fn count_children(&self, dir: &NullFsPath) -> eyre::Result<u64> {
+        let fs = self.fs_instance.read().await;
+        fs.count_children(dir).await
+    }
+
+    async fn real_path(&self, path: &NullFsPath) -> eyre::Result<NullFsPath> {
+        let fs = self.fs_instance.read().await;
+        fs.real_path(path).await
+    }
+
     async fn shallow_hash(&self, file: &File) -> eyre::Result<String> {
-        let fs = self.fs_instance.lock().await;
+        let fs = self.fs_instance.read().await;
         fs.shallow_hash(file).await
     }
 
     async fn read(&self, path: &NullFsPath) -> eyre::Result<Vec<u8>> {
-        let fs = self.fs_instance.lock().await;
+        let fs = self.fs_instance.read().await;
         fs.read(path).await
     }
 
+    async fn read_stream(&self, path: &NullFsPath) -> eyre::Result<ByteStream> {
+        let fs = self.fs_instance.read().await;
+        fs.read_stream(path).await
+    }
+
+    async fn read_range(&self, path: &NullFsPath, range: Range<u64>) -> eyre::Result<ByteStream> {
+        let fs = self.fs_instance.read().await;
+        fs.read_range(path, range).await
+    }
+
     async fn write(&self, file: &File, bytes: &[u8]) -> eyre::Result<()> {
-        let fs = self.fs_instance.lock().await;
+        let fs = self.fs_instance.read().await;
         fs.write(file, bytes).await
     }
 
+    async fn write_stream(&self, file: &File, chunks: ByteStream) -> eyre::Result<()> {
+        let fs = self.fs_instance.read().await;
+        fs.write_stream(file, chunks).await
+    }
+
     async fn delete(&self, file: &File) -> eyre::Result<()> {
-        let fs = self.fs_instance.lock().await;
+        let fs = self.fs_instance.read().await;
         fs.delete(file).await
     }
 }
@@ -85,19 +124,19 @@ impl AnyFs {
         NullFsPath::from_to_str(format!("@/{}", self.get_volume_name()))
     }
 
-    pub fn from_volume_item(name: &str, vol: &VolumeItem) -> Self {
-        use tokio::sync::Mutex;
-
-        let fs_impl = Arc::new(Mutex::new(match &vol.store {
-            StoreKind::Local { root } => LocalVolume {
-                name: name.to_owned(),
-                root: root.clone(),
-            },
-        }));
+    pub fn from_volume_item(name: &str, vol: &VolumeItem) -> eyre::Result<Self> {
+        Self::from_volume_item_at(name, vol, None)
+    }
 
-        Self {
+    /// Builds a volume whose reads are served from a filesystem snapshot of its root
+    pub fn from_volume_item_at(
+        name: &str,
+        vol: &VolumeItem,
+        snapshot_root: Option<PathBuf>,
+    ) -> eyre::Result<Self> {
+        Ok(Self {
             volume_name: name.to_owned(),
-            fs_instance: fs_impl as Arc<Mutex<dyn NullFs>>,
-        }
+            fs_instance: backend::build_volume(name, &vol.store, snapshot_root)?,
+        })
     }
 }
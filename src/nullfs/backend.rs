@@ -0,0 +1,136 @@
+use crate::{
+    config::StoreKind,
+    nullfs::{NullFs, local_fs::LocalVolume, mem_fs::MemVolume, s3_fs::S3Volume},
+};
+use eyre::ContextCompat;
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, LazyLock, RwLock},
+};
+
+/// Volume implementation wrapped by an [`super::any_fs::AnyFs`]
+pub type SharedFs = Arc<tokio::sync::RwLock<dyn NullFs>>;
+
+/// Builds the volumes of one `store.type`
+pub trait BackendFactory: Send + Sync {
+    /// `snapshot_root` is only set for volumes configured with an `fsSnapshot` hook
+    fn build(
+        &self,
+        name: &str,
+        store: &StoreKind,
+        snapshot_root: Option<PathBuf>,
+    ) -> eyre::Result<SharedFs>;
+}
+
+impl<F> BackendFactory for F
+where
+    F: Fn(&str, &StoreKind, Option<PathBuf>) -> eyre::Result<SharedFs> + Send + Sync,
+{
+    fn build(
+        &self,
+        name: &str,
+        store: &StoreKind,
+        snapshot_root: Option<PathBuf>,
+    ) -> eyre::Result<SharedFs> {
+        self(name, store, snapshot_root)
+    }
+}
+
+static BACKENDS: LazyLock<RwLock<HashMap<String, Arc<dyn BackendFactory>>>> = LazyLock::new(|| {
+    let builtins: [(&str, Arc<dyn BackendFactory>); 3] = [
+        ("local", Arc::new(build_local)),
+        ("s3", Arc::new(build_s3)),
+        ("memory", Arc::new(build_memory)),
+    ];
+
+    RwLock::new(
+        builtins
+            .into_iter()
+            .map(|(kind, factory)| (kind.to_owned(), factory))
+            .collect(),
+    )
+});
+
+/// Registers the factory building the volumes whose `store.type` is `kind`,
+/// a previous registration for the same type is replaced
+#[allow(unused)]
+pub fn register_backend(kind: &str, factory: impl BackendFactory + 'static) {
+    BACKENDS
+        .write()
+        .unwrap()
+        .insert(kind.to_owned(), Arc::new(factory));
+}
+
+pub fn is_registered(kind: &str) -> bool {
+    BACKENDS.read().unwrap().contains_key(kind)
+}
+
+/// Builds the volume `name` with the factory registered for its store type
+pub fn build_volume(
+    name: &str,
+    store: &StoreKind,
+    snapshot_root: Option<PathBuf>,
+) -> eyre::Result<SharedFs> {
+    let factory = BACKENDS
+        .read()
+        .unwrap()
+        .get(store.kind())
+        .cloned()
+        .with_context(|| format!("No backend registered for store type {:?}", store.kind()))?;
+
+    factory.build(name, store, snapshot_root)
+}
+
+fn build_local(
+    name: &str,
+    store: &StoreKind,
+    snapshot_root: Option<PathBuf>,
+) -> eyre::Result<SharedFs> {
+    let StoreKind::Local {
+        root,
+        follow_symlinks,
+        trash,
+        trash_retention_days,
+    } = store
+    else {
+        eyre::bail!("Expected a local store, got {:?}", store.kind());
+    };
+
+    Ok(Arc::new(tokio::sync::RwLock::new(LocalVolume {
+        name: name.to_owned(),
+        root: root.clone(),
+        snapshot_root,
+        follow_symlinks: *follow_symlinks,
+        trash: *trash,
+        trash_retention_days: *trash_retention_days,
+    })))
+}
+
+fn build_s3(name: &str, store: &StoreKind, _: Option<PathBuf>) -> eyre::Result<SharedFs> {
+    let StoreKind::S3 {
+        bucket,
+        prefix,
+        region,
+        endpoint,
+    } = store
+    else {
+        eyre::bail!("Expected a s3 store, got {:?}", store.kind());
+    };
+
+    Ok(Arc::new(tokio::sync::RwLock::new(S3Volume::new(
+        name,
+        bucket,
+        prefix.clone(),
+        region.clone(),
+        endpoint.clone(),
+    ))))
+}
+
+fn build_memory(name: &str, store: &StoreKind, _: Option<PathBuf>) -> eyre::Result<SharedFs> {
+    let StoreKind::Memory = store else {
+        eyre::bail!("Expected a memory store, got {:?}", store.kind());
+    };
+
+    Ok(Arc::new(tokio::sync::RwLock::new(MemVolume::new(name))))
+}
@@ -0,0 +1,161 @@
+use crate::nullfs::{ByteStream, hashing};
+use futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, ops::Range};
+
+/// Chunks are never cut shorter than this, except for the last one of a file
+pub const MIN_CHUNK_SIZE: usize = 16 * 1024;
+
+/// Chunks are cut at this size when no cut point showed up before
+pub const MAX_CHUNK_SIZE: usize = 256 * 1024;
+
+/// A cut point is where the top bits of the rolling hash are all zero, 16 bits make the
+/// chunks 64 KiB long on average
+const CUT_MASK: u64 = !0 << 48;
+
+/// Random values mixed into the rolling hash for each byte, both ends of a transfer have to
+/// agree on them
+const GEAR: [u64; 256] = {
+    let mut table = [0; 256];
+    let mut seed: u64 = 0;
+    let mut i = 0;
+    while i < table.len() {
+        // splitmix64
+        seed = seed.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = seed;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+
+    table
+};
+
+/// A content defined slice of a file, cut points only depend on the bytes around them so an
+/// edit only changes the chunks it touches
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Chunk {
+    pub offset: u64,
+    pub len: u64,
+    /// CRC32 of the chunk, tells most chunks apart before their strong hashes are compared
+    pub weak_hash: u32,
+    /// Hash of the chunk with the configured algorithm, see [`hashing::digest`]
+    pub strong_hash: String,
+}
+
+impl Chunk {
+    pub fn range(&self) -> Range<u64> {
+        self.offset..self.offset + self.len
+    }
+}
+
+/// Cuts a file into [`Chunk`]s as its bytes arrive
+#[derive(Default)]
+pub struct Chunker {
+    chunks: Vec<Chunk>,
+    offset: u64,
+    current: Vec<u8>,
+    rolling: u64,
+}
+
+impl Chunker {
+    pub fn update(&mut self, data: &[u8]) {
+        for byte in data {
+            self.current.push(*byte);
+            self.rolling = (self.rolling << 1).wrapping_add(GEAR[*byte as usize]);
+
+            let len = self.current.len();
+            if (len >= MIN_CHUNK_SIZE && self.rolling & CUT_MASK == 0) || len >= MAX_CHUNK_SIZE {
+                self.cut();
+            }
+        }
+    }
+
+    fn cut(&mut self) {
+        if self.current.is_empty() {
+            return;
+        }
+
+        let len = self.current.len() as u64;
+        self.chunks.push(Chunk {
+            offset: self.offset,
+            len,
+            weak_hash: crc32fast::hash(&self.current),
+            strong_hash: hashing::digest(&self.current),
+        });
+        self.offset += len;
+        self.current.clear();
+        self.rolling = 0;
+    }
+
+    pub fn finish(mut self) -> Vec<Chunk> {
+        self.cut();
+        self.chunks
+    }
+}
+
+/// Chunks of the content read from `stream`
+pub async fn chunks_of(mut stream: ByteStream) -> eyre::Result<Vec<Chunk>> {
+    let mut chunker = Chunker::default();
+    while let Some(bytes) = stream.try_next().await? {
+        chunker.update(&bytes);
+    }
+
+    Ok(chunker.finish())
+}
+
+/// Where the bytes of a rebuilt file come from, in order
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Piece {
+    /// Already in the local copy
+    Local(Range<u64>),
+    /// To be downloaded from the remote file
+    Remote(Range<u64>),
+}
+
+/// Pieces rebuilding the file chunked as `remote` out of the one chunked as `local`, the
+/// contiguous ranges of a same side are merged so that they are read or downloaded at once
+pub fn plan(local: &[Chunk], remote: &[Chunk]) -> Vec<Piece> {
+    let mut by_weak_hash = HashMap::<u32, Vec<&Chunk>>::new();
+    for chunk in local {
+        by_weak_hash.entry(chunk.weak_hash).or_default().push(chunk);
+    }
+
+    let mut pieces: Vec<Piece> = vec![];
+    for chunk in remote {
+        let known = by_weak_hash.get(&chunk.weak_hash).and_then(|candidates| {
+            candidates.iter().find(|candidate| {
+                candidate.len == chunk.len && candidate.strong_hash == chunk.strong_hash
+            })
+        });
+        let piece = match known {
+            Some(known) => Piece::Local(known.range()),
+            None => Piece::Remote(chunk.range()),
+        };
+
+        match (pieces.last_mut(), piece) {
+            (Some(Piece::Local(last)), Piece::Local(next))
+            | (Some(Piece::Remote(last)), Piece::Remote(next))
+                if last.end == next.start =>
+            {
+                last.end = next.end;
+            }
+            (_, piece) => pieces.push(piece),
+        }
+    }
+
+    pieces
+}
+
+/// Bytes the `pieces` download
+pub fn remote_bytes(pieces: &[Piece]) -> u64 {
+    pieces
+        .iter()
+        .map(|piece| match piece {
+            Piece::Remote(range) => range.end - range.start,
+            Piece::Local(_) => 0,
+        })
+        .sum()
+}
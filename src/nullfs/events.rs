@@ -0,0 +1,47 @@
+use serde::Serialize;
+
+/// Events kept for the subscribers that fall behind, older ones are dropped past this
+pub const EVENTS_CAPACITY: usize = 1024;
+
+/// What the synchronizer just did, streamed by `/v1/events`
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum SyncEvent {
+    /// Pulled from a relay, to be applied
+    CommandStashed {
+        volume: String,
+        command: String,
+    },
+    CommandApplied {
+        volume: String,
+        command: String,
+        bytes: u64,
+    },
+    /// Retried later, see [`super::share::CommandStash::mark_failed`]
+    CommandFailed {
+        volume: String,
+        command: String,
+        error: String,
+    },
+    /// A relay answered its liveness probe after failing it, or for the first time
+    RelayUp {
+        volume: String,
+        relay: String,
+    },
+    RelayDown {
+        volume: String,
+        relay: String,
+    },
+}
+
+impl SyncEvent {
+    pub fn volume(&self) -> &str {
+        match self {
+            Self::CommandStashed { volume, .. }
+            | Self::CommandApplied { volume, .. }
+            | Self::CommandFailed { volume, .. }
+            | Self::RelayUp { volume, .. }
+            | Self::RelayDown { volume, .. } => volume,
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use crate::config::FsSnapshotHook;
+use eyre::Context;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+
+#[derive(Clone, Debug)]
+struct ActiveSnapshot {
+    mount: PathBuf,
+    taken: Instant,
+}
+
+/// Point-in-time filesystem snapshots currently served in place of the live volume roots
+#[derive(Debug, Default)]
+pub struct FsSnapshots {
+    active: Mutex<HashMap<String, ActiveSnapshot>>,
+}
+
+impl FsSnapshots {
+    pub async fn mount_of(&self, volume: &str) -> Option<PathBuf> {
+        let active = self.active.lock().await;
+        active.get(volume).map(|snapshot| snapshot.mount.clone())
+    }
+
+    /// Takes a new snapshot of `root` unless the current one is recent enough, the replaced
+    /// snapshot is destroyed
+    pub async fn refresh(
+        &self,
+        volume: &str,
+        root: &Path,
+        hook: &FsSnapshotHook,
+    ) -> eyre::Result<PathBuf> {
+        let mut active = self.active.lock().await;
+        let max_age = Duration::from_secs(hook.max_age_secs.unwrap_or(60));
+        if let Some(current) = active.get(volume)
+            && current.taken.elapsed() < max_age
+        {
+            return Ok(current.mount.clone());
+        }
+
+        let output = run_hook(&hook.create, volume, root, None).await?;
+        let mount = output
+            .lines()
+            .rev()
+            .map(|line| line.trim())
+            .find(|line| !line.is_empty())
+            .map(PathBuf::from)
+            .ok_or_else(|| {
+                eyre::eyre!("Snapshot hook of @/{volume} did not output a mount path")
+            })?;
+
+        if !mount.is_dir() {
+            eyre::bail!(
+                "Snapshot mount {} of @/{volume} is not a directory",
+                mount.display()
+            );
+        }
+
+        tracing::info!("Serving @/{} from snapshot {}", volume, mount.display());
+        let previous = active.insert(
+            volume.to_owned(),
+            ActiveSnapshot {
+                mount: mount.clone(),
+                taken: Instant::now(),
+            },
+        );
+
+        if let Some(previous) = previous
+            && previous.mount != mount
+            && let Some(destroy) = &hook.destroy
+            && let Err(e) = run_hook(destroy, volume, root, Some(&previous.mount)).await
+        {
+            tracing::error!("Failed to destroy snapshot of @/{volume}: {e}");
+        }
+
+        Ok(mount)
+    }
+
+    /// Destroys every active snapshot
+    pub async fn release_all(&self, hooks: impl Fn(&str) -> Option<(PathBuf, FsSnapshotHook)>) {
+        let mut active = self.active.lock().await;
+        for (volume, snapshot) in active.drain() {
+            if let Some((root, hook)) = hooks(&volume)
+                && let Some(destroy) = &hook.destroy
+                && let Err(e) = run_hook(destroy, &volume, &root, Some(&snapshot.mount)).await
+            {
+                tracing::error!("Failed to destroy snapshot of @/{volume}: {e}");
+            }
+        }
+    }
+}
+
+/// Runs a hook through the system shell, returns its standard output
+async fn run_hook(
+    command: &str,
+    volume: &str,
+    root: &Path,
+    snapshot: Option<&Path>,
+) -> eyre::Result<String> {
+    let mut process = if cfg!(windows) {
+        let mut process = tokio::process::Command::new("cmd");
+        process.arg("/C").arg(command);
+        process
+    } else {
+        let mut process = tokio::process::Command::new("sh");
+        process.arg("-c").arg(command);
+        process
+    };
+
+    process
+        .env("NULLFS_VOLUME", volume)
+        .env("NULLFS_ROOT", root);
+    if let Some(snapshot) = snapshot {
+        process.env("NULLFS_SNAPSHOT", snapshot);
+    }
+
+    let output = process
+        .output()
+        .await
+        .wrap_err_with(|| format!("Running snapshot hook {command:?}"))?;
+
+    if !output.status.success() {
+        eyre::bail!(
+            "Snapshot hook {:?} failed with {}: {}",
+            command,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
@@ -0,0 +1,276 @@
+use crate::nullfs::{File, FileStat, NodeKind, NullFs, NullFsPath, any_fs::AnyFs};
+use async_recursion::async_recursion;
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    io::Read,
+    path::PathBuf,
+    sync::{
+        Arc, OnceLock,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+use tokio::sync::Semaphore;
+
+/// Files under this size are hashed inline, offloading them costs more than it saves
+pub const OFFLOAD_THRESHOLD: u64 = 256 * 1024;
+
+/// Algorithm of the content and Merkle hashes, a relay and its pullers have to agree on it
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgo {
+    #[default]
+    Sha256,
+    Blake3,
+}
+
+impl HashAlgo {
+    pub fn name(&self) -> &'static str {
+        match self {
+            HashAlgo::Sha256 => "sha256",
+            HashAlgo::Blake3 => "blake3",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        [HashAlgo::Sha256, HashAlgo::Blake3]
+            .into_iter()
+            .find(|algo| algo.name() == name)
+    }
+}
+
+static ALGO: OnceLock<HashAlgo> = OnceLock::new();
+
+/// Sets the algorithm of the hashes computed by this node, defaults to sha256,
+/// only the first call has an effect
+pub fn configure_algo(algo: Option<HashAlgo>) {
+    let algo = algo.unwrap_or_default();
+    if ALGO.set(algo).is_ok() {
+        tracing::debug!("Hashing with {}", algo.name());
+    }
+}
+
+pub fn algo() -> HashAlgo {
+    ALGO.get().copied().unwrap_or_default()
+}
+
+/// Incremental hasher of the configured algorithm, the digest is lowercase hex
+pub enum ContentHasher {
+    Sha256(Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl ContentHasher {
+    pub fn new() -> Self {
+        Self::with_algo(algo())
+    }
+
+    pub fn with_algo(algo: HashAlgo) -> Self {
+        match algo {
+            HashAlgo::Sha256 => Self::Sha256(Sha256::new()),
+            HashAlgo::Blake3 => Self::Blake3(Box::default()),
+        }
+    }
+
+    pub fn update(&mut self, data: impl AsRef<[u8]>) {
+        match self {
+            Self::Sha256(hasher) => hasher.update(data),
+            Self::Blake3(hasher) => {
+                hasher.update(data.as_ref());
+            }
+        }
+    }
+
+    pub fn finalize(self) -> String {
+        match self {
+            Self::Sha256(hasher) => format!("{:x}", hasher.finalize()),
+            Self::Blake3(hasher) => hasher.finalize().to_hex().to_string(),
+        }
+    }
+}
+
+impl Default for ContentHasher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Hash of `data` with the configured algorithm
+pub fn digest(data: impl AsRef<[u8]>) -> String {
+    let mut hasher = ContentHasher::new();
+    hasher.update(data);
+    hasher.finalize()
+}
+
+/// Hash of a directory from the `(path, hash)` of its children, sorted so that two
+/// identical trees hash equal whatever the listing order
+pub fn merkle_hash<'a>(children: impl IntoIterator<Item = (&'a NullFsPath, &'a str)>) -> String {
+    let mut children = children
+        .into_iter()
+        .map(|(path, hash)| (path.to_string(), hash))
+        .collect::<Vec<_>>();
+    children.sort();
+
+    let mut hasher = ContentHasher::new();
+    for (path, hash) in children {
+        hasher.update(path);
+        hasher.update(hash);
+    }
+
+    hasher.finalize()
+}
+
+/// Hash of the metadata of `file` and of its direct `entries`, see [`NullFs::shallow_hash`]
+pub fn shallow_hash(file: &File, mut entries: Vec<File>) -> String {
+    let mut hasher = ContentHasher::new();
+    hasher.update(stat_line(&file.stat));
+
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    for entry in entries {
+        hasher.update(format!("{}\n", entry.path));
+        hasher.update(stat_line(&entry.stat));
+    }
+
+    hasher.finalize()
+}
+
+fn stat_line(stat: &FileStat) -> String {
+    match &stat.node {
+        NodeKind::File { size } => format!("file {size} {}\n", stat.modified),
+        NodeKind::Dir => format!("dir {}\n", stat.modified),
+        NodeKind::Symlink { target } => format!("link {target} {}\n", stat.modified),
+    }
+}
+
+/// Hash of a path along with the trees of its entries, `children` is empty for files
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct HashTree {
+    pub path: NullFsPath,
+    pub hash: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<HashTree>,
+}
+
+impl HashTree {
+    /// Hashes every file below `path` once, directories get the hash [`NullFs::hash`] gives them
+    #[async_recursion]
+    pub async fn build(fs: &AnyFs, path: &NullFsPath) -> eyre::Result<Self> {
+        if !fs.stats(path).await?.is_dir() {
+            return Ok(Self {
+                path: path.clone(),
+                hash: fs.hash(path).await?,
+                children: vec![],
+            });
+        }
+
+        let mut children = vec![];
+        for entry in fs.dir(path).await? {
+            children.push(Self::build(fs, &entry.path).await?);
+        }
+
+        Ok(Self {
+            path: path.clone(),
+            hash: merkle_hash(
+                children
+                    .iter()
+                    .map(|child| (&child.path, child.hash.as_str())),
+            ),
+            children,
+        })
+    }
+
+    /// Paths where the two trees diverge, subtrees of matching hashes are not descended into
+    /// and an entry missing on one side is reported without its content
+    pub fn diff(&self, other: &HashTree) -> Vec<NullFsPath> {
+        if self.hash == other.hash {
+            return vec![];
+        }
+
+        if self.children.is_empty() || other.children.is_empty() {
+            return vec![self.path.clone()];
+        }
+
+        let theirs = other
+            .children
+            .iter()
+            .map(|child| (&child.path, child))
+            .collect::<IndexMap<_, _>>();
+        let mut diverging = vec![];
+        for child in &self.children {
+            match theirs.get(&child.path) {
+                Some(their) => diverging.extend(child.diff(their)),
+                None => diverging.push(child.path.clone()),
+            }
+        }
+
+        let ours = self
+            .children
+            .iter()
+            .map(|child| &child.path)
+            .collect::<std::collections::HashSet<_>>();
+        diverging.extend(
+            other
+                .children
+                .iter()
+                .filter(|child| !ours.contains(&child.path))
+                .map(|child| child.path.clone()),
+        );
+
+        diverging
+    }
+}
+
+static WORKERS: OnceLock<Semaphore> = OnceLock::new();
+
+/// Sets the amount of files that can be hashed concurrently off the async runtime,
+/// defaults to the available parallelism, only the first call has an effect
+pub fn configure_workers(workers: Option<usize>) {
+    let workers = workers
+        .or_else(|| std::thread::available_parallelism().ok().map(|n| n.get()))
+        .unwrap_or(4)
+        .max(1);
+
+    if WORKERS.set(Semaphore::new(workers)).is_ok() {
+        tracing::debug!("Hashing with {workers} worker(s)");
+    }
+}
+
+/// Flags the blocking job as cancelled when the awaiting future is dropped
+struct CancelOnDrop(Arc<AtomicBool>);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Hashes a file on the blocking pool, dropping the returned future stops the job
+pub async fn hash_file_offloaded(path: PathBuf) -> eyre::Result<String> {
+    configure_workers(None);
+    let _permit = WORKERS.get().unwrap().acquire().await?;
+
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let _guard = CancelOnDrop(cancelled.clone());
+
+    tokio::task::spawn_blocking(move || {
+        let mut file = std::fs::File::open(&path)?;
+        let mut hasher = ContentHasher::new();
+        let mut buffer = vec![0u8; 64 * 1024];
+
+        loop {
+            if cancelled.load(Ordering::Relaxed) {
+                eyre::bail!("Hashing {} was cancelled", path.display());
+            }
+
+            let n = file.read(&mut buffer)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buffer[..n]);
+        }
+
+        Ok(hasher.finalize())
+    })
+    .await?
+}
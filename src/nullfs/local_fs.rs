@@ -1,41 +1,423 @@
 use crate::nullfs::{
-    self, File, FileStat, FileType, NodeKind, NullFs, NullFsPath, systime_to_millis,
+    self, ByteStream, DirListing, DirPage, FILE_TYPE_SNIFF_LEN, File, FileStat, FileType, NodeKind,
+    NullFs, NullFsPath, SortKey, hashing, systime_to_millis,
 };
+use async_recursion::async_recursion;
 use async_trait::async_trait;
+use chrono::Utc;
 use eyre::{Context, ContextCompat};
+use futures::{Stream, StreamExt, TryStreamExt};
 use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha256};
-use std::path::{Path, PathBuf};
-use tokio::io::AsyncReadExt;
+use std::{
+    collections::HashSet,
+    fs::FileTimes,
+    io::SeekFrom,
+    ops::Range,
+    path::{Component, Path, PathBuf},
+    time::{Duration, UNIX_EPOCH},
+};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio_util::io::ReaderStream;
+
+/// Suffix of the hidden sibling a file is written to before being renamed into place
+pub const TEMP_SUFFIX: &str = ".nullfs-tmp";
+
+/// Directory, relative to the volume root, the deleted entries are moved to when trashed
+pub const TRASH_DIR: &str = ".nullfs-trash";
+
+/// Days the trashed entries are kept by default
+pub const DEFAULT_TRASH_RETENTION_DAYS: u64 = 30;
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct LocalVolume {
     pub name: String,
     pub root: PathBuf,
+    /// Read-only point-in-time copy of `root` to serve reads from, writes still go to `root`
+    #[serde(default)]
+    pub snapshot_root: Option<PathBuf>,
+    /// Links are read through when set, otherwise they are listed as [`NodeKind::Symlink`]
+    #[serde(default)]
+    pub follow_symlinks: bool,
+    /// Deleted entries are moved to `<root>/.nullfs-trash/<timestamp>/` instead of removed
+    #[serde(default)]
+    pub trash: bool,
+    #[serde(default)]
+    pub trash_retention_days: Option<u64>,
 }
 
 impl LocalVolume {
+    /// `a/b.txt` => `a/.b.txt.nullfs-tmp`
+    fn temp_sibling(path: &Path) -> PathBuf {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        path.with_file_name(format!(".{name}{TEMP_SUFFIX}"))
+    }
+
+    /// Type of the file at `path` from its extension, its first bytes are only read when the
+    /// extension tells nothing, a file that cannot be read stays [`FileType::Unkown`]
+    async fn file_type(path: &Path, vpath: &NullFsPath, stat: &FileStat) -> FileType {
+        let file_type = FileType::infer_from_path(vpath);
+        if file_type != FileType::Unkown || !matches!(stat.node, NodeKind::File { .. }) {
+            return file_type;
+        }
+
+        let mut head = vec![];
+        match tokio::fs::File::open(path).await {
+            Ok(file) => match file
+                .take(FILE_TYPE_SNIFF_LEN as u64)
+                .read_to_end(&mut head)
+                .await
+            {
+                Ok(_) => FileType::infer_from_bytes(&head),
+                Err(_) => file_type,
+            },
+            Err(_) => file_type,
+        }
+    }
+
+    /// Writes `file` chunk by chunk to a hidden sibling renamed into place once complete, a
+    /// failing chunk leaves the previous content as is
+    async fn write_chunks<B: AsRef<[u8]>>(
+        &self,
+        file: &File,
+        chunks: impl Stream<Item = eyre::Result<B>> + Send,
+    ) -> eyre::Result<()> {
+        let path = self.resolve(&file.path)?;
+
+        match &file.stat.node {
+            NodeKind::Dir => tokio::fs::create_dir_all(&path)
+                .await
+                .map_err(eyre::Report::from),
+            NodeKind::Symlink { target } => self.link(&path, target).await,
+            NodeKind::File { .. } => {
+                if let Some(parent) = path.parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+
+                // readers see either the previous content or the new one, never a partial write
+                let temp = Self::temp_sibling(&path);
+                let written = async {
+                    let mut chunks = std::pin::pin!(chunks);
+                    let mut out = tokio::fs::File::create(&temp).await?;
+                    while let Some(chunk) = chunks.try_next().await? {
+                        out.write_all(chunk.as_ref()).await?;
+                    }
+                    out.into_std()
+                        .await
+                        .set_times(Self::file_times(&file.stat))?;
+                    tokio::fs::rename(&temp, &path).await?;
+                    eyre::Ok(())
+                }
+                .await;
+                if written.is_err() {
+                    tokio::fs::remove_file(&temp).await.ok();
+                }
+
+                written
+            }
+        }
+        .wrap_err_with(|| format!("Writing ({:?}) {}", file.stat.node, path.display()))
+    }
+
+    /// Points the link at `path` to `target`, replacing whatever is there, a relative target
+    /// is relative to the directory of the link and must stay within the volume
+    async fn link(&self, path: &Path, target: &str) -> eyre::Result<()> {
+        let parent = path.parent().unwrap_or(&self.root);
+        let resolved = Self::normalize_lexically(&parent.join(target));
+        if !resolved.starts_with(&self.root) {
+            eyre::bail!(
+                "Link target {target} is outside of the volume root {}",
+                self.root.display()
+            );
+        }
+
+        tokio::fs::create_dir_all(parent).await?;
+        match tokio::fs::symlink_metadata(path).await {
+            Ok(metadata) if metadata.is_dir() => tokio::fs::remove_dir_all(path).await?,
+            Ok(_) => tokio::fs::remove_file(path).await?,
+            Err(_) => {}
+        }
+
+        #[cfg(unix)]
+        tokio::fs::symlink(target, path).await?;
+        #[cfg(windows)]
+        match resolved.is_dir() {
+            true => tokio::fs::symlink_dir(target, path).await?,
+            false => tokio::fs::symlink_file(target, path).await?,
+        }
+
+        Ok(())
+    }
+
+    /// `a/b/../c` => `a/c`, without reading the filesystem
+    fn normalize_lexically(path: &Path) -> PathBuf {
+        let mut normalized = PathBuf::new();
+        for comp in path.components() {
+            match comp {
+                Component::ParentDir => {
+                    normalized.pop();
+                }
+                Component::CurDir => {}
+                comp => normalized.push(comp),
+            }
+        }
+
+        normalized
+    }
+
+    /// Where `path` leads once every link along it is followed
+    fn real(&self, path: &NullFsPath) -> eyre::Result<PathBuf> {
+        let path = self.resolve_read(path)?;
+        let real = path
+            .canonicalize()
+            .wrap_err_with(|| format!("Resolving {}", path.display()))?;
+
+        Ok(Self::strip_extended_prefix(real))
+    }
+
+    /// Target of the link at `path` when links are not followed
+    async fn link_target(&self, path: &Path) -> eyre::Result<Option<String>> {
+        if self.follow_symlinks || !path.is_symlink() {
+            return Ok(None);
+        }
+
+        let target = tokio::fs::read_link(path)
+            .await
+            .wrap_err_with(|| format!("Reading link {}", path.display()))?;
+
+        Ok(Some(target.to_string_lossy().into_owned()))
+    }
+
+    /// [`NullFs::hash`] of `path`, `ancestors` are the directories being hashed already, a
+    /// followed link back to one of them is left out instead of being walked forever
+    #[async_recursion]
+    async fn hash_within(
+        &self,
+        path: &NullFsPath,
+        ancestors: &mut HashSet<PathBuf>,
+    ) -> eyre::Result<String> {
+        let resolved_path = self.resolve_read(path)?;
+        if let Some(target) = self.link_target(&resolved_path).await? {
+            return Ok(hashing::digest(target));
+        }
+
+        let mut hasher = hashing::ContentHasher::new();
+        let mut buffer = [0u8; 8 * 1024];
+        if resolved_path.is_dir() {
+            let real = self.real(path)?;
+            ancestors.insert(real.clone());
+
+            let mut children = vec![];
+            for entry in self.dir(path).await? {
+                if entry.stat.is_dir() && ancestors.contains(&self.real(&entry.path)?) {
+                    tracing::warn!("Not hashing {}, it links back to a parent", entry.path);
+                    continue;
+                }
+
+                let hash = self.hash_within(&entry.path, ancestors).await?;
+                children.push((entry.path, hash));
+            }
+            ancestors.remove(&real);
+
+            return Ok(hashing::merkle_hash(
+                children.iter().map(|(path, hash)| (path, hash.as_str())),
+            ));
+        } else {
+            let size = tokio::fs::metadata(&resolved_path).await?.len();
+            if size >= hashing::OFFLOAD_THRESHOLD {
+                return hashing::hash_file_offloaded(resolved_path).await;
+            }
+
+            let file = tokio::fs::File::open(resolved_path).await?;
+            let mut reader = tokio::io::BufReader::new(file);
+
+            while let Ok(n) = reader.read(&mut buffer).await {
+                if n == 0 {
+                    break;
+                }
+                hasher.update(&buffer[..n]);
+            }
+        }
+
+        Ok(hasher.finalize())
+    }
+
+    /// Moves `path` to a directory of the trash named after the current time, its parents
+    /// relative to the root are kept so that it can be put back where it was
+    async fn move_to_trash(&self, path: &Path) -> eyre::Result<PathBuf> {
+        let rel = path.strip_prefix(&self.root)?;
+        if rel.as_os_str().is_empty() {
+            eyre::bail!("Cannot trash the volume root {}", self.root.display());
+        }
+
+        let stamp = Utc::now().format("%Y-%m-%dT%H-%M-%S%.3fZ").to_string();
+        let trashed = self.root.join(TRASH_DIR).join(stamp).join(rel);
+        if let Some(parent) = trashed.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::rename(path, &trashed).await?;
+
+        Ok(trashed)
+    }
+
+    /// Removes the directories of the trash older than the retention, returns how many
+    pub async fn prune_trash(&self) -> eyre::Result<usize> {
+        let trash = self.root.join(TRASH_DIR);
+        if !trash.is_dir() {
+            return Ok(0);
+        }
+
+        let retention_days = self
+            .trash_retention_days
+            .unwrap_or(DEFAULT_TRASH_RETENTION_DAYS);
+        let max_age = Duration::from_secs(retention_days * 24 * 3600);
+        let mut entries = tokio::fs::read_dir(&trash)
+            .await
+            .with_context(|| format!("Reading directory {}", trash.display()))?;
+
+        let mut pruned = 0;
+        while let Some(entry) = entries.next_entry().await? {
+            let age = entry
+                .metadata()
+                .await?
+                .modified()?
+                .elapsed()
+                .unwrap_or_default();
+            if age > max_age {
+                tokio::fs::remove_dir_all(entry.path())
+                    .await
+                    .with_context(|| format!("Removing {}", entry.path().display()))?;
+                pruned += 1;
+            }
+        }
+
+        Ok(pruned)
+    }
+
+    /// Times of `stat` to stamp a written file with, so that the next capture sees it unchanged
+    fn file_times(stat: &FileStat) -> FileTimes {
+        #[allow(unused_mut)]
+        let mut times =
+            FileTimes::new().set_modified(UNIX_EPOCH + Duration::from_millis(stat.modified));
+        #[cfg(windows)]
+        if let Some(created) = stat.created {
+            use std::os::windows::fs::FileTimesExt;
+            times = times.set_created(UNIX_EPOCH + Duration::from_millis(created));
+        }
+        #[cfg(target_os = "macos")]
+        if let Some(created) = stat.created {
+            use std::os::macos::fs::FileTimesExt;
+            times = times.set_created(UNIX_EPOCH + Duration::from_millis(created));
+        }
+
+        times
+    }
+
+    fn read_root(&self) -> &Path {
+        self.snapshot_root.as_deref().unwrap_or(&self.root)
+    }
+
+    /// `@/vol_name/b/c` =>` C:/some/snapshot/b/c`, for read operations
+    fn resolve_read(&self, path: &NullFsPath) -> eyre::Result<PathBuf> {
+        let resolved = self.read_root().join(self.resolve_rel(path)?);
+        Self::ensure_within(self.read_root(), &resolved)?;
+
+        Ok(resolved)
+    }
+
     /// `@/vol_name/b/c` =>` C:/some/root/b/c`
     fn resolve(&self, path: &NullFsPath) -> eyre::Result<PathBuf> {
-        let mut components = path.components().into_iter();
+        let resolved = self.canonicalize(&self.resolve_rel(path)?)?;
+        Self::ensure_within(&self.root, &resolved)?;
 
-        if let Some(comp) = components.next() {
-            if comp.ne(&self.name) {
-                eyre::bail!(
-                    "Wrong volume: first component is expected to be @/{}, got @/{} instead",
-                    self.name,
-                    comp
-                );
+        Ok(resolved)
+    }
+
+    /// Fails when `path` leads out of `root`, symlinks included, the part of `path` that
+    /// does not exist yet is checked from the closest existing ancestor
+    fn ensure_within(root: &Path, path: &Path) -> eyre::Result<()> {
+        let outside = || {
+            eyre::eyre!(
+                "Path {} is outside of the volume root {}",
+                path.display(),
+                root.display()
+            )
+        };
+
+        if path.components().any(|comp| comp == Component::ParentDir) {
+            return Err(outside());
+        }
+
+        let mut existing = path;
+        while std::fs::symlink_metadata(existing).is_err() {
+            match existing.parent() {
+                Some(parent) => existing = parent,
+                None => break,
             }
         }
 
+        let root = Self::strip_extended_prefix(
+            root.canonicalize()
+                .wrap_err_with(|| format!("Resolving root {}", root.display()))?,
+        );
+        let existing = Self::strip_extended_prefix(
+            existing
+                .canonicalize()
+                .wrap_err_with(|| format!("Resolving {}", existing.display()))?,
+        );
+        if !existing.starts_with(&root) {
+            return Err(outside());
+        }
+
+        Ok(())
+    }
+
+    /// `@/vol_name/b/c` =>` b/c`
+    fn resolve_rel(&self, path: &NullFsPath) -> eyre::Result<PathBuf> {
+        let mut components = path.components().into_iter();
+
+        if let Some(comp) = components.next()
+            && comp.ne(&self.name)
+        {
+            eyre::bail!(
+                "Wrong volume: first component is expected to be @/{}, got @/{} instead",
+                self.name,
+                comp
+            );
+        }
+
         let mut output = PathBuf::new();
-        while let Some(comp) = components.next() {
+        for comp in components {
             output.push(comp);
         }
 
-        self.canonicalize(&output)
+        Ok(output)
+    }
+
+    /// Entries of a directory, without the writes in progress and the links leading out of
+    /// the volume
+    async fn read_entries(&self, dir: &Path) -> eyre::Result<Vec<tokio::fs::DirEntry>> {
+        let mut entries = tokio::fs::read_dir(dir)
+            .await
+            .with_context(|| format!("Reading directory {}", dir.display()))?;
+
+        let mut kept = vec![];
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            // a write in progress
+            if path.to_string_lossy().ends_with(TEMP_SUFFIX) {
+                continue;
+            }
+
+            if Self::ensure_within(self.read_root(), &path).is_err() {
+                tracing::warn!("Skipping {}, it leads out of the volume", path.display());
+                continue;
+            }
+
+            kept.push(entry);
+        }
+
+        Ok(kept)
     }
 
     /// * `C:/some/root/b/c` -> `@/vol_name/b/c`
@@ -46,13 +428,13 @@ impl LocalVolume {
             return NullFsPath::from_to_str(format!("@/{}", self.name))?.extend_from_rel(&path);
         }
 
-        match path.strip_prefix(&self.root) {
+        match path.strip_prefix(self.read_root()) {
             Ok(out) => NullFsPath::from_to_str(format!("@/{}", self.name))?.extend_from_rel(out),
             Err(_) => {
                 eyre::bail!(
                     "Bad prefix: could not make sense of {}, expected prefix {}",
                     path.display(),
-                    self.root.display()
+                    self.read_root().display()
                 )
             }
         }
@@ -84,29 +466,31 @@ impl NullFs for LocalVolume {
     async fn init(&mut self) -> eyre::Result<()> {
         self.name = self.name.trim().to_owned();
         self.root = Self::strip_extended_prefix(self.root.canonicalize()?);
-        tracing::debug!("/{} <---> {}", self.name, self.root.display());
+        if let Some(snapshot_root) = &self.snapshot_root {
+            self.snapshot_root = Some(Self::strip_extended_prefix(snapshot_root.canonicalize()?));
+        }
+        tracing::debug!("/{} <---> {}", self.name, self.read_root().display());
+        if self.trash {
+            self.prune_trash().await?;
+        }
 
         Ok(())
     }
 
     async fn dir(&self, dir: &NullFsPath) -> eyre::Result<Vec<nullfs::File>> {
-        let dir = self.resolve(dir)?;
+        let dir = self.resolve_read(dir)?;
 
         if dir.is_file() {
             return Ok(vec![]);
         }
 
-        let mut entries = tokio::fs::read_dir(&dir)
-            .await
-            .with_context(|| format!("Reading directory {}", dir.display()))?;
-
         let mut results = vec![];
-        while let Some(entry) = entries.next_entry().await? {
+        for entry in self.read_entries(&dir).await? {
             let path = entry.path();
             tracing::debug!("{} --> {}", path.display(), self.to_virtual(&path)?);
             let vpath = self.to_virtual(&path)?;
             let stat = self.stats(&vpath).await?;
-            let file_type = FileType::infer_from_path(&vpath);
+            let file_type = Self::file_type(&path, &vpath, &stat).await;
 
             results.push(File {
                 path: vpath,
@@ -118,6 +502,60 @@ impl NullFs for LocalVolume {
         Ok(results)
     }
 
+    /// Sorting by name only needs the entry names, then only the window is stat'ed
+    async fn dir_page(&self, dir: &NullFsPath, page: &DirPage) -> eyre::Result<DirListing> {
+        if page.sort != SortKey::Name {
+            return Ok(page.apply(self.dir(dir).await?));
+        }
+
+        let dir = self.resolve_read(dir)?;
+        if dir.is_file() {
+            return Ok(DirListing {
+                entries: vec![],
+                total: 0,
+            });
+        }
+
+        let mut named = vec![];
+        for entry in self.read_entries(&dir).await? {
+            let path = entry.path();
+            let file_type = entry.file_type().await?;
+            // links only are directories when followed, as in stats
+            let is_dir = match file_type.is_symlink() {
+                true => self.follow_symlinks && path.is_dir(),
+                false => file_type.is_dir(),
+            };
+            named.push((is_dir, self.to_virtual(&path)?));
+        }
+
+        named.sort_by(|(a_is_dir, a), (b_is_dir, b)| {
+            page.ordering(*a_is_dir, *b_is_dir, a.file_name().cmp(&b.file_name()))
+        });
+        let total = named.len();
+
+        let mut entries = vec![];
+        for (_, vpath) in named.drain(page.window(total)) {
+            let stat = self.stats(&vpath).await?;
+            entries.push(File {
+                file_type: Self::file_type(&self.resolve_read(&vpath)?, &vpath, &stat).await,
+                stat,
+                path: vpath,
+            });
+        }
+
+        Ok(DirListing { entries, total })
+    }
+
+    /// Only the entry names are read, nothing gets stat'ed
+    async fn count_children(&self, dir: &NullFsPath) -> eyre::Result<u64> {
+        let dir = self.resolve_read(dir)?;
+        if dir.is_file() {
+            return Ok(0);
+        }
+
+        Ok(self.read_entries(&dir).await?.len() as u64)
+    }
+
     async fn mkdir(&self, path: &NullFsPath) -> eyre::Result<()> {
         tokio::fs::create_dir_all(self.resolve(path)?)
             .await
@@ -143,11 +581,13 @@ impl NullFs for LocalVolume {
     }
 
     async fn stats(&self, path: &NullFsPath) -> eyre::Result<FileStat> {
-        let path = self.resolve(path)?;
+        let path = self.resolve_read(path)?;
 
-        let metadata = tokio::fs::metadata(&path)
-            .await
-            .with_context(|| format!("Could not read metadata for {}", path.display()))?;
+        let metadata = match self.follow_symlinks {
+            true => tokio::fs::metadata(&path).await,
+            false => tokio::fs::symlink_metadata(&path).await,
+        }
+        .with_context(|| format!("Could not read metadata for {}", path.display()))?;
         let accessed = metadata.accessed().map(systime_to_millis).ok();
         let modified = metadata
             .modified()
@@ -155,16 +595,16 @@ impl NullFs for LocalVolume {
             .ok()
             .with_context(|| format!("Could not read modified time for {}", path.display()))?;
         let created = metadata.created().map(systime_to_millis).ok();
-        let is_dir = metadata.is_dir();
+        let node = match self.link_target(&path).await? {
+            Some(target) => NodeKind::Symlink { target },
+            None if metadata.is_dir() => NodeKind::Dir,
+            None => NodeKind::File {
+                size: metadata.len(),
+            },
+        };
 
         Ok(FileStat {
-            node: if is_dir {
-                NodeKind::Dir
-            } else {
-                NodeKind::File {
-                    size: metadata.len(),
-                }
-            },
+            node,
             created,
             accessed,
             modified,
@@ -172,91 +612,104 @@ impl NullFs for LocalVolume {
     }
 
     async fn hash(&self, path: &NullFsPath) -> eyre::Result<String> {
-        let resolved_path = self.resolve(path)?;
-
-        let mut hasher = Sha256::new();
-        let mut buffer = [0u8; 8 * 1024];
-        if resolved_path.is_dir() {
-            for entry in self.dir(path).await? {
-                let hash = self.hash(&entry.path).await?;
-                hasher.update(entry.path.to_string());
-                hasher.update(hash);
-            }
-        } else {
-            let file = tokio::fs::File::open(resolved_path).await?;
-            let mut reader = tokio::io::BufReader::new(file);
-
-            while let Ok(n) = reader.read(&mut buffer).await {
-                if n == 0 {
-                    break;
-                }
-                hasher.update(&buffer[..n]);
-            }
-        }
-
-        Ok(format!("{:x}", hasher.finalize()))
+        self.hash_within(path, &mut HashSet::new()).await
     }
 
-    async fn shallow_hash(&self, file: &nullfs::File) -> eyre::Result<String> {
-        if self.resolve(&file.path)?.is_relative() {
-            eyre::bail!("Provided file has a relative path {}", file.path);
-        }
+    async fn real_path(&self, path: &NullFsPath) -> eyre::Result<NullFsPath> {
+        self.to_virtual(&self.real(path)?)
+    }
 
-        let mut hasher = Sha256::new();
-        hasher.update(file.stat.modified.to_string());
+    #[cfg(unix)]
+    async fn is_mount_point(&self, path: &NullFsPath) -> eyre::Result<bool> {
+        use std::os::unix::fs::MetadataExt;
 
-        match file.stat.node {
-            NodeKind::Dir => {
-                for entry in self.dir(&file.path).await? {
-                    let hash = self.shallow_hash(&entry).await?;
-                    hasher.update(hash);
-                }
-            }
-            NodeKind::File { size } => {
-                hasher.update(size.to_string());
-            }
-        }
+        let root_device = tokio::fs::metadata(self.read_root()).await?.dev();
+        let path = self.resolve_read(path)?;
+        let device = tokio::fs::symlink_metadata(&path)
+            .await
+            .with_context(|| format!("Could not read metadata for {}", path.display()))?
+            .dev();
 
-        Ok(format!("{:x}", hasher.finalize()))
+        Ok(device != root_device)
     }
 
     async fn exists(&self, path: &NullFsPath) -> eyre::Result<bool> {
-        let path = self.resolve(path)?;
+        let path = self.resolve_read(path)?;
 
-        Ok(path.exists())
+        Ok(match self.follow_symlinks {
+            true => path.exists(),
+            // a link to what is not there yet still exists
+            false => tokio::fs::symlink_metadata(&path).await.is_ok(),
+        })
     }
 
     async fn read(&self, path: &NullFsPath) -> eyre::Result<Vec<u8>> {
-        let path = self.resolve(path)?;
+        let path = self.resolve_read(path)?;
 
         tokio::fs::read(&path)
             .await
             .wrap_err_with(|| format!("Reading {}", path.display()))
     }
 
-    async fn write(&self, file: &File, bytes: &[u8]) -> eyre::Result<()> {
-        let path = self.resolve(&file.path)?;
+    async fn read_stream(&self, path: &NullFsPath) -> eyre::Result<ByteStream> {
+        let path = self.resolve_read(path)?;
+        let file = tokio::fs::File::open(&path)
+            .await
+            .wrap_err_with(|| format!("Reading {}", path.display()))?;
 
-        if file.stat.is_dir() {
-            tokio::fs::create_dir_all(&path).await
-        } else {
-            if let Some(parent) = path.parent() {
-                tokio::fs::create_dir_all(parent).await?;
-            }
+        Ok(ReaderStream::new(file).map_err(eyre::Report::from).boxed())
+    }
 
-            tokio::fs::write(&path, bytes).await
-        }
-        .wrap_err_with(|| format!("Writing ({:?}) {}", file.stat.node, path.display()))
+    async fn read_range(&self, path: &NullFsPath, range: Range<u64>) -> eyre::Result<ByteStream> {
+        let path = self.resolve_read(path)?;
+        let mut file = tokio::fs::File::open(&path)
+            .await
+            .wrap_err_with(|| format!("Reading {}", path.display()))?;
+        file.seek(SeekFrom::Start(range.start))
+            .await
+            .wrap_err_with(|| format!("Seeking {} in {}", range.start, path.display()))?;
+        let slice = file.take(range.end.saturating_sub(range.start));
+
+        Ok(ReaderStream::new(slice).map_err(eyre::Report::from).boxed())
+    }
+
+    async fn write(&self, file: &File, bytes: &[u8]) -> eyre::Result<()> {
+        self.write_chunks(file, futures::stream::iter([eyre::Ok(bytes)]))
+            .await
+    }
+
+    /// Only one chunk is held at a time
+    async fn write_stream(&self, file: &File, chunks: ByteStream) -> eyre::Result<()> {
+        self.write_chunks(file, chunks).await
     }
 
     async fn delete(&self, file: &File) -> eyre::Result<()> {
         let path = self.resolve(&file.path)?;
 
-        if !path.exists() {
+        // the link is removed, not what it points to
+        let Ok(metadata) = tokio::fs::symlink_metadata(&path).await else {
+            return Ok(());
+        };
+
+        if self.trash {
+            let trashed = self
+                .move_to_trash(&path)
+                .await
+                .wrap_err_with(|| format!("Trashing {}", path.display()))?;
+            tracing::info!("Trashed {} to {}", file.path, trashed.display());
+            // the trash only grows on deletes, it is pruned then and on startup
+            match self.prune_trash().await {
+                Ok(0) => {}
+                Ok(pruned) => {
+                    tracing::info!("Pruned {pruned} trash directories of @/{}", self.name)
+                }
+                Err(e) => tracing::warn!("Failed to prune the trash of @/{}: {e}", self.name),
+            }
+
             return Ok(());
         }
 
-        if path.is_dir() {
+        if metadata.is_dir() {
             tokio::fs::remove_dir_all(&path).await
         } else {
             tokio::fs::remove_file(&path).await
@@ -0,0 +1,269 @@
+use crate::nullfs::{self, File, FileStat, FileType, NodeKind, NullFs, NullFsPath, hashing};
+use async_trait::async_trait;
+use eyre::ContextCompat;
+use indexmap::IndexMap;
+use std::sync::{
+    Arc, Mutex,
+    atomic::{AtomicU64, Ordering},
+};
+
+type Entries = IndexMap<NullFsPath, (FileStat, Vec<u8>)>;
+
+/// Volume living in memory, clones share the same content
+///
+/// Modification times come from a logical clock advancing by one millisecond on each change,
+/// [`MemVolume::set_modified`] moves them explicitly so tests never have to wait for mtimes
+#[derive(Clone, Debug, Default)]
+pub struct MemVolume {
+    pub name: String,
+    entries: Arc<Mutex<Entries>>,
+    clock: Arc<AtomicU64>,
+}
+
+impl MemVolume {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    fn check_volume(&self, path: &NullFsPath) -> eyre::Result<()> {
+        let volume = path.volume_name()?;
+        if volume.ne(&self.name) {
+            eyre::bail!(
+                "Wrong volume: first component is expected to be @/{}, got @/{} instead",
+                self.name,
+                volume
+            );
+        }
+
+        Ok(())
+    }
+
+    fn is_root(path: &NullFsPath) -> bool {
+        path.components().len() <= 1
+    }
+
+    fn is_below(path: &NullFsPath, ancestor: &NullFsPath) -> bool {
+        let comps = path.components();
+        let prefix = ancestor.components();
+        comps.len() > prefix.len() && comps.starts_with(&prefix)
+    }
+
+    fn dir_stat(modified: u64) -> FileStat {
+        FileStat {
+            node: NodeKind::Dir,
+            modified,
+            created: None,
+            accessed: None,
+        }
+    }
+
+    /// Creates the missing directories leading to `path`, `path` included
+    fn make_dirs(entries: &mut Entries, path: &NullFsPath, modified: u64) -> eyre::Result<()> {
+        let mut pending = vec![];
+        let mut curr = Some(path.clone());
+        while let Some(path) = curr {
+            if Self::is_root(&path) {
+                break;
+            }
+
+            match entries.get(&path) {
+                Some((stat, _)) if stat.is_dir() => break,
+                Some(_) => eyre::bail!("{path} is a file"),
+                None => {}
+            }
+
+            curr = path.parent();
+            pending.push(path);
+        }
+
+        for path in pending.into_iter().rev() {
+            entries.insert(path, (Self::dir_stat(modified), vec![]));
+        }
+
+        Ok(())
+    }
+
+    /// Sets the modification time of an entry
+    #[allow(unused)]
+    pub fn set_modified(&self, path: &NullFsPath, modified: u64) -> eyre::Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        let (stat, _) = entries
+            .get_mut(path)
+            .with_context(|| format!("{path} not found"))?;
+        stat.modified = modified;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl NullFs for MemVolume {
+    async fn init(&mut self) -> eyre::Result<()> {
+        self.name = self.name.trim().to_owned();
+
+        Ok(())
+    }
+
+    async fn dir(&self, dir: &NullFsPath) -> eyre::Result<Vec<nullfs::File>> {
+        self.check_volume(dir)?;
+        let depth = dir.components().len() + 1;
+        let entries = self.entries.lock().unwrap();
+
+        Ok(entries
+            .iter()
+            .filter(|(path, _)| path.components().len() == depth && Self::is_below(path, dir))
+            .map(|(path, (stat, _))| File {
+                file_type: FileType::infer_from_path(path),
+                path: path.clone(),
+                stat: stat.clone(),
+            })
+            .collect())
+    }
+
+    async fn mkdir(&self, path: &NullFsPath) -> eyre::Result<()> {
+        self.check_volume(path)?;
+        let modified = self.tick();
+        Self::make_dirs(&mut self.entries.lock().unwrap(), path, modified)
+    }
+
+    async fn copy(&self, o: &NullFsPath, d: &NullFsPath) -> eyre::Result<()> {
+        let bytes = self.read(o).await?;
+        let modified = self.tick();
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(parent) = d.parent() {
+            Self::make_dirs(&mut entries, &parent, modified)?;
+        }
+
+        let mut stat = entries
+            .get(o)
+            .map(|(stat, _)| stat.clone())
+            .with_context(|| format!("{o} not found"))?;
+        stat.modified = modified;
+        entries.insert(d.clone(), (stat, bytes));
+
+        Ok(())
+    }
+
+    async fn rename(&self, o: &NullFsPath, d: &NullFsPath) -> eyre::Result<()> {
+        self.check_volume(d)?;
+        let modified = self.tick();
+        let mut entries = self.entries.lock().unwrap();
+        if !entries.contains_key(o) {
+            eyre::bail!("{o} not found");
+        }
+
+        if let Some(parent) = d.parent() {
+            Self::make_dirs(&mut entries, &parent, modified)?;
+        }
+
+        let prefix = o.components().len();
+        let moved = entries
+            .keys()
+            .filter(|path| *path == o || Self::is_below(path, o))
+            .cloned()
+            .collect::<Vec<_>>();
+        for path in moved {
+            let entry = entries.shift_remove(&path).unwrap();
+            let target = d.extend(path.components()[prefix..].to_vec())?;
+            entries.insert(target, entry);
+        }
+
+        Ok(())
+    }
+
+    async fn stats(&self, path: &NullFsPath) -> eyre::Result<FileStat> {
+        self.check_volume(path)?;
+        if Self::is_root(path) {
+            return Ok(Self::dir_stat(0));
+        }
+
+        self.entries
+            .lock()
+            .unwrap()
+            .get(path)
+            .map(|(stat, _)| stat.clone())
+            .with_context(|| format!("Could not read metadata for {path}"))
+    }
+
+    async fn exists(&self, path: &NullFsPath) -> eyre::Result<bool> {
+        self.check_volume(path)?;
+
+        Ok(Self::is_root(path) || self.entries.lock().unwrap().contains_key(path))
+    }
+
+    async fn read(&self, path: &NullFsPath) -> eyre::Result<Vec<u8>> {
+        self.check_volume(path)?;
+        let entries = self.entries.lock().unwrap();
+        match entries.get(path) {
+            Some((stat, bytes)) if stat.is_file() => Ok(bytes.clone()),
+            Some(_) => eyre::bail!("Reading {path}: is a directory"),
+            None => eyre::bail!("Reading {path}: not found"),
+        }
+    }
+
+    async fn write(&self, file: &File, bytes: &[u8]) -> eyre::Result<()> {
+        self.check_volume(&file.path)?;
+        let modified = self.tick();
+        let mut entries = self.entries.lock().unwrap();
+        if file.stat.is_dir() {
+            return Self::make_dirs(&mut entries, &file.path, modified);
+        }
+
+        if let Some(parent) = file.path.parent() {
+            Self::make_dirs(&mut entries, &parent, modified)?;
+        }
+
+        // a link is kept along with its target as content, which is what it hashes to
+        let (node, bytes) = match &file.stat.node {
+            NodeKind::Symlink { target } => (file.stat.node.clone(), target.as_bytes()),
+            _ => (
+                NodeKind::File {
+                    size: bytes.len() as u64,
+                },
+                bytes,
+            ),
+        };
+        let stat = FileStat {
+            node,
+            modified,
+            created: Some(modified),
+            accessed: None,
+        };
+        entries.insert(file.path.clone(), (stat, bytes.to_vec()));
+
+        Ok(())
+    }
+
+    async fn delete(&self, file: &File) -> eyre::Result<()> {
+        self.check_volume(&file.path)?;
+        self.entries
+            .lock()
+            .unwrap()
+            .retain(|path, _| *path != file.path && !Self::is_below(path, &file.path));
+
+        Ok(())
+    }
+
+    async fn hash(&self, path: &NullFsPath) -> eyre::Result<String> {
+        if self.stats(path).await?.is_dir() {
+            let mut children = vec![];
+            for entry in self.dir(path).await? {
+                let hash = self.hash(&entry.path).await?;
+                children.push((entry.path, hash));
+            }
+
+            return Ok(hashing::merkle_hash(
+                children.iter().map(|(path, hash)| (path, hash.as_str())),
+            ));
+        }
+
+        Ok(hashing::digest(self.read(path).await?))
+    }
+}
@@ -0,0 +1,193 @@
+use indexmap::IndexMap;
+use std::{
+    fmt::Write,
+    sync::{
+        LazyLock, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+/// Upper bounds in seconds of the sync tick duration buckets
+const TICK_BUCKETS: [f64; 10] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+/// Metrics of this node, rendered by `GET /metrics`
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::default);
+
+/// Values keyed by the value of their single label
+type Series = Mutex<IndexMap<String, u64>>;
+
+#[derive(Debug, Default)]
+struct Histogram {
+    /// Observations per bucket, the last one counts the values above every bound
+    buckets: [AtomicU64; TICK_BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+/// Counters and gauges exported in the Prometheus text exposition format
+#[derive(Debug, Default)]
+pub struct Metrics {
+    commands_stashed: Series,
+    commands_applied: Series,
+    command_failures: Series,
+    bytes_downloaded: AtomicU64,
+    sync_tick: Histogram,
+    stash_pending: Series,
+    relay_up: Series,
+}
+
+impl Metrics {
+    pub fn commands_stashed(&self, volume: &str, count: u64) {
+        *self
+            .commands_stashed
+            .lock()
+            .unwrap()
+            .entry(volume.to_owned())
+            .or_default() += count;
+    }
+
+    pub fn command_applied(&self, volume: &str) {
+        *self
+            .commands_applied
+            .lock()
+            .unwrap()
+            .entry(volume.to_owned())
+            .or_default() += 1;
+    }
+
+    pub fn command_failed(&self, volume: &str) {
+        *self
+            .command_failures
+            .lock()
+            .unwrap()
+            .entry(volume.to_owned())
+            .or_default() += 1;
+    }
+
+    pub fn bytes_downloaded(&self, bytes: u64) {
+        self.bytes_downloaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn sync_tick(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        let bucket = TICK_BUCKETS
+            .iter()
+            .position(|bound| secs <= *bound)
+            .unwrap_or(TICK_BUCKETS.len());
+        self.sync_tick.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sync_tick
+            .sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+        self.sync_tick.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stash_pending(&self, volume: &str, pending: u64) {
+        self.stash_pending
+            .lock()
+            .unwrap()
+            .insert(volume.to_owned(), pending);
+    }
+
+    pub fn relay_up(&self, relay: &str, alive: bool) {
+        self.relay_up
+            .lock()
+            .unwrap()
+            .insert(relay.to_owned(), alive as u64);
+    }
+
+    /// Text exposition format, see <https://prometheus.io/docs/instrumenting/exposition_formats>
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        write_series(
+            &mut out,
+            "nullfs_commands_stashed_total",
+            "counter",
+            "Commands stashed to be applied",
+            "volume",
+            &self.commands_stashed,
+        );
+        write_series(
+            &mut out,
+            "nullfs_commands_applied_total",
+            "counter",
+            "Stashed commands applied",
+            "volume",
+            &self.commands_applied,
+        );
+        write_series(
+            &mut out,
+            "nullfs_command_failures_total",
+            "counter",
+            "Failed attempts at applying a stashed command",
+            "volume",
+            &self.command_failures,
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP nullfs_bytes_downloaded_total Bytes downloaded from the relays"
+        );
+        let _ = writeln!(out, "# TYPE nullfs_bytes_downloaded_total counter");
+        let _ = writeln!(
+            out,
+            "nullfs_bytes_downloaded_total {}",
+            self.bytes_downloaded.load(Ordering::Relaxed)
+        );
+
+        let name = "nullfs_sync_tick_duration_seconds";
+        let _ = writeln!(out, "# HELP {name} Duration of the sync cycles");
+        let _ = writeln!(out, "# TYPE {name} histogram");
+        let mut cumulated = 0;
+        for (i, count) in self.sync_tick.buckets.iter().enumerate() {
+            cumulated += count.load(Ordering::Relaxed);
+            let bound = TICK_BUCKETS
+                .get(i)
+                .map(|bound| bound.to_string())
+                .unwrap_or("+Inf".to_owned());
+            let _ = writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {cumulated}");
+        }
+        let sum = self.sync_tick.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "{name}_sum {sum}");
+        let _ = writeln!(
+            out,
+            "{name}_count {}",
+            self.sync_tick.count.load(Ordering::Relaxed)
+        );
+
+        write_series(
+            &mut out,
+            "nullfs_stash_pending",
+            "gauge",
+            "Stashed commands waiting to be applied",
+            "volume",
+            &self.stash_pending,
+        );
+        write_series(
+            &mut out,
+            "nullfs_relay_up",
+            "gauge",
+            "Whether the last liveness probe of the relay succeeded",
+            "relay",
+            &self.relay_up,
+        );
+
+        out
+    }
+}
+
+fn write_series(
+    out: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    label: &str,
+    series: &Series,
+) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+    for (value, count) in series.lock().unwrap().iter() {
+        let value = value.replace('\\', "\\\\").replace('"', "\\\"");
+        let _ = writeln!(out, "{name}{{{label}=\"{value}\"}} {count}");
+    }
+}
@@ -1,37 +1,69 @@
 use crate::{
-    config::{NodeConfig, NodeIdentifier},
+    config::{LiveConfig, NodeConfig, NodeIdentifier, RelayOrder},
     nullfs::{
         any_fs::AnyFs,
-        share::{CommandStash, ShareNode},
+        events::SyncEvent,
+        metrics::METRICS,
+        quarantine::DEFAULT_CONFLICT_SUFFIX,
+        share::{
+            ApplyReport, CommandStash, DEFAULT_BUSY_RETRIES, DEFAULT_DELTA_THRESHOLD,
+            DEFAULT_LIVENESS_TTL, DEFAULT_MAX_ATTEMPTS, DEFAULT_STREAM_THRESHOLD, ShareNode,
+        },
+        snapshot::{SYNC_STATE_PREFIX, StateStore},
+        throttle::RateLimiter,
+        volume_state::VolumeStates,
     },
 };
 use async_trait::async_trait;
+use bytes::Bytes;
 use chrono::{DateTime, TimeZone, Utc};
+use eyre::Context;
+use futures::{StreamExt, TryStreamExt, stream::BoxStream};
+use indexmap::{IndexMap, IndexSet};
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, utf8_percent_encode};
 use rand::seq::SliceRandom;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::{
+    cmp::Ordering,
+    collections::HashMap,
     fmt::{self, Debug},
-    hash::Hash,
+    hash::{DefaultHasher, Hash, Hasher},
+    ops::Range,
     path::{Path, PathBuf},
     sync::Arc,
-    time::{SystemTime, UNIX_EPOCH},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 use tokio_util::sync::CancellationToken;
 
 pub mod any_fs;
+pub mod backend;
+pub mod delta;
+pub mod events;
+pub mod fs_snapshot;
+pub mod hashing;
 pub mod local_fs;
+pub mod mem_fs;
+pub mod metrics;
+pub mod quarantine;
+pub mod s3_fs;
+pub mod search;
 pub mod share;
 pub mod snapshot;
+pub mod throttle;
+pub mod volume_state;
 
 #[derive(Serialize, Deserialize, Clone, Debug, Hash, PartialEq, Eq)]
 #[serde(rename_all = "lowercase")]
 pub enum FileType {
     Image,
     Video,
+    Audio,
     Document,
     Executable,
     Archive,
     Text,
+    /// Also stands for the types added by later versions
+    #[serde(other)]
     Unkown,
 }
 
@@ -45,8 +77,14 @@ pub struct File {
 #[derive(Serialize, Deserialize, Clone, Debug, Hash, PartialEq, Eq)]
 #[serde(tag = "type", rename_all = "camelCase")]
 pub enum NodeKind {
-    File { size: u64 },
+    File {
+        size: u64,
+    },
     Dir,
+    /// A link recorded as is rather than followed, `target` is what it points to
+    Symlink {
+        target: String,
+    },
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Hash, PartialEq, Eq)]
@@ -60,10 +98,20 @@ pub struct FileStat {
 #[derive(Serialize, Deserialize, Clone, Debug, Hash, PartialEq, Eq)]
 #[serde(tag = "type")]
 pub enum Command {
-    Delete { file: File },
-    Write { file: File },
-    Touch { file: File },
-    // Rename { from: PathBuf, to: PathBuf },
+    Delete {
+        file: File,
+    },
+    Write {
+        file: File,
+    },
+    Touch {
+        file: File,
+    },
+    /// A file moved within the volume, its content is unchanged
+    Rename {
+        from: File,
+        to: File,
+    },
 }
 
 #[derive(Clone, Debug)]
@@ -75,17 +123,66 @@ pub struct StashedCommand {
     pub volume: String,
     #[allow(unused)]
     pub state: i32,
+    /// Failed attempts so far
+    pub attempts: u32,
 }
 
 #[derive(Clone, Debug)]
 pub struct Synchronizer;
 
+/// Chunks of a file being read
+pub type ByteStream = BoxStream<'static, eyre::Result<Bytes>>;
+
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Volumes synced at the same time within a sync cycle
+pub const DEFAULT_SYNC_CONCURRENCY: usize = 4;
+
+/// Time the sync in progress is given to finish once shutting down
+pub const DEFAULT_SHUTDOWN_GRACE_SECS: u64 = 30;
+
+/// Leading bytes read to tell the type of a file its extension says nothing about
+pub const FILE_TYPE_SNIFF_LEN: usize = 4096;
+
+fn chunked(data: Bytes) -> ByteStream {
+    let chunks = (0..data.len())
+        .step_by(READ_CHUNK_SIZE)
+        .map(move |start| Ok(data.slice(start..(start + READ_CHUNK_SIZE).min(data.len()))))
+        .collect::<Vec<_>>();
+
+    futures::stream::iter(chunks).boxed()
+}
+
+/// A volume paired with each relay it pulls from
+pub type EdgeNodes = Vec<(AnyFs, ShareNode)>;
+
+/// Outcome of one or more sync passes
+#[derive(Clone, Debug, Default)]
+pub struct SyncSummary {
+    pub applied: usize,
+    pub skipped: usize,
+    pub failed: usize,
+    pub bytes: u64,
+    pub errors: Vec<String>,
+    /// Relays skipped because their liveness probe failed
+    pub unreachable: IndexSet<String>,
+}
+
 impl FileType {
+    /// Formats already compressed, compressing them again only costs CPU
+    pub fn is_compressed(&self) -> bool {
+        matches!(
+            self,
+            FileType::Image | FileType::Video | FileType::Audio | FileType::Archive
+        )
+    }
+
     pub fn infer_from_path(path: &NullFsPath) -> Self {
         match path.extension().map(|s| s.to_lowercase()) {
             Some(ext) => match ext.to_lowercase().as_ref() {
                 "png" | "jpg" | "jpeg" | "gif" | "bmp" | "webp" | "tiff" => FileType::Image,
                 "mp4" | "mkv" | "avi" | "mov" | "flv" | "wmv" | "webm" => FileType::Video,
+                "mp3" | "flac" | "wav" | "ogg" | "m4a" | "opus" | "aac" => FileType::Audio,
                 "pdf" | "doc" | "docx" | "xls" | "xlsx" | "ppt" | "pptx" => FileType::Document,
                 "exe" | "bat" | "sh" | "bin" | "app" => FileType::Executable,
                 "zip" | "rar" | "7z" | "tar" | "gz" | "bz2" => FileType::Archive,
@@ -96,7 +193,104 @@ impl FileType {
         }
     }
 
-    pub fn mime_from_path(path: &NullFsPath) -> String {
+    /// Type told by the leading bytes of a file, [`FILE_TYPE_SNIFF_LEN`] of them are enough,
+    /// content without a known signature is text when it decodes as utf-8 without nul bytes
+    pub fn infer_from_bytes(bytes: &[u8]) -> Self {
+        let starts = |signatures: &[&[u8]]| signatures.iter().any(|sig| bytes.starts_with(sig));
+        let at = |offset: usize, sig: &[u8]| bytes.get(offset..offset + sig.len()) == Some(sig);
+        let le32 = |offset: usize| {
+            bytes
+                .get(offset..offset + 4)
+                .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        };
+        // two letters alone would take text for an image or a program, their headers are checked
+        let bitmap = starts(&[b"BM"])
+            && le32(14).is_some_and(|size| [12, 40, 52, 56, 64, 108, 124].contains(&size));
+        let portable_exe =
+            starts(&[b"MZ"]) && le32(0x3c).is_some_and(|pe| at(pe as usize, b"PE\0\0"));
+
+        if starts(&[
+            b"\x89PNG\r\n\x1a\n",
+            b"\xff\xd8\xff",
+            b"GIF87a",
+            b"GIF89a",
+            b"II*\0",
+            b"MM\0*",
+        ]) || bitmap
+            || (starts(&[b"RIFF"]) && at(8, b"WEBP"))
+            || (at(4, b"ftyp")
+                && [b"avif", b"heic", b"heif", b"mif1"]
+                    .iter()
+                    .any(|b| at(8, *b)))
+        {
+            return FileType::Image;
+        }
+
+        if starts(&[b"ID3", b"\xff\xfb", b"\xff\xf3", b"fLaC", b"OggS"])
+            || (starts(&[b"RIFF"]) && at(8, b"WAVE"))
+            || (at(4, b"ftyp") && at(8, b"M4A "))
+        {
+            return FileType::Audio;
+        }
+
+        if starts(&[b"\x1a\x45\xdf\xa3", b"FLV"])
+            || (starts(&[b"RIFF"]) && at(8, b"AVI "))
+            || at(4, b"ftyp")
+        {
+            return FileType::Video;
+        }
+
+        if starts(&[b"%PDF-", b"\xd0\xcf\x11\xe0\xa1\xb1\x1a\xe1"]) {
+            return FileType::Document;
+        }
+
+        if starts(&[
+            b"PK\x03\x04",
+            b"PK\x05\x06",
+            b"Rar!\x1a\x07",
+            b"7z\xbc\xaf\x27\x1c",
+            b"\x1f\x8b",
+            b"BZh",
+            b"\xfd7zXZ\0",
+        ]) || at(257, b"ustar")
+        {
+            return FileType::Archive;
+        }
+
+        if starts(&[
+            b"\x7fELF",
+            b"\xfe\xed\xfa\xce",
+            b"\xfe\xed\xfa\xcf",
+            b"\xce\xfa\xed\xfe",
+            b"\xcf\xfa\xed\xfe",
+            b"#!",
+        ]) || portable_exe
+        {
+            return FileType::Executable;
+        }
+
+        // the sample may end in the middle of a character
+        let decodes = match std::str::from_utf8(bytes) {
+            Ok(_) => true,
+            Err(e) => e.error_len().is_none(),
+        };
+        match !bytes.is_empty() && decodes && !bytes.contains(&0) {
+            true => FileType::Text,
+            false => FileType::Unkown,
+        }
+    }
+
+    /// Content type of a file from its extension, `overrides` (extension to content type, see
+    /// [`crate::config::NodeConfig::mime_overrides`]) are looked up before the built-in table
+    pub fn mime_from_path(path: &NullFsPath, overrides: &IndexMap<String, String>) -> String {
+        if let Some(ext) = path.extension()
+            && let Some((_, mime)) = overrides
+                .iter()
+                .find(|(known, _)| known.trim_start_matches('.').eq_ignore_ascii_case(&ext))
+        {
+            return mime.clone();
+        }
+
         path.extension()
             .map(|ext| match ext.to_lowercase().as_ref() {
                 "png" => "image/png",
@@ -105,6 +299,16 @@ impl FileType {
                 "bmp" => "image/bmp",
                 "webp" => "image/webp",
                 "tiff" => "image/tiff",
+                "svg" => "image/svg+xml",
+                "ico" => "image/vnd.microsoft.icon",
+                "avif" => "image/avif",
+                "mp3" => "audio/mpeg",
+                "wav" => "audio/wav",
+                "ogg" => "audio/ogg",
+                "flac" => "audio/flac",
+                "m4a" => "audio/mp4",
+                "opus" => "audio/opus",
+                "aac" => "audio/aac",
                 "mp4" => "video/mp4",
                 "mkv" => "video/x-matroska",
                 "avi" => "video/x-msvideo",
@@ -138,6 +342,21 @@ impl FileType {
                 "json" => "application/json",
                 "xml" => "application/xml",
                 "yaml" | "yml" => "application/x-yaml",
+                "toml" => "application/toml",
+                "html" | "htm" => "text/html; charset=utf-8",
+                "css" => "text/css; charset=utf-8",
+                "js" | "mjs" => "text/javascript; charset=utf-8",
+                "wasm" => "application/wasm",
+                "woff" => "font/woff",
+                "woff2" => "font/woff2",
+                "ttf" => "font/ttf",
+                "otf" => "font/otf",
+                "epub" => "application/epub+zip",
+                "odt" => "application/vnd.oasis.opendocument.text",
+                "ods" => "application/vnd.oasis.opendocument.spreadsheet",
+                "rtf" => "application/rtf",
+                "xz" => "application/x-xz",
+                "zst" => "application/zstd",
                 _ => "application/octet-stream",
             })
             .unwrap_or_else(|| "application/octet-stream")
@@ -146,33 +365,86 @@ impl FileType {
 }
 
 impl Synchronizer {
-    pub async fn run_sync(
-        config: Arc<NodeConfig>,
-        identifer: Arc<NodeIdentifier>,
-    ) -> eyre::Result<()> {
-        tracing::info!("Started sync");
-        let tick = tokio::time::Duration::from_secs(config.refresh_secs.unwrap_or(5).max(1));
-        let stash_store = CommandStash::new(&identifer).await?;
+    /// Resolves the relays of each volume and initializes the underlying stores
+    pub async fn prepare(
+        config: &NodeConfig,
+        identifer: &NodeIdentifier,
+    ) -> eyre::Result<Vec<EdgeNodes>> {
+        let stash_store = CommandStash::new(config, identifer)
+            .await?
+            .collapsing(config.collapse_commands.unwrap_or(true))
+            .max_attempts(config.max_command_attempts.unwrap_or(DEFAULT_MAX_ATTEMPTS))
+            .busy_retries(config.stash_busy_retries.unwrap_or(DEFAULT_BUSY_RETRIES));
 
         let stash = Arc::new(stash_store);
+        // one bucket for every transfer of the node
+        let throttle = config
+            .max_download_bytes_per_sec
+            .map(|rate| Arc::new(RateLimiter::new(rate)));
+
+        // the hashes a volume was last synced to outlive restarts and reloads
+        let mut hash_stores = HashMap::new();
+        for volume_name in config.volumes.keys() {
+            let store = StateStore::open(&config.data_dir().join(format!(
+                "{SYNC_STATE_PREFIX}{volume_name}-{}.db",
+                identifer.uuid
+            )))
+            .await?;
+            let hashes = Arc::new(tokio::sync::Mutex::new(store.load_synced().await?));
+            hash_stores.insert(volume_name.clone(), (store, hashes));
+        }
+
         let mut vol2relay = config
             .volumes
             .clone()
             .into_iter()
             .map(|(volume_name, volume)| {
+                let (hashes_store, hashes) = hash_stores[&volume_name].clone();
                 volume
                     .pull_from
                     .iter()
-                    .map(|share| {
-                        config.resolve_alias(share).map(|relay| {
-                            (
-                                AnyFs::from_volume_item(&volume_name, &volume),
+                    .enumerate()
+                    .map(|(priority, share)| {
+                        config.resolve_alias(share).and_then(|relay| {
+                            Ok((
+                                AnyFs::from_volume_item(&volume_name, &volume)?,
                                 ShareNode {
+                                    client: ShareNode::client_for(
+                                        &relay,
+                                        config.compression.unwrap_or(true),
+                                    )?,
                                     name: share.clone(),
                                     store: stash.clone(),
                                     relay,
+                                    priority,
+                                    tie_break: volume.tie_break.clone(),
+                                    relay_order: volume.relay_order,
+                                    conflict_suffix: volume
+                                        .conflict_suffix
+                                        .clone()
+                                        .unwrap_or(DEFAULT_CONFLICT_SUFFIX.to_owned()),
+                                    keep_versions: volume.keep_versions,
+                                    stream_threshold: config
+                                        .stream_threshold_bytes
+                                        .unwrap_or(DEFAULT_STREAM_THRESHOLD),
+                                    delta_threshold: config
+                                        .delta_threshold_bytes
+                                        .unwrap_or(DEFAULT_DELTA_THRESHOLD),
+                                    throttle: throttle.clone(),
+                                    merkle: config.merkle,
+                                    dry_run: config.dry_run,
+                                    volume_priority: volume.priority,
+                                    command_ttl: config.command_ttl_secs.map(Duration::from_secs),
+                                    hashes: hashes.clone(),
+                                    hashes_store: Some(hashes_store.clone()),
+                                    liveness_ttl: config
+                                        .liveness_ttl_secs
+                                        .map(Duration::from_secs)
+                                        .unwrap_or(DEFAULT_LIVENESS_TTL),
+                                    liveness: Arc::default(),
+                                    shutdown: CancellationToken::default(),
                                 },
-                            )
+                            ))
                         })
                     })
                     .collect::<eyre::Result<Vec<_>>>()
@@ -187,107 +459,470 @@ impl Synchronizer {
             fs.init().await?;
         }
 
-        loop {
-            tracing::info!("{} :: Syncing...", config.name);
+        Ok(vol2relay)
+    }
 
-            vol2relay.shuffle(&mut rand::rng()); // !
+    /// Orders the volumes by decreasing priority, randomly among equal priorities
+    pub fn schedule(vol2relay: &mut [EdgeNodes]) {
+        vol2relay.shuffle(&mut rand::rng()); // !
+        vol2relay.sort_by_key(|edge_nodes| {
+            let priority = edge_nodes
+                .first()
+                .map(|(_, share_node)| share_node.volume_priority)
+                .unwrap_or_default();
+            std::cmp::Reverse(priority)
+        });
+    }
 
-            let identifer = identifer.clone();
-            tracing::debug!("Pull/stash state");
-            for edge_nodes in vol2relay.iter_mut() {
-                edge_nodes.shuffle(&mut rand::rng());
+    /// Orders the relays of a volume as its [`RelayOrder`] says, relays of equal priority go
+    /// in random order
+    pub fn order_relays(edge_nodes: &mut EdgeNodes) {
+        edge_nodes.shuffle(&mut rand::rng());
+        let by_priority = edge_nodes
+            .first()
+            .is_some_and(|(_, share_node)| share_node.relay_order == RelayOrder::Priority);
+        if by_priority {
+            edge_nodes.sort_by_key(|(_, share_node)| share_node.priority);
+        }
+    }
 
-                for (fs, share_node) in edge_nodes {
-                    if !share_node.is_alive().await? {
-                        continue;
-                    }
+    /// Runs exactly one pull + apply pass accross all volumes, paused volumes are left out
+    ///
+    /// Each volume is pulled then applied on its own, up to `concurrency` volumes at a time
+    /// started by decreasing priority, so a slow relay only holds back its own volumes
+    pub async fn sync_once(
+        vol2relay: &mut [EdgeNodes],
+        identifer: Arc<NodeIdentifier>,
+        states: &VolumeStates,
+        concurrency: usize,
+    ) -> eyre::Result<SyncSummary> {
+        Self::schedule(vol2relay);
+
+        // futures do nothing until polled, the first `concurrency` start right away
+        let syncs = vol2relay
+            .iter_mut()
+            .map(|edge_nodes| Self::sync_volume(edge_nodes, identifer.clone(), states))
+            .collect::<Vec<_>>();
+        let mut syncs = futures::stream::iter(syncs).buffer_unordered(concurrency.max(1));
+
+        let mut summary = SyncSummary::default();
+        while let Some(synced) = syncs.next().await {
+            summary.merge(synced?);
+        }
+
+        Ok(summary)
+    }
+
+    /// Pulls then applies the commands of one volume, each from the first of its shuffled
+    /// relays that succeeds
+    async fn sync_volume(
+        edge_nodes: &mut EdgeNodes,
+        identifer: Arc<NodeIdentifier>,
+        states: &VolumeStates,
+    ) -> eyre::Result<SyncSummary> {
+        let mut summary = SyncSummary::default();
+        let volume = edge_nodes
+            .first()
+            .map(|(fs, _)| fs.get_volume_name())
+            .unwrap_or_default();
+
+        if states.is_paused(&volume) {
+            tracing::debug!("Skipping paused volume @/{volume}");
+            return Ok(summary);
+        }
+
+        tracing::debug!("Pull/stash state of @/{volume}");
+        Self::order_relays(edge_nodes);
+        for (fs, share_node) in edge_nodes.iter_mut() {
+            let alive = share_node.is_alive().await?;
+            states.report_liveness(&volume, &share_node.name, alive);
+            if !alive {
+                summary.unreachable.insert(share_node.name.clone());
+                continue;
+            }
 
-                    if let Err(e) = share_node.pull(fs, identifer.clone()).await {
-                        tracing::error!(
-                            "Failed to pull @/{} from {}: {}",
-                            fs.get_volume_name(),
-                            share_node.name,
-                            e
-                        );
-                    } else {
-                        break;
+            match share_node.pull(fs, identifer.clone()).await {
+                Ok(stashed) => {
+                    states.report_pull(&volume, &share_node.name);
+                    for command in stashed {
+                        states.publish(SyncEvent::CommandStashed {
+                            volume: volume.clone(),
+                            command: command.to_string(),
+                        });
                     }
+                    break;
+                }
+                Err(e) => {
+                    let error = format!(
+                        "Failed to pull @/{} from {}: {}",
+                        fs.get_volume_name(),
+                        share_node.name,
+                        e
+                    );
+                    tracing::error!("{error}");
+                    states.report_error(&volume, &error);
+                    summary.errors.push(error);
                 }
             }
+        }
 
-            tracing::debug!("Apply stashed state");
-            for edge_nodes in vol2relay.iter_mut() {
-                edge_nodes.shuffle(&mut rand::rng());
+        // paused while pulling
+        if states.is_paused(&volume) {
+            return Ok(summary);
+        }
 
-                for (fs, share_node) in edge_nodes {
-                    if !share_node.is_alive().await? {
-                        continue;
-                    }
+        tracing::debug!("Apply stashed state of @/{volume}");
+        Self::order_relays(edge_nodes);
+        let relays = edge_nodes
+            .iter()
+            .map(|(_, share_node)| share_node.clone())
+            .collect::<Vec<_>>();
+
+        for (fs, share_node) in edge_nodes.iter_mut() {
+            let alive = share_node.is_alive().await?;
+            states.report_liveness(&volume, &share_node.name, alive);
+            if !alive {
+                summary.unreachable.insert(share_node.name.clone());
+                continue;
+            }
+
+            match share_node.apply_commands(fs, &relays).await {
+                Ok(report) => {
+                    states.report_ok(&volume);
+                    states.report_apply(&volume);
+                    Self::publish_report(states, &volume, &report);
+                    summary.absorb(report);
+                    break;
+                }
+                Err(e) => {
+                    let error = format!(
+                        "Failed to sync @/{} from {}: {}",
+                        fs.get_volume_name(),
+                        share_node.name,
+                        e
+                    );
+                    tracing::error!("{error}");
+                    states.report_error(&volume, &error);
+                    summary.errors.push(error);
+                }
+            }
+        }
 
-                    if let Err(e) = share_node.apply_commands(fs).await {
-                        tracing::error!(
-                            "Failed to sync @/{} from {}: {}",
-                            fs.get_volume_name(),
-                            share_node.name,
-                            e
-                        );
-                    } else {
-                        break;
+        Ok(summary)
+    }
+
+    /// Publishes the outcome of each command of `report`, see [`VolumeStates::subscribe`]
+    fn publish_report(states: &VolumeStates, volume: &str, report: &ApplyReport) {
+        for (command, bytes) in &report.applied_commands {
+            states.publish(SyncEvent::CommandApplied {
+                volume: volume.to_owned(),
+                command: command.to_string(),
+                bytes: *bytes,
+            });
+        }
+
+        for failure in &report.failures {
+            states.publish(SyncEvent::CommandFailed {
+                volume: volume.to_owned(),
+                command: failure.command.to_string(),
+                error: failure.error.clone(),
+            });
+        }
+    }
+
+    /// Syncs on every refresh until `shutdown` is cancelled, the cycle in progress then stops
+    /// after the command being applied
+    pub async fn run_sync(
+        live: Arc<LiveConfig>,
+        identifer: Arc<NodeIdentifier>,
+        states: Arc<VolumeStates>,
+        shutdown: CancellationToken,
+    ) -> eyre::Result<()> {
+        tracing::info!("Started sync");
+        let mut config = live.current();
+        let mut last_vacuum = tokio::time::Instant::now();
+        let mut vol2relay = Self::prepare(&config, &identifer).await?;
+        Self::watch_shutdown(&mut vol2relay, &shutdown);
+
+        loop {
+            let reloaded = live.current();
+            if !Arc::ptr_eq(&reloaded, &config) {
+                match Self::prepare(&reloaded, &identifer).await {
+                    Ok(mut prepared) => {
+                        tracing::info!("Syncing with the reloaded configuration");
+                        Self::watch_shutdown(&mut prepared, &shutdown);
+                        vol2relay = prepared;
+                        config = reloaded;
                     }
+                    Err(e) => tracing::error!(
+                        "Could not sync with the reloaded configuration, keeping the previous one: {e}"
+                    ),
                 }
             }
+            let vacuum_period = config
+                .stash_vacuum_secs
+                .map(tokio::time::Duration::from_secs);
 
-            tokio::time::sleep(tick).await;
+            tracing::info!("{} :: Syncing...", config.name);
+            let started = tokio::time::Instant::now();
+            let concurrency = config.sync_concurrency.unwrap_or(DEFAULT_SYNC_CONCURRENCY);
+            let summary =
+                Self::sync_once(&mut vol2relay, identifer.clone(), &states, concurrency).await?;
+            METRICS.sync_tick(started.elapsed());
+            tracing::info!("{} :: {}", config.name, summary);
+            Self::record_gauges(&vol2relay).await;
+
+            if let Some(period) = vacuum_period
+                && last_vacuum.elapsed() >= period
+                && let Some((_, share_node)) = vol2relay.iter().flatten().next()
+            {
+                match share_node.store.vacuum().await {
+                    Ok(purged) => tracing::info!("Vacuumed stash, purged {purged} command(s)"),
+                    Err(e) => tracing::error!("Failed to vacuum stash: {e}"),
+                }
+                last_vacuum = tokio::time::Instant::now();
+            }
+
+            tokio::select! {
+                _ = tokio::time::sleep(config.refresh_delay()) => {},
+                _ = shutdown.cancelled() => return Ok(()),
+            }
         }
     }
 
+    /// Lets the relays of `vol2relay` stop at the next command once `shutdown` is cancelled
+    pub fn watch_shutdown(vol2relay: &mut [EdgeNodes], shutdown: &CancellationToken) {
+        for (_, share_node) in vol2relay.iter_mut().flatten() {
+            share_node.shutdown = shutdown.clone();
+        }
+    }
+
+    /// Refreshes the stash depth and relay liveness exported by `GET /metrics`
+    async fn record_gauges(vol2relay: &[EdgeNodes]) {
+        for edge_nodes in vol2relay {
+            for (_, share_node) in edge_nodes {
+                if let Some(alive) = share_node.liveness() {
+                    METRICS.relay_up(&share_node.name, alive);
+                }
+            }
+
+            // every relay of a volume shares the same stash
+            if let Some((fs, share_node)) = edge_nodes.first() {
+                let volume = fs.get_volume_name();
+                match share_node.store.counts(&volume).await {
+                    Ok(counts) => METRICS.stash_pending(&volume, counts.pending + counts.retrying),
+                    Err(e) => {
+                        tracing::error!("Failed to count the stashed commands of {volume}: {e}")
+                    }
+                }
+            }
+        }
+    }
+
+    /// Once `shutdown` is cancelled the sync in progress gets `shutdownGraceSecs` to reach
+    /// the end of a command, it is dropped past that
     pub async fn run(
-        config: Arc<NodeConfig>,
+        live: Arc<LiveConfig>,
         identifer: Arc<NodeIdentifier>,
+        states: Arc<VolumeStates>,
         shutdown: CancellationToken,
     ) -> eyre::Result<()> {
-        let task = Self::run_sync(config, identifer);
+        let grace = Duration::from_secs(
+            live.current()
+                .shutdown_grace_secs
+                .unwrap_or(DEFAULT_SHUTDOWN_GRACE_SECS),
+        );
+        let task = Self::run_sync(live, identifer, states, shutdown.clone());
+        tokio::pin!(task);
         tokio::select! {
-            _ = task => {},
+            result = &mut task => return result,
             _ = shutdown.cancelled() => {}
         };
 
+        match tokio::time::timeout(grace, task).await {
+            Ok(result) => result,
+            Err(_) => {
+                tracing::warn!("Sync still running after {grace:?}, stopped mid-command");
+                Ok(())
+            }
+        }
+    }
+}
+
+impl SyncSummary {
+    pub fn absorb(&mut self, report: ApplyReport) {
+        self.applied += report.applied;
+        self.skipped += report.skipped;
+        self.failed += report.failures.len();
+        self.bytes += report.bytes;
+        self.errors.extend(
+            report
+                .failures
+                .into_iter()
+                .map(|failure| format!("{}: {}", failure.command, failure.error)),
+        );
+    }
+
+    pub fn merge(&mut self, other: SyncSummary) {
+        self.applied += other.applied;
+        self.skipped += other.skipped;
+        self.failed += other.failed;
+        self.bytes += other.bytes;
+        self.errors.extend(other.errors);
+        self.unreachable.extend(other.unreachable);
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.failed == 0 && self.errors.is_empty() && self.unreachable.is_empty()
+    }
+}
+
+impl fmt::Display for SyncSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} applied, {} skipped, {} failed, {} bytes downloaded, {} error(s)",
+            self.applied,
+            self.skipped,
+            self.failed,
+            self.bytes,
+            self.errors.len()
+        )?;
+
+        if !self.unreachable.is_empty() {
+            let relays = self.unreachable.iter().cloned().collect::<Vec<_>>();
+            write!(f, ", unreachable: {}", relays.join(", "))?;
+        }
+
         Ok(())
     }
 }
 
-impl ToString for Command {
-    fn to_string(&self) -> String {
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Command::Delete { file } => {
-                format!("-- {} :: {}", file.path, file.stat.node.to_string())
-            }
-            Command::Write { file } => {
-                format!("++ {} :: {}", file.path, file.stat.node.to_string())
-            }
-            Command::Touch { file } => format!("?? {}", file.path),
-            // Command::Rename { from, to } => format!("** {from} -> {to}"),
+            Command::Delete { file } => write!(f, "-- {} :: {}", file.path, file.stat.node),
+            Command::Write { file } => write!(f, "++ {} :: {}", file.path, file.stat.node),
+            Command::Touch { file } => write!(f, "?? {}", file.path),
+            Command::Rename { from, to } => write!(f, "** {} -> {}", from.path, to.path),
         }
     }
 }
 
-impl ToString for NodeKind {
-    fn to_string(&self) -> String {
+impl fmt::Display for NodeKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            NodeKind::File { size } => format!("{size} bytes"),
-            NodeKind::Dir => "dir".to_owned(),
+            NodeKind::File { size } => write!(f, "{size} bytes"),
+            NodeKind::Dir => write!(f, "dir"),
+            NodeKind::Symlink { target } => write!(f, "link to {target}"),
         }
     }
 }
 
 impl FileStat {
     pub fn is_dir(&self) -> bool {
-        matches!(self.node, NodeKind::Dir { .. })
+        matches!(self.node, NodeKind::Dir)
     }
 
+    /// Anything but a directory, links included
     pub fn is_file(&self) -> bool {
         !self.is_dir()
     }
+
+    pub fn is_symlink(&self) -> bool {
+        matches!(self.node, NodeKind::Symlink { .. })
+    }
+
+    /// Size of a file, zero for a directory or a link
+    pub fn size(&self) -> u64 {
+        match self.node {
+            NodeKind::File { size } => size,
+            NodeKind::Dir | NodeKind::Symlink { .. } => 0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SortKey {
+    #[default]
+    Name,
+    Size,
+    Modified,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/// Window of a directory listing, directories come first whatever the sort
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct DirPage {
+    #[serde(default)]
+    pub offset: usize,
+    /// Every entry after `offset` when unset
+    pub limit: Option<usize>,
+    #[serde(default)]
+    pub sort: SortKey,
+    #[serde(default)]
+    pub order: SortOrder,
+}
+
+/// Entries of a [`DirPage`] and the count of all the entries of the directory
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct DirListing {
+    pub entries: Vec<File>,
+    pub total: usize,
+}
+
+impl DirPage {
+    /// Applies the sort order to an ascending comparison, directories first
+    pub fn ordering(&self, a_is_dir: bool, b_is_dir: bool, ascending: Ordering) -> Ordering {
+        let ordering = match self.order {
+            SortOrder::Asc => ascending,
+            SortOrder::Desc => ascending.reverse(),
+        };
+
+        b_is_dir.cmp(&a_is_dir).then(ordering)
+    }
+
+    pub fn compare(&self, a: &File, b: &File) -> Ordering {
+        let by_name = a.path.file_name().cmp(&b.path.file_name());
+        let ascending = match self.sort {
+            SortKey::Name => by_name,
+            SortKey::Size => a.stat.size().cmp(&b.stat.size()).then(by_name),
+            SortKey::Modified => a.stat.modified.cmp(&b.stat.modified).then(by_name),
+        };
+
+        self.ordering(a.stat.is_dir(), b.stat.is_dir(), ascending)
+    }
+
+    /// Indices of the window within `total` sorted entries
+    pub fn window(&self, total: usize) -> Range<usize> {
+        let start = self.offset.min(total);
+        let end = match self.limit {
+            Some(limit) => start.saturating_add(limit).min(total),
+            None => total,
+        };
+
+        start..end
+    }
+
+    /// Sorts a full listing and keeps the window
+    pub fn apply(&self, mut files: Vec<File>) -> DirListing {
+        files.sort_by(|a, b| self.compare(a, b));
+        let total = files.len();
+        let window = self.window(total);
+
+        DirListing {
+            entries: files.drain(window).collect(),
+            total,
+        }
+    }
 }
 
 pub fn systime_to_millis(t: SystemTime) -> u64 {
@@ -310,8 +945,14 @@ pub trait NullFs: Debug + Send + Sync {
 
     async fn dir(&self, dir: &NullFsPath) -> eyre::Result<Vec<File>>;
 
+    /// Sorted window of a directory, the default sorts the whole [`NullFs::dir`]
+    async fn dir_page(&self, dir: &NullFsPath, page: &DirPage) -> eyre::Result<DirListing> {
+        Ok(page.apply(self.dir(dir).await?))
+    }
+
     async fn mkdir(&self, path: &NullFsPath) -> eyre::Result<()>;
 
+    #[allow(unused)]
     async fn copy(&self, o: &NullFsPath, d: &NullFsPath) -> eyre::Result<()>;
 
     async fn rename(&self, o: &NullFsPath, d: &NullFsPath) -> eyre::Result<()>;
@@ -320,11 +961,34 @@ pub trait NullFs: Debug + Send + Sync {
 
     async fn exists(&self, path: &NullFsPath) -> eyre::Result<bool>;
 
-    // TODO: stream
     async fn read(&self, path: &NullFsPath) -> eyre::Result<Vec<u8>>;
 
+    /// Reads a file chunk by chunk, the default chunks a full [`NullFs::read`]
+    async fn read_stream(&self, path: &NullFsPath) -> eyre::Result<ByteStream> {
+        Ok(chunked(Bytes::from(self.read(path).await?)))
+    }
+
+    /// Reads the bytes of a file within `range`, clamped to the end of the file
+    async fn read_range(&self, path: &NullFsPath, range: Range<u64>) -> eyre::Result<ByteStream> {
+        let data = Bytes::from(self.read(path).await?);
+        let end = (range.end as usize).min(data.len());
+        let start = (range.start as usize).min(end);
+
+        Ok(chunked(data.slice(start..end)))
+    }
+
     async fn write(&self, file: &File, bytes: &[u8]) -> eyre::Result<()>;
 
+    /// Writes a file as its chunks arrive, the default buffers them for a [`NullFs::write`]
+    async fn write_stream(&self, file: &File, mut chunks: ByteStream) -> eyre::Result<()> {
+        let mut bytes = vec![];
+        while let Some(chunk) = chunks.try_next().await? {
+            bytes.extend_from_slice(&chunk);
+        }
+
+        self.write(file, &bytes).await
+    }
+
     async fn delete(&self, file: &File) -> eyre::Result<()>;
 
     /// Computes the hash of a folder entry
@@ -332,15 +996,41 @@ pub trait NullFs: Debug + Send + Sync {
     /// * A file hash is calculated based on its content
     async fn hash(&self, path: &NullFsPath) -> eyre::Result<String>;
 
-    /// Recursively tracks down time based metadata changes
-    /// * A folder hash is the cumulated shallow hash of its entries
-    /// * A file hash is calculated based on its time of modification
+    /// Whether the entry lives on another device than the volume root (mount point)
+    async fn is_mount_point(&self, _path: &NullFsPath) -> eyre::Result<bool> {
+        Ok(false)
+    }
+
+    /// Entries directly below `dir`, the default counts a full [`NullFs::dir`]
+    async fn count_children(&self, dir: &NullFsPath) -> eyre::Result<u64> {
+        Ok(self.dir(dir).await?.len() as u64)
+    }
+
+    /// Path of the entry once the links leading to it are followed, the same path for the
+    /// volumes without links
+    async fn real_path(&self, path: &NullFsPath) -> eyre::Result<NullFsPath> {
+        Ok(path.clone())
+    }
+
+    /// Tracks down time based metadata changes
+    /// * A folder hash covers the modification time, size and type of its direct entries,
+    ///   a change deeper down only shows in the hash of the subdirectory holding it
+    /// * A file hash is calculated based on its time of modification and size
     /// * Cheap way to track down change accross time, especially for modified files
-    async fn shallow_hash(&self, file: &File) -> eyre::Result<String>;
+    async fn shallow_hash(&self, file: &File) -> eyre::Result<String> {
+        let entries = match file.stat.is_dir() {
+            true => self.dir(&file.path).await?,
+            false => vec![],
+        };
+
+        Ok(hashing::shallow_hash(file, entries))
+    }
 }
 
-#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+#[derive(Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
 /// Normalized Posix style only Path implementation
+///
+/// Ordered component by component, `@/v/a/b` sorts before `@/v/a-b` as `a` does before `a-b`
 pub struct NullFsPath(Vec<String>);
 
 impl NullFsPath {
@@ -360,7 +1050,13 @@ impl NullFsPath {
             eyre::bail!("Path expected to start with @/");
         }
 
-        Ok(Self(ss.map(|s| s.to_owned()).collect()))
+        let mut comps = vec![];
+        for comp in ss.filter(|comp| *comp != ".") {
+            check_component(comp).wrap_err_with(|| format!("Invalid path {s:?}"))?;
+            comps.push(comp.to_owned());
+        }
+
+        Ok(Self(comps))
     }
 
     pub fn volume_name(&self) -> eyre::Result<String> {
@@ -374,7 +1070,10 @@ impl NullFsPath {
     #[allow(unused)]
     pub fn extend(&self, comps: Vec<String>) -> eyre::Result<Self> {
         let mut out = self.0.clone();
-        out.extend(comps);
+        for comp in comps.into_iter().filter(|comp| comp != ".") {
+            check_component(&comp).wrap_err_with(|| format!("Invalid path under {self}"))?;
+            out.push(comp);
+        }
 
         Ok(Self(out))
     }
@@ -388,6 +1087,37 @@ impl NullFsPath {
         self.extend(components)
     }
 
+    /// `@/vol/a/b` -> `@/vol/a`, the volume root has no parent
+    pub fn parent(&self) -> Option<Self> {
+        (self.0.len() > 1).then(|| Self(self.0[..self.0.len() - 1].to_vec()))
+    }
+
+    /// `@/vol/a` + `b` -> `@/vol/a/b`, `component` is a single name
+    pub fn join(&self, component: &str) -> eyre::Result<Self> {
+        if component.is_empty() || component == "." {
+            eyre::bail!("Cannot join {component:?} to {self}");
+        }
+        check_component(component).wrap_err_with(|| format!("Invalid path under {self}"))?;
+
+        let mut out = self.0.clone();
+        out.push(component.to_owned());
+
+        Ok(Self(out))
+    }
+
+    /// Whether the path is `ancestor` or lies below it
+    pub fn is_within(&self, ancestor: &NullFsPath) -> bool {
+        self.0.starts_with(&ancestor.0)
+    }
+
+    /// `@/vol/a/b.txt` -> `b.txt`, the volume root has no file name
+    pub fn file_name(&self) -> Option<&str> {
+        match self.0.as_slice() {
+            [_, .., name] => Some(name),
+            _ => None,
+        }
+    }
+
     #[allow(unused)]
     pub fn components(&self) -> Vec<String> {
         self.0.clone()
@@ -401,6 +1131,55 @@ impl NullFsPath {
                 .map(|s| s.to_string_lossy().to_string())
         })
     }
+
+    /// Percent-encoded form safe to embed in a query string, only `/` and the unreserved
+    /// characters are left as is
+    pub fn url_encoded(&self) -> String {
+        const QUERY_VALUE: &AsciiSet = &NON_ALPHANUMERIC
+            .remove(b'/')
+            .remove(b'-')
+            .remove(b'_')
+            .remove(b'.')
+            .remove(b'~');
+
+        utf8_percent_encode(&self.to_string(), QUERY_VALUE).to_string()
+    }
+
+    /// Matches the components against a `/` separated glob, `*` and `?` stay within
+    /// a component while `**` spans any number of them, e.g. `**/*.txt` or `vol/**/c.txt`
+    #[allow(unused)]
+    pub fn matches_glob(&self, pattern: &str) -> bool {
+        let pattern = pattern
+            .trim_start_matches("@/")
+            .split('/')
+            .filter(|part| !part.is_empty())
+            .collect::<Vec<_>>();
+
+        glob_components(&pattern, &self.0)
+    }
+}
+
+fn glob_components(pattern: &[&str], comps: &[String]) -> bool {
+    match pattern.split_first() {
+        None => comps.is_empty(),
+        Some((&"**", rest)) => (0..=comps.len()).any(|skip| glob_components(rest, &comps[skip..])),
+        Some((part, rest)) => comps.split_first().is_some_and(|(comp, comps)| {
+            let (part, comp) = (
+                part.chars().collect::<Vec<_>>(),
+                comp.chars().collect::<Vec<_>>(),
+            );
+            glob_segment(&part, &comp) && glob_components(rest, comps)
+        }),
+    }
+}
+
+fn glob_segment(pattern: &[char], value: &[char]) -> bool {
+    match pattern.split_first() {
+        None => value.is_empty(),
+        Some(('*', rest)) => (0..=value.len()).any(|skip| glob_segment(rest, &value[skip..])),
+        Some(('?', rest)) => !value.is_empty() && glob_segment(rest, &value[1..]),
+        Some((c, rest)) => value.first() == Some(c) && glob_segment(rest, &value[1..]),
+    }
 }
 
 impl fmt::Display for NullFsPath {
@@ -438,28 +1217,139 @@ pub fn normalize(path: &Path) -> eyre::Result<Vec<String>> {
     let mut new_path = vec![];
     let components = path.components();
     for comp in components {
-        new_path.push(comp.as_os_str().to_string_lossy().to_string());
+        let comp = comp.as_os_str().to_string_lossy().to_string();
+        if comp == "." {
+            continue;
+        }
+
+        check_component(&comp).wrap_err_with(|| format!("Invalid path {}", path.display()))?;
+        new_path.push(comp);
     }
 
     Ok(new_path)
 }
 
+/// Rejects the components that could lead out of a volume once joined to its root
+fn check_component(comp: &str) -> eyre::Result<()> {
+    if comp == ".." {
+        eyre::bail!("Parent directory components are not allowed");
+    }
+
+    if comp.contains(['/', '\\', '\0']) {
+        eyre::bail!("Component {comp:?} contains a path separator");
+    }
+
+    Ok(())
+}
+
+/// Whether each command of a batch is still needed once the later ones are accounted for
+/// * a `Write` or `Touch` is pointless when its path, or a parent, is deleted later on without
+///   being renamed in between
+/// * a `Delete` of a file is pointless when the next needed command writes it back as it was
+pub fn needed_commands(commands: &[Command]) -> Vec<bool> {
+    let mut needed = vec![true; commands.len()];
+
+    let mut deleted: Vec<&NullFsPath> = vec![];
+    for (i, command) in commands.iter().enumerate().rev() {
+        match command {
+            Command::Delete { file } => deleted.push(&file.path),
+            Command::Write { file } | Command::Touch { file } => {
+                if deleted.iter().any(|path| file.path.is_within(path)) {
+                    needed[i] = false;
+                }
+            }
+            Command::Rename { from, to } => {
+                // the renamed entries are not the ones deleted later on
+                let related = |path: &NullFsPath| {
+                    [&from.path, &to.path]
+                        .iter()
+                        .any(|moved| moved.is_within(path) || path.is_within(moved))
+                };
+                deleted.retain(|path| !related(path));
+            }
+        }
+    }
+
+    let mut next_needed: Option<&Command> = None;
+    for (i, command) in commands.iter().enumerate().rev() {
+        if !needed[i] {
+            continue;
+        }
+
+        if let (Command::Delete { file: deleted }, Some(Command::Write { file: written })) =
+            (command, next_needed)
+            && deleted.stat.is_file()
+            && deleted.path == written.path
+            && deleted.stat == written.stat
+        {
+            needed[i] = false;
+            continue;
+        }
+        next_needed = Some(command);
+    }
+
+    needed
+}
+
 /// Folds contiguous equal subsequence (a variant of RLE algorithm)
 /// This is useful for collapsing operations in a noisy log
 ///
 /// E.g. `[1, 2, 3, 1, 2, 3, 4, 5, 4, 5, 1, 2] -> [1, 2, 3, 4, 5, 1, 2]`
-pub fn reduce_contiguous_subsequences<T: Eq + Clone>(seq: &[T]) -> Vec<T> {
+///
+/// After each element, the longest tail of the output repeated right after it in `seq` is
+/// skipped. Such a repeat starts at an earlier occurrence of the next element, only those are
+/// tried and compared through rolling hashes, which is close to linear unless a few values
+/// make up most of `seq`
+pub fn reduce_contiguous_subsequences<T: Eq + Hash + Clone>(seq: &[T]) -> Vec<T> {
+    const BASE: u64 = 0x0000_0100_0000_01b3;
+
+    let hashes = seq
+        .iter()
+        .map(|item| {
+            let mut hasher = DefaultHasher::new();
+            item.hash(&mut hasher);
+            hasher.finish()
+        })
+        .collect::<Vec<_>>();
+    let mut powers = vec![1u64; seq.len() + 1];
+    let mut seq_prefix = vec![0u64; seq.len() + 1];
+    for k in 0..seq.len() {
+        powers[k + 1] = powers[k].wrapping_mul(BASE);
+        seq_prefix[k + 1] = seq_prefix[k].wrapping_mul(BASE).wrapping_add(hashes[k]);
+    }
+    let window = |prefix: &[u64], range: Range<usize>| {
+        prefix[range.end].wrapping_sub(prefix[range.start].wrapping_mul(powers[range.len()]))
+    };
+
     let mut out = vec![];
+    let mut out_prefix = vec![0u64];
+    // indices in `out` of each element hash, ascending
+    let mut starts: HashMap<u64, Vec<usize>> = HashMap::new();
     let mut i = 0;
 
     while i < seq.len() {
         out.push(seq[i].clone());
-
+        out_prefix.push(
+            out_prefix[out.len() - 1]
+                .wrapping_mul(BASE)
+                .wrapping_add(hashes[i]),
+        );
+        starts.entry(hashes[i]).or_default().push(out.len() - 1);
+
+        let remaining = seq.len() - i - 1;
         let mut skip = 0;
-        for len in (1..=out.len().min(seq.len() - i - 1)).rev() {
-            if out[out.len() - len..] == seq[i + 1..i + 1 + len] {
-                skip = len; // repeat
-                break;
+        if let Some(candidates) = hashes.get(i + 1).and_then(|next| starts.get(next)) {
+            // longest repeats first
+            let fitting = candidates.partition_point(|start| out.len() - start > remaining);
+            for &start in &candidates[fitting..] {
+                let len = out.len() - start;
+                let next = i + 1..i + 1 + len;
+                if window(&out_prefix, start..out.len()) == window(&seq_prefix, next.clone())
+                    && out[start..] == seq[next]
+                {
+                    skip = len; // repeat
+                    break;
+                }
             }
         }
 
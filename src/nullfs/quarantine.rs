@@ -0,0 +1,170 @@
+use crate::nullfs::NullFsPath;
+use sha2::{Digest, Sha256};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Directory, relative to the volume root, receiving the quarantined files by default
+pub const DEFAULT_QUARANTINE_DIR: &str = ".nullfs-conflicts";
+
+/// Inserted before the extension of the conflict copies, see [`conflict_sibling`]
+pub const DEFAULT_CONFLICT_SUFFIX: &str = " (conflict {node})";
+
+/// Directory, relative to the volume root, keeping the overwritten versions of files
+pub const VERSIONS_DIR: &str = ".nullfs-versions";
+
+const MAX_STEM_LEN: usize = 96;
+const WINDOWS_RESERVED: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Names the quarantined copies of conflicting files
+///
+/// A name is `<stem>.conflict-<node>-<millis>-<seq>-<hash>[.<ext>]`, the sequence is
+/// monotonic for the lifetime of the namer so two conflicts within the same millisecond
+/// never collide, and every component is restricted to characters valid on Windows and Posix
+#[allow(unused)]
+#[derive(Debug)]
+pub struct QuarantineNamer {
+    node_id: String,
+    counter: AtomicU64,
+}
+
+#[allow(unused)]
+impl QuarantineNamer {
+    pub fn new(node_id: &str) -> Self {
+        Self {
+            node_id: sanitize(node_id),
+            counter: AtomicU64::new(0),
+        }
+    }
+
+    /// File name of the quarantined copy of `original` holding `content`
+    pub fn name_for(&self, original: &NullFsPath, content: &[u8], millis: u64) -> String {
+        let seq = self.counter.fetch_add(1, Ordering::Relaxed);
+        let hash = format!("{:x}", Sha256::digest(content));
+        let file_name = original.file_name().unwrap_or_default().to_owned();
+        let (stem, ext) = match file_name.rsplit_once('.') {
+            Some((stem, ext)) if !stem.is_empty() && !ext.is_empty() => {
+                (stem.to_string(), Some(sanitize(ext)))
+            }
+            _ => (file_name, None),
+        };
+
+        let mut stem = sanitize(&stem)
+            .chars()
+            .take(MAX_STEM_LEN)
+            .collect::<String>();
+        if WINDOWS_RESERVED.contains(&stem.to_uppercase().as_str()) {
+            stem.insert(0, '_');
+        }
+
+        let name = format!(
+            "{stem}.conflict-{}-{millis}-{seq}-{}",
+            self.node_id,
+            &hash[..8]
+        );
+        match ext {
+            Some(ext) => format!("{name}.{ext}"),
+            None => name,
+        }
+    }
+
+    /// Path of the quarantined copy, the parents of `original` are mirrored under
+    /// `quarantine_dir` so same named files of different directories stay apart
+    pub fn path_for(
+        &self,
+        quarantine_dir: &str,
+        original: &NullFsPath,
+        content: &[u8],
+        millis: u64,
+    ) -> eyre::Result<NullFsPath> {
+        let comps = original.components();
+        if comps.len() < 2 {
+            eyre::bail!("Cannot quarantine volume root {original}");
+        }
+
+        let name = self.name_for(original, content, millis);
+        let parents = comps[1..comps.len() - 1].to_vec();
+        let root = NullFsPath::from_to_str(format!("@/{}", comps[0]))?;
+
+        root.extend(
+            quarantine_dir
+                .split('/')
+                .map(|s| s.to_string())
+                .chain(parents)
+                .chain([name])
+                .collect(),
+        )
+    }
+}
+
+/// Sibling of `original` receiving the remote version of a file edited on both sides, the
+/// `suffix` goes between the stem and the extension with `{node}` replaced by `node`
+///
+/// `@/vol/a/notes.txt` becomes `@/vol/a/notes (conflict laptop).txt` with the default suffix
+pub fn conflict_sibling(
+    original: &NullFsPath,
+    suffix: &str,
+    node: &str,
+) -> eyre::Result<NullFsPath> {
+    let Some(parent) = original.parent() else {
+        eyre::bail!("Cannot write a conflict copy of volume root {original}");
+    };
+
+    let file_name = original.file_name().unwrap_or_default().to_owned();
+    let suffix = suffix.replace("{node}", node);
+    let name = match file_name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() && !ext.is_empty() => {
+            format!("{}.{}", sanitize(&format!("{stem}{suffix}")), sanitize(ext))
+        }
+        _ => sanitize(&format!("{file_name}{suffix}")),
+    };
+
+    parent.extend(vec![name])
+}
+
+/// Directory of the kept versions of `original`, its path is mirrored under [`VERSIONS_DIR`]
+///
+/// `@/vol/a/notes.txt` keeps its versions in `@/vol/.nullfs-versions/a/notes.txt/`
+pub fn versions_dir(original: &NullFsPath) -> eyre::Result<NullFsPath> {
+    let comps = original.components();
+    if comps.len() < 2 {
+        eyre::bail!("Cannot keep versions of volume root {original}");
+    }
+
+    NullFsPath::from_to_str(format!("@/{}", comps[0]))?.extend(
+        [VERSIONS_DIR.to_owned()]
+            .into_iter()
+            .chain(comps[1..].iter().cloned())
+            .collect(),
+    )
+}
+
+/// Version of `original` last modified at `modified`, named `<modified>.<ext>` in its
+/// [`versions_dir`]
+pub fn version_path(original: &NullFsPath, modified: u64) -> eyre::Result<NullFsPath> {
+    let name = match original.extension() {
+        Some(ext) => format!("{modified}.{}", sanitize(&ext)),
+        None => modified.to_string(),
+    };
+
+    versions_dir(original)?.join(&name)
+}
+
+/// Replaces the characters Windows rejects in file names, trailing dots and spaces included
+fn sanitize(value: &str) -> String {
+    let sanitized = value
+        .chars()
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect::<String>();
+    let trimmed = sanitized.trim_end_matches(['.', ' ']);
+    if trimmed.is_empty() {
+        "_".to_string()
+    } else {
+        trimmed.to_string()
+    }
+}
@@ -0,0 +1,381 @@
+use crate::nullfs::{
+    self, ByteStream, File, FileStat, FileType, NodeKind, NullFs, NullFsPath, hashing,
+};
+use async_trait::async_trait;
+use eyre::{Context, ContextCompat};
+use futures::{StreamExt, TryStreamExt};
+use object_store::{
+    GetOptions, GetRange, ObjectMeta, ObjectStore, PutPayload, aws::AmazonS3Builder, path::Path,
+};
+use reqwest::Url;
+use std::{ops::Range, sync::Arc};
+
+/// Volume stored in an S3 compatible bucket, `@/vol_name/a/b.txt` maps to the key `prefix/a/b.txt`
+///
+/// Directories only exist through the keys they prefix, credentials are read from the usual
+/// `AWS_*` environment variables
+#[derive(Clone, Debug)]
+pub struct S3Volume {
+    pub name: String,
+    pub bucket: String,
+    pub prefix: Option<String>,
+    pub region: Option<String>,
+    pub endpoint: Option<Url>,
+    store: Option<Arc<dyn ObjectStore>>,
+}
+
+impl S3Volume {
+    pub fn new(
+        name: &str,
+        bucket: &str,
+        prefix: Option<String>,
+        region: Option<String>,
+        endpoint: Option<Url>,
+    ) -> Self {
+        Self {
+            name: name.to_owned(),
+            bucket: bucket.to_owned(),
+            prefix,
+            region,
+            endpoint,
+            store: None,
+        }
+    }
+
+    /// Volume on top of an already configured object store
+    #[allow(unused)]
+    pub fn with_store(name: &str, prefix: Option<String>, store: Arc<dyn ObjectStore>) -> Self {
+        Self {
+            store: Some(store),
+            ..Self::new(name, "", prefix, None, None)
+        }
+    }
+
+    fn store(&self) -> eyre::Result<&Arc<dyn ObjectStore>> {
+        self.store
+            .as_ref()
+            .with_context(|| format!("Volume {} is not initialized", self.name))
+    }
+
+    fn prefix_parts(&self) -> impl Iterator<Item = &str> {
+        self.prefix
+            .iter()
+            .flat_map(|prefix| prefix.split('/'))
+            .filter(|part| !part.is_empty())
+    }
+
+    /// `@/vol_name/b/c` => `prefix/b/c`
+    pub fn resolve(&self, path: &NullFsPath) -> eyre::Result<Path> {
+        let mut components = path.components().into_iter();
+
+        if let Some(comp) = components.next()
+            && comp.ne(&self.name)
+        {
+            eyre::bail!(
+                "Wrong volume: first component is expected to be @/{}, got @/{} instead",
+                self.name,
+                comp
+            );
+        }
+
+        let parts = self
+            .prefix_parts()
+            .map(|part| part.to_owned())
+            .chain(components)
+            .collect::<Vec<_>>();
+
+        Ok(Path::from_iter(parts))
+    }
+
+    /// `prefix/b/c` => `@/vol_name/b/c`
+    pub fn to_virtual(&self, key: &Path) -> eyre::Result<NullFsPath> {
+        let prefix = self.prefix_parts().collect::<Vec<_>>();
+        let parts = key.parts().collect::<Vec<_>>();
+        if parts.len() < prefix.len()
+            || parts
+                .iter()
+                .zip(&prefix)
+                .any(|(part, prefix)| part.as_ref() != *prefix)
+        {
+            eyre::bail!("Bad prefix: key {key} is not under the volume prefix");
+        }
+
+        NullFsPath::from_to_str(format!("@/{}", self.name))?.extend(
+            parts[prefix.len()..]
+                .iter()
+                .map(|part| part.as_ref().to_owned())
+                .collect(),
+        )
+    }
+
+    fn is_root(&self, path: &NullFsPath) -> bool {
+        path.components().len() <= 1
+    }
+
+    async fn is_dir(&self, key: &Path) -> eyre::Result<bool> {
+        let mut listing = self.store()?.list(Some(key));
+
+        Ok(listing.next().await.transpose()?.is_some())
+    }
+
+    async fn head(&self, key: &Path) -> eyre::Result<Option<ObjectMeta>> {
+        match self.store()?.head(key).await {
+            Ok(meta) => Ok(Some(meta)),
+            Err(object_store::Error::NotFound { .. }) => Ok(None),
+            Err(e) => Err(e).wrap_err_with(|| format!("Reading metadata of {key}")),
+        }
+    }
+
+    fn file_stat(meta: &ObjectMeta) -> FileStat {
+        FileStat {
+            node: NodeKind::File { size: meta.size },
+            modified: meta.last_modified.timestamp_millis() as u64,
+            created: None,
+            accessed: None,
+        }
+    }
+
+    fn dir_stat() -> FileStat {
+        FileStat {
+            node: NodeKind::Dir,
+            modified: 0,
+            created: None,
+            accessed: None,
+        }
+    }
+}
+
+/// Single part uploads have the MD5 of their content as ETag, multipart ones do not
+pub fn is_plain_md5(etag: &str) -> bool {
+    let etag = etag.trim_matches('"');
+    etag.len() == 32 && etag.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+#[async_trait]
+impl NullFs for S3Volume {
+    async fn init(&mut self) -> eyre::Result<()> {
+        self.name = self.name.trim().to_owned();
+        if self.store.is_none() {
+            let mut builder = AmazonS3Builder::from_env().with_bucket_name(&self.bucket);
+            if let Some(region) = &self.region {
+                builder = builder.with_region(region);
+            }
+            if let Some(endpoint) = &self.endpoint {
+                builder = builder
+                    .with_endpoint(endpoint.as_str().trim_end_matches('/'))
+                    .with_allow_http(endpoint.scheme() == "http");
+            }
+
+            let store = builder
+                .build()
+                .wrap_err_with(|| format!("Configuring bucket {}", self.bucket))?;
+            self.store = Some(Arc::new(store));
+        }
+        tracing::debug!(
+            "/{} <---> s3://{}/{}",
+            self.name,
+            self.bucket,
+            self.prefix.as_deref().unwrap_or_default()
+        );
+
+        Ok(())
+    }
+
+    async fn dir(&self, dir: &NullFsPath) -> eyre::Result<Vec<nullfs::File>> {
+        let key = self.resolve(dir)?;
+        let listing = self
+            .store()?
+            .list_with_delimiter(Some(&key))
+            .await
+            .wrap_err_with(|| format!("Listing {key}"))?;
+
+        let mut results = vec![];
+        for prefix in listing.common_prefixes {
+            let vpath = self.to_virtual(&prefix)?;
+            results.push(File {
+                file_type: FileType::infer_from_path(&vpath),
+                path: vpath,
+                stat: Self::dir_stat(),
+            });
+        }
+
+        for meta in listing.objects {
+            let vpath = self.to_virtual(&meta.location)?;
+            results.push(File {
+                file_type: FileType::infer_from_path(&vpath),
+                path: vpath,
+                stat: Self::file_stat(&meta),
+            });
+        }
+
+        Ok(results)
+    }
+
+    async fn mkdir(&self, _path: &NullFsPath) -> eyre::Result<()> {
+        // directories are implied by the keys below them
+        Ok(())
+    }
+
+    async fn copy(&self, o: &NullFsPath, d: &NullFsPath) -> eyre::Result<()> {
+        self.store()?
+            .copy(&self.resolve(o)?, &self.resolve(d)?)
+            .await
+            .wrap_err(format!("Copy {o} to {d}"))
+    }
+
+    async fn rename(&self, o: &NullFsPath, d: &NullFsPath) -> eyre::Result<()> {
+        self.store()?
+            .rename(&self.resolve(o)?, &self.resolve(d)?)
+            .await
+            .wrap_err(format!("Rename {o} to {d}"))
+    }
+
+    async fn stats(&self, path: &NullFsPath) -> eyre::Result<FileStat> {
+        if self.is_root(path) {
+            return Ok(Self::dir_stat());
+        }
+
+        let key = self.resolve(path)?;
+        if let Some(meta) = self.head(&key).await? {
+            return Ok(Self::file_stat(&meta));
+        }
+
+        if self.is_dir(&key).await? {
+            return Ok(Self::dir_stat());
+        }
+
+        eyre::bail!("Could not read metadata for {key}: not found")
+    }
+
+    async fn hash(&self, path: &NullFsPath) -> eyre::Result<String> {
+        if self.stats(path).await?.is_dir() {
+            let mut children = vec![];
+            for entry in self.dir(path).await? {
+                let hash = self.hash(&entry.path).await?;
+                children.push((entry.path, hash));
+            }
+
+            return Ok(hashing::merkle_hash(
+                children.iter().map(|(path, hash)| (path, hash.as_str())),
+            ));
+        }
+
+        let key = self.resolve(path)?;
+        if let Some(etag) = self.head(&key).await?.and_then(|meta| meta.e_tag)
+            && is_plain_md5(&etag)
+        {
+            return Ok(etag.trim_matches('"').to_lowercase());
+        }
+
+        let mut hasher = hashing::ContentHasher::new();
+        let mut stream = self
+            .store()?
+            .get(&key)
+            .await
+            .wrap_err_with(|| format!("Reading {key}"))?
+            .into_stream();
+        while let Some(chunk) = stream.try_next().await? {
+            hasher.update(&chunk);
+        }
+
+        Ok(hasher.finalize())
+    }
+
+    async fn exists(&self, path: &NullFsPath) -> eyre::Result<bool> {
+        if self.is_root(path) {
+            return Ok(true);
+        }
+
+        let key = self.resolve(path)?;
+        Ok(self.head(&key).await?.is_some() || self.is_dir(&key).await?)
+    }
+
+    async fn read(&self, path: &NullFsPath) -> eyre::Result<Vec<u8>> {
+        let key = self.resolve(path)?;
+        let bytes = self
+            .store()?
+            .get(&key)
+            .await
+            .wrap_err_with(|| format!("Reading {key}"))?
+            .bytes()
+            .await
+            .wrap_err_with(|| format!("Reading {key}"))?;
+
+        Ok(bytes.to_vec())
+    }
+
+    async fn read_stream(&self, path: &NullFsPath) -> eyre::Result<ByteStream> {
+        let key = self.resolve(path)?;
+        let stream = self
+            .store()?
+            .get(&key)
+            .await
+            .wrap_err_with(|| format!("Reading {key}"))?
+            .into_stream();
+
+        Ok(stream.map_err(eyre::Report::from).boxed())
+    }
+
+    async fn read_range(&self, path: &NullFsPath, range: Range<u64>) -> eyre::Result<ByteStream> {
+        let key = self.resolve(path)?;
+        let options = GetOptions {
+            range: Some(GetRange::Bounded(range)),
+            ..Default::default()
+        };
+        let stream = self
+            .store()?
+            .get_opts(&key, options)
+            .await
+            .wrap_err_with(|| format!("Reading {key}"))?
+            .into_stream();
+
+        Ok(stream.map_err(eyre::Report::from).boxed())
+    }
+
+    async fn write(&self, file: &File, bytes: &[u8]) -> eyre::Result<()> {
+        if file.stat.is_dir() {
+            return Ok(());
+        }
+
+        if file.stat.is_symlink() {
+            eyre::bail!(
+                "Writing {}: links are not supported by s3 volumes",
+                file.path
+            );
+        }
+
+        let key = self.resolve(&file.path)?;
+        self.store()?
+            .put(&key, PutPayload::from(bytes.to_vec()))
+            .await
+            .wrap_err_with(|| format!("Writing ({:?}) {}", file.stat.node, key))?;
+
+        Ok(())
+    }
+
+    async fn delete(&self, file: &File) -> eyre::Result<()> {
+        let key = self.resolve(&file.path)?;
+        if self.head(&key).await?.is_some() {
+            return self
+                .store()?
+                .delete(&key)
+                .await
+                .wrap_err_with(|| format!("Removing {key}"));
+        }
+
+        let store = self.store()?;
+        let keys = store
+            .list(Some(&key))
+            .map_ok(|meta| meta.location)
+            .try_collect::<Vec<_>>()
+            .await?;
+        for key in keys {
+            store
+                .delete(&key)
+                .await
+                .wrap_err_with(|| format!("Removing {key}"))?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,94 @@
+use crate::nullfs::{File, NullFs, NullFsPath, any_fs::AnyFs};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// Matches returned when the request does not ask for fewer
+pub const DEFAULT_MAX_RESULTS: usize = 200;
+
+/// Upper bound of the matches a request can ask for
+pub const MAX_RESULTS: usize = 1000;
+
+/// Bounds of a walk, what was found when one is reached is returned as is
+#[derive(Clone, Debug)]
+pub struct SearchLimits {
+    pub max_results: usize,
+    /// Entries listed, matching or not
+    pub max_visited: usize,
+    pub timeout: Duration,
+}
+
+impl Default for SearchLimits {
+    fn default() -> Self {
+        Self {
+            max_results: DEFAULT_MAX_RESULTS,
+            max_visited: 100_000,
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SearchResults {
+    pub files: Vec<File>,
+    /// The walk stopped on one of the [`SearchLimits`], there may be more matches
+    pub truncated: bool,
+}
+
+/// Breadth first walk below `root` for the entries whose name contains `query`, ignoring
+/// case, the directories below `root` that cannot be listed are skipped
+pub async fn find_by_name(
+    fs: &AnyFs,
+    root: &NullFsPath,
+    query: &str,
+    limits: &SearchLimits,
+) -> eyre::Result<SearchResults> {
+    let query = query.to_lowercase();
+    let deadline = Instant::now() + limits.timeout;
+
+    let mut files = vec![];
+    let mut visited = 0;
+    let mut pending = VecDeque::from([root.clone()]);
+    while let Some(dir) = pending.pop_front() {
+        let entries = match fs.dir(&dir).await {
+            Ok(entries) => entries,
+            Err(e) if &dir != root => {
+                tracing::warn!("Search skipped {dir}: {e}");
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+
+        for entry in entries {
+            if files.len() >= limits.max_results
+                || visited >= limits.max_visited
+                || Instant::now() >= deadline
+            {
+                return Ok(SearchResults {
+                    files,
+                    truncated: true,
+                });
+            }
+            visited += 1;
+
+            if entry.stat.is_dir() {
+                pending.push_back(entry.path.clone());
+            }
+
+            let matched = entry
+                .path
+                .file_name()
+                .is_some_and(|name| name.to_lowercase().contains(&query));
+            if matched {
+                files.push(entry);
+            }
+        }
+    }
+
+    Ok(SearchResults {
+        files,
+        truncated: false,
+    })
+}
@@ -1,23 +1,44 @@
 use std::{
+    collections::{HashMap, HashSet},
+    fmt,
     hash::{DefaultHasher, Hash, Hasher},
+    ops::Range,
+    path::Path,
     str::FromStr,
     sync::Arc,
+    time::{Duration, Instant},
 };
 
 use crate::{
-    config::{NodeIdentifier, RelayNode},
+    config::{NodeConfig, NodeIdentifier, RelayNode, RelayOrder, TieBreak},
     nullfs::{
-        Command, NullFs, NullFsPath, StashedCommand, any_fs::AnyFs, reduce_contiguous_subsequences,
+        ByteStream, Command, File, FileStat, FileType, NodeKind, NullFs, NullFsPath,
+        StashedCommand,
+        any_fs::AnyFs,
+        delta::{self, Chunk, Piece},
+        hashing::{self, HashAlgo, HashTree},
+        metrics::METRICS,
+        needed_commands,
+        quarantine::{conflict_sibling, version_path, versions_dir},
+        reduce_contiguous_subsequences,
+        snapshot::{MerkleNode, State, StateStore},
+        throttle::RateLimiter,
     },
 };
+use async_recursion::async_recursion;
 use chrono::{DateTime, Utc};
 use eyre::Context;
-use indexmap::IndexMap;
+use futures::{StreamExt, TryStreamExt};
+use indexmap::{IndexMap, IndexSet};
+use reqwest::header::{ACCEPT, CONTENT_TYPE, IF_NONE_MATCH, RANGE};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
 use sha2::{Digest, Sha256};
 use sqlx::{
     Row, SqlitePool,
-    sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+    sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteSynchronous},
 };
+use tokio::sync::Mutex;
+use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
 
 #[derive(Clone, Debug)]
@@ -25,22 +46,250 @@ pub struct ShareNode {
     pub name: String,
     pub store: Arc<CommandStash>,
     pub relay: RelayNode,
+    /// Position of the relay in the volume `pullFrom` list
+    pub priority: usize,
+    pub tie_break: Option<TieBreak>,
+    /// Order in which the relays of the volume are tried, see [`super::Synchronizer::order_relays`]
+    pub relay_order: RelayOrder,
+    /// Names the copy keeping the remote version of a conflicting file, see [`conflict_sibling`]
+    pub conflict_suffix: String,
+    /// Previous versions kept of each overwritten file, none when 0
+    pub keep_versions: usize,
+    /// Size above which a file is streamed to the volume rather than downloaded in memory
+    pub stream_threshold: u64,
+    /// Size above which a file differing from its local copy is rebuilt from the chunks the
+    /// copy shares with the relay, see [`ShareNode::download_delta`]
+    pub delta_threshold: u64,
+    /// Shared by the relays of every volume so that the cap holds for the whole node
+    pub throttle: Option<Arc<RateLimiter>>,
+    /// Skip the commands of the subtrees whose Merkle hash matches the relay
+    pub merkle: bool,
+    /// Log the commands instead of running them, they are still consumed from the stash
+    pub dry_run: bool,
+    /// Priority of the volume synced through this relay
+    pub volume_priority: u32,
+    /// Age after which a stashed command is revalidated against the relay before being applied
+    pub command_ttl: Option<Duration>,
+    /// Shared by every request to the relay, see [`ShareNode::client_for`]
+    pub client: reqwest::Client,
+    /// Content hashes of the local volume, shared by every relay of the volume
+    pub hashes: Arc<Mutex<State>>,
+    /// Where `hashes` is kept across restarts, they only live in memory without it
+    pub hashes_store: Option<StateStore>,
+    /// How long the outcome of [`ShareNode::is_alive`] is trusted
+    pub liveness_ttl: Duration,
+    /// Last liveness probe and when it was made
+    pub liveness: Arc<std::sync::Mutex<Option<(Instant, bool)>>>,
+    /// Once cancelled, pulls and applies stop before their next page or command
+    pub shutdown: CancellationToken,
 }
 
+pub const MSGPACK_MIME: &str = "application/msgpack";
+
+/// Identifier of the relay node answering a `commands` request
+pub const NODE_ID_HEADER: &str = "x-nullfs-node-id";
+
+/// Algorithm of the hashes answered by a relay, sha256 when missing
+pub const HASH_ALGO_HEADER: &str = "x-nullfs-hash-algo";
+
+/// Entries of a directory beyond the window answered by `/v1/dir`
+pub const TOTAL_COUNT_HEADER: &str = "x-nullfs-total-count";
+
+/// Where the next `/v1/commands` page starts, sent back as `since`
+pub const CURSOR_HEADER: &str = "x-nullfs-cursor";
+
+/// Commands left after the page answered by `/v1/commands`
+pub const REMAINING_HEADER: &str = "x-nullfs-remaining";
+
+/// Paths sent per `/v1/hashes` request
+pub const HASH_BATCH_SIZE: usize = 1000;
+
+pub const DEFAULT_LIVENESS_TTL: Duration = Duration::from_secs(10);
+
+/// Files above this size are streamed to the volume, see [`ShareNode::download_streamed`]
+pub const DEFAULT_STREAM_THRESHOLD: u64 = 8 * 1024 * 1024;
+
+/// Files above this size only get the chunks their local copy lacks, see
+/// [`ShareNode::download_delta`]
+pub const DEFAULT_DELTA_THRESHOLD: u64 = 1024 * 1024;
+
+/// A relay not answering its `/v1/info` within this delay is considered down
+pub const LIVENESS_PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Error object answered by a relay, `{ "code", "message" }` or a plain string for the
+/// relays predating structured errors
+#[derive(Deserialize, Debug)]
+struct RelayError {
+    error: serde_json::Value,
+}
+
+impl fmt::Display for RelayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.error, self.error.get("message")) {
+            (_, Some(serde_json::Value::String(message))) => {
+                let code = self.error.get("code").and_then(|code| code.as_str());
+                write!(f, "{message} ({})", code.unwrap_or("unknown"))
+            }
+            (serde_json::Value::String(message), _) => write!(f, "{message}"),
+            (error, _) => write!(f, "{error}"),
+        }
+    }
+}
+
+/// What running a single command did
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CommandOutcome {
+    Applied {
+        bytes: u64,
+    },
+    /// Nothing to do, already in sync or gone from the relay
+    Skipped,
+}
+
+/// Remote hashes fetched ahead of a batch of commands, see [`ShareNode::remote_hashes`]
+#[derive(Clone, Debug, Default)]
+pub struct Prefetched {
+    /// Hashes of the relay running the commands
+    pub own: IndexMap<NullFsPath, String>,
+    /// Hashes of the other relays of the volume by name, only fetched for a [`TieBreak`]
+    pub relays: HashMap<String, IndexMap<NullFsPath, String>>,
+    /// Modification times of the files on each relay by name, this one included, only
+    /// fetched for [`TieBreak::Newest`]
+    pub modified: HashMap<String, IndexMap<NullFsPath, u64>>,
+}
+
+#[derive(Clone, Debug)]
+pub struct CommandFailure {
+    pub command: Command,
+    pub error: String,
+}
+
+/// Amount of stashed commands of a volume in each state
+#[derive(Serialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct StashCounts {
+    pub pending: u64,
+    /// Failed at least once, waiting for their next attempt
+    pub retrying: u64,
+    pub done: u64,
+    pub dead_letter: u64,
+    /// Left out when unstashing, repeated or undone by a later command
+    pub collapsed: u64,
+}
+
+/// Outcome of applying the stashed commands of a volume
+#[derive(Clone, Debug, Default)]
+pub struct ApplyReport {
+    pub applied: usize,
+    pub skipped: usize,
+    pub bytes: u64,
+    pub failures: Vec<CommandFailure>,
+    /// Applied commands with the bytes each downloaded
+    pub applied_commands: Vec<(Command, u64)>,
+}
+
+// states of a stashed command
+const PENDING: i32 = 0;
+const RETRYING: i32 = 1;
+const DONE: i32 = 5;
+/// Never applied, see [`CommandStash::unstash`]
+const COLLAPSED: i32 = 6;
+const DEAD_LETTER: i32 = -1;
+
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+
+/// Delay before the first retry of a failed command, doubled on each failure
+const RETRY_BASE: Duration = Duration::from_secs(5);
+const RETRY_MAX: Duration = Duration::from_secs(3600);
+
+pub const DEFAULT_BUSY_RETRIES: u32 = 5;
+
+pub const DEFAULT_POOL_SIZE: u32 = 5;
+/// 100 000 pages (400 000kb)
+pub const DEFAULT_CACHE_PAGES: u32 = 100_000;
+
+/// Pause before retrying a query sqlite refused because of a lock, grows with each attempt
+const BUSY_BACKOFF: Duration = Duration::from_millis(50);
+
 #[derive(Debug)]
 pub struct CommandStash {
     pool: SqlitePool,
+    collapse: bool,
+    max_attempts: u32,
+    busy_retries: u32,
+}
+
+/// Whether sqlite gave up waiting on a lock held by another connection, in which case
+/// running the same statements again can succeed
+pub fn is_busy(error: &sqlx::Error) -> bool {
+    let sqlx::Error::Database(e) = error else {
+        return false;
+    };
+
+    // extended result codes keep the primary one in their low byte
+    match e.code().and_then(|code| code.parse::<i32>().ok()) {
+        Some(code) => matches!(code & 0xff, SQLITE_BUSY | SQLITE_LOCKED),
+        None => e.message().contains("database is locked"),
+    }
+}
+
+const SQLITE_BUSY: i32 = 5;
+const SQLITE_LOCKED: i32 = 6;
+
+/// Runs `op` again, up to `retries` times, while it fails with a busy or locked database,
+/// see [`is_busy`], any other error is returned right away
+pub async fn retry_busy<T, F, Fut>(retries: u32, mut op: F) -> Result<T, sqlx::Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, sqlx::Error>>,
+{
+    let mut attempt = 0;
+    loop {
+        match op().await {
+            Err(e) if attempt < retries && is_busy(&e) => {
+                attempt += 1;
+                let jitter = Duration::from_millis(rand::random::<u64>() % 50);
+                let delay = BUSY_BACKOFF * attempt + jitter;
+                tracing::debug!("Stash is locked, retrying in {delay:?} ({attempt}/{retries})");
+                tokio::time::sleep(delay).await;
+            }
+            result => return result,
+        }
+    }
 }
 
 impl CommandStash {
-    pub async fn new(identifier: &NodeIdentifier) -> eyre::Result<Self> {
-        let options =
-            SqliteConnectOptions::from_str(&format!("sqlite://.stash-{}.db", identifier.uuid))?
-                .pragma("cache_size", "100000") // 100 000 pages (400 000kb)
-                .create_if_missing(true);
+    /// Stash of the node kept in its [`NodeConfig::data_dir`]
+    pub async fn new(config: &NodeConfig, identifier: &NodeIdentifier) -> eyre::Result<Self> {
+        Self::open_with(
+            &config
+                .data_dir()
+                .join(format!(".stash-{}.db", identifier.uuid)),
+            config.stash_pool_size.unwrap_or(DEFAULT_POOL_SIZE),
+            config.stash_cache_pages.unwrap_or(DEFAULT_CACHE_PAGES),
+        )
+        .await
+    }
+
+    /// Same as [`CommandStash::open_with`] with the default pool size and cache
+    #[allow(unused)]
+    pub async fn open(path: &Path) -> eyre::Result<Self> {
+        Self::open_with(path, DEFAULT_POOL_SIZE, DEFAULT_CACHE_PAGES).await
+    }
+
+    /// The database is kept in WAL mode so the pull and apply phases do not lock each other
+    /// out, sqlite keeps the `-wal` and `-shm` files next to it while it is open
+    ///
+    /// Each of the `pool_size` connections caches up to `cache_pages` pages of the database
+    pub async fn open_with(path: &Path, pool_size: u32, cache_pages: u32) -> eyre::Result<Self> {
+        let options = SqliteConnectOptions::from_str(&format!("sqlite://{}", path.display()))?
+            .pragma("cache_size", cache_pages.to_string())
+            .journal_mode(SqliteJournalMode::Wal)
+            .synchronous(SqliteSynchronous::Normal)
+            .create_if_missing(true);
 
         let pool = SqlitePoolOptions::new()
-            .max_connections(5)
+            .max_connections(pool_size)
             .connect_with(options)
             .await?;
 
@@ -54,17 +303,126 @@ impl CommandStash {
                 volume TEXT NOT NULL,
                 state INT NOT NULL
             );
+            CREATE INDEX IF NOT EXISTS CommandByVolume ON Command (volume, state, timestamp);
+            CREATE TABLE IF NOT EXISTS Cursor (
+                relay TEXT NOT NULL,
+                volume TEXT NOT NULL,
+                cursor TEXT NOT NULL,
+                PRIMARY KEY (relay, volume)
+            );
         "#,
         )
         .execute(&pool)
         .await?;
 
-        Ok(Self { pool })
+        // stashes created before the origin of the commands was tracked
+        let columns = sqlx::query("PRAGMA table_info(Command)")
+            .fetch_all(&pool)
+            .await?
+            .iter()
+            .map(|row| row.try_get::<String, _>("name"))
+            .collect::<Result<HashSet<_>, _>>()?;
+        let added_columns = [
+            ("origin", "TEXT"),
+            ("done_at", "TEXT"),
+            ("attempts", "INT NOT NULL DEFAULT 0"),
+            ("retry_at", "TEXT"),
+        ];
+        for (column, decl) in added_columns {
+            if columns.contains(column) {
+                continue;
+            }
+
+            let added = sqlx::query(&format!("ALTER TABLE Command ADD COLUMN {column} {decl}"))
+                .execute(&pool)
+                .await;
+            // the server and the synchronizer may open the same stash concurrently
+            if let Err(e) = added
+                && !e.to_string().contains("duplicate column")
+            {
+                return Err(e).wrap_err_with(|| format!("Adding column {column} to the stash"));
+            }
+        }
+
+        Ok(Self {
+            pool,
+            collapse: true,
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            busy_retries: DEFAULT_BUSY_RETRIES,
+        })
+    }
+
+    /// Failed attempts after which a command goes to the dead letters, see
+    /// [`CommandStash::mark_failed`]
+    pub fn max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// Times a query refused because the database is locked is run again before failing,
+    /// see [`retry_busy`]
+    pub fn busy_retries(mut self, busy_retries: u32) -> Self {
+        self.busy_retries = busy_retries;
+        self
+    }
+
+    /// Whether contiguous repetitions of the same commands are folded, and the commands later
+    /// ones undo dropped, when unstashing, see [`reduce_contiguous_subsequences`] and
+    /// [`needed_commands`]
+    pub fn collapsing(mut self, collapse: bool) -> Self {
+        self.collapse = collapse;
+        self
+    }
+
+    /// `origin` is the node the commands were pulled from, see [`CommandStash::suppress_echoes`]
+    pub async fn stash(
+        &self,
+        commands: Vec<Command>,
+        fs: &AnyFs,
+        origin: Option<&str>,
+    ) -> eyre::Result<()> {
+        self.stash_with(commands, fs, origin, None).await
+    }
+
+    /// Same as [`CommandStash::stash`] for a page pulled from `relay`, the cursor following
+    /// it is kept in the same transaction so that the page is neither lost nor pulled twice
+    pub async fn stash_page(
+        &self,
+        commands: Vec<Command>,
+        fs: &AnyFs,
+        origin: Option<&str>,
+        relay: &str,
+        cursor: &str,
+    ) -> eyre::Result<()> {
+        self.stash_with(commands, fs, origin, Some((relay, cursor)))
+            .await
+    }
+
+    /// Where the next page of `volume` pulled from `relay` starts, see
+    /// [`CommandStash::stash_page`]
+    pub async fn cursor(&self, relay: &str, volume: &str) -> eyre::Result<Option<String>> {
+        let row = retry_busy(self.busy_retries, || {
+            sqlx::query("SELECT cursor FROM Cursor WHERE relay = ? AND volume = ?")
+                .bind(relay)
+                .bind(volume)
+                .fetch_optional(&self.pool)
+        })
+        .await?;
+
+        Ok(row.map(|row| row.try_get("cursor")).transpose()?)
     }
 
-    pub async fn stash(&self, commands: Vec<Command>, fs: &AnyFs) -> eyre::Result<()> {
-        for command in commands {
-            let to_stash = StashedCommand {
+    async fn stash_with(
+        &self,
+        commands: Vec<Command>,
+        fs: &AnyFs,
+        origin: Option<&str>,
+        cursor: Option<(&str, &str)>,
+    ) -> eyre::Result<()> {
+        let count = commands.len() as u64;
+        let to_stash = commands
+            .into_iter()
+            .map(|command| StashedCommand {
                 id: Uuid::new_v4().to_string(),
                 volume: fs.get_volume_name(),
                 hash: {
@@ -79,41 +437,68 @@ impl CommandStash {
                 },
                 command,
                 timestamp: Utc::now(),
-                state: 0,
-            };
+                state: PENDING,
+                attempts: 0,
+            })
+            .collect::<Vec<_>>();
 
-            sqlx::query(
-                r#"
-                INSERT INTO Command (id, hash, command, timestamp, volume, state)
-                VALUES (?, ?, ?, ?, ?, ?)
-            "#,
-            )
-            .bind(&to_stash.id)
-            .bind(&to_stash.hash)
-            .bind(serde_json::to_string(&to_stash.command).unwrap())
-            .bind(to_stash.timestamp.to_rfc3339())
-            .bind(to_stash.volume)
-            .bind(to_stash.state)
-            .execute(&self.pool)
-            .await?;
-        }
+        // the whole transaction is replayed, a failed one leaves nothing behind
+        retry_busy(self.busy_retries, || async {
+            let mut tx = self.pool.begin().await?;
+            for stashed in &to_stash {
+                sqlx::query(
+                    r#"
+                    INSERT INTO Command (id, hash, command, timestamp, volume, state, origin)
+                    VALUES (?, ?, ?, ?, ?, ?, ?)
+                "#,
+                )
+                .bind(&stashed.id)
+                .bind(&stashed.hash)
+                .bind(serde_json::to_string(&stashed.command).unwrap())
+                .bind(stashed.timestamp.to_rfc3339())
+                .bind(&stashed.volume)
+                .bind(stashed.state)
+                .bind(origin)
+                .execute(&mut *tx)
+                .await?;
+            }
+            if let Some((relay, cursor)) = cursor {
+                sqlx::query(
+                    "INSERT OR REPLACE INTO Cursor (relay, volume, cursor) VALUES (?, ?, ?)",
+                )
+                .bind(relay)
+                .bind(fs.get_volume_name())
+                .bind(cursor)
+                .execute(&mut *tx)
+                .await?;
+            }
+            tx.commit().await
+        })
+        .await?;
+        METRICS.commands_stashed(&fs.get_volume_name(), count);
 
         Ok(())
     }
 
+    /// Pending commands of a volume, the failed ones only once their retry time has come
     pub async fn unstash(&self, volume: &str) -> eyre::Result<Vec<StashedCommand>> {
-        let rows = sqlx::query(
-            "SELECT id, hash, command, timestamp, volume, state
-            FROM Command WHERE state = 0 AND volume = ?
-            ORDER BY timestamp ASC",
-        )
-        .bind(volume)
-        .fetch_all(&self.pool)
+        let now = Utc::now().to_rfc3339();
+        let rows = retry_busy(self.busy_retries, || {
+            sqlx::query(
+                "SELECT id, hash, command, timestamp, volume, state, attempts
+                FROM Command
+                WHERE volume = ? AND (state = ? OR (state = ? AND retry_at <= ?))
+                ORDER BY timestamp ASC",
+            )
+            .bind(volume)
+            .bind(PENDING)
+            .bind(RETRYING)
+            .bind(&now)
+            .fetch_all(&self.pool)
+        })
         .await?;
 
-        let mut results = IndexMap::new();
-        let mut contiguous_hashes = vec![];
-
+        let mut stashed = vec![];
         for row in rows {
             let id: String = row.try_get("id")?;
             let hash: String = row.try_get("hash")?;
@@ -121,6 +506,7 @@ impl CommandStash {
             let ts_str: String = row.try_get("timestamp")?;
             let volume: String = row.try_get("volume")?;
             let state: i32 = row.try_get("state")?;
+            let attempts: u32 = row.try_get("attempts")?;
 
             let timestamp = DateTime::parse_from_rfc3339(&ts_str)
                 .wrap_err_with(|| eyre::eyre!("Bad timestamp in row for hash {hash}"))?
@@ -128,42 +514,397 @@ impl CommandStash {
             let command = serde_json::from_str::<Command>(&cmd_str)
                 .wrap_err_with(|| eyre::eyre!("Parsing stored command for hash {hash}"))?;
 
-            contiguous_hashes.push(hash.clone());
-            results.insert(
-                hash.clone(),
-                StashedCommand {
-                    id,
-                    hash,
-                    timestamp,
-                    command,
-                    volume,
-                    state,
-                },
-            );
+            stashed.push(StashedCommand {
+                id,
+                hash,
+                timestamp,
+                command,
+                volume,
+                state,
+                attempts,
+            });
+        }
+
+        if !self.collapse {
+            return Ok(stashed);
+        }
+
+        let hashes = stashed.iter().map(|op| op.hash.clone()).collect::<Vec<_>>();
+        let mut collapsed = reduce_contiguous_subsequences(&hashes)
+            .into_iter()
+            .peekable();
+
+        // the collapsed hashes are a subsequence of the stashed ones, every row left out
+        // repeats a kept command and is retired right away so it does not linger
+        let mut folded = vec![];
+        let mut dropped = vec![];
+        for op in stashed {
+            if collapsed.peek() == Some(&op.hash) {
+                collapsed.next();
+                folded.push(op);
+            } else {
+                dropped.push(op);
+            }
         }
 
-        let collapsed_ops = reduce_contiguous_subsequences(&contiguous_hashes);
-        Ok(collapsed_ops
+        // then the commands undone by later ones, see `needed_commands`
+        let commands = folded
             .iter()
-            .map(|k| results.get(k).unwrap().clone())
-            .collect())
+            .map(|op| op.command.clone())
+            .collect::<Vec<_>>();
+        let mut kept = vec![];
+        for (op, needed) in folded.into_iter().zip(needed_commands(&commands)) {
+            match needed {
+                true => kept.push(op),
+                false => dropped.push(op),
+            }
+        }
+
+        if !dropped.is_empty() {
+            tracing::info!(
+                "Collapsed {} of {} stashed commands for {}",
+                dropped.len(),
+                dropped.len() + kept.len(),
+                volume
+            );
+        }
+
+        for op in &dropped {
+            self.mark_collapsed(op).await?;
+        }
+
+        Ok(kept)
     }
 
     pub async fn mark_done(&self, stashed: &StashedCommand) -> eyre::Result<()> {
-        sqlx::query("UPDATE Command SET state = 5 WHERE id = ?")
-            .bind(&stashed.id)
-            .execute(&self.pool)
-            .await?;
+        let done_at = Utc::now().to_rfc3339();
+        retry_busy(self.busy_retries, || {
+            sqlx::query("UPDATE Command SET state = ?, done_at = ? WHERE id = ?")
+                .bind(DONE)
+                .bind(&done_at)
+                .bind(&stashed.id)
+                .execute(&self.pool)
+        })
+        .await?;
+        METRICS.command_applied(&stashed.volume);
 
         tracing::debug!("Operation done id={}, hash={}", stashed.id, stashed.hash);
         Ok(())
     }
+
+    /// Retires a command left out by the collapsing, it is neither counted as applied nor
+    /// compared by [`CommandStash::suppress_echoes`]
+    async fn mark_collapsed(&self, stashed: &StashedCommand) -> eyre::Result<()> {
+        retry_busy(self.busy_retries, || {
+            sqlx::query("UPDATE Command SET state = ? WHERE id = ?")
+                .bind(COLLAPSED)
+                .bind(&stashed.id)
+                .execute(&self.pool)
+        })
+        .await?;
+
+        tracing::debug!(
+            "Operation collapsed id={}, hash={}",
+            stashed.id,
+            stashed.hash
+        );
+        Ok(())
+    }
+
+    /// Schedules the retry of a failed command, the delay doubles on each attempt and the
+    /// command is moved to the dead letters once it failed `max_attempts` times
+    pub async fn mark_failed(&self, stashed: &StashedCommand, error: &str) -> eyre::Result<()> {
+        METRICS.command_failed(&stashed.volume);
+        let attempts = stashed.attempts + 1;
+        if attempts >= self.max_attempts {
+            tracing::error!(
+                "Giving up on {} after {} attempts: {}",
+                stashed.command,
+                attempts,
+                error
+            );
+            retry_busy(self.busy_retries, || {
+                sqlx::query("UPDATE Command SET state = ?, attempts = ? WHERE id = ?")
+                    .bind(DEAD_LETTER)
+                    .bind(attempts)
+                    .bind(&stashed.id)
+                    .execute(&self.pool)
+            })
+            .await?;
+
+            return Ok(());
+        }
+
+        let delay = RETRY_BASE
+            .saturating_mul(2u32.saturating_pow(attempts - 1))
+            .min(RETRY_MAX);
+        let retry_at = (Utc::now() + delay).to_rfc3339();
+        tracing::debug!(
+            "Retrying {} in {}s (attempt {}/{})",
+            stashed.command,
+            delay.as_secs(),
+            attempts,
+            self.max_attempts
+        );
+        retry_busy(self.busy_retries, || {
+            sqlx::query("UPDATE Command SET state = ?, attempts = ?, retry_at = ? WHERE id = ?")
+                .bind(RETRYING)
+                .bind(attempts)
+                .bind(&retry_at)
+                .bind(&stashed.id)
+                .execute(&self.pool)
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    /// Leaves out the captured `commands` that only replay what was applied from `peer`,
+    /// the last command applied on a path tells whether the local change came from there
+    ///
+    /// Only the applied commands touching one of the captured paths are read back
+    pub async fn suppress_echoes(
+        &self,
+        volume: &str,
+        peer: &str,
+        commands: Vec<Command>,
+    ) -> eyre::Result<Vec<Command>> {
+        if commands.is_empty() {
+            return Ok(commands);
+        }
+
+        let captured = serde_json::to_string(
+            &commands
+                .iter()
+                .map(|command| match command {
+                    Command::Delete { file }
+                    | Command::Write { file }
+                    | Command::Touch { file }
+                    | Command::Rename { to: file, .. } => &file.path,
+                })
+                .collect::<HashSet<_>>(),
+        )?;
+        let rows = retry_busy(self.busy_retries, || {
+            sqlx::query(
+                "SELECT command, origin, done_at FROM Command
+                WHERE state = ?1 AND volume = ?2 AND done_at IS NOT NULL
+                AND (json_extract(command, '$.file.path') IN (SELECT value FROM json_each(?3))
+                    OR json_extract(command, '$.from.path') IN (SELECT value FROM json_each(?3))
+                    OR json_extract(command, '$.to.path') IN (SELECT value FROM json_each(?3)))
+                ORDER BY done_at ASC",
+            )
+            .bind(DONE)
+            .bind(volume)
+            .bind(&captured)
+            .fetch_all(&self.pool)
+        })
+        .await?;
+
+        let mut applied = HashMap::new();
+        for row in rows {
+            let cmd_str: String = row.try_get("command")?;
+            let origin: Option<String> = row.try_get("origin")?;
+            let done_str: String = row.try_get("done_at")?;
+            let command =
+                serde_json::from_str::<Command>(&cmd_str).wrap_err("Parsing stored command")?;
+            let done_at = DateTime::parse_from_rfc3339(&done_str)
+                .wrap_err_with(|| format!("Bad completion time {done_str}"))?
+                .timestamp_millis() as u64;
+
+            let paths = match &command {
+                Command::Delete { file } | Command::Write { file } | Command::Touch { file } => {
+                    vec![file.path.clone()]
+                }
+                Command::Rename { from, to } => vec![from.path.clone(), to.path.clone()],
+            };
+            for path in paths {
+                applied.insert(path, (origin.clone(), command.clone(), done_at));
+            }
+        }
+
+        let (kept, echoes): (Vec<_>, Vec<_>) = commands.into_iter().partition(|command| {
+            let target = match command {
+                Command::Delete { file } | Command::Write { file } | Command::Touch { file } => {
+                    file
+                }
+                Command::Rename { to, .. } => to,
+            };
+            let Some((Some(origin), last, done_at)) = applied.get(&target.path) else {
+                return true;
+            };
+            if origin != peer {
+                return true;
+            }
+
+            let is_echo = match (command, last) {
+                (Command::Delete { .. }, Command::Delete { .. }) => true,
+                (Command::Delete { .. }, _) | (_, Command::Delete { .. }) => false,
+                // modified locally after the pulled change was applied
+                _ => target.stat.modified <= *done_at,
+            };
+
+            !is_echo
+        });
+
+        if !echoes.is_empty() {
+            tracing::debug!(
+                "Suppressed {} commands of @/{volume} pulled from {peer}",
+                echoes.len()
+            );
+        }
+
+        Ok(kept)
+    }
+
+    pub async fn counts(&self, volume: &str) -> eyre::Result<StashCounts> {
+        let rows = retry_busy(self.busy_retries, || {
+            sqlx::query(
+                "SELECT state, COUNT(*) AS count FROM Command WHERE volume = ? GROUP BY state",
+            )
+            .bind(volume)
+            .fetch_all(&self.pool)
+        })
+        .await?;
+
+        let mut counts = StashCounts::default();
+        for row in rows {
+            let count = row.try_get::<i64, _>("count")? as u64;
+            match row.try_get::<i32, _>("state")? {
+                PENDING => counts.pending = count,
+                RETRYING => counts.retrying = count,
+                DONE => counts.done = count,
+                DEAD_LETTER => counts.dead_letter = count,
+                COLLAPSED => counts.collapsed = count,
+                state => tracing::warn!("Unknown state {state} in the stash"),
+            }
+        }
+
+        Ok(counts)
+    }
+
+    /// Purges applied and collapsed commands and reclaims the freed pages, the last command
+    /// applied to each path is kept for [`CommandStash::suppress_echoes`]
+    pub async fn vacuum(&self) -> eyre::Result<u64> {
+        let purged = retry_busy(self.busy_retries, || {
+            sqlx::query(
+                "DELETE FROM Command WHERE state = ?2 OR (state = ?1 AND rowid NOT IN (
+                    SELECT row FROM (
+                        SELECT row, ROW_NUMBER() OVER (
+                            PARTITION BY volume, path ORDER BY done_at DESC, row DESC
+                        ) AS rank
+                        FROM (
+                            SELECT rowid AS row, volume, done_at,
+                                json_extract(command, '$.file.path') AS path
+                            FROM Command WHERE state = ?1
+                            UNION ALL
+                            SELECT rowid, volume, done_at, json_extract(command, '$.from.path')
+                            FROM Command WHERE state = ?1
+                            UNION ALL
+                            SELECT rowid, volume, done_at, json_extract(command, '$.to.path')
+                            FROM Command WHERE state = ?1
+                        )
+                        WHERE path IS NOT NULL
+                    )
+                    WHERE rank = 1
+                ))",
+            )
+            .bind(DONE)
+            .bind(COLLAPSED)
+            .execute(&self.pool)
+        })
+        .await?
+        .rows_affected();
+
+        retry_busy(self.busy_retries, || {
+            sqlx::query("VACUUM").execute(&self.pool)
+        })
+        .await?;
+
+        Ok(purged)
+    }
 }
 
 impl ShareNode {
+    /// Http client keeping its connections to `relay` alive between requests,
+    /// compressed responses are accepted and decoded when `compression` is set
+    pub fn client_for(relay: &RelayNode, compression: bool) -> eyre::Result<reqwest::Client> {
+        let mut builder = reqwest::Client::builder()
+            .connect_timeout(Duration::from_secs(
+                relay.connect_timeout_secs.unwrap_or(10),
+            ))
+            .zstd(compression)
+            .gzip(compression);
+        if let Some(timeout) = relay.timeout_secs {
+            builder = builder.timeout(Duration::from_secs(timeout));
+        }
+        if let Some(path) = &relay.ca_cert {
+            let pem = std::fs::read(path)
+                .wrap_err_with(|| format!("Reading certificate {}", path.display()))?;
+            let cert = reqwest::Certificate::from_pem(&pem)
+                .wrap_err_with(|| format!("Parsing certificate {}", path.display()))?;
+            builder = builder.add_root_certificate(cert);
+        }
+
+        builder
+            .build()
+            .wrap_err_with(|| format!("Building http client for {}", relay.address))
+    }
+
+    /// Parses a json response, keeping the raw body around to explain a mismatch
+    async fn parse_json<T: DeserializeOwned>(
+        &self,
+        response: reqwest::Response,
+    ) -> eyre::Result<T> {
+        let endpoint = response.url().path().to_owned();
+        let content_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("none")
+            .to_owned();
+        let body = response
+            .text()
+            .await
+            .wrap_err_with(|| format!("Reading response of {endpoint} from {}", self.name))?;
+
+        decode_json(&body, &content_type)
+            .wrap_err_with(|| format!("Parsing response of {endpoint} from {}", self.name))
+    }
+
+    /// Outcome of the last liveness probe while it is fresh, see [`ShareNode::is_alive`]
+    pub fn liveness(&self) -> Option<bool> {
+        self.liveness
+            .lock()
+            .unwrap()
+            .filter(|(probed_at, _)| probed_at.elapsed() < self.liveness_ttl)
+            .map(|(_, alive)| alive)
+    }
+
+    /// The next [`ShareNode::is_alive`] probes the relay again
+    pub fn expire_liveness(&self) {
+        self.liveness.lock().unwrap().take();
+    }
+
+    /// Probes `/v1/info` on the relay, the outcome is reused for [`ShareNode::liveness_ttl`]
+    /// unless a request to the relay fails in the meantime
     pub async fn is_alive(&self) -> eyre::Result<bool> {
-        let client = reqwest::Client::new();
-        let response = client.get(self.relay.address.clone()).send().await;
+        if let Some(alive) = self.liveness() {
+            return Ok(alive);
+        }
+
+        let alive = self.probe().await?;
+        *self.liveness.lock().unwrap() = Some((Instant::now(), alive));
+
+        Ok(alive)
+    }
+
+    async fn probe(&self) -> eyre::Result<bool> {
+        let response = self
+            .client
+            .get(self.relay.address.join("v1/info")?)
+            .timeout(LIVENESS_PROBE_TIMEOUT)
+            .send()
+            .await;
 
         match response {
             Ok(response) => {
@@ -193,47 +934,156 @@ impl ShareNode {
         }
     }
 
-    pub async fn pull(&self, fs: &AnyFs, identifer: Arc<NodeIdentifier>) -> eyre::Result<()> {
-        let client = reqwest::Client::new();
-        let response = client
-            .get(self.relay.address.join("v1/commands")?)
-            .query(&[
-                ("volume", fs.get_volume_name()),
+    /// Stashes the commands the relay has for this node, they are returned as well
+    ///
+    /// They come page after page, each stashed along with the cursor following it so that an
+    /// interrupted pull resumes where it stopped, relays without cursors answer all of them
+    /// at once
+    pub async fn pull(
+        &self,
+        fs: &AnyFs,
+        identifer: Arc<NodeIdentifier>,
+    ) -> eyre::Result<Vec<Command>> {
+        let volume = fs.get_volume_name();
+        let mut pulled = vec![];
+        loop {
+            let since = self
+                .store
+                .cursor(&self.name, &volume)
+                .await?
+                .unwrap_or_default();
+            let mut query = vec![
+                ("volume", volume.clone()),
                 ("node_id", identifer.uuid.to_owned()),
-            ])
-            .basic_auth(&self.relay.auth.name, self.relay.auth.password.clone())
-            .send()
-            .await?;
+                ("since", since.clone()),
+            ];
+            if let Some(realm) = &self.relay.realm {
+                query.push(("realm", realm.to_owned()));
+            }
 
-        if !response.status().is_success() {
-            eyre::bail!(
-                "Remote {} answered status {}: {:?}",
-                self.name,
-                response.status(),
-                response.text().await
-            )
+            let response = self
+                .client
+                .get(self.relay.address.join("v1/commands")?)
+                .query(&query)
+                .header(ACCEPT, format!("{MSGPACK_MIME}, application/json;q=0.9"))
+                .basic_auth(&self.relay.auth.name, self.relay.auth.password.clone())
+                .send()
+                .await
+                .inspect_err(|_| self.expire_liveness())?;
+
+            if !response.status().is_success() {
+                eyre::bail!(
+                    "Remote {} answered status {}: {:?}",
+                    self.name,
+                    response.status(),
+                    response.text().await
+                )
+            }
+
+            let header = |name: &str| {
+                response
+                    .headers()
+                    .get(name)
+                    .and_then(|value| value.to_str().ok())
+                    .map(|value| value.to_owned())
+            };
+            let origin = header(NODE_ID_HEADER);
+            let cursor = header(CURSOR_HEADER);
+            let remaining = header(REMAINING_HEADER)
+                .and_then(|remaining| remaining.parse::<u64>().ok())
+                .unwrap_or_default();
+            let is_msgpack =
+                header(CONTENT_TYPE.as_str()).is_some_and(|value| value.starts_with(MSGPACK_MIME));
+
+            let external_changes = if is_msgpack {
+                let bytes = response.bytes().await?;
+                rmp_serde::from_slice::<Vec<Command>>(&bytes)
+                    .wrap_err_with(|| format!("Parsing msgpack commands from {}", self.name))?
+            } else {
+                self.parse_json::<Vec<Command>>(response).await?
+            };
+
+            match &cursor {
+                Some(cursor) => {
+                    self.store
+                        .stash_page(
+                            external_changes.clone(),
+                            fs,
+                            origin.as_deref(),
+                            &self.name,
+                            cursor,
+                        )
+                        .await?
+                }
+                None => {
+                    self.store
+                        .stash(external_changes.clone(), fs, origin.as_deref())
+                        .await?
+                }
+            }
+            pulled.extend(external_changes);
+
+            // a cursor that did not move would ask for the same page again
+            match cursor {
+                Some(cursor)
+                    if remaining > 0 && cursor != since && !self.shutdown.is_cancelled() =>
+                {
+                    tracing::debug!("{remaining} commands of @/{volume} left on {}", self.name);
+                }
+                _ => break,
+            }
         }
 
-        let external_changes = response
-            .json::<Vec<Command>>()
-            .await
-            .wrap_err_with(|| format!("Parsing remote response from {}", self.relay.address))?;
+        Ok(pulled)
+    }
 
-        self.store.stash(external_changes, fs).await?;
+    /// Downloads a file and fails unless its content hashes to `expected`, the hash is
+    /// computed as the chunks arrive
+    ///
+    /// `known` is the hash last synced, sent as `If-None-Match` so that an unchanged file
+    /// costs a `304 Not Modified` alone, in which case nothing is returned
+    pub async fn download_verified(
+        &self,
+        path: &NullFsPath,
+        expected: &str,
+        known: Option<&str>,
+    ) -> eyre::Result<Option<Vec<u8>>> {
+        let Some((data, hash)) = self.download_hashed(path, known).await? else {
+            return Ok(None);
+        };
+        if hash != expected {
+            eyre::bail!(
+                "Refusing {path} from {}: content hash {hash}, expected {expected}",
+                self.name
+            );
+        }
 
-        Ok(())
+        Ok(Some(data))
     }
 
-    pub async fn download(&self, path: &NullFsPath) -> eyre::Result<Vec<u8>> {
-        let client = reqwest::Client::new();
-        let response = client
+    /// Download response of a file, a `304 Not Modified` when the content still hashes
+    /// to `known`
+    async fn download_response(
+        &self,
+        path: &NullFsPath,
+        known: Option<&str>,
+    ) -> eyre::Result<reqwest::Response> {
+        let mut request = self
+            .client
             .get(self.relay.address.join("v1/download")?)
             .query(&[("path", path.to_string())])
-            .basic_auth(&self.relay.auth.name, self.relay.auth.password.clone())
+            .basic_auth(&self.relay.auth.name, self.relay.auth.password.clone());
+        if let Some(known) = known {
+            request = request.header(IF_NONE_MATCH, format!("\"{known}\""));
+        }
+        let response = request
             .send()
-            .await?;
+            .await
+            .inspect_err(|_| self.expire_liveness())?;
 
-        if !response.status().is_success() {
+        let not_modified =
+            known.is_some() && response.status() == reqwest::StatusCode::NOT_MODIFIED;
+        if !(response.status().is_success() || not_modified) {
             eyre::bail!(
                 "Download failed, remote {} answered status {}: {:?}",
                 self.name,
@@ -242,115 +1092,1334 @@ impl ShareNode {
             )
         }
 
-        Ok(response.bytes().await?.to_vec())
+        Ok(response)
     }
 
-    pub async fn remote_hash(&self, path: &NullFsPath) -> eyre::Result<String> {
-        let client = reqwest::Client::new();
-        let response = client
-            .get(self.relay.address.join("v1/hash")?)
+    /// Streams a file to `fs` as its chunks arrive instead of holding it in memory, the
+    /// destination is only replaced once the content hashed to `expected`
+    ///
+    /// Nothing is written and `false` is returned when the relay still has `known`, see
+    /// [`ShareNode::download_verified`]
+    pub async fn download_streamed(
+        &self,
+        fs: &AnyFs,
+        file: &File,
+        expected: &str,
+        known: Option<&str>,
+    ) -> eyre::Result<bool> {
+        struct Verifying {
+            response: reqwest::Response,
+            hasher: hashing::ContentHasher,
+        }
+
+        let response = self.download_response(&file.path, known).await?;
+        if response.status() == reqwest::StatusCode::NOT_MODIFIED {
+            return Ok(false);
+        }
+
+        let (path, name, expected) = (file.path.clone(), self.name.clone(), expected.to_owned());
+        let (throttle, priority) = (self.throttle.clone(), self.volume_priority);
+        let state = Verifying {
+            response,
+            hasher: hashing::ContentHasher::new(),
+        };
+
+        let chunks = futures::stream::try_unfold(state, move |mut state| {
+            let (path, name, expected) = (path.clone(), name.clone(), expected.clone());
+            let throttle = throttle.clone();
+            async move {
+                if let Some(chunk) = state.response.chunk().await? {
+                    if let Some(throttle) = &throttle {
+                        throttle.acquire(chunk.len() as u64, priority).await;
+                    }
+                    state.hasher.update(&chunk);
+                    METRICS.bytes_downloaded(chunk.len() as u64);
+                    return Ok(Some((chunk, state)));
+                }
+
+                // failing the last chunk keeps the destination as it was
+                let content_hash = state.hasher.finalize();
+                if content_hash != expected {
+                    eyre::bail!(
+                        "Refusing {path} from {name}: content hash {content_hash}, expected {expected}"
+                    );
+                }
+
+                Ok(None)
+            }
+        });
+
+        fs.write_stream(file, Box::pin(chunks)).await?;
+
+        Ok(true)
+    }
+
+    /// Chunks of a remote file, see [`delta::Chunk`]
+    pub async fn remote_chunks(&self, path: &NullFsPath) -> eyre::Result<Vec<Chunk>> {
+        let response = self
+            .client
+            .get(self.relay.address.join("v1/chunks")?)
             .query(&[("path", path.to_string())])
             .basic_auth(&self.relay.auth.name, self.relay.auth.password.clone())
             .send()
-            .await?;
+            .await
+            .inspect_err(|_| self.expire_liveness())?;
 
         if !response.status().is_success() {
             eyre::bail!(
-                "Could not get hash, remote {} answered with status {}: {:?}",
+                "Could not get chunks, remote {} answered with status {}: {:?}",
                 self.name,
                 response.status(),
                 response.text().await
             )
         }
 
-        response.json().await.map_err(|e| e.into())
+        self.check_hash_algo(&response)?;
+        self.parse_json(response).await
     }
 
-    pub async fn remote_exists(&self, path: &NullFsPath) -> eyre::Result<bool> {
-        let client = reqwest::Client::new();
-        let response = client
-            .get(self.relay.address.join("v1/exists")?)
+    /// Bytes of a remote file within `range`, as they arrive
+    async fn download_range(
+        &self,
+        path: &NullFsPath,
+        range: Range<u64>,
+    ) -> eyre::Result<ByteStream> {
+        let response = self
+            .client
+            .get(self.relay.address.join("v1/download")?)
             .query(&[("path", path.to_string())])
             .basic_auth(&self.relay.auth.name, self.relay.auth.password.clone())
+            .header(RANGE, format!("bytes={}-{}", range.start, range.end - 1))
             .send()
-            .await?;
+            .await
+            .inspect_err(|_| self.expire_liveness())?;
 
-        if !response.status().is_success() {
+        // a relay ignoring the range would send the whole file
+        if response.status() != reqwest::StatusCode::PARTIAL_CONTENT {
             eyre::bail!(
-                "Could not check if it exists, remote {} answered with status {}: {:?}",
+                "Ranged download failed, remote {} answered status {}",
                 self.name,
-                response.status(),
-                response.text().await
+                response.status()
             )
         }
 
-        response.json().await.map_err(|e| e.into())
-    }
+        let (throttle, priority) = (self.throttle.clone(), self.volume_priority);
+        let chunks = futures::stream::try_unfold(response, move |mut response| {
+            let throttle = throttle.clone();
+            async move {
+                let Some(chunk) = response.chunk().await? else {
+                    return Ok(None);
+                };
+                if let Some(throttle) = &throttle {
+                    throttle.acquire(chunk.len() as u64, priority).await;
+                }
+                METRICS.bytes_downloaded(chunk.len() as u64);
 
-    pub async fn run_command(&self, command: &Command, fs: &AnyFs) -> eyre::Result<()> {
+                Ok(Some((chunk, response)))
+            }
+        });
+
+        Ok(Box::pin(chunks))
+    }
+
+    /// Rebuilds `file` from the chunks its local copy shares with the relay, only the others
+    /// are downloaded, see [`delta::plan`]. The local copy is only replaced once the rebuilt
+    /// content hashed to `expected`, returns the bytes downloaded
+    pub async fn download_delta(
+        &self,
+        fs: &AnyFs,
+        file: &File,
+        expected: &str,
+    ) -> eyre::Result<u64> {
+        struct Rebuilding {
+            bytes: ByteStream,
+            hasher: hashing::ContentHasher,
+        }
+
+        let remote = self.remote_chunks(&file.path).await?;
+        let local = delta::chunks_of(fs.read_stream(&file.path).await?).await?;
+        let pieces = delta::plan(&local, &remote);
+        let downloaded = delta::remote_bytes(&pieces);
+
+        let (node, local_fs, path) = (self.clone(), fs.clone(), file.path.clone());
+        let opened = futures::stream::iter(pieces).then(move |piece| {
+            let (node, local_fs, path) = (node.clone(), local_fs.clone(), path.clone());
+            async move {
+                match piece {
+                    Piece::Local(range) => local_fs.read_range(&path, range).await,
+                    Piece::Remote(range) => node.download_range(&path, range).await,
+                }
+            }
+        });
+
+        let (path, name, expected) = (file.path.clone(), self.name.clone(), expected.to_owned());
+        let state = Rebuilding {
+            bytes: Box::pin(opened.try_flatten()),
+            hasher: hashing::ContentHasher::new(),
+        };
+        let chunks = futures::stream::try_unfold(state, move |mut state| {
+            let (path, name, expected) = (path.clone(), name.clone(), expected.clone());
+            async move {
+                if let Some(chunk) = state.bytes.try_next().await? {
+                    state.hasher.update(&chunk);
+                    return Ok(Some((chunk, state)));
+                }
+
+                // failing the last chunk keeps the local copy as it was
+                let content_hash = state.hasher.finalize();
+                if content_hash != expected {
+                    eyre::bail!(
+                        "Refusing {path} rebuilt from {name}: content hash {content_hash}, expected {expected}"
+                    );
+                }
+
+                Ok(None)
+            }
+        });
+
+        fs.write_stream(file, Box::pin(chunks)).await?;
+
+        Ok(downloaded)
+    }
+
+    /// Downloaded bytes along with their content hash, none when it still hashes to `known`
+    async fn download_hashed(
+        &self,
+        path: &NullFsPath,
+        known: Option<&str>,
+    ) -> eyre::Result<Option<(Vec<u8>, String)>> {
+        let mut response = self.download_response(path, known).await?;
+        if response.status() == reqwest::StatusCode::NOT_MODIFIED {
+            return Ok(None);
+        }
+
+        let mut hasher = hashing::ContentHasher::new();
+        let mut data = vec![];
+        while let Some(chunk) = response.chunk().await? {
+            if let Some(throttle) = &self.throttle {
+                throttle
+                    .acquire(chunk.len() as u64, self.volume_priority)
+                    .await;
+            }
+            hasher.update(&chunk);
+            data.extend_from_slice(&chunk);
+        }
+        METRICS.bytes_downloaded(data.len() as u64);
+
+        Ok(Some((data, hasher.finalize())))
+    }
+
+    /// Refuses the hashes of a relay computed with another algorithm than the local ones,
+    /// they would never match
+    fn check_hash_algo(&self, response: &reqwest::Response) -> eyre::Result<()> {
+        let remote = match response.headers().get(HASH_ALGO_HEADER) {
+            Some(value) => value
+                .to_str()
+                .ok()
+                .and_then(HashAlgo::from_name)
+                .ok_or_else(|| {
+                    eyre::eyre!("Unknown hash algorithm from {}: {value:?}", self.name)
+                })?,
+            None => HashAlgo::default(),
+        };
+
+        if remote != hashing::algo() {
+            eyre::bail!(
+                "Remote {} hashes with {} while this node uses {}, refusing to compare",
+                self.name,
+                remote.name(),
+                hashing::algo().name()
+            );
+        }
+
+        Ok(())
+    }
+
+    pub async fn remote_hash(&self, path: &NullFsPath) -> eyre::Result<String> {
+        let response = self
+            .client
+            .get(self.relay.address.join("v1/hash")?)
+            .query(&[("path", path.to_string())])
+            .basic_auth(&self.relay.auth.name, self.relay.auth.password.clone())
+            .send()
+            .await
+            .inspect_err(|_| self.expire_liveness())?;
+
+        if !response.status().is_success() {
+            eyre::bail!(
+                "Could not get hash, remote {} answered with status {}: {:?}",
+                self.name,
+                response.status(),
+                response.text().await
+            )
+        }
+
+        self.check_hash_algo(&response)?;
+        self.parse_json(response).await
+    }
+
+    /// Content hashes of several remote paths in one request, the paths the relay could not
+    /// hash are missing from the answer
+    pub async fn remote_hashes(
+        &self,
+        paths: &[NullFsPath],
+    ) -> eyre::Result<IndexMap<NullFsPath, String>> {
+        let response = self
+            .client
+            .post(self.relay.address.join("v1/hashes")?)
+            .json(paths)
+            .basic_auth(&self.relay.auth.name, self.relay.auth.password.clone())
+            .send()
+            .await
+            .inspect_err(|_| self.expire_liveness())?;
+
+        if !response.status().is_success() {
+            eyre::bail!(
+                "Could not get hashes, remote {} answered with status {}: {:?}",
+                self.name,
+                response.status(),
+                response.text().await
+            )
+        }
+
+        self.check_hash_algo(&response)?;
+        self.parse_json(response).await
+    }
+
+    /// Remote metadata of several paths of a volume, the missing ones are left out
+    pub async fn remote_stats(
+        &self,
+        paths: &[NullFsPath],
+    ) -> eyre::Result<IndexMap<NullFsPath, FileStat>> {
+        let response = self
+            .client
+            .post(self.relay.address.join("v1/stats")?)
+            .json(paths)
+            .basic_auth(&self.relay.auth.name, self.relay.auth.password.clone())
+            .send()
+            .await
+            .inspect_err(|_| self.expire_liveness())?;
+
+        if !response.status().is_success() {
+            eyre::bail!(
+                "Could not get metadata, remote {} answered with status {}: {:?}",
+                self.name,
+                response.status(),
+                response.text().await
+            )
+        }
+
+        self.parse_json(response).await
+    }
+
+    /// Files the commands may compare with the local ones
+    fn prefetch_paths<'a>(commands: impl Iterator<Item = &'a Command>) -> Vec<NullFsPath> {
+        commands
+            .filter_map(|command| match command {
+                Command::Write { file }
+                | Command::Touch { file }
+                | Command::Rename { to: file, .. }
+                    if file.stat.is_file() =>
+                {
+                    Some(file.path.clone())
+                }
+                _ => None,
+            })
+            .collect::<IndexSet<_>>()
+            .into_iter()
+            .collect()
+    }
+
+    /// Remote hashes of the files the commands may compare, fetched in batches of
+    /// [`HASH_BATCH_SIZE`], relays without `/v1/hashes` get asked one path at a time instead
+    async fn prefetch_hashes<'a>(
+        &self,
+        commands: impl Iterator<Item = &'a Command>,
+    ) -> IndexMap<NullFsPath, String> {
+        let paths = Self::prefetch_paths(commands);
+
+        let mut hashes = IndexMap::new();
+        for batch in paths.chunks(HASH_BATCH_SIZE) {
+            match self.remote_hashes(batch).await {
+                Ok(batch) => hashes.extend(batch),
+                Err(e) => {
+                    tracing::warn!("Could not prefetch hashes from {}: {}", self.name, e);
+                    break;
+                }
+            }
+        }
+
+        hashes
+    }
+
+    /// Remote modification times of the files the commands may compare, fetched like
+    /// [`ShareNode::prefetch_hashes`], relays without `/v1/stats` get asked one path at a time
+    async fn prefetch_modified<'a>(
+        &self,
+        commands: impl Iterator<Item = &'a Command>,
+    ) -> IndexMap<NullFsPath, u64> {
+        let paths = Self::prefetch_paths(commands);
+
+        let mut modified = IndexMap::new();
+        for batch in paths.chunks(HASH_BATCH_SIZE) {
+            match self.remote_stats(batch).await {
+                Ok(batch) => {
+                    modified.extend(batch.into_iter().map(|(path, stat)| (path, stat.modified)))
+                }
+                Err(e) => {
+                    tracing::warn!("Could not prefetch metadata from {}: {}", self.name, e);
+                    break;
+                }
+            }
+        }
+
+        modified
+    }
+
+    /// Remote hash of a path, taken from `prefetched` when it is there
+    async fn remote_hash_in(
+        &self,
+        path: &NullFsPath,
+        prefetched: &Prefetched,
+    ) -> eyre::Result<String> {
+        match prefetched.own.get(path) {
+            Some(hash) => Ok(hash.clone()),
+            None => self.remote_hash(path).await,
+        }
+    }
+
+    #[allow(unused)]
+    pub async fn remote_dir(&self, path: &NullFsPath) -> eyre::Result<Vec<File>> {
+        let response = self
+            .client
+            .get(self.relay.address.join("v1/dir")?)
+            .query(&[("path", path.to_string())])
+            .basic_auth(&self.relay.auth.name, self.relay.auth.password.clone())
+            .send()
+            .await
+            .inspect_err(|_| self.expire_liveness())?;
+
+        if !response.status().is_success() {
+            eyre::bail!(
+                "Could not list directory, remote {} answered with status {}: {:?}",
+                self.name,
+                response.status(),
+                response.text().await
+            )
+        }
+
+        self.parse_json(response).await
+    }
+
+    /// Metadata of a remote path, `None` when it does not exist
+    pub async fn remote_stat(&self, path: &NullFsPath) -> eyre::Result<Option<FileStat>> {
+        let response = self
+            .client
+            .get(self.relay.address.join("v1/stat")?)
+            .query(&[("path", path.to_string())])
+            .basic_auth(&self.relay.auth.name, self.relay.auth.password.clone())
+            .send()
+            .await
+            .inspect_err(|_| self.expire_liveness())?;
+
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        if !response.status().is_success() {
+            eyre::bail!(
+                "Could not get metadata, remote {} answered with status {}: {:?}",
+                self.name,
+                response.status(),
+                response.text().await
+            )
+        }
+
+        self.parse_json(response).await.map(Some)
+    }
+
+    /// Modification time of a remote file
+    pub async fn remote_modified(&self, path: &NullFsPath) -> eyre::Result<u64> {
+        self.remote_stat(path)
+            .await?
+            .map(|stat| stat.modified)
+            .ok_or_else(|| eyre::eyre!("{path} not found on remote {}", self.name))
+    }
+
+    /// Whether an expired command still matches the relay, a file written or touched since
+    /// comes with a newer command of its own
+    pub async fn revalidate(&self, command: &Command) -> eyre::Result<bool> {
+        match command {
+            Command::Delete { file } => Ok(!self.remote_exists(&file.path).await?),
+            Command::Write { file }
+            | Command::Touch { file }
+            | Command::Rename { to: file, .. } => Ok(match self.remote_stat(&file.path).await? {
+                Some(stat) if file.stat.is_dir() => stat.is_dir(),
+                Some(stat) => stat == file.stat,
+                None => false,
+            }),
+        }
+    }
+
+    fn is_expired(&self, stashed: &StashedCommand) -> bool {
+        self.command_ttl.is_some_and(|ttl| {
+            (Utc::now() - stashed.timestamp)
+                .to_std()
+                .is_ok_and(|age| age > ttl)
+        })
+    }
+
+    /// Picks the relay to download a file from when the relays of the volume disagree on its
+    /// content, falls back to the current relay when no tie-break policy is configured
+    ///
+    /// The hashes and times of `prefetched` are used as is, a relay missing from it is asked
+    /// on its own
+    async fn resolve_source<'a>(
+        &'a self,
+        file: &File,
+        relays: &'a [ShareNode],
+        prefetched: &Prefetched,
+    ) -> eyre::Result<&'a ShareNode> {
+        let Some(tie_break) = &self.tie_break else {
+            return Ok(self);
+        };
+
+        if relays.len() < 2 {
+            return Ok(self);
+        }
+
+        // a file missing from a relay is missing from its `/v1/hashes` answer as well
+        let mut candidates = vec![];
+        for relay in relays {
+            let known = match relay.name == self.name {
+                true => Some(&prefetched.own),
+                false => prefetched.relays.get(&relay.name),
+            };
+            let hash = match known {
+                Some(hashes) => hashes.get(&file.path).cloned(),
+                None => match relay.remote_hashes(std::slice::from_ref(&file.path)).await {
+                    Ok(mut hashes) => hashes.swap_remove(&file.path),
+                    Err(e) => {
+                        tracing::warn!("Ignoring {} for {}: {}", relay.name, file.path, e);
+                        None
+                    }
+                },
+            };
+            if let Some(hash) = hash {
+                candidates.push((relay, hash));
+            }
+        }
+
+        let distinct = candidates.iter().map(|(_, h)| h).collect::<HashSet<_>>();
+        if distinct.len() <= 1 {
+            return Ok(candidates
+                .iter()
+                .map(|(relay, _)| *relay)
+                .find(|relay| relay.name.eq(&self.name))
+                .or_else(|| candidates.first().map(|(relay, _)| *relay))
+                .unwrap_or(self));
+        }
+
+        let chosen = match tie_break {
+            TieBreak::Priority => candidates
+                .iter()
+                .map(|(relay, _)| *relay)
+                .min_by_key(|relay| relay.priority),
+            TieBreak::Quorum => {
+                let mut votes = HashMap::<&str, usize>::new();
+                for (_, hash) in &candidates {
+                    *votes.entry(hash.as_str()).or_default() += 1;
+                }
+
+                // the first relay of `pullFrom` wins among equal votes
+                candidates
+                    .iter()
+                    .max_by(|(a, ha), (b, hb)| {
+                        votes[ha.as_str()]
+                            .cmp(&votes[hb.as_str()])
+                            .then(b.priority.cmp(&a.priority))
+                    })
+                    .map(|(relay, _)| *relay)
+            }
+            TieBreak::Newest => {
+                let mut dated = vec![];
+                for (relay, _) in &candidates {
+                    let known = prefetched
+                        .modified
+                        .get(&relay.name)
+                        .and_then(|modified| modified.get(&file.path));
+                    let modified = match known {
+                        Some(modified) => Ok(*modified),
+                        None => relay.remote_modified(&file.path).await,
+                    };
+                    match modified {
+                        Ok(modified) => dated.push((*relay, modified)),
+                        Err(e) => {
+                            tracing::warn!("Ignoring {} for {}: {}", relay.name, file.path, e)
+                        }
+                    }
+                }
+
+                // Older priority wins on equal timestamps
+                dated
+                    .into_iter()
+                    .max_by(|(a, ma), (b, mb)| ma.cmp(mb).then(b.priority.cmp(&a.priority)))
+                    .map(|(relay, _)| relay)
+            }
+        }
+        .unwrap_or(self);
+
+        tracing::info!(
+            "Relays disagree on {}, resolved to {} ({:?})",
+            file.path,
+            chosen.name,
+            tie_break
+        );
+
+        Ok(chosen)
+    }
+
+    pub async fn remote_exists(&self, path: &NullFsPath) -> eyre::Result<bool> {
+        let response = self
+            .client
+            .get(self.relay.address.join("v1/exists")?)
+            .query(&[("path", path.to_string())])
+            .basic_auth(&self.relay.auth.name, self.relay.auth.password.clone())
+            .send()
+            .await
+            .inspect_err(|_| self.expire_liveness())?;
+
+        if !response.status().is_success() {
+            eyre::bail!(
+                "Could not check if it exists, remote {} answered with status {}: {:?}",
+                self.name,
+                response.status(),
+                response.text().await
+            )
+        }
+
+        self.parse_json(response).await
+    }
+
+    /// Merkle node of a remote path, `None` when the relay does not serve Merkle trees
+    pub async fn remote_merkle(&self, path: &NullFsPath) -> eyre::Result<Option<MerkleNode>> {
+        let response = self
+            .client
+            .get(self.relay.address.join("v1/merkle")?)
+            .query(&[("path", path.to_string())])
+            .basic_auth(&self.relay.auth.name, self.relay.auth.password.clone())
+            .send()
+            .await
+            .inspect_err(|_| self.expire_liveness())?;
+
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        if !response.status().is_success() {
+            eyre::bail!(
+                "Could not get merkle node, remote {} answered with status {}: {:?}",
+                self.name,
+                response.status(),
+                response.text().await
+            )
+        }
+
+        self.check_hash_algo(&response)?;
+        self.parse_json(response).await.map(Some)
+    }
+
+    /// Hash tree of a remote path, compare it with a local one through [`HashTree::diff`]
+    pub async fn fetch_tree(&self, path: &NullFsPath) -> eyre::Result<HashTree> {
+        let response = self
+            .client
+            .get(self.relay.address.join("v1/tree")?)
+            .query(&[("path", path.to_string())])
+            .basic_auth(&self.relay.auth.name, self.relay.auth.password.clone())
+            .send()
+            .await
+            .inspect_err(|_| self.expire_liveness())?;
+
+        if !response.status().is_success() {
+            eyre::bail!(
+                "Could not get hash tree, remote {} answered with status {}: {:?}",
+                self.name,
+                response.status(),
+                response.text().await
+            )
+        }
+
+        self.check_hash_algo(&response)?;
+        self.parse_json(response).await
+    }
+
+    /// Local paths diverging from the relay below `path`, see [`HashTree::diff`]
+    pub async fn diverging_paths(
+        &self,
+        fs: &AnyFs,
+        path: &NullFsPath,
+    ) -> eyre::Result<Vec<NullFsPath>> {
+        let remote = self.fetch_tree(path).await?;
+        let local = HashTree::build(fs, path).await?;
+
+        Ok(local.diff(&remote))
+    }
+
+    /// Content hash of a local file, only recomputed when its mtime or size changed since
+    /// the last time it was hashed
+    pub async fn local_hash(&self, fs: &AnyFs, path: &NullFsPath) -> eyre::Result<String> {
+        let stat = fs.stats(path).await?;
+        if stat.is_dir() {
+            return fs.hash(path).await;
+        }
+
+        let file = File {
+            file_type: FileType::infer_from_path(path),
+            path: path.clone(),
+            stat,
+        };
+        let mut hashes = self.hashes.lock().await;
+        if let Some(hash) = hashes.cached_hash(&file) {
+            return Ok(hash.to_owned());
+        }
+
+        let hash = fs.hash(path).await?;
+        hashes.remember_hash(&file, hash.clone());
+
+        Ok(hash)
+    }
+
+    /// Whether the local file has the content of the relay it would be downloaded from, see
+    /// [`ShareNode::download_source`], sizes are compared first so that only files of the
+    /// same size get hashed on both sides
+    async fn same_content(
+        &self,
+        fs: &AnyFs,
+        file: &File,
+        relays: &[ShareNode],
+        prefetched: &Prefetched,
+    ) -> eyre::Result<bool> {
+        let path = &file.path;
+        // another relay may win the tie-break, its size is not known here
+        let resolving = self.tie_break.is_some() && relays.len() > 1;
+        if !resolving
+            && !prefetched.own.contains_key(path)
+            && let Some(remote) = self.remote_stat(path).await?
+            && remote.node != fs.stats(path).await?.node
+        {
+            return Ok(false);
+        }
+
+        let (_, hash) = self.download_source(file, relays, prefetched).await?;
+        Ok(hash == self.local_hash(fs, path).await?)
+    }
+
+    /// Local counterpart of the relay Merkle hash, directory hashes are memoized in `memo`
+    #[async_recursion]
+    async fn local_merkle(
+        &self,
+        fs: &AnyFs,
+        path: &NullFsPath,
+        memo: &mut HashMap<NullFsPath, String>,
+    ) -> eyre::Result<String> {
+        if let Some(hash) = memo.get(path) {
+            return Ok(hash.clone());
+        }
+
+        let hash = if fs.stats(path).await?.is_dir() {
+            let mut children = vec![];
+            for entry in fs.dir(path).await? {
+                let hash = self.local_merkle(fs, &entry.path, memo).await?;
+                children.push((entry.path, hash));
+            }
+            hashing::merkle_hash(children.iter().map(|(path, hash)| (path, hash.as_str())))
+        } else {
+            self.local_hash(fs, path).await?
+        };
+
+        memo.insert(path.clone(), hash.clone());
+        Ok(hash)
+    }
+
+    /// Paths already identical on both sides, the relay Merkle tree is only walked down
+    /// through the directories leading to one of `targets`
+    pub async fn unchanged_paths(
+        &self,
+        fs: &AnyFs,
+        targets: &[NullFsPath],
+    ) -> eyre::Result<Vec<NullFsPath>> {
+        let targets = targets
+            .iter()
+            .map(|target| target.components())
+            .collect::<Vec<_>>();
+        let leads_to = |path: &NullFsPath, strictly: bool| {
+            let prefix = path.components();
+            targets.iter().any(|target| {
+                target.starts_with(&prefix) && (!strictly || target.len() > prefix.len())
+            })
+        };
+
+        let mut memo = HashMap::new();
+        let mut unchanged = vec![];
+        let mut pending = vec![fs.volume_root()?];
+        while let Some(path) = pending.pop() {
+            let Some(remote) = self.remote_merkle(&path).await? else {
+                continue;
+            };
+
+            for (child, hash) in remote.children {
+                if !leads_to(&child, false) {
+                    continue;
+                }
+
+                if fs.exists(&child).await?
+                    && self.local_merkle(fs, &child, &mut memo).await? == hash
+                {
+                    unchanged.push(child);
+                } else if leads_to(&child, true) {
+                    pending.push(child);
+                }
+            }
+        }
+
+        Ok(unchanged)
+    }
+
+    /// Downloads `file` from the relay holding it and checks it against its remote hash, a
+    /// local file with the same content is copied instead when its prefetched hash is already
+    /// known locally
+    ///
+    /// `prefetched` holds remote hashes known beforehand, see [`Prefetched`]
+    async fn fetch(
+        &self,
+        fs: &AnyFs,
+        file: &File,
+        relays: &[ShareNode],
+        prefetched: &Prefetched,
+    ) -> eyre::Result<CommandOutcome> {
+        // a link is recreated from its target, there is nothing to download
+        if let NodeKind::Symlink { target } = &file.stat.node {
+            fs.write(file, &[]).await?;
+            self.hashes
+                .lock()
+                .await
+                .mark_synced(&file.path, hashing::digest(target));
+            return Ok(CommandOutcome::Applied { bytes: 0 });
+        }
+
+        self.keep_version(fs, &file.path).await?;
+        if let Some(hash) = prefetched.own.get(&file.path)
+            && self.copy_duplicate(fs, file, hash).await?
+        {
+            self.hashes
+                .lock()
+                .await
+                .mark_synced(&file.path, hash.clone());
+            return Ok(CommandOutcome::Applied { bytes: 0 });
+        }
+
+        let (source, hash) = self.download_source(file, relays, prefetched).await?;
+        let mut downloaded = None;
+        if file.stat.size() > self.delta_threshold
+            && fs.exists(&file.path).await?
+            && matches!(fs.stats(&file.path).await?.node, NodeKind::File { .. })
+        {
+            match source.download_delta(fs, file, &hash).await {
+                Ok(bytes) => downloaded = Some(bytes),
+                Err(e) => tracing::warn!(
+                    "Delta transfer of {} from {} failed, downloading it whole: {e}",
+                    file.path,
+                    source.name
+                ),
+            }
+        }
+
+        if downloaded.is_none() {
+            // a local file left from the last sync is not fetched again if the relay kept it
+            let known = match fs.exists(&file.path).await? {
+                true => self.synced_hash(&file.path).await,
+                false => None,
+            };
+            // large files are not held in memory, see [`ShareNode::download_streamed`]
+            let written = if file.stat.size() > self.stream_threshold {
+                source
+                    .download_streamed(fs, file, &hash, known.as_deref())
+                    .await?
+            } else {
+                match source
+                    .download_verified(&file.path, &hash, known.as_deref())
+                    .await?
+                {
+                    Some(data) => {
+                        fs.write(file, &data).await?;
+                        true
+                    }
+                    None => false,
+                }
+            };
+            if !written {
+                tracing::debug!(
+                    "{} is unchanged on {} since its last sync",
+                    file.path,
+                    source.name
+                );
+                return Ok(CommandOutcome::Skipped);
+            }
+        }
+
+        let written = File {
+            stat: fs.stats(&file.path).await?,
+            ..file.clone()
+        };
+        let mut hashes = self.hashes.lock().await;
+        hashes.remember_hash(&written, hash.clone());
+        hashes.mark_synced(&file.path, hash);
+
+        Ok(CommandOutcome::Applied {
+            bytes: downloaded.unwrap_or(written.stat.size()),
+        })
+    }
+
+    /// Relay to download `file` from, along with the content hash the download must match
+    async fn download_source<'a>(
+        &'a self,
+        file: &File,
+        relays: &'a [ShareNode],
+        prefetched: &Prefetched,
+    ) -> eyre::Result<(&'a ShareNode, String)> {
+        let source = self.resolve_source(file, relays, prefetched).await?;
+        let known = match source.name == self.name {
+            true => prefetched.own.get(&file.path),
+            false => prefetched
+                .relays
+                .get(&source.name)
+                .and_then(|hashes| hashes.get(&file.path)),
+        };
+        let hash = match known {
+            Some(hash) => hash.clone(),
+            None => source.remote_hash(&file.path).await?,
+        };
+
+        Ok((source, hash))
+    }
+
+    /// Hash `path` had when last synced, if it ever was
+    async fn synced_hash(&self, path: &NullFsPath) -> Option<String> {
+        self.hashes
+            .lock()
+            .await
+            .synced_hash(path)
+            .map(str::to_owned)
+    }
+
+    /// Records the content of a local file left as is because it already matches the relay
+    async fn mark_synced(&self, fs: &AnyFs, path: &NullFsPath) -> eyre::Result<()> {
+        let hash = self.local_hash(fs, path).await?;
+        self.hashes.lock().await.mark_synced(path, hash);
+
+        Ok(())
+    }
+
+    /// A local file edited since its last sync is kept when the relay brings a version of
+    /// its own, which is written next to it instead, see [`conflict_sibling`]
+    ///
+    /// The caller already checked the remote content differs from the local one, nothing is
+    /// detected for the files never synced by this node
+    async fn keep_local_edit(
+        &self,
+        fs: &AnyFs,
+        file: &File,
+        relays: &[ShareNode],
+        prefetched: &Prefetched,
+    ) -> eyre::Result<Option<CommandOutcome>> {
+        let Some(synced) = self.synced_hash(&file.path).await else {
+            return Ok(None);
+        };
+
+        if file.stat.is_symlink()
+            || !fs.exists(&file.path).await?
+            || fs.stats(&file.path).await?.is_dir()
+        {
+            return Ok(None);
+        }
+
+        let local = self.local_hash(fs, &file.path).await?;
+        if local == synced {
+            return Ok(None);
+        }
+
+        // only edited locally when the relay still has the version last synced
+        let (source, hash) = self.download_source(file, relays, prefetched).await?;
+        let Some(data) = source
+            .download_verified(&file.path, &hash, Some(&synced))
+            .await?
+        else {
+            return Ok(Some(CommandOutcome::Skipped));
+        };
+        let conflict = File {
+            path: conflict_sibling(&file.path, &self.conflict_suffix, &source.name)?,
+            ..file.clone()
+        };
+        fs.write(&conflict, &data).await?;
+        tracing::warn!(
+            "{} was edited both locally and on {}, kept the local version and wrote the remote one to {}",
+            file.path,
+            source.name,
+            conflict.path
+        );
+
+        // the local version is the one the next remote edits are compared to
+        self.hashes.lock().await.mark_synced(&file.path, local);
+
+        Ok(Some(CommandOutcome::Applied {
+            bytes: data.len() as u64,
+        }))
+    }
+
+    /// Copies the local file about to be overwritten among its versions, the oldest past
+    /// `keep_versions` are removed, see [`versions_dir`]
+    async fn keep_version(&self, fs: &AnyFs, path: &NullFsPath) -> eyre::Result<()> {
+        if self.keep_versions == 0 || !fs.exists(path).await? {
+            return Ok(());
+        }
+
+        let stat = fs.stats(path).await?;
+        if !matches!(stat.node, NodeKind::File { .. }) {
+            return Ok(());
+        }
+
+        // named after the modification time, a retried overwrite keeps the same version once
+        let version = version_path(path, stat.modified)?;
+        if let Some(parent) = version.parent() {
+            fs.mkdir(&parent).await?;
+        }
+        fs.copy(path, &version).await?;
+
+        let mut versions = fs.dir(&versions_dir(path)?).await?;
+        versions.sort_by_key(|version| {
+            version
+                .path
+                .file_name()
+                .and_then(|name| name.split('.').next())
+                .and_then(|modified| modified.parse::<u64>().ok())
+                .unwrap_or_default()
+        });
+        let excess = versions.len().saturating_sub(self.keep_versions);
+        for version in &versions[..excess] {
+            fs.delete(version).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Copies to `file` the local file last hashed `hash`, false when there is none or its
+    /// content changed since
+    async fn copy_duplicate(&self, fs: &AnyFs, file: &File, hash: &str) -> eyre::Result<bool> {
+        let candidate = self.hashes.lock().await.path_with_hash(hash).cloned();
+        let Some(candidate) = candidate else {
+            return Ok(false);
+        };
+
+        if candidate == file.path
+            || !fs.exists(&candidate).await?
+            || self.local_hash(fs, &candidate).await? != hash
+        {
+            return Ok(false);
+        }
+
+        if let Some(parent) = file.path.parent() {
+            fs.mkdir(&parent).await?;
+        }
+        fs.copy(&candidate, &file.path).await?;
+        tracing::info!("Copied {} from its duplicate {}", file.path, candidate);
+
+        Ok(true)
+    }
+
+    /// Runs `command` against the local volume, the hashes it learnt are persisted whatever
+    /// the outcome, see [`ShareNode::save_hashes`]
+    pub async fn run_command(
+        &self,
+        command: &Command,
+        fs: &AnyFs,
+        relays: &[ShareNode],
+        prefetched: &Prefetched,
+    ) -> eyre::Result<CommandOutcome> {
+        let outcome = self.execute(command, fs, relays, prefetched).await;
+        self.save_hashes().await?;
+
+        outcome
+    }
+
+    /// Writes the changes to `hashes` to the state database of the volume, if any
+    pub async fn save_hashes(&self) -> eyre::Result<()> {
+        let Some(store) = &self.hashes_store else {
+            return Ok(());
+        };
+
+        store.save_synced(&mut *self.hashes.lock().await).await
+    }
+
+    async fn execute(
+        &self,
+        command: &Command,
+        fs: &AnyFs,
+        relays: &[ShareNode],
+        prefetched: &Prefetched,
+    ) -> eyre::Result<CommandOutcome> {
         match command {
             Command::Delete { file } => {
-                if fs.exists(&file.path).await? {
-                    fs.delete(file).await?;
+                self.hashes.lock().await.forget_synced(&file.path);
+                if !fs.exists(&file.path).await? {
+                    return Ok(CommandOutcome::Skipped);
                 }
+
+                fs.delete(file).await?;
             }
             Command::Write { file } => {
                 if !self.remote_exists(&file.path).await? {
-                    return Ok(());
+                    return Ok(CommandOutcome::Skipped);
                 }
 
                 if file.stat.is_file() {
-                    if fs.exists(&file.path).await? {
-                        let remote_hash = self.remote_hash(&file.path).await?;
-                        let local_hash = fs.hash(&file.path).await?;
-                        if remote_hash == local_hash {
-                            tracing::warn!("Already commited: Skipping update for {}", file.path);
-                            return Ok(());
-                        }
+                    if fs.exists(&file.path).await?
+                        && self.same_content(fs, file, relays, prefetched).await?
+                    {
+                        tracing::warn!("Already commited: Skipping update for {}", file.path);
+                        self.mark_synced(fs, &file.path).await?;
+                        return Ok(CommandOutcome::Skipped);
+                    }
+
+                    if let Some(outcome) =
+                        self.keep_local_edit(fs, file, relays, prefetched).await?
+                    {
+                        return Ok(outcome);
                     }
 
-                    let data = self.download(&file.path).await?;
-                    fs.write(file, &data).await?;
+                    return self.fetch(fs, file, relays, prefetched).await;
                 } else {
                     fs.write(file, &[]).await?;
                 }
             }
             Command::Touch { file } => {
                 if fs.exists(&file.path).await? {
-                    let remote_hash = self.remote_hash(&file.path).await?;
-                    let local_hash = fs.hash(&file.path).await?;
-                    if remote_hash == local_hash {
+                    if self.same_content(fs, file, relays, prefetched).await? {
                         tracing::warn!(
                             "Metadata update not yet supported, skipping touch for {}",
                             file.path
                         );
-                        return Ok(());
+                        self.mark_synced(fs, &file.path).await?;
+                        return Ok(CommandOutcome::Skipped);
+                    }
+
+                    if let Some(outcome) =
+                        self.keep_local_edit(fs, file, relays, prefetched).await?
+                    {
+                        return Ok(outcome);
                     }
 
-                    fs.delete(file).await?;
+                    // a file is replaced by the write, keeping it until the download succeeds
+                    if fs.stats(&file.path).await?.is_dir() {
+                        fs.delete(file).await?;
+                    }
+                }
+
+                return self.fetch(fs, file, relays, prefetched).await;
+            }
+            Command::Rename { from, to } => {
+                if !self.remote_exists(&to.path).await? {
+                    return Ok(CommandOutcome::Skipped);
                 }
 
-                let data = self.download(&file.path).await?;
-                fs.write(file, &data).await?;
+                let remote_hash = self.remote_hash_in(&to.path, prefetched).await?;
+                self.hashes.lock().await.forget_synced(&from.path);
+                if fs.exists(&to.path).await? && self.local_hash(fs, &to.path).await? == remote_hash
+                {
+                    self.hashes.lock().await.mark_synced(&to.path, remote_hash);
+                    if !fs.exists(&from.path).await? {
+                        return Ok(CommandOutcome::Skipped);
+                    }
+
+                    fs.delete(from).await?;
+                } else if fs.exists(&from.path).await?
+                    && self.local_hash(fs, &from.path).await? == remote_hash
+                {
+                    if let Some(parent) = to.path.parent() {
+                        fs.mkdir(&parent).await?;
+                    }
+
+                    fs.rename(&from.path, &to.path).await?;
+                    self.hashes.lock().await.mark_synced(&to.path, remote_hash);
+                } else {
+                    // the local copy diverged, fetch the renamed file instead
+                    if fs.exists(&from.path).await? {
+                        fs.delete(from).await?;
+                    }
+
+                    return self.fetch(fs, to, relays, prefetched).await;
+                }
             }
         };
 
-        Ok(())
+        Ok(CommandOutcome::Applied { bytes: 0 })
+    }
+
+    async fn forget_hashes(&self, command: &Command) {
+        let mut hashes = self.hashes.lock().await;
+        match command {
+            Command::Delete { file } | Command::Write { file } | Command::Touch { file } => {
+                hashes.forget(&file.path)
+            }
+            Command::Rename { from, to } => {
+                hashes.forget(&from.path);
+                hashes.forget(&to.path);
+            }
+        }
     }
 
-    pub async fn apply_commands(&self, fs: &AnyFs) -> eyre::Result<()> {
+    /// Applies the stashed commands of a volume, `relays` are the relays the volume pulls from
+    pub async fn apply_commands(
+        &self,
+        fs: &AnyFs,
+        relays: &[ShareNode],
+    ) -> eyre::Result<ApplyReport> {
+        let mut report = ApplyReport::default();
         let stashed = self.store.unstash(&fs.get_volume_name()).await?;
-        for op in stashed {
+
+        let mut unchanged = vec![];
+        if self.merkle && !stashed.is_empty() {
+            let targets = stashed
+                .iter()
+                .map(|op| match &op.command {
+                    Command::Delete { file }
+                    | Command::Write { file }
+                    | Command::Touch { file }
+                    | Command::Rename { to: file, .. } => file.path.clone(),
+                })
+                .collect::<Vec<_>>();
+
+            match self.unchanged_paths(fs, &targets).await {
+                Ok(paths) => unchanged = paths,
+                Err(e) => tracing::warn!("Merkle comparison with {} failed: {}", self.name, e),
+            }
+        }
+
+        let unchanged = unchanged
+            .iter()
+            .map(|path| path.components())
+            .collect::<Vec<_>>();
+        let in_sync = |command: &Command| match command {
+            Command::Write { file } | Command::Touch { file } => {
+                let comps = file.path.components();
+                unchanged.iter().any(|prefix| comps.starts_with(prefix))
+            }
+            Command::Delete { .. } | Command::Rename { .. } => false,
+        };
+
+        let pending = || {
+            stashed
+                .iter()
+                .map(|op| &op.command)
+                .filter(|command| !in_sync(command))
+        };
+        let mut prefetched = Prefetched {
+            own: self.prefetch_hashes(pending()).await,
+            ..Default::default()
+        };
+        // the other relays are only compared to break ties, a relay down is left out
+        if self.tie_break.is_some() && relays.len() > 1 {
+            for relay in relays.iter().filter(|relay| relay.name != self.name) {
+                let hashes = match relay.is_alive().await.unwrap_or(false) {
+                    true => relay.prefetch_hashes(pending()).await,
+                    false => IndexMap::new(),
+                };
+                prefetched.relays.insert(relay.name.clone(), hashes);
+            }
+        }
+        if self.tie_break == Some(TieBreak::Newest) && relays.len() > 1 {
+            for relay in relays {
+                let alive = relay.name == self.name || relay.is_alive().await.unwrap_or(false);
+                let modified = match alive {
+                    true => relay.prefetch_modified(pending()).await,
+                    false => IndexMap::new(),
+                };
+                prefetched.modified.insert(relay.name.clone(), modified);
+            }
+        }
+
+        let total = stashed.len();
+        for (done, op) in stashed.into_iter().enumerate() {
+            // the stash is left consistent between two commands, the rest is applied on restart
+            if self.shutdown.is_cancelled() {
+                tracing::info!(
+                    "Shutting down, {} command(s) of @/{} left in the stash",
+                    total - done,
+                    fs.get_volume_name()
+                );
+                break;
+            }
+
             let action = async {
-                self.run_command(&op.command, fs).await?;
-                self.store.mark_done(&op).await
+                let stale = self.is_expired(&op) && !self.revalidate(&op.command).await?;
+                if stale {
+                    tracing::info!("Dropping stale command {} from {}", op.command, self.name);
+                }
+
+                let outcome = match stale || in_sync(&op.command) {
+                    true => CommandOutcome::Skipped,
+                    false if self.dry_run => {
+                        tracing::info!("Dry run, would apply {} from {}", op.command, self.name);
+                        CommandOutcome::Skipped
+                    }
+                    false => {
+                        // the content is about to change, the new one is remembered when known
+                        self.forget_hashes(&op.command).await;
+                        self.run_command(&op.command, fs, relays, &prefetched)
+                            .await?
+                    }
+                };
+                self.store.mark_done(&op).await?;
+                eyre::Ok(outcome)
             };
 
-            if let Err(e) = action.await {
-                tracing::error!("Failed {}: {}", op.command.to_string(), e);
+            match action.await {
+                Ok(CommandOutcome::Applied { bytes }) => {
+                    report.applied += 1;
+                    report.bytes += bytes;
+                    report.applied_commands.push((op.command, bytes));
+                }
+                Ok(CommandOutcome::Skipped) => report.skipped += 1,
+                Err(e) => {
+                    tracing::error!("Failed {}: {}", op.command, e);
+                    if let Err(e) = self.store.mark_failed(&op, &e.to_string()).await {
+                        tracing::error!("Could not schedule the retry of {}: {}", op.command, e);
+                    }
+                    report.failures.push(CommandFailure {
+                        command: op.command.clone(),
+                        error: e.to_string(),
+                    });
+                }
             }
         }
 
-        Ok(())
+        Ok(report)
     }
 }
+
+/// Decodes a relay json body, telling apart error objects from malformed responses
+pub fn decode_json<T: DeserializeOwned>(body: &str, content_type: &str) -> eyre::Result<T> {
+    const MAX_SNIPPET: usize = 200;
+
+    serde_json::from_str::<T>(body).or_else(|e| {
+        if let Ok(relay_error) = serde_json::from_str::<RelayError>(body) {
+            eyre::bail!("Relay answered with an error: {relay_error}");
+        }
+
+        let snippet = match body.char_indices().nth(MAX_SNIPPET) {
+            Some((end, _)) => format!("{}...", &body[..end]),
+            None => body.to_owned(),
+        };
+
+        eyre::bail!("Malformed response ({e}), content-type {content_type:?}, body: {snippet:?}")
+    })
+}
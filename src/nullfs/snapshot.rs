@@ -2,26 +2,118 @@ use crate::{
     nullfs::NullFs,
     nullfs::NullFsPath,
     nullfs::any_fs::AnyFs,
-    nullfs::{Command, File},
+    nullfs::hashing,
+    nullfs::systime_to_millis,
+    nullfs::{Command, File, FileType},
+    nullfs::{
+        local_fs::TRASH_DIR,
+        quarantine::{DEFAULT_QUARANTINE_DIR, VERSIONS_DIR},
+    },
 };
 use async_recursion::async_recursion;
 use eyre::{Context, ContextCompat};
+use flate2::read::GzDecoder;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
 use indexmap::{IndexMap, IndexSet};
 use serde::{Deserialize, Serialize};
-use std::{collections::HashSet, path::PathBuf};
+use sqlx::{
+    Row, SqlitePool,
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions, SqliteSynchronous},
+};
+use std::{
+    collections::HashSet,
+    io::Read,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Gitignore-like patterns, at the root of a volume, of the paths never captured
+pub const IGNORE_FILE: &str = ".nullfsignore";
+
+/// Prefix of the state files a relay keeps for each pulling peer
+pub const PEER_STATE_PREFIX: &str = ".ext-state-";
+
+/// Prefix of the state files keeping the content hashes of each synced volume, see
+/// [`StateStore::save_synced`]
+pub const SYNC_STATE_PREFIX: &str = ".sync-state-";
+
+/// Prefix of the state files holding the Merkle tree a relay serves for each volume
+pub const MERKLE_STATE_PREFIX: &str = ".merkle-state-";
+
+/// Commands answered per page of `/v1/commands` once the peer sends a cursor, see
+/// [`Snapshot::capture_page`]
+pub const COMMANDS_PAGE_SIZE: usize = 1000;
+
+/// Directories modified more recently than this are listed again on the next capture,
+/// an entry added within the same mtime tick would go unnoticed otherwise (FAT counts
+/// in steps of 2 seconds)
+const RACY_DIR_MTIME_MS: u64 = 2000;
 
 #[derive(Clone, Debug)]
 pub struct Snapshot {
     fs: AnyFs,
+    mtime_tolerance_ms: u64,
+    skip_mounts: bool,
+    quarantine_dir: String,
+    merkle: bool,
+    incremental: bool,
+}
+
+/// A node of the Merkle tree of a volume, `children` is empty for files
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct MerkleNode {
+    pub hash: String,
+    pub children: IndexMap<NullFsPath, String>,
+}
+
+/// Change to the hashes of a [`State`], replayed in order by [`StateStore::save_synced`]
+#[derive(Clone, Debug)]
+enum StateChange {
+    Remembered(NullFsPath),
+    /// The path and everything below it
+    Forgotten(NullFsPath),
+    Synced(NullFsPath),
+    /// The path and everything below it
+    Unsynced(NullFsPath),
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct State {
     store: IndexMap<NullFsPath, File>,
     dirs: IndexMap<NullFsPath, IndexSet<File>>,
+    /// Content hash of the files, only trusted while the `store` entry matches the file
+    #[serde(default)]
     hashes: IndexMap<NullFsPath, String>,
+    /// Hash of each directory computed from its children hashes, only kept when enabled
+    #[serde(default)]
+    merkle: IndexMap<NullFsPath, String>,
+    /// [`NullFs::shallow_hash`] of each directory as of the last capture, the directories
+    /// that kept theirs are not listed again, only their subdirectories are visited
+    #[serde(default)]
+    shallow: IndexMap<NullFsPath, String>,
+    /// Modification time of each directory as of its last listing, see [`Snapshot::incremental`]
+    #[serde(default)]
+    dir_mtimes: IndexMap<NullFsPath, u64>,
+    /// Content of the ignore file the shallow hashes were recorded with
+    #[serde(default)]
+    ignore_patterns: String,
+    /// A path for each hash remembered through [`State::remember_hash`], rebuilt by
+    /// [`StateStore::load_synced`]
+    #[serde(skip)]
+    by_hash: IndexMap<String, NullFsPath>,
+    /// Content hash of the files as of their last sync with a relay, see [`State::synced_hash`]
+    #[serde(skip)]
+    synced: IndexMap<NullFsPath, String>,
+    /// Changes not written yet by [`StateStore::save_synced`]
+    #[serde(skip)]
+    journal: Vec<StateChange>,
     #[serde(skip)]
     commands: IndexSet<Command>,
+    #[serde(skip)]
+    mtime_tolerance_ms: u64,
 }
 
 impl State {
@@ -31,19 +123,26 @@ impl State {
         }
     }
 
+    /// Modification times closer than `tolerance_ms` are considered equal as long as the size
+    /// did not change, this absorbs timestamp precision differences accross filesystems
+    pub fn with_mtime_tolerance(mut self, tolerance_ms: u64) -> Self {
+        self.mtime_tolerance_ms = tolerance_ms;
+        self
+    }
+
     pub fn update_on_change(&mut self, file: &File) -> eyre::Result<bool> {
         if file.stat.is_dir() {
             eyre::bail!("Fatal: expected entry to be a file");
         }
 
         if let Some(prev) = self.store.get(&file.path) {
-            if prev.stat.modified != file.stat.modified {
+            let drift = prev.stat.modified.abs_diff(file.stat.modified);
+            let changed = drift > self.mtime_tolerance_ms || prev.stat.node != file.stat.node;
+            if changed || drift != 0 {
                 self.store.insert(file.path.clone(), file.clone());
-
-                return Ok(true);
             }
 
-            return Ok(false);
+            return Ok(changed);
         }
 
         self.store.insert(file.path.clone(), file.clone());
@@ -51,96 +150,908 @@ impl State {
         Ok(true)
     }
 
+    /// Hash of `file` as long as it was not modified since the hash was computed
+    pub fn cached_hash(&self, file: &File) -> Option<&str> {
+        let known = self.store.get(&file.path)?;
+        if known.stat.modified != file.stat.modified || known.stat.node != file.stat.node {
+            return None;
+        }
+
+        self.hashes.get(&file.path).map(|hash| hash.as_str())
+    }
+
+    pub fn remember_hash(&mut self, file: &File, hash: String) {
+        self.store.insert(file.path.clone(), file.clone());
+        self.by_hash.insert(hash.clone(), file.path.clone());
+        self.hashes.insert(file.path.clone(), hash);
+        self.journal
+            .push(StateChange::Remembered(file.path.clone()));
+    }
+
+    /// A file last known with the content `hash`, it may have changed since
+    pub fn path_with_hash(&self, hash: &str) -> Option<&NullFsPath> {
+        self.by_hash.get(hash)
+    }
+
+    /// Forgets a path and everything below it
+    pub fn forget(&mut self, path: &NullFsPath) {
+        let prefix = path.components();
+        let keep = |p: &NullFsPath| !p.components().starts_with(&prefix);
+        self.store.retain(|p, _| keep(p));
+        self.dirs.retain(|p, _| keep(p));
+        self.hashes.retain(|p, _| keep(p));
+        self.merkle.retain(|p, _| keep(p));
+        self.shallow.retain(|p, _| keep(p));
+        self.dir_mtimes.retain(|p, _| keep(p));
+        self.by_hash.retain(|_, p| keep(p));
+        self.journal.push(StateChange::Forgotten(path.clone()));
+    }
+
+    /// Hash of the content `path` was last synced to, it tells local edits apart from the
+    /// remote ones and is left alone by [`State::forget`]
+    pub fn synced_hash(&self, path: &NullFsPath) -> Option<&str> {
+        self.synced.get(path).map(|hash| hash.as_str())
+    }
+
+    pub fn mark_synced(&mut self, path: &NullFsPath, hash: String) {
+        self.synced.insert(path.clone(), hash);
+        self.journal.push(StateChange::Synced(path.clone()));
+    }
+
+    /// Forgets the synced hash of a path and everything below it
+    pub fn forget_synced(&mut self, path: &NullFsPath) {
+        let prefix = path.components();
+        self.synced
+            .retain(|p, _| !p.components().starts_with(&prefix));
+        self.journal.push(StateChange::Unsynced(path.clone()));
+    }
+
+    pub fn merkle_node(&self, path: &NullFsPath) -> Option<MerkleNode> {
+        let Some(hash) = self.merkle.get(path) else {
+            return self.hashes.get(path).map(|hash| MerkleNode {
+                hash: hash.clone(),
+                children: IndexMap::new(),
+            });
+        };
+
+        let children = self
+            .dirs
+            .get(path)?
+            .iter()
+            .filter_map(|child| {
+                let hash = match child.stat.is_dir() {
+                    true => self.merkle.get(&child.path),
+                    false => self.hashes.get(&child.path),
+                };
+                hash.map(|hash| (child.path.clone(), hash.clone()))
+            })
+            .collect();
+
+        Some(MerkleNode {
+            hash: hash.clone(),
+            children,
+        })
+    }
+
+    /// Pairs the deleted files with the written ones sharing their size and content hash,
+    /// only the candidates of the current capture are compared
+    fn detect_renames(&self) -> Vec<(File, File)> {
+        let mut deleted = self
+            .commands
+            .iter()
+            .filter_map(|command| match command {
+                Command::Delete { file } if file.stat.is_file() => {
+                    Some((file, self.hashes.get(&file.path)?))
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        let mut renames = vec![];
+        for command in &self.commands {
+            if let Command::Write { file } = command
+                && file.stat.is_file()
+                && let Some(hash) = self.hashes.get(&file.path)
+                && let Some(pos) = deleted.iter().position(|(from, from_hash)| {
+                    from.stat.node == file.stat.node && *from_hash == hash
+                })
+            {
+                let (from, _) = deleted.swap_remove(pos);
+                renames.push((from.clone(), file.clone()));
+            }
+        }
+
+        renames
+    }
+
     pub fn finalize(&mut self) {
+        let renames = self.detect_renames();
+        if !renames.is_empty() {
+            let sources = renames
+                .iter()
+                .map(|(from, _)| from.path.clone())
+                .collect::<HashSet<_>>();
+            let targets = renames
+                .into_iter()
+                .map(|(from, to)| (to.path.clone(), (from, to)))
+                .collect::<IndexMap<_, _>>();
+
+            self.commands = std::mem::take(&mut self.commands)
+                .into_iter()
+                .filter_map(|command| match command {
+                    Command::Delete { file } if sources.contains(&file.path) => None,
+                    Command::Write { file } if targets.contains_key(&file.path) => {
+                        let (from, to) = targets[&file.path].clone();
+                        Some(Command::Rename { from, to })
+                    }
+                    command => Some(command),
+                })
+                .collect();
+        }
+
         let mut created = HashSet::new();
         let commands = self.commands.clone();
         for command in commands {
             match command {
                 Command::Delete { file } => {
-                    self.store.swap_remove(&file.path);
-                    self.dirs.swap_remove(&file.path);
+                    self.forget(&file.path);
                 }
                 Command::Write { file } => {
                     created.insert(file.path.clone());
                 }
+                Command::Rename { from, to } => {
+                    self.forget(&from.path);
+                    created.insert(to.path.clone());
+                }
                 Command::Touch { .. } => {}
             }
         }
 
         // False touch
         self.commands.retain(|command| {
-            if let Command::Touch { file } = command {
-                if created.contains(&file.path) {
-                    return false;
-                }
+            if let Command::Touch { file } = command
+                && created.contains(&file.path)
+            {
+                return false;
             }
 
             true
         });
-
-        // TODO: rename concept? (deletion + addition where file content matches)
-        // Renames require knowing the (path, old hash) and comparing all files
-        // Computing the hash for all files is not cheap
-        // Can't avoid O(n^2)
     }
 
     pub fn infer_commands(self) -> Vec<Command> {
         self.commands.into_iter().collect()
     }
 
-    pub async fn load_from(path: &PathBuf, create_if_none: bool) -> eyre::Result<Self> {
-        if create_if_none && !path.exists() {
-            tracing::warn!("Creating state file {}", path.display());
-            Self::new().save_to(path).await?;
-        }
-
-        let content = tokio::fs::read_to_string(path)
+    /// Loads a JSON state file as written before the states moved to sqlite, gzip compressed
+    /// files are detected and decompressed transparently
+    pub async fn load_legacy(path: &Path) -> eyre::Result<Self> {
+        let mut content = tokio::fs::read(path)
             .await
             .with_context(|| format!("Reading state from {}", path.display()))?;
 
-        serde_json::from_str(&content).map_err(|e| e.into())
+        if content.starts_with(&GZIP_MAGIC) {
+            let mut decompressed = vec![];
+            GzDecoder::new(content.as_slice())
+                .read_to_end(&mut decompressed)
+                .with_context(|| format!("Decompressing state from {}", path.display()))?;
+            content = decompressed;
+        }
+
+        serde_json::from_slice(&content).map_err(|e| e.into())
     }
+}
 
-    pub async fn save_to(&self, path: &PathBuf) -> eyre::Result<()> {
-        tracing::debug!("Saving state {}", path.display());
-        let content = serde_json::to_string(self)?;
-        tokio::fs::write(path, content)
+/// Position of a peer in the commands queued for it, handed out as an opaque `epoch.seq`
+/// token, the epoch tells apart the queues of a state database created again
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cursor {
+    pub epoch: String,
+    pub seq: i64,
+}
+
+impl std::fmt::Display for Cursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.epoch, self.seq)
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (epoch, seq) = s
+            .rsplit_once('.')
+            .with_context(|| format!("Invalid cursor {s:?}"))?;
+        let seq = seq
+            .parse()
+            .wrap_err_with(|| format!("Invalid cursor {s:?}"))?;
+
+        Ok(Self {
+            epoch: epoch.to_owned(),
+            seq,
+        })
+    }
+}
+
+/// Commands queued for a peer after its cursor, `remaining` are left for the next pages
+#[derive(Clone, Debug)]
+pub struct CommandPage {
+    pub commands: Vec<Command>,
+    pub cursor: Cursor,
+    pub remaining: u64,
+}
+
+/// Sqlite database backing a [`State`], one row per path
+///
+/// A capture only reads the rows of the directories it walks through, see
+/// [`StateStore::load_dir`], and only writes those back
+#[derive(Clone, Debug)]
+pub struct StateStore {
+    pool: SqlitePool,
+}
+
+impl StateStore {
+    /// Opens or creates the database at `path`, the JSON state file sharing its name (`path`
+    /// with a `.json` extension) is imported then removed
+    pub async fn open(path: &Path) -> eyre::Result<Self> {
+        if !path.exists() {
+            tracing::warn!("Creating state database {}", path.display());
+        }
+
+        let options = SqliteConnectOptions::from_str(&format!("sqlite://{}", path.display()))?
+            .synchronous(SqliteSynchronous::Normal)
+            .create_if_missing(true);
+
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect_with(options)
             .await
-            .with_context(|| format!("Save state into {}", path.display()))?;
+            .wrap_err_with(|| format!("Opening state database {}", path.display()))?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS Entry (
+                path TEXT NOT NULL PRIMARY KEY,
+                parent TEXT,
+                file TEXT,
+                hash TEXT,
+                merkle TEXT,
+                shallow TEXT,
+                children TEXT,
+                mtime INTEGER
+            );
+            CREATE INDEX IF NOT EXISTS EntryByParent ON Entry (parent);
+            CREATE TABLE IF NOT EXISTS Meta (
+                key TEXT NOT NULL PRIMARY KEY,
+                value TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS Synced (
+                path TEXT NOT NULL PRIMARY KEY,
+                hash TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS Outbox (
+                seq INTEGER PRIMARY KEY AUTOINCREMENT,
+                command TEXT NOT NULL
+            );
+        "#,
+        )
+        .execute(&pool)
+        .await?;
+
+        // databases created before the directory mtimes were recorded
+        let has_mtime =
+            sqlx::query("SELECT 1 FROM pragma_table_info('Entry') WHERE name = 'mtime'")
+                .fetch_optional(&pool)
+                .await?
+                .is_some();
+        if !has_mtime {
+            sqlx::query("ALTER TABLE Entry ADD COLUMN mtime INTEGER")
+                .execute(&pool)
+                .await?;
+        }
+
+        let store = Self { pool };
+        let legacy = path.with_extension("json");
+        if legacy != path && legacy.exists() {
+            tracing::info!("Importing state file {}", legacy.display());
+            let state = State::load_legacy(&legacy).await?;
+            store.save(&state).await?;
+            tokio::fs::remove_file(&legacy)
+                .await
+                .with_context(|| format!("Removing {}", legacy.display()))?;
+        }
+
+        Ok(store)
+    }
+
+    /// Loads the rows of `dir` and of its children that `state` does not hold yet
+    pub async fn load_dir(&self, state: &mut State, dir: &NullFsPath) -> eyre::Result<()> {
+        let rows = sqlx::query(
+            r#"
+            SELECT path, file, hash, merkle, shallow, children, mtime FROM Entry
+            WHERE path = ?1 OR parent = ?1
+        "#,
+        )
+        .bind(dir.to_string())
+        .fetch_all(&self.pool)
+        .await?;
+
+        for row in rows {
+            let path = NullFsPath::from_to_str(row.try_get::<String, _>("path")?)?;
+            if let Some(file) = row.try_get::<Option<String>, _>("file")?
+                && !state.store.contains_key(&path)
+            {
+                state
+                    .store
+                    .insert(path.clone(), serde_json::from_str(&file)?);
+            }
+
+            if let Some(children) = row.try_get::<Option<String>, _>("children")?
+                && !state.dirs.contains_key(&path)
+            {
+                state
+                    .dirs
+                    .insert(path.clone(), serde_json::from_str(&children)?);
+            }
+
+            let hashes = [
+                ("hash", &mut state.hashes),
+                ("merkle", &mut state.merkle),
+                ("shallow", &mut state.shallow),
+            ];
+            for (column, map) in hashes {
+                if let Some(hash) = row.try_get::<Option<String>, _>(column)? {
+                    map.entry(path.clone()).or_insert(hash);
+                }
+            }
+
+            if let Some(mtime) = row.try_get::<Option<i64>, _>("mtime")? {
+                state.dir_mtimes.entry(path.clone()).or_insert(mtime as u64);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Content of the ignore file as of the last capture
+    pub async fn ignore_patterns(&self) -> eyre::Result<String> {
+        let patterns = sqlx::query("SELECT value FROM Meta WHERE key = 'ignore_patterns'")
+            .fetch_optional(&self.pool)
+            .await?
+            .map(|row| row.try_get::<String, _>("value"))
+            .transpose()?;
+
+        Ok(patterns.unwrap_or_default())
+    }
+
+    /// Forgets every shallow hash and directory mtime, all directories get walked and listed
+    /// on the next capture
+    pub async fn reset_shallow(&self) -> eyre::Result<()> {
+        sqlx::query("UPDATE Entry SET shallow = NULL, mtime = NULL")
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Writes back the rows `state` holds, the paths deleted or renamed by its commands are
+    /// removed along with everything below them
+    pub async fn save(&self, state: &State) -> eyre::Result<()> {
+        self.save_with(state, false).await
+    }
+
+    /// Same as [`StateStore::save`], the commands of `state` being queued in the same
+    /// transaction so that they are not lost to a peer that did not get them yet
+    pub async fn save_queued(&self, state: &State) -> eyre::Result<()> {
+        self.save_with(state, true).await
+    }
+
+    async fn save_with(&self, state: &State, queue: bool) -> eyre::Result<()> {
+        let mut tx = self.pool.begin().await?;
+        for command in &state.commands {
+            let gone = match command {
+                Command::Delete { file } => &file.path,
+                Command::Rename { from, .. } => &from.path,
+                _ => continue,
+            };
+
+            sqlx::query("DELETE FROM Entry WHERE path = ?1 OR substr(path, 1, length(?2)) = ?2")
+                .bind(gone.to_string())
+                .bind(format!("{gone}/"))
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        let paths = state
+            .store
+            .keys()
+            .chain(state.dirs.keys())
+            .chain(state.hashes.keys())
+            .chain(state.merkle.keys())
+            .chain(state.shallow.keys())
+            .chain(state.dir_mtimes.keys())
+            .collect::<IndexSet<_>>();
+        for path in paths {
+            sqlx::query(
+                r#"
+                INSERT OR REPLACE INTO Entry (path, parent, file, hash, merkle, shallow, children, mtime)
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+            "#,
+            )
+            .bind(path.to_string())
+            .bind(path.parent().map(|parent| parent.to_string()))
+            .bind(
+                state
+                    .store
+                    .get(path)
+                    .map(serde_json::to_string)
+                    .transpose()?,
+            )
+            .bind(state.hashes.get(path))
+            .bind(state.merkle.get(path))
+            .bind(state.shallow.get(path))
+            .bind(
+                state
+                    .dirs
+                    .get(path)
+                    .map(serde_json::to_string)
+                    .transpose()?,
+            )
+            .bind(state.dir_mtimes.get(path).map(|mtime| *mtime as i64))
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        sqlx::query("INSERT OR REPLACE INTO Meta (key, value) VALUES ('ignore_patterns', ?)")
+            .bind(&state.ignore_patterns)
+            .execute(&mut *tx)
+            .await?;
+
+        if queue {
+            for command in &state.commands {
+                sqlx::query("INSERT INTO Outbox (command) VALUES (?)")
+                    .bind(serde_json::to_string(command)?)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+        }
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Hashes of the files of a volume along with the hashes they were last synced to, as
+    /// kept by [`StateStore::save_synced`]
+    pub async fn load_synced(&self) -> eyre::Result<State> {
+        let mut state = State::new();
+        let rows = sqlx::query(
+            "SELECT path, file, hash FROM Entry WHERE file IS NOT NULL AND hash IS NOT NULL",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        for row in rows {
+            let path = NullFsPath::from_to_str(row.try_get::<String, _>("path")?)?;
+            let file = serde_json::from_str(&row.try_get::<String, _>("file")?)?;
+            let hash: String = row.try_get("hash")?;
+            state.store.insert(path.clone(), file);
+            state.by_hash.insert(hash.clone(), path.clone());
+            state.hashes.insert(path, hash);
+        }
+
+        let rows = sqlx::query("SELECT path, hash FROM Synced")
+            .fetch_all(&self.pool)
+            .await?;
+        for row in rows {
+            let path = NullFsPath::from_to_str(row.try_get::<String, _>("path")?)?;
+            state.synced.insert(path, row.try_get("hash")?);
+        }
+
+        Ok(state)
+    }
+
+    /// Writes the hashes `state` remembered, synced or forgot since the last call, they are
+    /// kept for the next call when the write fails
+    pub async fn save_synced(&self, state: &mut State) -> eyre::Result<()> {
+        let journal = std::mem::take(&mut state.journal);
+        if journal.is_empty() {
+            return Ok(());
+        }
+
+        let saved = async {
+            let mut tx = self.pool.begin().await?;
+            for change in &journal {
+                match change {
+                    StateChange::Remembered(path) => {
+                        // forgotten since, a later change removes the row
+                        let (Some(file), Some(hash)) =
+                            (state.store.get(path), state.hashes.get(path))
+                        else {
+                            continue;
+                        };
+                        sqlx::query(
+                            "INSERT OR REPLACE INTO Entry (path, parent, file, hash) VALUES (?, ?, ?, ?)",
+                        )
+                        .bind(path.to_string())
+                        .bind(path.parent().map(|parent| parent.to_string()))
+                        .bind(serde_json::to_string(file)?)
+                        .bind(hash)
+                        .execute(&mut *tx)
+                        .await?;
+                    }
+                    StateChange::Synced(path) => {
+                        let Some(hash) = state.synced.get(path) else {
+                            continue;
+                        };
+                        sqlx::query("INSERT OR REPLACE INTO Synced (path, hash) VALUES (?, ?)")
+                            .bind(path.to_string())
+                            .bind(hash)
+                            .execute(&mut *tx)
+                            .await?;
+                    }
+                    StateChange::Forgotten(path) | StateChange::Unsynced(path) => {
+                        let table = match change {
+                            StateChange::Forgotten(_) => "Entry",
+                            _ => "Synced",
+                        };
+                        sqlx::query(&format!(
+                            "DELETE FROM {table} WHERE path = ?1 OR substr(path, 1, length(?2)) = ?2"
+                        ))
+                        .bind(path.to_string())
+                        .bind(format!("{path}/"))
+                        .execute(&mut *tx)
+                        .await?;
+                    }
+                }
+            }
+            tx.commit().await?;
+
+            eyre::Ok(())
+        }
+        .await;
+
+        if saved.is_err() {
+            state.journal.splice(0..0, journal);
+        }
+
+        saved
+    }
+
+    /// Tells apart the queue of this database from the one of a database created again,
+    /// picked on first use
+    pub async fn outbox_epoch(&self) -> eyre::Result<String> {
+        sqlx::query("INSERT OR IGNORE INTO Meta (key, value) VALUES ('outbox_epoch', ?)")
+            .bind(uuid::Uuid::new_v4().simple().to_string())
+            .execute(&self.pool)
+            .await?;
+
+        let row = sqlx::query("SELECT value FROM Meta WHERE key = 'outbox_epoch'")
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(row.try_get("value")?)
+    }
+
+    /// Drops the queued commands up to `seq`, the peer got them
+    pub async fn ack(&self, seq: i64) -> eyre::Result<()> {
+        sqlx::query("DELETE FROM Outbox WHERE seq <= ?")
+            .bind(seq)
+            .execute(&self.pool)
+            .await?;
 
         Ok(())
     }
+
+    /// Up to `limit` commands queued after `seq`, in the order they were captured
+    pub async fn queued(&self, seq: i64, limit: usize) -> eyre::Result<Vec<(i64, Command)>> {
+        let rows =
+            sqlx::query("SELECT seq, command FROM Outbox WHERE seq > ? ORDER BY seq LIMIT ?")
+                .bind(seq)
+                .bind(limit as i64)
+                .fetch_all(&self.pool)
+                .await?;
+
+        rows.iter()
+            .map(|row| {
+                let command = serde_json::from_str(&row.try_get::<String, _>("command")?)
+                    .wrap_err("Parsing queued command")?;
+                Ok((row.try_get("seq")?, command))
+            })
+            .collect()
+    }
+
+    pub async fn queued_count(&self, seq: i64) -> eyre::Result<u64> {
+        let row = sqlx::query("SELECT COUNT(*) AS count FROM Outbox WHERE seq > ?")
+            .bind(seq)
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(row.try_get::<i64, _>("count")? as u64)
+    }
+
+    /// Every queued command, the queue is emptied
+    pub async fn take_queued(&self) -> eyre::Result<Vec<Command>> {
+        let count = self.queued_count(0).await?;
+        let queued = self.queued(0, count as usize).await?;
+        if let Some((last, _)) = queued.last() {
+            self.ack(*last).await?;
+        }
+
+        Ok(queued.into_iter().map(|(_, command)| command).collect())
+    }
+
+    pub async fn close(self) {
+        self.pool.close().await;
+    }
 }
 
 impl Snapshot {
     pub fn new(fs: AnyFs) -> Self {
-        Self { fs }
+        Self {
+            fs,
+            mtime_tolerance_ms: 0,
+            skip_mounts: false,
+            quarantine_dir: DEFAULT_QUARANTINE_DIR.to_string(),
+            merkle: false,
+            incremental: false,
+        }
+    }
+
+    /// Do not descend into directories mounted from another device, like `find -xdev`
+    pub fn skip_mounts(mut self, skip: bool) -> Self {
+        self.skip_mounts = skip;
+        self
+    }
+
+    /// Keeps the Merkle hash of every directory in the state, all files get hashed
+    pub fn merkle(mut self, merkle: bool) -> Self {
+        self.merkle = merkle;
+        self
+    }
+
+    /// Directories whose mtime did not change since their last listing are not listed again,
+    /// their known entries are only checked for content edits since those leave the mtime of
+    /// the parent alone
+    ///
+    /// Only sound where adding, removing or renaming an entry bumps the mtime of its directory,
+    /// as on POSIX filesystems and NTFS. FAT only keeps it to 2 seconds, some network
+    /// filesystems (SMB, NFS with attribute caching) report stale directory mtimes and the
+    /// memory and s3 stores have none worth trusting, entries can go unnoticed there until
+    /// something else changes in the same directory
+    pub fn incremental(mut self, incremental: bool) -> Self {
+        self.incremental = incremental;
+        self
+    }
+
+    /// Quarantined files never sync back, `dir` is relative to the volume root
+    pub fn quarantine_dir(mut self, dir: &str) -> Self {
+        self.quarantine_dir = dir.to_string();
+        self
+    }
+
+    /// Whether `path` is the quarantine, the trash or the kept versions, none of them syncs
+    fn is_quarantine(&self, path: &NullFsPath) -> bool {
+        path.components()
+            .get(1..)
+            .map(|rel| rel.join("/"))
+            .is_some_and(|rel| {
+                rel == self.quarantine_dir || rel == TRASH_DIR || rel == VERSIONS_DIR
+            })
     }
 
+    /// See [`State::with_mtime_tolerance`]
+    pub fn mtime_tolerance(mut self, tolerance_ms: u64) -> Self {
+        self.mtime_tolerance_ms = tolerance_ms;
+        self
+    }
+
+    /// `state_path` is the [`StateStore`] database of the volume
+    ///
+    /// Commands still queued by [`Snapshot::capture_page`] come first, the peer stopped paging
+    #[allow(clippy::ptr_arg)]
     pub async fn capture(self, state_path: &PathBuf) -> eyre::Result<Vec<Command>> {
-        let mut state = State::load_from(state_path, true).await?;
+        let store = StateStore::open(state_path).await?;
+        let commands = async {
+            let state = self.refresh(&store, false).await?;
+            let mut commands = store.take_queued().await?;
+            commands.extend(state.infer_commands());
+
+            Ok(commands)
+        }
+        .await;
+        store.close().await;
+
+        commands
+    }
+
+    /// Up to `limit` commands following `since`, the ones before it are dropped from the
+    /// queue of the peer, the volume is only captured again once the queue is empty
+    ///
+    /// A cursor of another epoch, or none, starts from the first queued command
+    #[allow(clippy::ptr_arg)]
+    pub async fn capture_page(
+        self,
+        state_path: &PathBuf,
+        since: Option<&Cursor>,
+        limit: usize,
+    ) -> eyre::Result<CommandPage> {
+        let store = StateStore::open(state_path).await?;
+        let page = async {
+            let epoch = store.outbox_epoch().await?;
+            let after = match since {
+                Some(cursor) if cursor.epoch == epoch => cursor.seq,
+                _ => 0,
+            };
+
+            store.ack(after).await?;
+            if store.queued_count(after).await? == 0 {
+                self.refresh(&store, true).await?;
+            }
+
+            let queued = store.queued(after, limit).await?;
+            let seq = queued.last().map_or(after, |(seq, _)| *seq);
+
+            Ok(CommandPage {
+                commands: queued.into_iter().map(|(_, command)| command).collect(),
+                cursor: Cursor { epoch, seq },
+                remaining: store.queued_count(seq).await?,
+            })
+        }
+        .await;
+        store.close().await;
+
+        page
+    }
+
+    /// Brings the Merkle tree of the state up to date and returns its node at `path`
+    #[allow(clippy::ptr_arg)]
+    pub async fn merkle_node(
+        self,
+        state_path: &PathBuf,
+        path: &NullFsPath,
+    ) -> eyre::Result<Option<MerkleNode>> {
+        let store = StateStore::open(state_path).await?;
+        let node = async {
+            let mut state = self.merkle(true).refresh(&store, false).await?;
+            store.load_dir(&mut state, path).await?;
+
+            Ok(state.merkle_node(path))
+        }
+        .await;
+        store.close().await;
+
+        node
+    }
+
+    /// `queue` keeps the inferred commands in the store, see [`StateStore::save_queued`]
+    async fn refresh(&self, store: &StateStore, queue: bool) -> eyre::Result<State> {
+        let mut state = State::new().with_mtime_tolerance(self.mtime_tolerance_ms);
         let root = self.fs.volume_root()?;
-        self.capture_path(&mut state, &root).await?;
+        let (ignore, patterns) = self.load_ignore(&root).await?;
+        // paths ignored or not anymore can be anywhere
+        if store.ignore_patterns().await? != patterns {
+            store.reset_shallow().await?;
+        }
+        state.ignore_patterns = patterns;
+        self.capture_path(&mut state, store, &root, &ignore, &mut HashSet::new())
+            .await?;
 
         state.finalize();
-        state.save_to(state_path).await?;
+        match queue {
+            true => store.save_queued(&state).await?,
+            false => store.save(&state).await?,
+        }
+
+        Ok(state)
+    }
+
+    /// Patterns of the `.nullfsignore` at the volume root along with its content, nothing is
+    /// ignored without one
+    async fn load_ignore(&self, root: &NullFsPath) -> eyre::Result<(Gitignore, String)> {
+        let path = root.join(IGNORE_FILE)?;
+        if !self.fs.exists(&path).await? {
+            return Ok((Gitignore::empty(), String::new()));
+        }
+
+        let content = String::from_utf8(self.fs.read(&path).await?)
+            .wrap_err_with(|| format!("Reading {path}"))?;
+        let mut builder = GitignoreBuilder::new("");
+        for line in content.lines() {
+            builder
+                .add_line(None, line)
+                .wrap_err_with(|| format!("Bad pattern {line:?} in {path}"))?;
+        }
+
+        let ignore = builder
+            .build()
+            .wrap_err_with(|| format!("Reading {path}"))?;
+
+        Ok((ignore, content))
+    }
 
-        Ok(state.infer_commands())
+    fn is_ignored(ignore: &Gitignore, file: &File) -> bool {
+        let rel = file
+            .path
+            .components()
+            .get(1..)
+            .unwrap_or_default()
+            .join("/");
+        ignore.matched(rel, file.stat.is_dir()).is_ignore()
     }
 
+    /// `ancestors` are the directories being captured, as they are once links are followed
     #[async_recursion]
-    async fn capture_path(&self, state: &mut State, path: &NullFsPath) -> eyre::Result<()> {
-        let is_dir = self.fs.stats(path).await?.is_dir();
-        if !is_dir {
+    async fn capture_path(
+        &self,
+        state: &mut State,
+        store: &StateStore,
+        path: &NullFsPath,
+        ignore: &Gitignore,
+        ancestors: &mut HashSet<NullFsPath>,
+    ) -> eyre::Result<()> {
+        let stat = self.fs.stats(path).await?;
+        if !stat.is_dir() {
             return Ok(());
         }
+        let modified = stat.modified;
+        store.load_dir(state, path).await?;
 
-        let mut curr_files = IndexSet::from_iter(self.fs.dir(path).await?.into_iter());
-        curr_files.sort_by_key(|k| k.path.to_string());
-        let prev_files = state.dirs.get(path);
+        let known = match self.incremental && state.dir_mtimes.get(path) == Some(&modified) {
+            true => self.restat_listing(state, path).await?,
+            false => None,
+        };
+
+        // any change to the entries folds into the shallow hash of the directory
+        let dir = File {
+            file_type: FileType::infer_from_path(path),
+            path: path.to_owned(),
+            stat,
+        };
+        let shallow = match &known {
+            // the last listing still holds, its entries are only stat'ed again
+            Some(files) => hashing::shallow_hash(&dir, files.iter().cloned().collect()),
+            None => self.fs.shallow_hash(&dir).await?,
+        };
+        if state.dirs.contains_key(path)
+            && state.shallow.get(path) == Some(&shallow)
+            && (!self.merkle || state.merkle.contains_key(path))
+        {
+            // the entries are as they were, the subdirectories may have changed below
+            let listed = state.dirs[path].clone();
+            let real = self.fs.real_path(path).await?;
+            ancestors.insert(real.clone());
+            for entry in listed.iter().filter(|entry| !entry.stat.is_file()) {
+                if !ancestors.contains(&self.fs.real_path(&entry.path).await?) {
+                    self.capture_path(state, store, &entry.path, ignore, ancestors)
+                        .await?;
+                }
+            }
+            ancestors.remove(&real);
+
+            if self.merkle {
+                self.fold_merkle(state, path, &listed).await?;
+            }
+            return Ok(());
+        }
+        state.shallow.insert(path.to_owned(), shallow);
+
+        let listed_again = known.is_none();
+        let curr_files = match known {
+            Some(files) => files,
+            None => self.list_dir(path, ignore).await?,
+        };
+
+        let age = systime_to_millis(SystemTime::now()).saturating_sub(modified);
+        match age > RACY_DIR_MTIME_MS {
+            true => state.dir_mtimes.insert(path.to_owned(), modified),
+            false => state.dir_mtimes.shift_remove(path),
+        };
 
         let mut all_new = false;
-        if let Some(prev_files) = prev_files {
+        if !listed_again {
+            // nothing was added nor removed since the last listing
+        } else if let Some(prev_files) = state.dirs.get(path) {
             let prev_map = prev_files
                 .iter()
                 .map(|f| (&f.path, f))
@@ -171,6 +1082,11 @@ impl Snapshot {
                     "Fatal: expected item to be found in previous history".to_string()
                 })?;
 
+                // ignored since the last capture, not gone
+                if Self::is_ignored(ignore, item) {
+                    continue;
+                }
+
                 state.commands.insert(Command::Delete {
                     file: (*item).to_owned(),
                 });
@@ -180,7 +1096,10 @@ impl Snapshot {
         }
 
         state.dirs.insert(path.to_owned(), curr_files.clone());
+        let listed = curr_files.clone();
 
+        let real = self.fs.real_path(path).await?;
+        ancestors.insert(real.clone());
         for entry in curr_files {
             if all_new {
                 state.commands.insert(Command::Write {
@@ -189,18 +1108,154 @@ impl Snapshot {
             }
 
             if entry.stat.is_file() {
-                if state.update_on_change(&entry)? {
+                let changed = state.update_on_change(&entry)?;
+                if changed || state.cached_hash(&entry).is_none() {
+                    let hash = self.fs.hash(&entry.path).await?;
+                    state.hashes.insert(entry.path.clone(), hash);
+                }
+
+                if changed {
                     state.commands.insert(Command::Touch {
                         file: entry.to_owned(),
                         // the client will have to check the size, if != asks for the hash,
                         // if != then replace the file on their side
                     });
                 }
+            } else if ancestors.contains(&self.fs.real_path(&entry.path).await?) {
+                // a followed link back to a directory being captured would never end
+                tracing::warn!("Skipping {}, it links back to a parent", entry.path);
             } else {
-                self.capture_path(state, &entry.path).await?;
+                self.capture_path(state, store, &entry.path, ignore, ancestors)
+                    .await?;
             }
         }
+        ancestors.remove(&real);
+
+        if self.merkle {
+            self.fold_merkle(state, path, &listed).await?;
+        }
 
         Ok(())
     }
+
+    /// Records the Merkle hash of `path` from the hashes of its `listed` entries
+    async fn fold_merkle(
+        &self,
+        state: &mut State,
+        path: &NullFsPath,
+        listed: &IndexSet<File>,
+    ) -> eyre::Result<()> {
+        let mut children = vec![];
+        for entry in listed {
+            let cached = match entry.stat.is_dir() {
+                true => state.merkle.get(&entry.path),
+                false => state.hashes.get(&entry.path),
+            };
+            let hash = match cached {
+                Some(hash) => hash.clone(),
+                None => {
+                    let hash = self.fs.hash(&entry.path).await?;
+                    state.hashes.insert(entry.path.clone(), hash.clone());
+                    hash
+                }
+            };
+            children.push((entry.path.clone(), hash));
+        }
+
+        let hash = hashing::merkle_hash(children.iter().map(|(path, hash)| (path, hash.as_str())));
+        state.merkle.insert(path.to_owned(), hash);
+
+        Ok(())
+    }
+
+    /// Entries of `dir` minus the quarantine, the ignored paths and the skipped mount points
+    async fn list_dir(&self, dir: &NullFsPath, ignore: &Gitignore) -> eyre::Result<IndexSet<File>> {
+        let mut files = IndexSet::new();
+        for entry in self.fs.dir(dir).await? {
+            if entry.stat.is_dir() && self.is_quarantine(&entry.path) {
+                continue;
+            }
+
+            if Self::is_ignored(ignore, &entry) {
+                continue;
+            }
+
+            if self.skip_mounts
+                && entry.stat.is_dir()
+                && self.fs.is_mount_point(&entry.path).await?
+            {
+                tracing::info!("Skipping mount point {}", entry.path);
+                continue;
+            }
+
+            files.insert(entry);
+        }
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+
+        Ok(files)
+    }
+
+    /// Last listing of `dir` with fresh stats, none when an entry is gone anyway and the
+    /// directory has to be listed again
+    async fn restat_listing(
+        &self,
+        state: &State,
+        dir: &NullFsPath,
+    ) -> eyre::Result<Option<IndexSet<File>>> {
+        let Some(known) = state.dirs.get(dir) else {
+            return Ok(None);
+        };
+
+        let mut files = IndexSet::new();
+        for entry in known {
+            let Ok(stat) = self.fs.stats(&entry.path).await else {
+                tracing::debug!("{} is gone, listing {dir} again", entry.path);
+                return Ok(None);
+            };
+
+            files.insert(File {
+                stat,
+                ..entry.clone()
+            });
+        }
+
+        Ok(Some(files))
+    }
+}
+
+/// Removes the peer state files of `dir` that were not saved for longer than `max_age`,
+/// a returning peer simply resyncs from scratch
+pub async fn prune_peer_states(dir: &Path, max_age: Duration) -> eyre::Result<usize> {
+    let mut entries = tokio::fs::read_dir(dir)
+        .await
+        .with_context(|| format!("Reading directory {}", dir.display()))?;
+
+    let mut pruned = 0;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().to_string();
+        // states captured before they moved to sqlite are JSON files
+        if !name.starts_with(PEER_STATE_PREFIX)
+            || !(name.ends_with(".db") || name.ends_with(".json"))
+        {
+            continue;
+        }
+
+        let modified = entry.metadata().await?.modified()?;
+        let age = SystemTime::now()
+            .duration_since(modified)
+            .unwrap_or_default();
+        if age > max_age {
+            tracing::info!(
+                "Pruning peer state {} (untouched for {} days)",
+                name,
+                age.as_secs() / 86400
+            );
+            tokio::fs::remove_file(entry.path())
+                .await
+                .with_context(|| format!("Removing {}", entry.path().display()))?;
+            pruned += 1;
+        }
+    }
+
+    Ok(pruned)
 }
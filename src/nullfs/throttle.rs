@@ -0,0 +1,75 @@
+use std::{collections::BTreeMap, time::Duration};
+use tokio::{sync::Mutex, time::Instant};
+
+/// Token bucket capping the download rate of a node, shared by all its transfers
+///
+/// The bucket holds up to one second of transfer, a chunk taking more than what is left
+/// puts the bucket in debt and its transfer waits for the debt to be refilled, holding the
+/// bucket meanwhile so the concurrent transfers queue behind it
+///
+/// Transfers of a volume yield to those of higher priority volumes that drew from the
+/// bucket within the last second, see [`RateLimiter::acquire`]
+#[derive(Debug)]
+pub struct RateLimiter {
+    bytes_per_sec: u64,
+    bucket: Mutex<Bucket>,
+    /// Last time a transfer of each volume priority drew from the bucket
+    drawn: std::sync::Mutex<BTreeMap<u32, Instant>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        let bytes_per_sec = bytes_per_sec.max(1);
+        Self {
+            bytes_per_sec,
+            bucket: Mutex::new(Bucket {
+                tokens: bytes_per_sec as f64,
+                refilled: Instant::now(),
+            }),
+            drawn: std::sync::Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Waits until `bytes` more can be transferred within the rate
+    ///
+    /// Under contention a transfer gets a share of the rate weighted by `priority + 1`:
+    /// against a higher priority it waits, once the bucket is released, for as long again
+    /// as the weights differ, so lower priorities slow down but still make progress
+    pub async fn acquire(&self, bytes: u64, priority: u32) {
+        let top = self.top_priority(priority);
+        let rate = self.bytes_per_sec as f64;
+        let mut bucket = self.bucket.lock().await;
+        let now = Instant::now();
+        let refill = now.duration_since(bucket.refilled).as_secs_f64() * rate;
+        bucket.tokens = (bucket.tokens + refill).min(rate) - bytes as f64;
+        bucket.refilled = now;
+
+        if bucket.tokens < 0.0 {
+            tokio::time::sleep(Duration::from_secs_f64(-bucket.tokens / rate)).await;
+            bucket.tokens = 0.0;
+            bucket.refilled = Instant::now();
+        }
+        drop(bucket);
+
+        if top > priority {
+            let yielded = (top - priority) as f64 / (priority as f64 + 1.0);
+            tokio::time::sleep(Duration::from_secs_f64(bytes as f64 / rate * yielded)).await;
+        }
+    }
+
+    /// Records a draw at `priority`, highest priority that drew within the last second
+    fn top_priority(&self, priority: u32) -> u32 {
+        let now = Instant::now();
+        let mut drawn = self.drawn.lock().unwrap();
+        drawn.insert(priority, now);
+        drawn.retain(|_, at| now.duration_since(*at) < Duration::from_secs(1));
+
+        drawn.keys().next_back().copied().unwrap_or(priority)
+    }
+}
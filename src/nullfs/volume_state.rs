@@ -0,0 +1,195 @@
+use crate::nullfs::{
+    events::{EVENTS_CAPACITY, SyncEvent},
+    systime_to_millis,
+};
+use eyre::Context;
+use indexmap::IndexMap;
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    sync::Mutex,
+    time::SystemTime,
+};
+use tokio::sync::broadcast;
+
+/// Sync status of a volume, reads are served whatever the status
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "status", rename_all = "camelCase")]
+pub enum VolumeStatus {
+    Active,
+    /// Deliberately excluded from the sync cycles
+    Paused,
+    /// The last sync attempt failed
+    Degraded {
+        error: String,
+    },
+}
+
+/// What the synchronizer last did for a volume, times are in milliseconds since the epoch
+#[derive(Serialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct SyncActivity {
+    pub last_pull: Option<u64>,
+    pub last_apply: Option<u64>,
+    /// Relay the last successful pull came from
+    pub last_relay: Option<String>,
+    /// Outcome of the last liveness probe of each relay the volume pulls from
+    pub relays: IndexMap<String, bool>,
+}
+
+/// Runtime state of the volumes shared by the synchronizer and the server
+#[derive(Debug)]
+pub struct VolumeStates {
+    paused: Mutex<HashSet<String>>,
+    errors: Mutex<HashMap<String, String>>,
+    activity: Mutex<HashMap<String, SyncActivity>>,
+    /// Where the paused volumes are persisted, kept in memory only when unset
+    path: Option<PathBuf>,
+    events: broadcast::Sender<SyncEvent>,
+}
+
+impl Default for VolumeStates {
+    fn default() -> Self {
+        Self {
+            paused: Mutex::default(),
+            errors: Mutex::default(),
+            activity: Mutex::default(),
+            path: None,
+            events: broadcast::channel(EVENTS_CAPACITY).0,
+        }
+    }
+}
+
+impl VolumeStates {
+    /// Restores the paused volumes persisted at `path` if any
+    pub fn load(path: Option<PathBuf>) -> eyre::Result<Self> {
+        let mut paused = HashSet::new();
+        if let Some(path) = &path
+            && path.exists()
+        {
+            paused = std::fs::read_to_string(path)
+                .map_err(eyre::Report::from)
+                .and_then(|content| serde_json::from_str(&content).map_err(|e| e.into()))
+                .wrap_err_with(|| format!("Reading paused volumes from {}", path.display()))?;
+        }
+
+        Ok(Self {
+            paused: Mutex::new(paused),
+            path,
+            ..Self::default()
+        })
+    }
+
+    fn persist(&self, paused: &HashSet<String>) -> eyre::Result<()> {
+        if let Some(path) = &self.path {
+            std::fs::write(path, serde_json::to_string(paused)?)
+                .wrap_err_with(|| format!("Writing paused volumes to {}", path.display()))?;
+        }
+
+        Ok(())
+    }
+
+    pub fn pause(&self, volume: &str) -> eyre::Result<()> {
+        let mut paused = self.paused.lock().unwrap();
+        if paused.insert(volume.to_owned()) {
+            tracing::warn!("Paused sync of @/{volume}");
+            self.persist(&paused)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn resume(&self, volume: &str) -> eyre::Result<()> {
+        let mut paused = self.paused.lock().unwrap();
+        if paused.remove(volume) {
+            tracing::info!("Resumed sync of @/{volume}");
+            self.persist(&paused)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn is_paused(&self, volume: &str) -> bool {
+        self.paused.lock().unwrap().contains(volume)
+    }
+
+    pub fn report_error(&self, volume: &str, error: &str) {
+        let mut errors = self.errors.lock().unwrap();
+        errors.insert(volume.to_owned(), error.to_owned());
+    }
+
+    pub fn report_ok(&self, volume: &str) {
+        self.errors.lock().unwrap().remove(volume);
+    }
+
+    pub fn report_pull(&self, volume: &str, relay: &str) {
+        let mut activity = self.activity.lock().unwrap();
+        let activity = activity.entry(volume.to_owned()).or_default();
+        activity.last_pull = Some(systime_to_millis(SystemTime::now()));
+
+        match activity.last_relay.replace(relay.to_owned()) {
+            Some(previous) if previous != relay => {
+                tracing::info!("@/{volume} now served by {relay} in place of {previous}")
+            }
+            Some(_) => tracing::debug!("@/{volume} served by {relay}"),
+            None => tracing::info!("@/{volume} served by {relay}"),
+        }
+    }
+
+    pub fn report_apply(&self, volume: &str) {
+        let mut activity = self.activity.lock().unwrap();
+        activity.entry(volume.to_owned()).or_default().last_apply =
+            Some(systime_to_millis(SystemTime::now()));
+    }
+
+    pub fn report_liveness(&self, volume: &str, relay: &str, alive: bool) {
+        let mut activity = self.activity.lock().unwrap();
+        let previous = activity
+            .entry(volume.to_owned())
+            .or_default()
+            .relays
+            .insert(relay.to_owned(), alive);
+
+        if previous != Some(alive) {
+            let (volume, relay) = (volume.to_owned(), relay.to_owned());
+            self.publish(match alive {
+                true => SyncEvent::RelayUp { volume, relay },
+                false => SyncEvent::RelayDown { volume, relay },
+            });
+        }
+    }
+
+    /// Sends `event` to the current subscribers, it is lost when there are none
+    pub fn publish(&self, event: SyncEvent) {
+        self.events.send(event).ok();
+    }
+
+    /// Events published from now on, a subscriber more than [`EVENTS_CAPACITY`] events behind
+    /// misses the oldest ones rather than holding back the synchronizer
+    pub fn subscribe(&self) -> broadcast::Receiver<SyncEvent> {
+        self.events.subscribe()
+    }
+
+    pub fn activity(&self, volume: &str) -> SyncActivity {
+        self.activity
+            .lock()
+            .unwrap()
+            .get(volume)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub fn status(&self, volume: &str) -> VolumeStatus {
+        if self.is_paused(volume) {
+            return VolumeStatus::Paused;
+        }
+
+        match self.errors.lock().unwrap().get(volume) {
+            Some(error) => VolumeStatus::Degraded {
+                error: error.clone(),
+            },
+            None => VolumeStatus::Active,
+        }
+    }
+}
@@ -1,72 +1,212 @@
 use crate::{
-    config::{NodeConfig, NodeIdentifier, User},
-    nullfs::{NullFs, NullFsPath, any_fs::AnyFs, snapshot::Snapshot},
+    config::{Access, NodeConfig, NodeIdentifier, StoreKind, User, is_safe_identifier},
+    nullfs::{
+        DirPage, File, FileStat, FileType, NodeKind, NullFs, NullFsPath,
+        any_fs::AnyFs,
+        delta,
+        fs_snapshot::FsSnapshots,
+        hashing::{self, HashTree},
+        metrics::METRICS,
+        quarantine::DEFAULT_QUARANTINE_DIR,
+        search::{MAX_RESULTS, SearchLimits, find_by_name},
+        share::{
+            CURSOR_HEADER, CommandStash, HASH_ALGO_HEADER, MSGPACK_MIME, NODE_ID_HEADER,
+            REMAINING_HEADER, TOTAL_COUNT_HEADER,
+        },
+        snapshot::{COMMANDS_PAGE_SIZE, Cursor, MERKLE_STATE_PREFIX, PEER_STATE_PREFIX, Snapshot},
+        systime_to_millis,
+        volume_state::{VolumeStates, VolumeStatus},
+    },
+    server::{CurrentConfig, error::ApiError},
+};
+use actix_web::{
+    HttpMessage, HttpRequest, HttpResponse, Responder,
+    error::PayloadError,
+    http::header::{self, ACCEPT},
+    web,
 };
-use actix_web::{HttpResponse, Responder, body::BoxBody, web};
 use actix_web_httpauth::extractors::basic::BasicAuth;
-use serde::Deserialize;
+use eyre::WrapErr;
+use futures::{SinkExt, StreamExt, TryStreamExt};
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::{path::PathBuf, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    ops::Range,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+use tokio::sync::broadcast::error::RecvError;
 
-pub fn basic_auth(
-    auth: BasicAuth,
-    volume: &str,
-    config: web::Data<Arc<NodeConfig>>,
-) -> Option<User> {
+/// Largest body accepted by `/v1/upload`
+pub const MAX_UPLOAD_SIZE: usize = 1024 * 1024 * 1024;
+
+/// Most node ids a [`PeerRegistry`] remembers, the least recently seen are forgotten first
+pub const MAX_PEER_CLAIMS: usize = 4096;
+
+/// Identity (user and realm) that first presented a peer node id
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+struct PeerClaim {
+    user: String,
+    realm: Option<String>,
+}
+
+/// Remembers which identity first presented a given peer node id, up to [`MAX_PEER_CLAIMS`].
+///
+/// Two nodes presenting the same node id, user and realm cannot be told apart and share a peer
+/// state, which is what a cloned machine does when its data directory was copied along with its
+/// configuration: the clone gets a node id of its own once its `.id-<name>` file is removed.
+#[derive(Debug, Default)]
+pub struct PeerRegistry {
+    /// Ordered from the least to the most recently seen
+    peers: Mutex<IndexMap<String, PeerClaim>>,
+    /// Where the claims are persisted, kept in memory only when unset
+    path: Option<PathBuf>,
+}
+
+impl PeerRegistry {
+    /// Restores the claims persisted at `path` if any
+    pub fn load(path: Option<PathBuf>) -> eyre::Result<Self> {
+        let mut peers = IndexMap::new();
+        if let Some(path) = &path
+            && path.exists()
+        {
+            peers = std::fs::read_to_string(path)
+                .map_err(eyre::Report::from)
+                .and_then(|content| serde_json::from_str(&content).map_err(|e| e.into()))
+                .wrap_err_with(|| format!("Reading peer claims from {}", path.display()))?;
+        }
+
+        Ok(Self {
+            peers: Mutex::new(peers),
+            path,
+        })
+    }
+
+    fn persist(&self, peers: &IndexMap<String, PeerClaim>) -> eyre::Result<()> {
+        if let Some(path) = &self.path {
+            std::fs::write(path, serde_json::to_string(peers)?)
+                .wrap_err_with(|| format!("Writing peer claims to {}", path.display()))?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns false when `node_id` was already claimed by another identity
+    pub fn claim(&self, node_id: &str, user: &str, realm: Option<&str>) -> eyre::Result<bool> {
+        let mut peers = self.peers.lock().unwrap();
+        let identity = PeerClaim {
+            user: user.to_owned(),
+            realm: realm.map(|r| r.to_owned()),
+        };
+
+        if let Some(index) = peers.get_index_of(node_id) {
+            if peers[index] != identity {
+                return Ok(false);
+            }
+            // only the order changed, it is saved along with the next new claim
+            let last = peers.len() - 1;
+            peers.move_index(index, last);
+            return Ok(true);
+        }
+
+        if peers.len() >= MAX_PEER_CLAIMS {
+            peers.shift_remove_index(0);
+        }
+        peers.insert(node_id.to_owned(), identity);
+        self.persist(&peers)?;
+
+        Ok(true)
+    }
+}
+
+pub fn basic_auth(auth: BasicAuth, volume: &str, config: CurrentConfig) -> Option<(User, Access)> {
     let user = User {
         name: auth.user_id().to_owned(),
         password: auth.password().map(|password| password.to_owned()),
     };
 
-    if config.allow(volume, &user) {
-        return Some(user);
-    }
-
-    None
+    let access = config.access_level(volume, &user)?;
+    Some((user, access))
 }
 
+/// Rejects the requests of users without at least `needed` access to `volume`
 pub fn check_auth(
     auth: BasicAuth,
     volume: &str,
-    config: web::Data<Arc<NodeConfig>>,
-) -> Option<HttpResponse> {
-    let user = User {
-        name: auth.user_id().to_owned(),
-        password: auth.password().map(|password| password.to_owned()),
-    };
+    config: CurrentConfig,
+    needed: Access,
+) -> Result<(), ApiError> {
+    let name = auth.user_id().to_owned();
 
     match basic_auth(auth, volume, config) {
-        Some(_) => None,
-        None => Some(HttpResponse::BadRequest().json(json!({
-            "error": format!("User {:?} targetting volume {:?} unauthorized", user.name, volume)
-        }))),
+        Some((_, access)) if access >= needed => Ok(()),
+        Some(_) => Err(ApiError::Forbidden(format!(
+            "User {name:?} has read-only access to volume {volume:?}"
+        ))),
+        None => Err(ApiError::Unauthorized(format!(
+            "User {name:?} targetting volume {volume:?} unauthorized"
+        ))),
+    }
+}
+
+/// Same as [`check_auth`] for the read endpoints of anonymous volumes, served without credentials
+pub fn check_anonymous_auth(
+    auth: Option<BasicAuth>,
+    volume: &str,
+    config: CurrentConfig,
+    needed: Access,
+) -> Result<(), ApiError> {
+    match auth {
+        Some(auth) => check_auth(auth, volume, config, needed),
+        None if needed == Access::Ro && config.allows_anonymous(volume) => Ok(()),
+        None => Err(ApiError::Unauthorized(format!(
+            "Volume {volume:?} requires credentials"
+        ))),
     }
 }
 
 pub async fn with_fs<F, Fut>(
-    config: web::Data<Arc<NodeConfig>>,
+    config: CurrentConfig,
+    snapshots: &FsSnapshots,
     volume_name: &str,
     ff: F,
-) -> HttpResponse<BoxBody>
+) -> Result<HttpResponse, ApiError>
 where
     F: FnOnce(AnyFs) -> Fut,
-    Fut: Future<Output = HttpResponse<BoxBody>>,
+    Fut: Future<Output = Result<HttpResponse, ApiError>>,
 {
-    match config.get_initialized_fs_volume(volume_name).await {
+    let snapshot_root = snapshots.mount_of(volume_name).await;
+    match config
+        .get_initialized_fs_volume_at(volume_name, snapshot_root)
+        .await
+    {
         Ok(Some(fs)) => ff(fs).await,
-        Ok(None) => HttpResponse::BadRequest().json(json!({
-            "error": format!("Volume {volume_name:?} not found")
-        })),
-        Err(e) => HttpResponse::BadRequest().json(json!({
-            "error": format!("Could not retrieve volume {volume_name}: {e}")
-        })),
+        Ok(None) => Err(ApiError::NotFound(format!(
+            "Volume {volume_name:?} not found"
+        ))),
+        Err(e) => Err(ApiError::Internal(format!(
+            "Could not retrieve volume {volume_name}: {e}"
+        ))),
     }
 }
 
+/// Volume a requested path belongs to
+fn volume_of(path: &NullFsPath) -> Result<String, ApiError> {
+    path.volume_name()
+        .map_err(|_| ApiError::BadRequest(format!("Volume not found in {path}")))
+}
+
 #[derive(Deserialize, Debug)]
 pub struct CommandsParams {
     pub volume: String,
     pub node_id: String,
+    pub realm: Option<String>,
+    /// Cursor of the last page the peer stashed, empty on its first paged pull, every
+    /// command is answered at once without it
+    pub since: Option<String>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -74,158 +214,966 @@ pub struct WithPath {
     pub path: NullFsPath,
 }
 
+/// Serializes as msgpack when the client accepts it, json otherwise
+fn negotiate<T: serde::Serialize>(req: &HttpRequest, value: &T) -> Result<HttpResponse, ApiError> {
+    let accepts_msgpack = req
+        .headers()
+        .get(ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains(MSGPACK_MIME));
+
+    if !accepts_msgpack {
+        return Ok(HttpResponse::Ok().json(value));
+    }
+
+    match rmp_serde::to_vec_named(value) {
+        Ok(bytes) => Ok(HttpResponse::Ok().content_type(MSGPACK_MIME).body(bytes)),
+        Err(e) => Err(ApiError::Internal(e.to_string())),
+    }
+}
+
+/// Snapshot of a volume set up from its configuration
+fn volume_snapshot(config: &NodeConfig, volume_name: &str, fs: &AnyFs) -> Snapshot {
+    let volume = config.volumes.get(volume_name);
+    let skip_mounts = volume.is_some_and(|volume| volume.skip_mounts);
+    let incremental = volume.is_some_and(|volume| volume.incremental_scan);
+    let quarantine_dir = volume
+        .and_then(|volume| volume.quarantine_dir.as_deref())
+        .unwrap_or(DEFAULT_QUARANTINE_DIR);
+
+    Snapshot::new(fs.clone())
+        .skip_mounts(skip_mounts)
+        .incremental(incremental)
+        .quarantine_dir(quarantine_dir)
+        .mtime_tolerance(config.mtime_tolerance_ms.unwrap_or_default())
+}
+
 pub async fn commands(
+    req: HttpRequest,
     auth: BasicAuth,
-    config: web::Data<Arc<NodeConfig>>,
+    config: CurrentConfig,
     this_node: web::Data<Arc<NodeIdentifier>>,
+    peers: web::Data<PeerRegistry>,
+    snapshots: web::Data<FsSnapshots>,
     params: web::Query<CommandsParams>,
-) -> impl Responder {
+) -> Result<HttpResponse, ApiError> {
     let volume_name = params.volume.trim();
-    if let Some(bad_resp) = check_auth(auth, volume_name, config.clone()) {
-        return bad_resp;
+    let user_name = auth.user_id().to_owned();
+    check_auth(auth, volume_name, config.clone(), Access::Ro)?;
+
+    let realm = params.realm.as_deref();
+    if !is_safe_identifier(&params.node_id) || !realm.is_none_or(is_safe_identifier) {
+        return Err(ApiError::BadRequest(format!(
+            "Invalid node id {:?} or realm {:?}",
+            params.node_id, realm
+        )));
+    }
+
+    let since = match params.since.as_deref() {
+        Some("") | None => None,
+        Some(since) => Some(
+            since
+                .parse::<Cursor>()
+                .map_err(|e| ApiError::BadRequest(e.to_string()))?,
+        ),
+    };
+
+    if params.node_id.eq(&this_node.uuid) || !peers.claim(&params.node_id, &user_name, realm)? {
+        tracing::warn!(
+            "Refusing node id {} presented by {:?} (realm {:?}): identity collision",
+            params.node_id,
+            user_name,
+            realm
+        );
+        return Err(ApiError::Conflict(format!(
+            "Node id {} is already used by another peer",
+            params.node_id
+        )));
+    }
+
+    if let Some(volume) = config.volumes.get(volume_name)
+        && let Some(hook) = &volume.fs_snapshot
+        && let StoreKind::Local { root, .. } = &volume.store
+        && let Err(e) = snapshots.refresh(volume_name, root, hook).await
+    {
+        return Err(ApiError::Internal(format!(
+            "Could not snapshot volume {volume_name}: {e}"
+        )));
     }
 
-    with_fs(config.clone(), volume_name, async |fs| {
+    with_fs(config.clone(), &snapshots, volume_name, async |fs| {
         let commands = async {
-            let snapshot = Snapshot::new(fs.clone());
-            let state_file = PathBuf::from(format!(
-                ".ext-state-{}-{}-{}.json",
-                fs.get_volume_name(),
-                this_node.uuid,
-                params.node_id
-            ));
-
-            snapshot.capture(&state_file).await
+            let snapshot = volume_snapshot(&config, volume_name, &fs);
+            let state_file = config.data_dir().join(match realm {
+                Some(realm) => format!(
+                    "{PEER_STATE_PREFIX}{}-{}-{}-{}.db",
+                    fs.get_volume_name(),
+                    this_node.uuid,
+                    realm,
+                    params.node_id
+                ),
+                None => format!(
+                    "{PEER_STATE_PREFIX}{}-{}-{}.db",
+                    fs.get_volume_name(),
+                    this_node.uuid,
+                    params.node_id
+                ),
+            });
+
+            let (captured, page) = match params.since {
+                Some(_) => {
+                    let page = snapshot
+                        .capture_page(&state_file, since.as_ref(), COMMANDS_PAGE_SIZE)
+                        .await?;
+                    (page.commands, Some((page.cursor, page.remaining)))
+                }
+                None => (snapshot.capture(&state_file).await?, None),
+            };
+
+            // the stash of this node, set up by `server::run`
+            let commands = match req.app_data::<web::Data<Arc<CommandStash>>>() {
+                Some(stash) => {
+                    stash
+                        .suppress_echoes(&fs.get_volume_name(), &params.node_id, captured)
+                        .await?
+                }
+                None => captured,
+            };
+
+            eyre::Ok((commands, page))
         };
 
         return match commands.await {
-            Ok(res) => HttpResponse::Ok().json(res),
-            Err(e) => HttpResponse::InternalServerError().json(json!({
-                "error": e.to_string()
-            })),
+            Ok((commands, page)) => {
+                let mut response = negotiate(&req, &commands)?;
+                let mut headers = vec![(NODE_ID_HEADER, this_node.uuid.clone())];
+                if let Some((cursor, remaining)) = page {
+                    headers.push((CURSOR_HEADER, cursor.to_string()));
+                    headers.push((REMAINING_HEADER, remaining.to_string()));
+                }
+
+                for (name, value) in headers {
+                    if let Ok(value) = header::HeaderValue::from_str(&value) {
+                        response
+                            .headers_mut()
+                            .insert(header::HeaderName::from_static(name), value);
+                    }
+                }
+                Ok(response)
+            }
+            Err(e) => Err(e.into()),
         };
     })
     .await
 }
 
+/// Entry listed by `/v1/dir`, directories come with the count of their own entries, which
+/// clients reading a plain [`File`] ignore
+#[derive(Serialize, Debug)]
+pub struct DirEntry {
+    #[serde(flatten)]
+    pub file: File,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entries: Option<u64>,
+}
+
 pub async fn dir(
-    auth: BasicAuth,
-    config: web::Data<Arc<NodeConfig>>,
+    auth: Option<BasicAuth>,
+    config: CurrentConfig,
+    snapshots: web::Data<FsSnapshots>,
     params: web::Query<WithPath>,
-) -> impl Responder {
-    let volume_name;
-    if let Ok(volume) = params.path.volume_name() {
-        volume_name = volume;
-    } else {
-        return HttpResponse::BadRequest().json(json!({
-            "error": format!("Volume not found in {}", params.path)
-        }));
+    page: web::Query<DirPage>,
+) -> Result<HttpResponse, ApiError> {
+    let volume_name = volume_of(&params.path)?;
+
+    check_anonymous_auth(auth, &volume_name, config.clone(), Access::Ro)?;
+
+    with_fs(config.clone(), &snapshots, &volume_name, async |fs| {
+        let listed = async {
+            let listing = fs.dir_page(&params.path, &page).await?;
+            let mut entries = vec![];
+            for file in listing.entries {
+                let count = match file.stat.is_dir() {
+                    true => Some(fs.count_children(&file.path).await?),
+                    false => None,
+                };
+                entries.push(DirEntry {
+                    file,
+                    entries: count,
+                });
+            }
+
+            eyre::Ok((listing.total, entries))
+        };
+
+        match listed.await {
+            Ok((total, entries)) => Ok(HttpResponse::Ok()
+                .insert_header((TOTAL_COUNT_HEADER, total.to_string()))
+                .json(entries)),
+            Err(e) => Err(e.into()),
+        }
+    })
+    .await
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SearchParams {
+    pub volume: String,
+    pub q: String,
+    pub limit: Option<usize>,
+}
+
+/// Entries of a volume whose name contains `q`, see [`find_by_name`]
+pub async fn search(
+    auth: Option<BasicAuth>,
+    config: CurrentConfig,
+    snapshots: web::Data<FsSnapshots>,
+    params: web::Query<SearchParams>,
+) -> Result<HttpResponse, ApiError> {
+    check_anonymous_auth(auth, &params.volume, config.clone(), Access::Ro)?;
+
+    if params.q.trim().is_empty() {
+        return Err(ApiError::BadRequest("Empty search query".to_owned()));
     }
 
-    if let Some(bad_resp) = check_auth(auth, &volume_name, config.clone()) {
-        return bad_resp;
+    let root = match NullFsPath::from_to_str(format!("@/{}", params.volume)) {
+        Ok(root) => root,
+        Err(e) => return Err(ApiError::BadRequest(e.to_string())),
+    };
+    let mut limits = SearchLimits::default();
+    if let Some(limit) = params.limit {
+        limits.max_results = limit.clamp(1, MAX_RESULTS);
     }
 
-    with_fs(config.clone(), &volume_name, async |fs| {
-        match fs.dir(&params.path).await {
-            Ok(res) => HttpResponse::Ok().json(res),
-            Err(e) => HttpResponse::InternalServerError().json(json!({
-                "error": e.to_string()
-            })),
+    with_fs(
+        config.clone(),
+        &snapshots,
+        &params.volume,
+        async |fs| match find_by_name(&fs, &root, params.q.trim(), &limits).await {
+            Ok(res) => Ok(HttpResponse::Ok().json(res)),
+            Err(e) => Err(e.into()),
+        },
+    )
+    .await
+}
+
+pub async fn hash(
+    req: HttpRequest,
+    auth: Option<BasicAuth>,
+    config: CurrentConfig,
+    snapshots: web::Data<FsSnapshots>,
+    params: web::Query<WithPath>,
+) -> Result<HttpResponse, ApiError> {
+    let volume_name = volume_of(&params.path)?;
+
+    check_anonymous_auth(auth, &volume_name, config.clone(), Access::Ro)?;
+
+    with_fs(
+        config.clone(),
+        &snapshots,
+        &volume_name,
+        async |fs| match fs.hash(&params.path).await {
+            Ok(res) => {
+                let etag = content_etag(&res);
+                if is_not_modified(&req, &etag) {
+                    return Ok(HttpResponse::NotModified()
+                        .insert_header(header::ETag(etag))
+                        .finish());
+                }
+
+                Ok(HttpResponse::Ok()
+                    .insert_header((HASH_ALGO_HEADER, hashing::algo().name()))
+                    .insert_header(header::ETag(etag))
+                    .json(res))
+            }
+            Err(e) => Err(e.into()),
+        },
+    )
+    .await
+}
+
+/// Hashes of a path and of everything below it, see [`HashTree`]
+pub async fn tree(
+    auth: BasicAuth,
+    config: CurrentConfig,
+    snapshots: web::Data<FsSnapshots>,
+    params: web::Query<WithPath>,
+) -> Result<HttpResponse, ApiError> {
+    let volume_name = volume_of(&params.path)?;
+
+    check_auth(auth, &volume_name, config.clone(), Access::Ro)?;
+
+    with_fs(
+        config.clone(),
+        &snapshots,
+        &volume_name,
+        async |fs| match HashTree::build(&fs, &params.path).await {
+            Ok(res) => Ok(HttpResponse::Ok()
+                .insert_header((HASH_ALGO_HEADER, hashing::algo().name()))
+                .json(res)),
+            Err(e) => Err(e.into()),
+        },
+    )
+    .await
+}
+
+/// Content defined chunks of a file, a puller holding a previous version only downloads the
+/// ones it lacks, see [`delta::plan`]
+pub async fn chunks(
+    auth: BasicAuth,
+    config: CurrentConfig,
+    snapshots: web::Data<FsSnapshots>,
+    params: web::Query<WithPath>,
+) -> Result<HttpResponse, ApiError> {
+    let volume_name = volume_of(&params.path)?;
+
+    check_auth(auth, &volume_name, config.clone(), Access::Ro)?;
+
+    with_fs(config.clone(), &snapshots, &volume_name, async |fs| {
+        match fs.stats(&params.path).await.map(|stat| stat.node) {
+            Ok(NodeKind::File { .. }) => {}
+            Ok(_) => {
+                return Err(ApiError::BadRequest(format!(
+                    "{} is not a file",
+                    params.path
+                )));
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        let chunks = match fs.read_stream(&params.path).await {
+            Ok(stream) => delta::chunks_of(stream).await,
+            Err(e) => Err(e),
+        };
+        match chunks {
+            Ok(res) => Ok(HttpResponse::Ok()
+                .insert_header((HASH_ALGO_HEADER, hashing::algo().name()))
+                .json(res)),
+            Err(e) => Err(e.into()),
         }
     })
     .await
 }
 
-pub async fn hash(
+/// Largest body accepted by `/v1/hashes` and `/v1/stats`
+pub const MAX_HASHES_BODY: usize = 4 * 1024 * 1024;
+
+/// Volume of a batch of paths, all of them have to be in the same one
+fn batch_volume(paths: &[NullFsPath]) -> Result<Option<String>, ApiError> {
+    let Some(first) = paths.first() else {
+        return Ok(None);
+    };
+
+    let volume_name = volume_of(first)?;
+    if let Some(path) = paths
+        .iter()
+        .find(|path| path.volume_name().ok().as_ref() != Some(&volume_name))
+    {
+        return Err(ApiError::BadRequest(format!(
+            "{path} is not in volume {volume_name:?}"
+        )));
+    }
+
+    Ok(Some(volume_name))
+}
+
+/// Content hashes of several paths of a single volume, the paths that cannot be hashed are
+/// left out of the answer
+pub async fn hashes(
     auth: BasicAuth,
-    config: web::Data<Arc<NodeConfig>>,
+    config: CurrentConfig,
+    snapshots: web::Data<FsSnapshots>,
+    paths: web::Json<Vec<NullFsPath>>,
+) -> Result<HttpResponse, ApiError> {
+    let paths = paths.into_inner();
+    let Some(volume_name) = batch_volume(&paths)? else {
+        return Ok(HttpResponse::Ok().json(IndexMap::<NullFsPath, String>::new()));
+    };
+
+    check_auth(auth, &volume_name, config.clone(), Access::Ro)?;
+
+    with_fs(config.clone(), &snapshots, &volume_name, async |fs| {
+        let mut hashes = IndexMap::new();
+        for path in paths {
+            match fs.hash(&path).await {
+                Ok(hash) => {
+                    hashes.insert(path, hash);
+                }
+                Err(e) => tracing::debug!("Could not hash {path}: {e}"),
+            }
+        }
+
+        Ok(HttpResponse::Ok()
+            .insert_header((HASH_ALGO_HEADER, hashing::algo().name()))
+            .json(hashes))
+    })
+    .await
+}
+
+/// Metadata of several paths of a single volume, the missing paths are left out of the answer
+pub async fn stats(
+    auth: BasicAuth,
+    config: CurrentConfig,
+    snapshots: web::Data<FsSnapshots>,
+    paths: web::Json<Vec<NullFsPath>>,
+) -> Result<HttpResponse, ApiError> {
+    let paths = paths.into_inner();
+    let Some(volume_name) = batch_volume(&paths)? else {
+        return Ok(HttpResponse::Ok().json(IndexMap::<NullFsPath, FileStat>::new()));
+    };
+
+    check_auth(auth, &volume_name, config.clone(), Access::Ro)?;
+
+    with_fs(config.clone(), &snapshots, &volume_name, async |fs| {
+        let mut stats = IndexMap::new();
+        for path in paths {
+            match fs.stats(&path).await {
+                Ok(stat) => {
+                    stats.insert(path, stat);
+                }
+                Err(e) => tracing::debug!("Could not stat {path}: {e}"),
+            }
+        }
+
+        Ok(HttpResponse::Ok().json(stats))
+    })
+    .await
+}
+
+/// Strong entity tag of a file, its content hash
+fn content_etag(hash: &str) -> header::EntityTag {
+    header::EntityTag::new_strong(hash.to_owned())
+}
+
+/// Whether the `If-None-Match` header of `req` matches `etag`, the copy of the client is then
+/// current and gets a `304 Not Modified`
+fn is_not_modified(req: &HttpRequest, etag: &header::EntityTag) -> bool {
+    match req.get_header::<header::IfNoneMatch>() {
+        Some(header::IfNoneMatch::Any) => true,
+        Some(header::IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(etag)),
+        None => false,
+    }
+}
+
+/// Byte range asked by the `Range` header, `Err` when it cannot be satisfied
+///
+/// Malformed and multi-range headers are ignored, the whole file is served instead
+fn requested_range(req: &HttpRequest, size: u64) -> Result<Option<Range<u64>>, ()> {
+    match req.get_header::<header::Range>() {
+        Some(header::Range::Bytes(specs)) if specs.len() == 1 => specs[0]
+            .to_satisfiable_range(size)
+            .map(|(start, end)| Some(start..end + 1))
+            .ok_or(()),
+        _ => Ok(None),
+    }
+}
+
+pub async fn download(
+    req: HttpRequest,
+    auth: Option<BasicAuth>,
+    config: CurrentConfig,
+    snapshots: web::Data<FsSnapshots>,
     params: web::Query<WithPath>,
-) -> impl Responder {
-    let volume_name;
-    if let Ok(volume) = params.path.volume_name() {
-        volume_name = volume;
-    } else {
-        return HttpResponse::BadRequest().json(json!({
-            "error": format!("Volume not found in {}", params.path)
-        }));
+) -> Result<HttpResponse, ApiError> {
+    let volume_name = volume_of(&params.path)?;
+
+    check_anonymous_auth(auth, &volume_name, config.clone(), Access::Ro)?;
+
+    with_fs(config.clone(), &snapshots, &volume_name, async |fs| {
+        let size = match fs.stats(&params.path).await.map(|stat| stat.node) {
+            Ok(NodeKind::File { size }) => size,
+            Ok(NodeKind::Dir) => {
+                return Err(ApiError::BadRequest(format!(
+                    "{} is a directory",
+                    params.path
+                )));
+            }
+            Ok(NodeKind::Symlink { target }) => {
+                return Err(ApiError::BadRequest(format!(
+                    "{} is a link to {target}",
+                    params.path
+                )));
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let etag = content_etag(&fs.hash(&params.path).await?);
+        // checked ahead of the range, a current copy needs no part of the file
+        if is_not_modified(&req, &etag) {
+            return Ok(HttpResponse::NotModified()
+                .insert_header(header::ETag(etag))
+                .finish());
+        }
+
+        let Ok(range) = requested_range(&req, size) else {
+            return Ok(HttpResponse::RangeNotSatisfiable()
+                .insert_header((header::CONTENT_RANGE, format!("bytes */{size}")))
+                .finish());
+        };
+
+        // the content hash sent as `ETag` is what the puller checks the body against
+        let streamed = match &range {
+            Some(range) => fs.read_range(&params.path, range.clone()).await,
+            None => fs.read_stream(&params.path).await,
+        };
+
+        match streamed {
+            Ok(body) => {
+                let mut resp = match &range {
+                    Some(range) => {
+                        let mut resp = HttpResponse::PartialContent();
+                        resp.insert_header((
+                            header::CONTENT_RANGE,
+                            format!("bytes {}-{}/{size}", range.start, range.end - 1),
+                        ));
+                        resp
+                    }
+                    None => HttpResponse::Ok(),
+                };
+
+                // the compression middleware leaves alone the bodies with an encoding set
+                let compress = config.compression.unwrap_or(true)
+                    && range.is_none()
+                    && !FileType::infer_from_path(&params.path).is_compressed();
+                if !compress {
+                    resp.insert_header(header::ContentEncoding::Identity);
+                }
+
+                Ok(resp
+                    .insert_header((header::ACCEPT_RANGES, "bytes"))
+                    .insert_header(header::ETag(etag))
+                    .insert_header((
+                        header::CONTENT_TYPE,
+                        FileType::mime_from_path(&params.path, &config.mime_overrides),
+                    ))
+                    .insert_header((header::CONTENT_SECURITY_POLICY, "sandbox"))
+                    .insert_header((header::X_CONTENT_TYPE_OPTIONS, "nosniff"))
+                    .streaming(body.map_err(actix_web::error::ErrorInternalServerError)))
+            }
+            Err(e) => Err(e.into()),
+        }
+    })
+    .await
+}
+
+/// Rejects the writes to a volume not flagged `writable`
+fn check_writable(config: &NodeConfig, volume_name: &str) -> Result<(), ApiError> {
+    match config.volumes.get(volume_name) {
+        Some(volume) if !volume.writable => Err(ApiError::Forbidden(format!(
+            "Volume {volume_name:?} is read-only"
+        ))),
+        _ => Ok(()),
     }
+}
 
-    if let Some(bad_resp) = check_auth(auth, &volume_name, config.clone()) {
-        return bad_resp;
+/// Volume of a path targeted by a write, the volume root itself cannot be replaced
+fn write_target(path: &NullFsPath) -> Result<String, ApiError> {
+    match path.volume_name() {
+        Ok(volume) if path.components().len() > 1 => Ok(volume),
+        _ => Err(ApiError::BadRequest(format!("No file targeted by {path}"))),
     }
+}
+
+/// Writes the body to a file as it is received, it is never held in memory
+pub async fn upload(
+    req: HttpRequest,
+    auth: BasicAuth,
+    config: CurrentConfig,
+    snapshots: web::Data<FsSnapshots>,
+    params: web::Query<WithPath>,
+    mut body: web::Payload,
+) -> Result<HttpResponse, ApiError> {
+    let volume_name = write_target(&params.path)?;
+
+    check_auth(auth, &volume_name, config.clone(), Access::Rw)?;
+
+    check_writable(&config, &volume_name)?;
+
+    with_fs(config.clone(), &snapshots, &volume_name, async |fs| {
+        // a chunked body does not declare its size
+        let declared = req
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|len| len.to_str().ok())
+            .and_then(|len| len.parse::<u64>().ok());
+        let file = File {
+            file_type: FileType::infer_from_path(&params.path),
+            path: params.path.clone(),
+            stat: FileStat {
+                node: NodeKind::File {
+                    size: declared.unwrap_or_default(),
+                },
+                modified: systime_to_millis(SystemTime::now()),
+                created: None,
+                accessed: None,
+            },
+        };
+
+        // the payload cannot leave the worker thread, its chunks are handed over to the volume
+        let (mut sender, receiver) = futures::channel::mpsc::channel::<eyre::Result<web::Bytes>>(4);
+        let (mut received, mut overflow) = (0, false);
+        let forward = async {
+            while let Some(chunk) = body.next().await {
+                let chunk = match chunk {
+                    Ok(chunk) => {
+                        received += chunk.len();
+                        Ok(chunk)
+                    }
+                    Err(e) => {
+                        overflow = matches!(e, PayloadError::Overflow);
+                        Err(eyre::eyre!("Receiving {}: {e}", params.path))
+                    }
+                };
+                let failed = chunk.is_err();
+                // the volume gave up on the write when the receiver is gone
+                if sender.send(chunk).await.is_err() || failed {
+                    break;
+                }
+            }
+            sender.close_channel();
+        };
+        let (_, written) = futures::join!(forward, fs.write_stream(&file, Box::pin(receiver)));
 
-    with_fs(config.clone(), &volume_name, async |fs| {
-        match fs.hash(&params.path).await {
-            Ok(res) => HttpResponse::Ok().json(res),
-            Err(e) => HttpResponse::InternalServerError().json(json!({
-                "error": e.to_string()
-            })),
+        match written {
+            Ok(_) => Ok(HttpResponse::Ok().json(json!({ "written": received }))),
+            Err(_) if overflow => Err(ApiError::PayloadTooLarge(format!(
+                "Upload of {} over the body limit",
+                params.path
+            ))),
+            Err(e) => Err(e.into()),
         }
     })
     .await
 }
 
-pub async fn download(
+pub async fn delete_file(
     auth: BasicAuth,
-    config: web::Data<Arc<NodeConfig>>,
+    config: CurrentConfig,
+    snapshots: web::Data<FsSnapshots>,
     params: web::Query<WithPath>,
-) -> impl Responder {
-    let volume_name;
-    if let Ok(volume) = params.path.volume_name() {
-        volume_name = volume;
-    } else {
-        return HttpResponse::BadRequest().json(json!({
-            "error": format!("Volume not found in {}", params.path)
-        }));
-    }
+) -> Result<HttpResponse, ApiError> {
+    let volume_name = write_target(&params.path)?;
+
+    check_auth(auth, &volume_name, config.clone(), Access::Rw)?;
+
+    check_writable(&config, &volume_name)?;
+
+    with_fs(config.clone(), &snapshots, &volume_name, async |fs| {
+        let deleted = async {
+            if !fs.exists(&params.path).await? {
+                return eyre::Ok(false);
+            }
 
-    if let Some(bad_resp) = check_auth(auth, &volume_name, config.clone()) {
-        return bad_resp;
+            let file = File {
+                file_type: FileType::infer_from_path(&params.path),
+                path: params.path.clone(),
+                stat: fs.stats(&params.path).await?,
+            };
+            fs.delete(&file).await?;
+            eyre::Ok(true)
+        };
+
+        match deleted.await {
+            Ok(true) => Ok(HttpResponse::Ok().json(json!({ "deleted": true }))),
+            Ok(false) => Err(ApiError::NotFound(format!("{} not found", params.path))),
+            Err(e) => Err(e.into()),
+        }
+    })
+    .await
+}
+
+pub async fn merkle(
+    req: HttpRequest,
+    auth: BasicAuth,
+    config: CurrentConfig,
+    this_node: web::Data<Arc<NodeIdentifier>>,
+    snapshots: web::Data<FsSnapshots>,
+    params: web::Query<WithPath>,
+) -> Result<HttpResponse, ApiError> {
+    let volume_name = volume_of(&params.path)?;
+
+    check_auth(auth, &volume_name, config.clone(), Access::Ro)?;
+
+    if !config.merkle {
+        return Err(ApiError::NotFound(
+            "Merkle trees are not enabled on this node".to_owned(),
+        ));
     }
 
-    with_fs(config.clone(), &volume_name, async |fs| {
-        match fs.read(&params.path).await {
-            // FIXME: stream
-            Ok(res) => HttpResponse::Ok().body(res),
-            Err(e) => HttpResponse::InternalServerError().json(json!({
-                "error": e.to_string()
-            })),
+    with_fs(config.clone(), &snapshots, &volume_name, async |fs| {
+        let state_file = config.data_dir().join(format!(
+            "{MERKLE_STATE_PREFIX}{}-{}.db",
+            fs.get_volume_name(),
+            this_node.uuid
+        ));
+
+        match volume_snapshot(&config, &volume_name, &fs)
+            .merkle_node(&state_file, &params.path)
+            .await
+        {
+            Ok(Some(node)) => {
+                let mut response = negotiate(&req, &node)?;
+                response.headers_mut().insert(
+                    header::HeaderName::from_static(HASH_ALGO_HEADER),
+                    header::HeaderValue::from_static(hashing::algo().name()),
+                );
+                Ok(response)
+            }
+            Ok(None) => Err(ApiError::NotFound(format!(
+                "{} is not part of the tree",
+                params.path
+            ))),
+            Err(e) => Err(e.into()),
         }
     })
     .await
 }
 
 pub async fn exists(
+    auth: Option<BasicAuth>,
+    config: CurrentConfig,
+    snapshots: web::Data<FsSnapshots>,
+    params: web::Query<WithPath>,
+) -> Result<HttpResponse, ApiError> {
+    let volume_name = volume_of(&params.path)?;
+
+    check_anonymous_auth(auth, &volume_name, config.clone(), Access::Ro)?;
+
+    with_fs(
+        config.clone(),
+        &snapshots,
+        &volume_name,
+        async |fs| match fs.exists(&params.path).await {
+            Ok(res) => Ok(HttpResponse::Ok().json(res)),
+            Err(e) => Err(e.into()),
+        },
+    )
+    .await
+}
+
+pub async fn stat(
     auth: BasicAuth,
-    config: web::Data<Arc<NodeConfig>>,
+    config: CurrentConfig,
+    snapshots: web::Data<FsSnapshots>,
     params: web::Query<WithPath>,
-) -> impl Responder {
-    let volume_name;
-    if let Ok(volume) = params.path.volume_name() {
-        volume_name = volume;
-    } else {
-        return HttpResponse::BadRequest().json(json!({
-            "error": format!("Volume not found in {}", params.path)
-        }));
-    }
+) -> Result<HttpResponse, ApiError> {
+    let volume_name = volume_of(&params.path)?;
 
-    if let Some(bad_resp) = check_auth(auth, &volume_name, config.clone()) {
-        return bad_resp;
-    }
+    check_auth(auth, &volume_name, config.clone(), Access::Ro)?;
+
+    with_fs(config.clone(), &snapshots, &volume_name, async |fs| {
+        let stat = async {
+            match fs.exists(&params.path).await? {
+                true => fs.stats(&params.path).await.map(Some),
+                false => Ok(None),
+            }
+        };
 
-    with_fs(config.clone(), &volume_name, async |fs| {
-        match fs.exists(&params.path).await {
-            Ok(res) => HttpResponse::Ok().json(res),
-            Err(e) => HttpResponse::InternalServerError().json(json!({
-                "error": e.to_string()
-            })),
+        match stat.await {
+            Ok(Some(res)) => Ok(HttpResponse::Ok().json(res)),
+            Ok(None) => Err(ApiError::NotFound(format!("{} not found", params.path))),
+            Err(e) => Err(e.into()),
         }
     })
     .await
 }
 
-pub async fn info(config: web::Data<Arc<NodeConfig>>) -> impl Responder {
+pub async fn pause(
+    auth: BasicAuth,
+    config: CurrentConfig,
+    states: web::Data<VolumeStates>,
+    volume_name: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    check_auth(auth, &volume_name, config.clone(), Access::Rw)?;
+
+    match states.pause(&volume_name) {
+        Ok(_) => Ok(HttpResponse::Ok().json(states.status(&volume_name))),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub async fn resume(
+    auth: BasicAuth,
+    config: CurrentConfig,
+    states: web::Data<VolumeStates>,
+    volume_name: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    check_auth(auth, &volume_name, config.clone(), Access::Rw)?;
+
+    match states.resume(&volume_name) {
+        Ok(_) => Ok(HttpResponse::Ok().json(states.status(&volume_name))),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub async fn health(config: CurrentConfig, states: web::Data<VolumeStates>) -> impl Responder {
+    let volumes = config
+        .volumes
+        .keys()
+        .map(|name| (name.clone(), states.status(name)))
+        .collect::<HashMap<_, _>>();
+    let degraded = volumes
+        .values()
+        .any(|status| matches!(status, VolumeStatus::Degraded { .. }));
+
+    HttpResponse::Ok().json(json!({
+        "name": config.name,
+        "status": if degraded { "degraded" } else { "ok" },
+        "volumes": volumes
+    }))
+}
+
+/// Sync progress of each volume, the stash counts are missing when this node has no stash
+pub async fn status(
+    req: HttpRequest,
+    config: CurrentConfig,
+    states: web::Data<VolumeStates>,
+) -> Result<HttpResponse, ApiError> {
+    // the stash of this node, set up by `server::run`
+    let stash = req.app_data::<web::Data<Arc<CommandStash>>>();
+
+    let mut volumes = serde_json::Map::new();
+    for (name, volume) in &config.volumes {
+        let commands = match stash {
+            Some(stash) => match stash.counts(name).await {
+                Ok(counts) => Some(counts),
+                Err(e) => {
+                    return Err(ApiError::Internal(format!(
+                        "Could not count the commands of {name}: {e}"
+                    )));
+                }
+            },
+            None => None,
+        };
+
+        let activity = states.activity(name);
+        let relays = volume
+            .pull_from
+            .iter()
+            .map(|relay| (relay.clone(), activity.relays.get(relay).copied()))
+            .collect::<IndexMap<_, _>>();
+
+        // same shape as the health report, extended with the sync progress
+        let mut report = json!(states.status(name));
+        report["commands"] = json!(commands);
+        report["lastPull"] = json!(activity.last_pull);
+        report["lastApply"] = json!(activity.last_apply);
+        report["lastRelay"] = json!(activity.last_relay);
+        report["relays"] = json!(relays);
+        volumes.insert(name.clone(), report);
+    }
+
+    Ok(HttpResponse::Ok().json(json!({
+        "name": config.name,
+        "volumes": volumes
+    })))
+}
+
+/// Streams the [`crate::nullfs::events::SyncEvent`]s of the volumes the client can read as json text messages over a
+/// WebSocket, a client too slow to keep up gets `{"type": "lagged", "missed": n}` in place of
+/// the events it missed
+pub async fn events(
+    req: HttpRequest,
+    body: web::Payload,
+    auth: Option<BasicAuth>,
+    config: CurrentConfig,
+    states: web::Data<VolumeStates>,
+) -> Result<HttpResponse, ApiError> {
+    let readable = config
+        .volumes
+        .keys()
+        .filter(|volume| {
+            check_anonymous_auth(auth.clone(), volume, config.clone(), Access::Ro).is_ok()
+        })
+        .cloned()
+        .collect::<HashSet<_>>();
+    if readable.is_empty() {
+        return Err(ApiError::Unauthorized(
+            "No volume readable with these credentials".to_owned(),
+        ));
+    }
+
+    let (response, mut session, mut messages) =
+        actix_ws::handle(&req, body).map_err(|e| ApiError::BadRequest(e.to_string()))?;
+    let mut events = states.subscribe();
+
+    actix_web::rt::spawn(async move {
+        loop {
+            let sent = tokio::select! {
+                event = events.recv() => match event {
+                    Ok(event) if !readable.contains(event.volume()) => Ok(()),
+                    Ok(event) => match serde_json::to_string(&event) {
+                        Ok(text) => session.text(text).await,
+                        Err(e) => {
+                            tracing::error!("Could not serialize {event:?}: {e}");
+                            Ok(())
+                        }
+                    },
+                    Err(RecvError::Lagged(missed)) => {
+                        let lagged = json!({ "type": "lagged", "missed": missed });
+                        session.text(lagged.to_string()).await
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+                message = messages.next() => match message {
+                    Some(Ok(actix_ws::Message::Ping(bytes))) => session.pong(&bytes).await,
+                    Some(Ok(actix_ws::Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => Ok(()),
+                },
+            };
+
+            if sent.is_err() {
+                return;
+            }
+        }
+
+        session.close(None).await.ok();
+    });
+
+    Ok(response)
+}
+
+/// Prometheus metrics of this node
+pub async fn metrics() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(METRICS.render())
+}
+
+/// Volumes the caller can read, unlike `/v1/info` the others are not named
+pub async fn volumes(auth: BasicAuth, config: CurrentConfig) -> Result<HttpResponse, ApiError> {
+    let user = User {
+        name: auth.user_id().to_owned(),
+        password: auth.password().map(|password| password.to_owned()),
+    };
+    if !config
+        .resolve_user(&user.name)
+        .is_some_and(|known| known.verify(&user))
+    {
+        return Err(ApiError::Unauthorized(format!(
+            "User {:?} unauthorized",
+            user.name
+        )));
+    }
+
+    let volumes = config
+        .volumes
+        .iter()
+        .filter(|(name, _)| config.allow(name, &user))
+        .map(|(name, volume)| {
+            let access = config.access_level(name, &user);
+            json!({
+                "name": name,
+                "kind": volume.store.kind(),
+                "writable": volume.writable && access == Some(Access::Rw),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    Ok(HttpResponse::Ok().json(json!({ "volumes": volumes })))
+}
+
+pub async fn info(config: CurrentConfig) -> Result<HttpResponse, ApiError> {
     let relay_nodes = config
         .relay_nodes
         .iter()
@@ -237,9 +1185,20 @@ pub async fn info(config: web::Data<Arc<NodeConfig>>) -> impl Responder {
         })
         .collect::<Vec<_>>();
 
-    HttpResponse::Ok().json(json!({
+    // served without credentials, the options of a store may hold some
+    let mut volumes = serde_json::Map::new();
+    for (name, volume) in &config.volumes {
+        let mut item =
+            serde_json::to_value(volume).map_err(|e| ApiError::Internal(e.to_string()))?;
+        item["store"] = json!({ "type": volume.store.kind() });
+        volumes.insert(name.clone(), item);
+    }
+
+    Ok(HttpResponse::Ok().json(json!({
         "name": config.name,
+        "realm": config.realm,
+        "hashAlgo": hashing::algo(),
         "relayNodes": relay_nodes,
-        "volumes": config.volumes
-    }))
+        "volumes": volumes
+    })))
 }
@@ -1,19 +1,33 @@
 use crate::{
-    config::{NodeConfig, NodeIdentifier, User},
-    nullfs::{File, FileType, NodeKind, NullFs, NullFsPath, millis_to_utc},
-    server::api::WithPath,
+    config::{NodeIdentifier, User},
+    nullfs::{
+        DirPage, File, FileType, NodeKind, NullFs, NullFsPath, millis_to_utc,
+        quarantine::versions_dir,
+        search::{SearchLimits, find_by_name},
+    },
+    server::{CurrentConfig, api::WithPath},
 };
 use actix_session::Session;
 use actix_web::{
     HttpResponse, Responder,
-    http::header::{CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_TYPE},
+    http::header::{
+        CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_SECURITY_POLICY, CONTENT_TYPE,
+        X_CONTENT_TYPE_OPTIONS,
+    },
     mime::{TEXT_CSS, TEXT_HTML},
     web,
 };
 use indexmap::IndexSet;
+use pulldown_cmark::{CodeBlockKind, Event, Parser, Tag, TagEnd, html};
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 
+/// Text files above this size are not loaded to be previewed
+const PREVIEW_MAX_BYTES: u64 = 512 * 1024;
+
+/// Entries listed per page when the query does not set a `limit`
+const BROWSER_PAGE_SIZE: usize = 200;
+
 #[derive(Serialize, Debug)]
 struct FileRow {
     icon: String,
@@ -22,16 +36,21 @@ struct FileRow {
     last_modified: String,
     path: NullFsPath,
     is_dir: bool,
+    /// Entries of a directory
+    entries: Option<u64>,
+    previewable: bool,
 }
 
 impl FileRow {
     pub fn from_file(file: File) -> Self {
         Self {
-            icon: match file.stat.is_dir() {
-                true => "📁".to_string(),
-                false => match file.file_type {
+            icon: match &file.stat.node {
+                NodeKind::Dir => "📁".to_string(),
+                NodeKind::Symlink { .. } => "🔗".to_string(),
+                NodeKind::File { .. } => match file.file_type {
                     FileType::Image => "🖼️",
                     FileType::Video => "🎬",
+                    FileType::Audio => "🎵",
                     FileType::Archive => "📦",
                     FileType::Document => "📄",
                     FileType::Text => "📝",
@@ -39,7 +58,7 @@ impl FileRow {
                 }
                 .to_owned(),
             },
-            name: file.path.components().last().unwrap().to_owned(),
+            name: file.path.file_name().unwrap_or_default().to_owned(),
             size: match file.stat.node {
                 NodeKind::File { size } => {
                     const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
@@ -57,20 +76,55 @@ impl FileRow {
                         format!("{:.1}{}", size, UNITS[unit])
                     }
                 }
-                NodeKind::Dir => "---".to_string(),
+                NodeKind::Dir | NodeKind::Symlink { .. } => "---".to_string(),
             },
             last_modified: {
                 millis_to_utc(file.stat.modified)
                     .format("%Y-%m-%d %H:%M:%S")
                     .to_string()
             },
+            previewable: is_previewable(&file),
             path: file.path,
             is_dir: file.stat.is_dir(),
+            entries: None,
+        }
+    }
+
+    /// Shows the entry count of a directory in place of its size
+    fn with_entries(self, entries: u64) -> Self {
+        Self {
+            size: match entries {
+                1 => "1 item".to_owned(),
+                n => format!("{n} items"),
+            },
+            entries: Some(entries),
+            ..self
         }
     }
 }
 
-// pub async fn check_user_session(session: Session) ->  {}
+fn is_previewable(file: &File) -> bool {
+    matches!(file.stat.node, NodeKind::File { .. })
+        && matches!(file.file_type, FileType::Text | FileType::Image)
+}
+
+/// Retrieves the logged user, or the redirection to the login page
+fn check_user_session(session: &Session) -> Result<User, Box<HttpResponse>> {
+    match session.get::<User>("user") {
+        // the session is extended by the middleware, see `session_middleware`
+        Ok(Some(stored_user)) => Ok(stored_user),
+        Ok(None) => Err(Box::new(
+            HttpResponse::SeeOther()
+                .insert_header(("Location", "/web/login?error=Not logged or expired"))
+                .finish(),
+        )),
+        Err(_) => Err(Box::new(
+            HttpResponse::SeeOther()
+                .insert_header(("Location", "/web/login?error=Bad cookie"))
+                .finish(),
+        )),
+    }
+}
 
 pub async fn style() -> impl Responder {
     HttpResponse::Ok()
@@ -94,9 +148,25 @@ pub struct MaybeLogout {
     pub logout: bool,
 }
 
+/// Entries below the browsed directory whose name contains `q`
+#[derive(Deserialize)]
+pub struct MaybeSearch {
+    pub q: String,
+}
+
+/// Files are served as attachments unless `inline` is set, `versions` lists the kept
+/// versions of a file instead, see [`versions_dir`]
+#[derive(Deserialize)]
+pub struct BrowseFlags {
+    #[serde(default)]
+    pub inline: bool,
+    #[serde(default)]
+    pub versions: bool,
+}
+
 pub async fn login_post(
     form: web::Form<LoginForm>,
-    config: web::Data<Arc<NodeConfig>>,
+    config: CurrentConfig,
     session: Session,
 ) -> impl Responder {
     let user = User {
@@ -108,14 +178,14 @@ pub async fn login_post(
         },
     };
 
-    if let Some(known_user) = config.resolve_user(&user.name) {
-        if *known_user == user {
-            session.insert("user", &user).unwrap();
+    if let Some(known_user) = config.resolve_user(&user.name)
+        && known_user.verify(&user)
+    {
+        session.insert("user", &user).unwrap();
 
-            return HttpResponse::SeeOther()
-                .insert_header(("Location", "/web/browser"))
-                .finish();
-        }
+        return HttpResponse::SeeOther()
+            .insert_header(("Location", "/web/browser"))
+            .finish();
     }
 
     HttpResponse::SeeOther()
@@ -123,8 +193,22 @@ pub async fn login_post(
         .finish()
 }
 
+/// Drops the logged user, the whole session is purged so that the browser is asked to delete
+/// its cookie rather than keep an empty one
+fn end_session(session: &Session) {
+    session.purge();
+}
+
+pub async fn logout(session: Session) -> impl Responder {
+    end_session(&session);
+
+    HttpResponse::SeeOther()
+        .insert_header(("Location", "/web/login"))
+        .finish()
+}
+
 pub async fn login(
-    config: web::Data<Arc<NodeConfig>>,
+    config: CurrentConfig,
     identity: web::Data<Arc<NodeIdentifier>>,
     qerror: Option<web::Query<MaybeError>>,
     qlogout: Option<web::Query<MaybeLogout>>,
@@ -134,10 +218,10 @@ pub async fn login(
     tera.add_raw_template("login", include_str!("views/login.html"))
         .expect("Failed to add raw template");
 
-    if let Some(flag) = qlogout {
-        if flag.logout {
-            session.remove("user");
-        }
+    if let Some(flag) = qlogout
+        && flag.logout
+    {
+        end_session(&session);
     }
 
     let mut ctx = tera::Context::new();
@@ -161,27 +245,17 @@ pub async fn login(
 }
 
 pub async fn browser(
-    config: web::Data<Arc<NodeConfig>>,
+    config: CurrentConfig,
     identity: web::Data<Arc<NodeIdentifier>>,
     params: Option<web::Query<WithPath>>,
+    qflags: Option<web::Query<BrowseFlags>>,
+    page: web::Query<DirPage>,
+    qsearch: Option<web::Query<MaybeSearch>>,
     session: Session,
 ) -> impl Responder {
-    let user;
-    match session.get::<User>("user") {
-        Ok(Some(stored_user)) => {
-            user = stored_user;
-            session.insert("user", &user).unwrap(); // resets TTL?
-        }
-        Ok(None) => {
-            return HttpResponse::SeeOther()
-                .insert_header(("Location", "/web/login?error=Not logged or expired"))
-                .finish();
-        }
-        Err(_) => {
-            return HttpResponse::SeeOther()
-                .insert_header(("Location", "/web/login?error=Bad cookie"))
-                .finish();
-        }
+    let user = match check_user_session(&session) {
+        Ok(user) => user,
+        Err(redirect) => return *redirect,
     };
 
     let mut tera = tera::Tera::default();
@@ -195,6 +269,23 @@ pub async fn browser(
     ctx.insert("version", &env!("CARGO_PKG_VERSION"));
     ctx.insert("entries_count", &0);
 
+    let mut page = page.into_inner();
+    page.limit = Some(page.limit.unwrap_or(BROWSER_PAGE_SIZE).max(1));
+    ctx.insert("sort", &page.sort);
+    ctx.insert("order", &page.order);
+    ctx.insert("limit", &page.limit);
+    ctx.insert("prev_offset", &None::<usize>);
+    ctx.insert("next_offset", &None::<usize>);
+    ctx.insert("first_entry", &0);
+    ctx.insert("last_entry", &0);
+    ctx.insert("path", &params.as_ref().map(|param| param.path.clone()));
+    let query = qsearch
+        .map(|q| q.q.trim().to_owned())
+        .filter(|q| !q.is_empty());
+    ctx.insert("query", &query);
+    ctx.insert("truncated", &false);
+    let versions = qflags.as_ref().is_some_and(|q| q.versions);
+
     if params.is_none() {
         let allowed_volumes = config.list_allowed_volumes(&user);
         ctx.insert("entries_count", &allowed_volumes.len());
@@ -212,33 +303,79 @@ pub async fn browser(
             }
 
             if let Some(fs) = config.get_initialized_fs_volume(&volume).await? {
-                let filename = param
-                    .path
-                    .components()
-                    .last()
-                    .cloned()
-                    .ok_or_else(|| eyre::eyre!("Could not get filename"))?;
-
-                let stats = fs.stats(&param.path).await?;
+                // the kept versions of a file are listed in place of its content
+                let path = match versions {
+                    true => versions_dir(&param.path)?,
+                    false => param.path.clone(),
+                };
+                if versions {
+                    ctx.insert("path", &Some(&path));
+                    if !fs.exists(&path).await? {
+                        ctx.insert("files", &[] as &[FileRow]);
+                        return Ok(None);
+                    }
+                }
+
+                let stats = fs.stats(&path).await?;
                 if stats.is_file() {
+                    let filename = path
+                        .file_name()
+                        .map(str::to_owned)
+                        .ok_or_else(|| eyre::eyre!("Could not get filename"))?;
                     return Ok(Some((
-                        FileType::mime_from_path(&param.path),
+                        FileType::mime_from_path(&path, &config.mime_overrides),
                         filename,
-                        fs.read(&param.path).await?,
+                        fs.read(&path).await?,
                     )));
                 }
 
-                let mut list = fs.dir(&param.path).await?;
-                list.sort_by_key(|f| !f.stat.is_dir());
-                ctx.insert("entries_count", &list.len());
+                if let Some(query) = &query {
+                    let found = find_by_name(&fs, &path, query, &SearchLimits::default()).await?;
+                    let depth = path.components().len();
+                    ctx.insert("entries_count", &found.files.len());
+                    ctx.insert("truncated", &found.truncated);
 
-                ctx.insert(
-                    "files",
-                    &list
+                    let rows = found
+                        .files
                         .into_iter()
-                        .map(|file| FileRow::from_file(file))
-                        .collect::<Vec<_>>(),
-                );
+                        .map(|file| {
+                            // where the match is, relative to the browsed directory
+                            let name = file.path.components()[depth..].join("/");
+                            FileRow {
+                                name,
+                                ..FileRow::from_file(file)
+                            }
+                        })
+                        .collect::<Vec<_>>();
+                    ctx.insert("files", &rows);
+                    return Ok(None);
+                }
+
+                let listing = fs.dir_page(&path, &page).await?;
+                let window = page.window(listing.total);
+                ctx.insert("entries_count", &listing.total);
+                ctx.insert("first_entry", &(window.start + 1).min(window.end));
+                ctx.insert("last_entry", &window.end);
+                if window.start > 0 {
+                    let limit = page.limit.unwrap_or(BROWSER_PAGE_SIZE);
+                    ctx.insert("prev_offset", &Some(window.start.saturating_sub(limit)));
+                }
+                if window.end < listing.total {
+                    ctx.insert("next_offset", &Some(window.end));
+                }
+
+                let mut rows = vec![];
+                for file in listing.entries {
+                    let row = match file.stat.is_dir() {
+                        true => {
+                            let entries = fs.count_children(&file.path).await?;
+                            FileRow::from_file(file).with_entries(entries)
+                        }
+                        false => FileRow::from_file(file),
+                    };
+                    rows.push(row);
+                }
+                ctx.insert("files", &rows);
             }
         } else {
             ctx.insert("files", &[] as &[FileRow]);
@@ -257,9 +394,19 @@ pub async fn browser(
         Ok(Some((mime, filename, data))) => HttpResponse::Ok()
             .insert_header((
                 CONTENT_DISPOSITION,
-                format!("attachment; filename=\"{}\"", filename),
+                format!(
+                    "{}; filename=\"{}\"",
+                    match qflags.is_some_and(|q| q.inline) {
+                        true => "inline",
+                        false => "attachment",
+                    },
+                    filename
+                ),
             ))
             .insert_header((CONTENT_TYPE, mime))
+            // served files, svg and html included, never run scripts on this origin
+            .insert_header((CONTENT_SECURITY_POLICY, "sandbox"))
+            .insert_header((X_CONTENT_TYPE_OPTIONS, "nosniff"))
             .insert_header((CONTENT_LENGTH, data.len().to_string()))
             .body(data),
         Err(e) => HttpResponse::InternalServerError()
@@ -267,3 +414,261 @@ pub async fn browser(
             .body(format!("An issue has occured: {e}")),
     }
 }
+
+pub async fn preview(
+    config: CurrentConfig,
+    identity: web::Data<Arc<NodeIdentifier>>,
+    params: web::Query<WithPath>,
+    session: Session,
+) -> impl Responder {
+    let user = match check_user_session(&session) {
+        Ok(user) => user,
+        Err(redirect) => return *redirect,
+    };
+
+    let try_render = async || -> eyre::Result<Option<String>> {
+        let volume = params.path.volume_name()?;
+        if !config.allow(&volume, &user) {
+            eyre::bail!("User {:?} cannot access volume {:?}", user.name, volume);
+        }
+
+        let Some(fs) = config.get_initialized_fs_volume(&volume).await? else {
+            eyre::bail!("Volume {volume:?} not found");
+        };
+
+        let file = File {
+            path: params.path.clone(),
+            file_type: FileType::infer_from_path(&params.path),
+            stat: fs.stats(&params.path).await?,
+        };
+
+        if !is_previewable(&file) {
+            return Ok(None);
+        }
+
+        let download = format!("/web/browser?path={}", params.path.url_encoded());
+        if file.file_type == FileType::Image {
+            return Ok(Some(format!(
+                "<img src=\"{download}&inline=true\" alt=\"{}\">",
+                escape_html(file.path.file_name().unwrap_or_default())
+            )));
+        }
+
+        if let NodeKind::File { size } = file.stat.node
+            && size > PREVIEW_MAX_BYTES
+        {
+            return Ok(Some(format!(
+                "<p>Too large to preview ({size} bytes), <a href=\"{download}\">download</a> it instead.</p>"
+            )));
+        }
+
+        let Ok(content) = String::from_utf8(fs.read(&params.path).await?) else {
+            return Ok(None); // binary
+        };
+
+        match params.path.extension().map(|ext| ext.to_lowercase()) {
+            Some(ext) if ext == "md" => Ok(Some(render_markdown(&content))),
+            ext => Ok(Some(format!(
+                "<pre><code>{}</code></pre>",
+                highlight(&content, ext.as_deref().unwrap_or_default())
+            ))),
+        }
+    };
+
+    match try_render().await {
+        Ok(Some(content)) => {
+            let mut tera = tera::Tera::default();
+            tera.add_raw_template("preview", include_str!("views/preview.html"))
+                .expect("Failed to add raw template");
+
+            let mut ctx = tera::Context::new();
+            ctx.insert("node_name", &config.name);
+            ctx.insert("node_id", &identity.uuid);
+            ctx.insert("username", &user.name);
+            ctx.insert("version", &env!("CARGO_PKG_VERSION"));
+            ctx.insert("path", &params.path);
+            ctx.insert("content", &content);
+
+            HttpResponse::Ok()
+                .insert_header((CONTENT_TYPE, TEXT_HTML))
+                // whatever a previewed file holds, it never runs scripts on this origin
+                .insert_header((
+                    CONTENT_SECURITY_POLICY,
+                    "script-src 'none'; object-src 'none'",
+                ))
+                .body(
+                    tera.render("preview", &ctx)
+                        .expect("Failed to render template"),
+                )
+        }
+        Ok(None) => HttpResponse::SeeOther()
+            .insert_header((
+                "Location",
+                format!("/web/browser?path={}", params.path.url_encoded()),
+            ))
+            .finish(),
+        Err(e) => HttpResponse::InternalServerError()
+            .insert_header((CONTENT_TYPE, TEXT_HTML))
+            .body(format!(
+                "An issue has occured: {}",
+                escape_html(&e.to_string())
+            )),
+    }
+}
+
+fn escape_html(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '&' => out.push_str("&amp;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+
+    out
+}
+
+/// Renders markdown to html, raw html is escaped and fenced code blocks are highlighted
+fn render_markdown(content: &str) -> String {
+    let mut events = vec![];
+    let mut code_block: Option<(String, String)> = None;
+
+    for event in Parser::new(content) {
+        match event {
+            Event::Start(Tag::CodeBlock(kind)) => {
+                let lang = match kind {
+                    CodeBlockKind::Fenced(lang) => lang.to_string(),
+                    CodeBlockKind::Indented => String::new(),
+                };
+                code_block = Some((lang, String::new()));
+            }
+            Event::End(TagEnd::CodeBlock) => {
+                if let Some((lang, code)) = code_block.take() {
+                    events.push(Event::Html(
+                        format!("<pre><code>{}</code></pre>", highlight(&code, &lang)).into(),
+                    ));
+                }
+            }
+            Event::Text(text) if code_block.is_some() => {
+                if let Some((_, code)) = code_block.as_mut() {
+                    code.push_str(&text);
+                }
+            }
+            Event::Html(raw) | Event::InlineHtml(raw) => events.push(Event::Text(raw)),
+            // `javascript:` and other schemes are not followed nor loaded
+            Event::Start(Tag::Link {
+                link_type,
+                dest_url,
+                title,
+                id,
+            }) if !is_safe_url(&dest_url) => events.push(Event::Start(Tag::Link {
+                link_type,
+                dest_url: "#".into(),
+                title,
+                id,
+            })),
+            Event::Start(Tag::Image {
+                link_type,
+                dest_url,
+                title,
+                id,
+            }) if !is_safe_url(&dest_url) => events.push(Event::Start(Tag::Image {
+                link_type,
+                dest_url: "".into(),
+                title,
+                id,
+            })),
+            event => events.push(event),
+        }
+    }
+
+    let mut out = String::new();
+    html::push_html(&mut out, events.into_iter());
+
+    out
+}
+
+/// Whether a link of a rendered file may be followed, only http(s) and relative urls are
+fn is_safe_url(url: &str) -> bool {
+    let url = url.trim_start().to_lowercase();
+    match url.find([':', '/', '?', '#']) {
+        Some(at) if url[at..].starts_with(':') => {
+            url.starts_with("http:") || url.starts_with("https:")
+        }
+        _ => true,
+    }
+}
+
+/// Naive single pass highlighter: comments, strings, numbers and a few common keywords
+fn highlight(source: &str, lang: &str) -> String {
+    const KEYWORDS: [&str; 24] = [
+        "fn", "let", "const", "var", "function", "def", "class", "struct", "enum", "impl", "if",
+        "else", "for", "while", "return", "match", "import", "use", "pub", "async", "await",
+        "true", "false", "null",
+    ];
+
+    let hash_comments = matches!(
+        lang,
+        "yaml" | "yml" | "toml" | "sh" | "bash" | "py" | "python" | "ini"
+    );
+    let slash_comments = !hash_comments && !matches!(lang, "" | "txt" | "text" | "csv" | "md");
+    if !hash_comments && !slash_comments {
+        return escape_html(source);
+    }
+
+    let chars = source.chars().collect::<Vec<_>>();
+    let mut out = String::with_capacity(source.len());
+    let mut i = 0;
+
+    let span = |out: &mut String, class: &str, text: &[char]| {
+        let text = text.iter().collect::<String>();
+        out.push_str(&format!(
+            "<span class=\"hl-{class}\">{}</span>",
+            escape_html(&text)
+        ));
+    };
+
+    while i < chars.len() {
+        let c = chars[i];
+        let is_comment = (hash_comments && c == '#')
+            || (slash_comments && c == '/' && chars.get(i + 1) == Some(&'/'));
+
+        if is_comment {
+            let end = (i..chars.len())
+                .find(|j| chars[*j] == '\n')
+                .unwrap_or(chars.len());
+            span(&mut out, "com", &chars[i..end]);
+            i = end;
+        } else if c == '"' || c == '\'' {
+            let mut end = i + 1;
+            while end < chars.len() && chars[end] != c && chars[end] != '\n' {
+                end += if chars[end] == '\\' { 2 } else { 1 };
+            }
+            let end = (end + 1).min(chars.len());
+            span(&mut out, "str", &chars[i..end]);
+            i = end;
+        } else if c.is_alphanumeric() || c == '_' {
+            let end = (i..chars.len())
+                .find(|j| !(chars[*j].is_alphanumeric() || chars[*j] == '_' || chars[*j] == '.'))
+                .unwrap_or(chars.len());
+            let word = chars[i..end].iter().collect::<String>();
+            if word.parse::<f64>().is_ok() {
+                span(&mut out, "num", &chars[i..end]);
+            } else if KEYWORDS.contains(&word.as_str()) {
+                span(&mut out, "kw", &chars[i..end]);
+            } else {
+                out.push_str(&escape_html(&word));
+            }
+            i = end;
+        } else {
+            out.push_str(&escape_html(&c.to_string()));
+            i += 1;
+        }
+    }
+
+    out
+}
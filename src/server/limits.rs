@@ -0,0 +1,88 @@
+use crate::server::{CurrentConfig, api::MAX_UPLOAD_SIZE, error::ApiError};
+use actix_web::{
+    Error, HttpMessage, ResponseError,
+    body::{BodyStream, BoxBody, MessageBody},
+    dev::{Payload, ServiceRequest, ServiceResponse},
+    error::PayloadError,
+    http::header::CONTENT_LENGTH,
+    middleware::Next,
+};
+use futures::StreamExt;
+use std::{future::Future, task::Poll, time::Duration};
+
+/// Rejects the request bodies over `maxBodyBytes` with a `413 Payload Too Large`, up front
+/// when the declared length is over, as soon as the limit is crossed otherwise
+pub async fn limit_body(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let config = req.extract::<CurrentConfig>().await?;
+    let max_body = config
+        .max_body_bytes
+        .unwrap_or(MAX_UPLOAD_SIZE as u64)
+        .min(MAX_UPLOAD_SIZE as u64);
+
+    let declared = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok())
+        .and_then(|len| len.parse::<u64>().ok());
+    if let Some(len) = declared
+        && len > max_body
+    {
+        let error = ApiError::PayloadTooLarge(format!(
+            "Body of {len} bytes over the limit of {max_body} bytes"
+        ));
+        return Ok(req.into_response(error.error_response()));
+    }
+
+    // chunked bodies do not declare their length
+    let mut received = 0;
+    let limited = req.take_payload().map(move |chunk| {
+        let chunk = chunk?;
+        received += chunk.len() as u64;
+        match received > max_body {
+            true => Err(PayloadError::Overflow),
+            false => Ok(chunk),
+        }
+    });
+    req.set_payload(Payload::from(limited.boxed_local()));
+
+    Ok(next.call(req).await?.map_into_boxed_body())
+}
+
+/// Fails the downloads not sent within `downloadTimeoutSecs`, with a `503` when the response
+/// did not start, by cutting the connection otherwise so that the client cannot take a
+/// truncated body for a whole one
+pub async fn limit_download_time(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let config = req.extract::<CurrentConfig>().await?;
+    let Some(timeout) = config.download_timeout_secs.map(Duration::from_secs) else {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    };
+
+    let deadline = tokio::time::Instant::now() + timeout;
+    let Ok(res) = tokio::time::timeout_at(deadline, next.call(req)).await else {
+        return Err(ApiError::Timeout(format!("Download not served within {timeout:?}")).into());
+    };
+
+    Ok(res?.map_body(|_, body| {
+        let mut body = Box::pin(body);
+        let mut expired = Box::pin(tokio::time::sleep_until(deadline));
+        let timed = futures::stream::poll_fn(move |cx| {
+            if expired.as_mut().poll(cx).is_ready() {
+                let error: Box<dyn std::error::Error> =
+                    format!("Download not sent within {timeout:?}").into();
+                return Poll::Ready(Some(Err(error)));
+            }
+
+            body.as_mut()
+                .poll_next(cx)
+                .map(|chunk| chunk.map(|chunk| chunk.map_err(Into::into)))
+        });
+
+        BoxBody::new(BodyStream::new(timed))
+    }))
+}
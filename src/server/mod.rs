@@ -1,84 +1,331 @@
 use crate::{
-    config::{NodeConfig, NodeIdentifier},
+    config::StoreKind,
+    config::{LiveConfig, NodeConfig, NodeIdentifier},
+    nullfs::{
+        DEFAULT_SHUTDOWN_GRACE_SECS,
+        fs_snapshot::FsSnapshots,
+        share::{
+            CURSOR_HEADER, CommandStash, HASH_ALGO_HEADER, NODE_ID_HEADER, REMAINING_HEADER,
+            TOTAL_COUNT_HEADER,
+        },
+        snapshot::prune_peer_states,
+        volume_state::VolumeStates,
+    },
     server::{
         api::*,
-        browser::{browser, login, login_post, style},
+        browser::{browser, login, login_post, logout, preview, style},
     },
 };
-use actix_session::{SessionMiddleware, config::PersistentSession, storage::CookieSessionStore};
+use actix_cors::Cors;
+use actix_session::{
+    SessionMiddleware,
+    config::{PersistentSession, TtlExtensionPolicy},
+    storage::CookieSessionStore,
+};
 use actix_web::{
-    App, HttpResponse, HttpServer, Responder,
+    App, FromRequest, HttpRequest, HttpResponse, HttpServer, Responder,
     cookie::{Key, SameSite, time::Duration},
-    http::header::CONTENT_TYPE,
+    dev::Payload,
+    error::ErrorInternalServerError,
+    http::{Method, header},
+    middleware::{Compress, Condition, from_fn},
     mime::TEXT_HTML,
     web,
 };
-use std::sync::Arc;
+use eyre::Context;
+use futures::future::{Ready, ready};
+use std::{
+    io::{ErrorKind, Write},
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration as StdDuration,
+};
 use tokio_util::sync::CancellationToken;
 
 mod api;
 mod browser;
+mod error;
+pub mod limits;
+
+#[cfg(test)]
+pub use api::{MAX_PEER_CLAIMS, PeerRegistry, WithPath};
+
+/// Configuration of the node when the request came in, reloads do not affect it afterwards
+///
+/// Taken from the [`LiveConfig`] of the app, a fixed `Arc<NodeConfig>` is accepted too
+#[derive(Clone, Debug)]
+pub struct CurrentConfig(Arc<NodeConfig>);
+
+impl Deref for CurrentConfig {
+    type Target = NodeConfig;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl FromRequest for CurrentConfig {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let config = req
+            .app_data::<web::Data<LiveConfig>>()
+            .map(|live| live.current())
+            .or_else(|| {
+                req.app_data::<web::Data<Arc<NodeConfig>>>()
+                    .map(|config| config.get_ref().clone())
+            });
+
+        ready(
+            config
+                .map(CurrentConfig)
+                .ok_or_else(|| ErrorInternalServerError("No configuration registered")),
+        )
+    }
+}
 
 pub async fn index(
-    config: web::Data<Arc<NodeConfig>>,
+    config: CurrentConfig,
     identifier: web::Data<Arc<NodeIdentifier>>,
 ) -> impl Responder {
     HttpResponse::Ok()
-        .append_header((CONTENT_TYPE, TEXT_HTML))
+        .append_header((header::CONTENT_TYPE, TEXT_HTML))
         .body(format!(
             "<p><a href='/web/login'>{} ({})</a> is up and running</p>",
             config.name, identifier.uuid
         ))
 }
 
+/// Prunes the stale peer states of `dir` at startup, then hourly
+async fn prune_peer_states_periodically(dir: PathBuf, max_age_days: u64) {
+    let max_age = StdDuration::from_secs(max_age_days * 24 * 3600);
+    let mut interval = tokio::time::interval(StdDuration::from_secs(3600));
+
+    loop {
+        interval.tick().await;
+        match prune_peer_states(&dir, max_age).await {
+            Ok(0) => {}
+            Ok(pruned) => tracing::info!("Pruned {pruned} stale peer state(s)"),
+            Err(e) => tracing::error!("Failed to prune peer states: {e}"),
+        }
+    }
+}
+
+/// Signing key of the `/web` session cookies, kept at `path` so that logins survive restarts,
+/// a missing or malformed key file is replaced by a new key only readable by its owner
+pub fn load_session_key(path: &Path) -> eyre::Result<Key> {
+    match std::fs::read(path) {
+        Ok(bytes) => match Key::try_from(bytes.as_slice()) {
+            Ok(key) => return Ok(key),
+            Err(e) => tracing::warn!(
+                "Malformed session key in {}: {e}, generating a new one",
+                path.display()
+            ),
+        },
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            tracing::warn!("Session key not found, generating a new one")
+        }
+        Err(e) => {
+            return Err(e).wrap_err_with(|| format!("Reading session key {}", path.display()));
+        }
+    }
+
+    let key = Key::generate();
+    std::fs::remove_file(path).ok();
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+        .open(path)
+        .and_then(|mut file| file.write_all(key.master()))
+        .wrap_err_with(|| format!("Writing session key {}", path.display()))?;
+
+    Ok(key)
+}
+
+/// Sessions of the `/web` scope, each request extends the session so that an active user is
+/// not logged out while browsing
+pub fn session_middleware(key: Key, secure: bool) -> SessionMiddleware<CookieSessionStore> {
+    SessionMiddleware::builder(CookieSessionStore::default(), key)
+        .cookie_name("nullfs".to_owned())
+        .cookie_secure(secure)
+        .cookie_same_site(SameSite::Lax)
+        .session_lifecycle(
+            PersistentSession::default()
+                .session_ttl(Duration::hours(2))
+                .session_ttl_extension_policy(TtlExtensionPolicy::OnEveryRequest),
+        )
+        .build()
+}
+
+/// Routes of the `/web` scope, to be wrapped in [`session_middleware`]
+pub fn web_routes(cfg: &mut web::ServiceConfig) {
+    cfg.route("/style.css", web::get().to(style))
+        .route("/browser", web::get().to(browser))
+        .route("/preview", web::get().to(preview))
+        .route("/login", web::get().to(login))
+        .route("/login", web::post().to(login_post))
+        .route("/logout", web::get().to(logout));
+}
+
+/// Cross-origin access to the `/v1` scope for the `corsAllowedOrigins` of `config`, left out
+/// when there are none
+pub fn api_cors(config: &NodeConfig) -> Condition<Cors> {
+    let cors = config
+        .cors_allowed_origins
+        .iter()
+        .fold(Cors::default(), |cors, origin| cors.allowed_origin(origin))
+        .allowed_methods([Method::GET, Method::POST, Method::DELETE])
+        .allowed_headers([
+            header::AUTHORIZATION,
+            header::ACCEPT,
+            header::CONTENT_TYPE,
+            header::RANGE,
+            header::IF_NONE_MATCH,
+        ])
+        .expose_headers([
+            header::CONTENT_RANGE,
+            header::ETAG,
+            header::HeaderName::from_static(HASH_ALGO_HEADER),
+            header::HeaderName::from_static(NODE_ID_HEADER),
+            header::HeaderName::from_static(TOTAL_COUNT_HEADER),
+            header::HeaderName::from_static(CURSOR_HEADER),
+            header::HeaderName::from_static(REMAINING_HEADER),
+        ])
+        .max_age(3600);
+
+    Condition::new(!config.cors_allowed_origins.is_empty(), cors)
+}
+
+/// Routes of the `/v1` scope
+pub fn api_routes(cfg: &mut web::ServiceConfig) {
+    cfg.route("/commands", web::get().to(commands))
+        .route("/dir", web::get().to(dir))
+        .route("/search", web::get().to(search))
+        .route("/hash", web::get().to(hash))
+        .service(
+            web::resource("/hashes")
+                .app_data(web::JsonConfig::default().limit(MAX_HASHES_BODY))
+                .route(web::post().to(hashes)),
+        )
+        .service(
+            web::resource("/stats")
+                .app_data(web::JsonConfig::default().limit(MAX_HASHES_BODY))
+                .route(web::post().to(stats)),
+        )
+        .route("/info", web::get().to(info))
+        .route("/volumes", web::get().to(volumes))
+        .route("/health", web::get().to(health))
+        .route("/status", web::get().to(status))
+        .route("/events", web::get().to(events))
+        .route("/exists", web::get().to(exists))
+        .route("/stat", web::get().to(stat))
+        .route("/merkle", web::get().to(merkle))
+        .route("/tree", web::get().to(tree))
+        .route("/chunks", web::get().to(chunks))
+        .service(
+            web::resource("/download")
+                .wrap(Compress::default())
+                .wrap(from_fn(limits::limit_download_time))
+                .route(web::get().to(download)),
+        )
+        .route("/upload", web::post().to(upload))
+        .route("/file", web::delete().to(delete_file))
+        .route("/volume/{name}/pause", web::post().to(pause))
+        .route("/volume/{name}/resume", web::post().to(resume));
+}
+
 pub async fn run(
-    config: Arc<NodeConfig>,
+    live: Arc<LiveConfig>,
     identifier: Arc<NodeIdentifier>,
+    states: Arc<VolumeStates>,
     shutdown: CancellationToken,
 ) -> eyre::Result<()> {
+    let config = live.current();
     let addr = format!("{}:{}", config.address, config.port);
+    let max_age_days = config.peer_state_max_age_days.unwrap_or(90);
     tracing::info!("Starting server on {addr}");
 
-    let key = Key::generate();
+    let key = load_session_key(
+        &config
+            .data_dir()
+            .join(format!(".session-key-{}", config.name.trim())),
+    )?;
+    let peers = web::Data::new(PeerRegistry::load(Some(
+        config
+            .data_dir()
+            .join(format!(".peer-claims-{}.json", identifier.uuid)),
+    ))?);
+    let snapshots = web::Data::new(FsSnapshots::default());
+    let states = web::Data::from(states);
+    let stash = web::Data::new(Arc::new(CommandStash::new(&config, &identifier).await?));
+    let app_snapshots = snapshots.clone();
+    let app_config = config.clone();
+    let app_live = web::Data::from(live.clone());
     let server = HttpServer::new(move || {
+        let config = app_config.clone();
         App::new()
             .app_data(web::Data::new(identifier.clone()))
-            .app_data(web::Data::new(config.clone()))
+            .app_data(peers.clone())
+            .app_data(app_snapshots.clone())
+            .app_data(app_live.clone())
+            .app_data(states.clone())
+            .app_data(stash.clone())
             .service(
                 web::scope("/v1")
-                    .route("/commands", web::get().to(commands))
-                    .route("/dir", web::get().to(dir))
-                    .route("/hash", web::get().to(hash))
-                    .route("/info", web::get().to(info))
-                    .route("/exists", web::get().to(exists))
-                    .route("/download", web::get().to(download)),
+                    .wrap(from_fn(limits::limit_body))
+                    .wrap(api_cors(&config))
+                    .configure(api_routes),
             )
             .service(
                 web::scope("/web")
-                    .wrap(
-                        SessionMiddleware::builder(CookieSessionStore::default(), key.clone())
-                            .cookie_name("nullfs".to_owned())
-                            .cookie_secure(config.secure)
-                            .cookie_same_site(SameSite::Lax)
-                            .session_lifecycle(
-                                PersistentSession::default().session_ttl(Duration::hours(2)),
-                            )
-                            .build(),
-                    )
-                    .route("/style.css", web::get().to(style))
-                    .route("/browser", web::get().to(browser))
-                    .route("/login", web::get().to(login))
-                    .route("/login", web::post().to(login_post)), // .default_service(web::to(|| HttpResponse::Ok())),
+                    .wrap(session_middleware(key.clone(), config.secure))
+                    .configure(web_routes),
             )
+            .route("/metrics", web::get().to(metrics))
             .route("/", web::get().to(index))
-    })
-    .bind(addr)?
+    });
+    // stopped through `shutdown`, the requests in flight get the grace period to complete
+    let server = server.disable_signals().shutdown_timeout(
+        config
+            .shutdown_grace_secs
+            .unwrap_or(DEFAULT_SHUTDOWN_GRACE_SECS),
+    );
+    let server = match &config.tls {
+        Some(tls) => server.bind_rustls_0_23(addr, tls.server_config()?)?,
+        None => server.bind(addr)?,
+    }
     .run();
+    let handle = server.handle();
+    tokio::pin!(server);
+
+    let pruning = prune_peer_states_periodically(config.data_dir().to_path_buf(), max_age_days);
 
     tokio::select! {
-        _ = server => {},
-        _ = shutdown.cancelled() => {}
+        _ = &mut server => {},
+        _ = pruning => {},
+        _ = shutdown.cancelled() => {
+            tracing::info!("Refusing new connections, draining the requests in flight");
+            // sent right away, the server future completes the stop
+            let stopped = handle.stop(true);
+            server.await.ok();
+            stopped.await;
+        }
     };
 
+    snapshots
+        .release_all(|volume_name| {
+            let config = live.current();
+            let volume = config.volumes.get(volume_name)?;
+            let StoreKind::Local { root, .. } = &volume.store else {
+                return None;
+            };
+            Some((root.clone(), volume.fs_snapshot.clone()?))
+        })
+        .await;
+
     Ok(())
 }
@@ -1,8 +1,140 @@
 use crate::{
-    config::{StoreKind, VolumeItem},
-    nullfs::{Command, NullFs, NullFsPath, any_fs::AnyFs, snapshot::Snapshot},
+    config::{
+        Access, LiveConfig, NodeConfig, NodeIdentifier, RelayNode, RelayOrder, StoreKind, TieBreak,
+        TlsConfig, User, VolumeItem, expand_env_vars, hash_password, obsolete_keys,
+    },
+    nullfs::{
+        ByteStream, Command, DirPage, EdgeNodes, File, FileStat, FileType, NodeKind, NullFs,
+        NullFsPath, SortOrder, SyncSummary, Synchronizer,
+        any_fs::AnyFs,
+        delta::{Chunker, MAX_CHUNK_SIZE},
+        fs_snapshot::FsSnapshots,
+        local_fs::TRASH_DIR,
+        mem_fs::MemVolume,
+        metrics::METRICS,
+        needed_commands,
+        quarantine::{
+            DEFAULT_CONFLICT_SUFFIX, DEFAULT_QUARANTINE_DIR, QuarantineNamer, VERSIONS_DIR,
+            conflict_sibling, versions_dir,
+        },
+        reduce_contiguous_subsequences,
+        s3_fs::{S3Volume, is_plain_md5},
+        search::SearchResults,
+        share::{
+            CommandOutcome, CommandStash, DEFAULT_DELTA_THRESHOLD, DEFAULT_LIVENESS_TTL,
+            DEFAULT_STREAM_THRESHOLD, Prefetched, ShareNode, TOTAL_COUNT_HEADER, decode_json,
+            is_busy, retry_busy,
+        },
+        snapshot::{Cursor, Snapshot, State, StateStore, prune_peer_states},
+        systime_to_millis,
+        throttle::RateLimiter,
+        volume_state::{VolumeStates, VolumeStatus},
+    },
+    server::{MAX_PEER_CLAIMS, PeerRegistry, WithPath, api_routes},
 };
-use std::{path::PathBuf, time::Duration};
+use actix_web::{App, HttpRequest, HttpResponse, HttpServer, web};
+use async_trait::async_trait;
+use bytes::Bytes;
+use flate2::{Compression, write::GzEncoder};
+use futures::TryStreamExt;
+use indexmap::IndexMap;
+use rand::Rng;
+use reqwest::Url;
+use sha2::{Digest, Sha256};
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io::Write,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        Arc,
+        atomic::{AtomicU32, AtomicU64, Ordering},
+    },
+    time::{Duration, Instant, SystemTime},
+};
+use tokio_util::sync::CancellationToken;
+
+/// Unique scratch path under the system temp directory
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("nullfs-{}-{name}", uuid::Uuid::new_v4()))
+}
+
+fn local_volume(root: &Path) -> VolumeItem {
+    VolumeItem {
+        allow: vec![],
+        pull_from: vec![],
+        store: StoreKind::Local {
+            root: root.to_path_buf(),
+            follow_symlinks: false,
+            trash: false,
+            trash_retention_days: None,
+        },
+        tie_break: None,
+        relay_order: RelayOrder::Random,
+        fs_snapshot: None,
+        skip_mounts: false,
+        incremental_scan: false,
+        quarantine_dir: None,
+        conflict_suffix: None,
+        keep_versions: 0,
+        priority: 0,
+        writable: false,
+        anonymous: false,
+    }
+}
+
+/// Serves the given routes on a random local port, returns the relay address
+fn spawn_mock_relay<F>(configure: F) -> eyre::Result<Url>
+where
+    F: Fn(&mut web::ServiceConfig) + Send + Clone + 'static,
+{
+    let server = HttpServer::new(move || App::new().configure(configure.clone()))
+        .workers(1)
+        .bind(("127.0.0.1", 0))?;
+    let address = server.addrs()[0];
+    actix_web::rt::spawn(server.run());
+
+    Ok(Url::parse(&format!("http://{address}"))?)
+}
+
+async fn mock_share_node(address: Url) -> eyre::Result<ShareNode> {
+    let relay = RelayNode {
+        address,
+        auth: User {
+            name: "user".to_owned(),
+            password: None,
+        },
+        realm: None,
+        timeout_secs: None,
+        connect_timeout_secs: None,
+        ca_cert: None,
+    };
+
+    Ok(ShareNode {
+        name: "mock".to_owned(),
+        store: Arc::new(CommandStash::open(&temp_path("stash.db")).await?),
+        client: ShareNode::client_for(&relay, true)?,
+        relay,
+        priority: 0,
+        tie_break: None,
+        relay_order: RelayOrder::Random,
+        conflict_suffix: DEFAULT_CONFLICT_SUFFIX.to_owned(),
+        keep_versions: 0,
+        stream_threshold: DEFAULT_STREAM_THRESHOLD,
+        delta_threshold: DEFAULT_DELTA_THRESHOLD,
+        throttle: None,
+        merkle: false,
+        dry_run: false,
+        volume_priority: 0,
+        command_ttl: None,
+        hashes: Arc::default(),
+        hashes_store: None,
+        liveness_ttl: DEFAULT_LIVENESS_TTL,
+        liveness: Arc::default(),
+        shutdown: CancellationToken::default(),
+    })
+}
 
 #[test]
 fn test_nullfs_path() -> eyre::Result<()> {
@@ -20,22 +152,68 @@ fn test_nullfs_path() -> eyre::Result<()> {
     Ok(())
 }
 
+#[test]
+fn test_nullfs_path_helpers() -> eyre::Result<()> {
+    let path = NullFsPath::from_to_str("@/vol/a/b.txt")?;
+    assert_eq!(path.file_name(), Some("b.txt"));
+    assert_eq!(path.parent(), Some(NullFsPath::from_to_str("@/vol/a")?));
+    assert_eq!(path.parent().unwrap().join("b.txt")?, path);
+
+    // the volume root has neither a parent nor a file name
+    let root = NullFsPath::from_to_str("@/vol")?;
+    assert_eq!(root.file_name(), None);
+    assert_eq!(root.parent(), None);
+    assert_eq!(root.join("a")?, NullFsPath::from_to_str("@/vol/a")?);
+
+    for bad in ["a/b", "..", "", "."] {
+        assert!(root.join(bad).is_err(), "{bad:?}");
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_nullfs_path_order() -> eyre::Result<()> {
+    let mut paths = ["@/vol/a-b", "@/vol/a/b", "@/vol/a", "@/vol/a/b/c", "@/vol"]
+        .into_iter()
+        .map(NullFsPath::from_to_str)
+        .collect::<eyre::Result<Vec<_>>>()?;
+    paths.sort();
+
+    // '-' sorts before '/' in the joined strings, a directory keeps its entries together here
+    let sorted = paths.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+    assert_eq!(
+        sorted,
+        vec!["@/vol", "@/vol/a", "@/vol/a/b", "@/vol/a/b/c", "@/vol/a-b"]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_nullfs_path_glob() -> eyre::Result<()> {
+    let path = NullFsPath::from_to_str("@/a/b/c.txt")?;
+    assert!(path.matches_glob("**/*.txt"));
+    assert!(path.matches_glob("a/**/c.txt"));
+    assert!(path.matches_glob("@/a/b/?.txt"));
+    assert!(!path.matches_glob("a/*"));
+    assert!(!path.matches_glob("*.txt"));
+    assert!(!path.matches_glob("a/**/d.txt"));
+
+    assert!(NullFsPath::from_to_str("@/a/c.txt")?.matches_glob("a/**/c.txt"));
+    assert!(NullFsPath::from_to_str("@/a/b")?.matches_glob("a/*"));
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_snapshot() -> eyre::Result<()> {
     let root = PathBuf::from("src/tests/test_dir");
-    let mut fs = AnyFs::from_volume_item(
-        "Screenshots",
-        &VolumeItem {
-            allow: vec![],
-            pull_from: vec![],
-            store: StoreKind::Local { root: root.clone() },
-        },
-    );
+    let mut fs = AnyFs::from_volume_item("Screenshots", &local_volume(&root))?;
     let local_root = root;
     fs.init().await?;
 
-    let state_file =
-        PathBuf::from("src/tests").join(format!("{}.state.json", fs.get_volume_name()));
+    let state_file = PathBuf::from("src/tests").join(format!("{}.state.db", fs.get_volume_name()));
 
     {
         tokio::fs::remove_file(&state_file).await.ok();
@@ -83,5 +261,6031 @@ async fn test_snapshot() -> eyre::Result<()> {
     let commands = snapshot.capture(&state_file).await?;
     assert_eq!(commands.len(), 1);
     assert!(matches!(commands[0], Command::Delete { .. }));
+
+    tokio::fs::remove_file(&state_file).await.ok();
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_legacy_state_import() -> eyre::Result<()> {
+    let state_file = temp_path("state.db");
+    let legacy_file = state_file.with_extension("json");
+
+    let path = NullFsPath::from_to_str("@/vol/a.txt")?;
+    let file = File {
+        file_type: FileType::infer_from_path(&path),
+        path,
+        stat: FileStat {
+            node: NodeKind::File { size: 42 },
+            modified: 1000,
+            created: None,
+            accessed: None,
+        },
+    };
+    let mut state = State::new();
+    state.remember_hash(&file, "abc".to_owned());
+
+    // gzip compressed, as written with the former `compressState`
+    let mut encoder = GzEncoder::new(vec![], Compression::default());
+    encoder.write_all(&serde_json::to_vec(&state)?)?;
+    tokio::fs::write(&legacy_file, encoder.finish()?).await?;
+
+    let store = StateStore::open(&state_file).await?;
+    assert!(!legacy_file.exists());
+
+    let mut loaded = State::new();
+    store
+        .load_dir(&mut loaded, &NullFsPath::from_to_str("@/vol")?)
+        .await?;
+    assert_eq!(loaded.cached_hash(&file), Some("abc"));
+
+    store.close().await;
+    tokio::fs::remove_file(&state_file).await.ok();
+    Ok(())
+}
+
+#[test]
+fn test_decode_relay_json() -> eyre::Result<()> {
+    let hash = decode_json::<String>("\"abc\"", "application/json")?;
+    assert_eq!(hash, "abc");
+
+    let err =
+        decode_json::<Vec<Command>>(r#"{"error": "Volume \"V\" not found"}"#, "application/json")
+            .unwrap_err();
+    assert!(err.to_string().contains("Relay answered with an error"));
+
+    let err = decode_json::<Vec<Command>>(
+        r#"{"error": {"code": "not_found", "message": "Volume \"V\" not found"}}"#,
+        "application/json",
+    )
+    .unwrap_err();
+    assert!(
+        err.to_string()
+            .contains("Volume \"V\" not found (not_found)")
+    );
+
+    let html = format!("<html>{}</html>", "x".repeat(500));
+    let err = decode_json::<Vec<Command>>(&html, "text/html").unwrap_err();
+    let message = err.to_string();
+    assert!(message.contains("Malformed response"));
+    assert!(message.contains("text/html"));
+    assert!(message.len() < 400);
+
+    Ok(())
+}
+
+fn sample_commands(count: usize) -> eyre::Result<Vec<Command>> {
+    (0..count)
+        .map(|i| {
+            let path = NullFsPath::from_to_str(format!("@/vol/dir-{}/file-{i}.txt", i % 100))?;
+            let file = File {
+                file_type: FileType::infer_from_path(&path),
+                path,
+                stat: FileStat {
+                    node: NodeKind::File { size: i as u64 },
+                    modified: 1_700_000_000_000 + i as u64,
+                    created: Some(1_700_000_000_000),
+                    accessed: None,
+                },
+            };
+
+            Ok(match i % 3 {
+                0 => Command::Write { file },
+                1 => Command::Touch { file },
+                _ => Command::Delete { file },
+            })
+        })
+        .collect()
+}
+
+#[actix_web::test]
+async fn test_hash_algo() -> eyre::Result<()> {
+    use crate::nullfs::hashing::{ContentHasher, HashAlgo};
+
+    let digest = |algo| {
+        let mut hasher = ContentHasher::with_algo(algo);
+        hasher.update(b"");
+        hasher.finalize()
+    };
+    assert_eq!(
+        digest(HashAlgo::Sha256),
+        "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+    );
+    assert_eq!(
+        digest(HashAlgo::Blake3),
+        "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262"
+    );
+
+    let config: NodeConfig = serde_yaml::from_str(
+        "name: node\naddress: 127.0.0.1\nport: 5567\nhashAlgo: blake3\nusers: []\n\
+         relayNodes: {}\nvolumes: {}\n",
+    )?;
+    assert_eq!(config.hash_algo, Some(HashAlgo::Blake3));
+
+    // hashes of another algorithm are never compared
+    let relay = spawn_mock_relay(|cfg| {
+        cfg.route(
+            "/v1/hash",
+            web::get().to(|| async {
+                HttpResponse::Ok()
+                    .insert_header(("x-nullfs-hash-algo", "blake3"))
+                    .json("af1349b9")
+            }),
+        );
+    })?;
+    let share_node = mock_share_node(relay).await?;
+    let error = share_node
+        .remote_hash(&NullFsPath::from_to_str("@/vol/a.txt")?)
+        .await
+        .unwrap_err();
+    assert!(error.to_string().contains("blake3"), "{error}");
+
+    Ok(())
+}
+
+#[test]
+fn test_commands_msgpack_roundtrip() -> eyre::Result<()> {
+    let commands = sample_commands(10)?;
+    let bytes = rmp_serde::to_vec_named(&commands)?;
+    let decoded = rmp_serde::from_slice::<Vec<Command>>(&bytes)?;
+    assert_eq!(commands, decoded);
+
+    Ok(())
+}
+
+#[test]
+#[ignore = "benchmark, run with --ignored --nocapture"]
+fn bench_commands_encoding() -> eyre::Result<()> {
+    let commands = sample_commands(50_000)?;
+
+    let start = Instant::now();
+    let json = serde_json::to_vec(&commands)?;
+    let _ = serde_json::from_slice::<Vec<Command>>(&json)?;
+    let json_time = start.elapsed();
+
+    let start = Instant::now();
+    let msgpack = rmp_serde::to_vec_named(&commands)?;
+    let _ = rmp_serde::from_slice::<Vec<Command>>(&msgpack)?;
+    let msgpack_time = start.elapsed();
+
+    println!("json: {} bytes, {json_time:?}", json.len());
+    println!("msgpack: {} bytes, {msgpack_time:?}", msgpack.len());
+    assert!(msgpack.len() < json.len());
+
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_download_checksum() -> eyre::Result<()> {
+    const CONTENT: &[u8] = b"some content that will be altered on the way";
+
+    let relay = spawn_mock_relay(|cfg| {
+        cfg.route(
+            "/v1/download",
+            web::get().to(|| async {
+                let mut corrupted = CONTENT.to_vec();
+                corrupted[3] ^= 0xff;
+                HttpResponse::Ok().body(corrupted)
+            }),
+        );
+    })?;
+
+    // the content hash is checked as the chunks arrive
+    let path = NullFsPath::from_to_str("@/vol/a.txt")?;
+    let hash = format!("{:x}", Sha256::digest(CONTENT));
+    let share_node = mock_share_node(relay).await?;
+    let err = share_node
+        .download_verified(&path, &hash, None)
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("Refusing"), "{err}");
+
+    let root = temp_path("corrupted");
+    tokio::fs::create_dir_all(&root).await?;
+    tokio::fs::write(root.join("a.txt"), b"before").await?;
+    let mut fs = AnyFs::from_volume_item("vol", &local_volume(&root))?;
+    fs.init().await?;
+    let file = File {
+        file_type: FileType::infer_from_path(&path),
+        stat: fs.stats(&path).await?,
+        path: path.clone(),
+    };
+    assert!(
+        share_node
+            .download_streamed(&fs, &file, &hash, None)
+            .await
+            .is_err()
+    );
+    assert_eq!(tokio::fs::read(root.join("a.txt")).await?, b"before");
+    tokio::fs::remove_dir_all(&root).await.ok();
+
+    let relay = spawn_mock_relay(|cfg| {
+        cfg.route(
+            "/v1/download",
+            web::get().to(|| async { HttpResponse::Ok().body(CONTENT) }),
+        );
+    })?;
+
+    let share_node = mock_share_node(relay).await?;
+    let data = share_node.download_verified(&path, &hash, None).await?;
+    assert_eq!(data.as_deref(), Some(CONTENT));
+
+    Ok(())
+}
+
+#[test]
+fn test_mtime_tolerance() -> eyre::Result<()> {
+    let path = NullFsPath::from_to_str("@/vol/a.txt")?;
+    let file_at = |modified: u64| File {
+        file_type: FileType::infer_from_path(&path),
+        path: path.clone(),
+        stat: FileStat {
+            node: NodeKind::File { size: 42 },
+            modified,
+            created: None,
+            accessed: None,
+        },
+    };
+
+    let mut state = State::new().with_mtime_tolerance(10);
+    assert!(state.update_on_change(&file_at(1000))?);
+    assert!(!state.update_on_change(&file_at(1001))?);
+    assert!(state.update_on_change(&file_at(1020))?);
+
+    let mut state = State::new();
+    assert!(state.update_on_change(&file_at(1000))?);
+    assert!(state.update_on_change(&file_at(1001))?);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_snapshot_caches_hashes() -> eyre::Result<()> {
+    let mut fs = AnyFs::from_volume_item("Docs", &local_volume(Path::new("src/tests/test_dir")))?;
+    fs.init().await?;
+
+    let state_file = temp_path("state.db");
+    Snapshot::new(fs.clone()).capture(&state_file).await?;
+
+    let path = NullFsPath::from_to_str("@/Docs/c/d.txt")?;
+    let mut file = File {
+        file_type: FileType::infer_from_path(&path),
+        stat: fs.stats(&path).await?,
+        path: path.clone(),
+    };
+    let store = StateStore::open(&state_file).await?;
+    let mut state = State::new();
+    store
+        .load_dir(&mut state, &NullFsPath::from_to_str("@/Docs/c")?)
+        .await?;
+    store.close().await;
+    assert_eq!(
+        state.cached_hash(&file),
+        Some(fs.hash(&path).await?.as_str())
+    );
+
+    // a newer mtime means the cached hash is stale
+    file.stat.modified += 1;
+    assert_eq!(state.cached_hash(&file), None);
+
+    tokio::fs::remove_file(&state_file).await.ok();
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_local_hash_cache() -> eyre::Result<()> {
+    let mem = MemVolume::new("Mem");
+    let fs = AnyFs {
+        volume_name: "Mem".to_owned(),
+        fs_instance: Arc::new(tokio::sync::RwLock::new(mem.clone())),
+    };
+    let path = NullFsPath::from_to_str("@/Mem/a.txt")?;
+    let file = File {
+        file_type: FileType::infer_from_path(&path),
+        path: path.clone(),
+        stat: FileStat {
+            node: NodeKind::File { size: 0 },
+            modified: 0,
+            created: None,
+            accessed: None,
+        },
+    };
+    fs.write(&file, b"first").await?;
+    let modified = fs.stats(&path).await?.modified;
+
+    let share_node = mock_share_node(Url::parse("http://127.0.0.1:1")?).await?;
+    let first = share_node.local_hash(&fs, &path).await?;
+    assert_eq!(first, fs.hash(&path).await?);
+
+    // same mtime and size, the content is not read again
+    fs.write(&file, b"other").await?;
+    mem.set_modified(&path, modified)?;
+    assert_eq!(share_node.local_hash(&fs, &path).await?, first);
+
+    mem.set_modified(&path, modified + 1)?;
+    assert_eq!(
+        share_node.local_hash(&fs, &path).await?,
+        fs.hash(&path).await?
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_prune_peer_states() -> eyre::Result<()> {
+    let dir = temp_path("states");
+    tokio::fs::create_dir_all(&dir).await?;
+
+    let stale = dir.join(".ext-state-vol-a-b.json");
+    let fresh = dir.join(".ext-state-vol-a-c.json");
+    let unrelated = dir.join("other.json");
+    for path in [&stale, &fresh, &unrelated] {
+        tokio::fs::write(path, "{}").await?;
+    }
+
+    let long_ago = SystemTime::now() - Duration::from_secs(100 * 24 * 3600);
+    std::fs::File::options()
+        .write(true)
+        .open(&stale)?
+        .set_modified(long_ago)?;
+    std::fs::File::options()
+        .write(true)
+        .open(&unrelated)?
+        .set_modified(long_ago)?;
+
+    let pruned = prune_peer_states(&dir, Duration::from_secs(90 * 24 * 3600)).await?;
+    assert_eq!(pruned, 1);
+    assert!(!stale.exists());
+    assert!(fresh.exists());
+    assert!(unrelated.exists());
+
+    tokio::fs::remove_dir_all(&dir).await.ok();
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_fs_snapshot_hook() -> eyre::Result<()> {
+    use crate::{config::FsSnapshotHook, nullfs::fs_snapshot::FsSnapshots};
+
+    let root = temp_path("live");
+    tokio::fs::create_dir_all(&root).await?;
+    tokio::fs::write(root.join("a.txt"), "before").await?;
+
+    // Poor man's snapshot: a plain copy of the root
+    let hook = FsSnapshotHook {
+        create: r#"dest="$NULLFS_ROOT.snap.$(date +%s%N)" && cp -r "$NULLFS_ROOT" "$dest" && echo "$dest""#
+            .to_owned(),
+        destroy: Some(r#"rm -rf "$NULLFS_SNAPSHOT""#.to_owned()),
+        max_age_secs: Some(0),
+    };
+
+    let snapshots = FsSnapshots::default();
+    let mount = snapshots.refresh("Live", &root, &hook).await?;
+    tokio::fs::write(root.join("a.txt"), "after").await?;
+
+    let mut fs = AnyFs::from_volume_item_at("Live", &local_volume(&root), Some(mount.clone()))?;
+    fs.init().await?;
+    let path = NullFsPath::from_to_str("@/Live/a.txt")?;
+    assert_eq!(fs.read(&path).await?, b"before");
+    assert_eq!(
+        fs.dir(&NullFsPath::from_to_str("@/Live")?).await?[0].path,
+        path
+    );
+
+    let next_mount = snapshots.refresh("Live", &root, &hook).await?;
+    assert!(!mount.exists());
+
+    snapshots
+        .release_all(|_| Some((root.clone(), hook.clone())))
+        .await;
+    assert!(!next_mount.exists());
+
+    tokio::fs::remove_dir_all(&root).await.ok();
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_offloaded_hash_matches_inline() -> eyre::Result<()> {
+    let root = temp_path("hashing");
+    tokio::fs::create_dir_all(&root).await?;
+    let content = vec![7u8; crate::nullfs::hashing::OFFLOAD_THRESHOLD as usize + 1];
+    tokio::fs::write(root.join("big.bin"), &content).await?;
+
+    let mut fs = AnyFs::from_volume_item("Big", &local_volume(&root))?;
+    fs.init().await?;
+    let hash = fs.hash(&NullFsPath::from_to_str("@/Big/big.bin")?).await?;
+    assert_eq!(hash, format!("{:x}", Sha256::digest(&content)));
+
+    tokio::fs::remove_dir_all(&root).await.ok();
+    Ok(())
+}
+
+#[tokio::test(flavor = "current_thread")]
+#[ignore = "benchmark, run with --ignored --nocapture"]
+async fn bench_runtime_responsiveness_while_hashing() -> eyre::Result<()> {
+    let root = temp_path("hashing-bench");
+    tokio::fs::create_dir_all(&root).await?;
+    tokio::fs::write(root.join("big.bin"), vec![1u8; 256 * 1024 * 1024]).await?;
+
+    let mut fs = AnyFs::from_volume_item("Big", &local_volume(&root))?;
+    fs.init().await?;
+
+    let hashing = tokio::spawn(async move {
+        let started = Instant::now();
+        fs.hash(&NullFsPath::from_to_str("@/Big/big.bin")?).await?;
+        eyre::Ok(started.elapsed())
+    });
+
+    let mut worst_lag = Duration::ZERO;
+    while !hashing.is_finished() {
+        let before = Instant::now();
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        worst_lag = worst_lag.max(before.elapsed().saturating_sub(Duration::from_millis(5)));
+    }
+
+    println!(
+        "hashed in {:?}, worst tick lag {worst_lag:?}",
+        hashing.await??
+    );
+    tokio::fs::remove_dir_all(&root).await.ok();
+    Ok(())
+}
+
+/// Local volume pretending that one of its directories is mounted from another device
+#[derive(Debug)]
+struct MountedFs {
+    inner: AnyFs,
+    mount: NullFsPath,
+}
+
+#[async_trait]
+impl NullFs for MountedFs {
+    async fn init(&mut self) -> eyre::Result<()> {
+        self.inner.init().await
+    }
+
+    async fn dir(&self, dir: &NullFsPath) -> eyre::Result<Vec<File>> {
+        self.inner.dir(dir).await
+    }
+
+    async fn mkdir(&self, path: &NullFsPath) -> eyre::Result<()> {
+        self.inner.mkdir(path).await
+    }
+
+    async fn copy(&self, o: &NullFsPath, d: &NullFsPath) -> eyre::Result<()> {
+        self.inner.copy(o, d).await
+    }
+
+    async fn rename(&self, o: &NullFsPath, d: &NullFsPath) -> eyre::Result<()> {
+        self.inner.rename(o, d).await
+    }
+
+    async fn stats(&self, path: &NullFsPath) -> eyre::Result<FileStat> {
+        self.inner.stats(path).await
+    }
+
+    async fn exists(&self, path: &NullFsPath) -> eyre::Result<bool> {
+        self.inner.exists(path).await
+    }
+
+    async fn read(&self, path: &NullFsPath) -> eyre::Result<Vec<u8>> {
+        self.inner.read(path).await
+    }
+
+    async fn write(&self, file: &File, bytes: &[u8]) -> eyre::Result<()> {
+        self.inner.write(file, bytes).await
+    }
+
+    async fn delete(&self, file: &File) -> eyre::Result<()> {
+        self.inner.delete(file).await
+    }
+
+    async fn hash(&self, path: &NullFsPath) -> eyre::Result<String> {
+        self.inner.hash(path).await
+    }
+
+    async fn is_mount_point(&self, path: &NullFsPath) -> eyre::Result<bool> {
+        Ok(path.eq(&self.mount))
+    }
+
+    async fn shallow_hash(&self, file: &File) -> eyre::Result<String> {
+        self.inner.shallow_hash(file).await
+    }
+}
+
+#[tokio::test]
+async fn test_snapshot_skips_mount_points() -> eyre::Result<()> {
+    let mut inner =
+        AnyFs::from_volume_item("Mounted", &local_volume(Path::new("src/tests/test_dir")))?;
+    inner.init().await?;
+    let fs = AnyFs {
+        volume_name: "Mounted".to_owned(),
+        fs_instance: Arc::new(tokio::sync::RwLock::new(MountedFs {
+            inner,
+            mount: NullFsPath::from_to_str("@/Mounted/c")?,
+        })),
+    };
+
+    let state_file = temp_path("state.db");
+    let commands = Snapshot::new(fs.clone())
+        .skip_mounts(true)
+        .capture(&state_file)
+        .await?;
+    assert!(
+        commands
+            .iter()
+            .all(|command| !command.to_string().contains("@/Mounted/c"))
+    );
+    assert!(
+        commands
+            .iter()
+            .any(|command| command.to_string().contains("@/Mounted/a.txt"))
+    );
+
+    tokio::fs::remove_file(&state_file).await.ok();
+    let commands = Snapshot::new(fs).capture(&state_file).await?;
+    assert!(
+        commands
+            .iter()
+            .any(|command| command.to_string().contains("@/Mounted/c/d.txt"))
+    );
+
+    tokio::fs::remove_file(&state_file).await.ok();
+    Ok(())
+}
+
+/// Volume recording the directories listed through it, [`NullFs::shallow_hash`] included
+#[derive(Debug)]
+struct ListingSpy {
+    inner: AnyFs,
+    listed: std::sync::Mutex<Vec<NullFsPath>>,
+    /// Listings wait for each other on it when set
+    rendezvous: Option<tokio::sync::Barrier>,
+}
+
+#[async_trait]
+impl NullFs for ListingSpy {
+    async fn init(&mut self) -> eyre::Result<()> {
+        self.inner.init().await
+    }
+
+    async fn dir(&self, dir: &NullFsPath) -> eyre::Result<Vec<File>> {
+        self.listed.lock().unwrap().push(dir.clone());
+        if let Some(rendezvous) = &self.rendezvous {
+            rendezvous.wait().await;
+        }
+        self.inner.dir(dir).await
+    }
+
+    async fn mkdir(&self, path: &NullFsPath) -> eyre::Result<()> {
+        self.inner.mkdir(path).await
+    }
+
+    async fn copy(&self, o: &NullFsPath, d: &NullFsPath) -> eyre::Result<()> {
+        self.inner.copy(o, d).await
+    }
+
+    async fn rename(&self, o: &NullFsPath, d: &NullFsPath) -> eyre::Result<()> {
+        self.inner.rename(o, d).await
+    }
+
+    async fn stats(&self, path: &NullFsPath) -> eyre::Result<FileStat> {
+        self.inner.stats(path).await
+    }
+
+    async fn exists(&self, path: &NullFsPath) -> eyre::Result<bool> {
+        self.inner.exists(path).await
+    }
+
+    async fn read(&self, path: &NullFsPath) -> eyre::Result<Vec<u8>> {
+        self.inner.read(path).await
+    }
+
+    async fn write(&self, file: &File, bytes: &[u8]) -> eyre::Result<()> {
+        self.inner.write(file, bytes).await
+    }
+
+    async fn delete(&self, file: &File) -> eyre::Result<()> {
+        self.inner.delete(file).await
+    }
+
+    async fn hash(&self, path: &NullFsPath) -> eyre::Result<String> {
+        self.inner.hash(path).await
+    }
+}
+
+/// Local volume refusing buffered writes, records how many chunks are streamed to it and the
+/// largest one
+#[derive(Debug)]
+struct ChunkCounter {
+    inner: AnyFs,
+    chunks: Arc<std::sync::Mutex<(usize, usize)>>,
+}
+
+#[async_trait]
+impl NullFs for ChunkCounter {
+    async fn init(&mut self) -> eyre::Result<()> {
+        self.inner.init().await
+    }
+
+    async fn dir(&self, dir: &NullFsPath) -> eyre::Result<Vec<File>> {
+        self.inner.dir(dir).await
+    }
+
+    async fn mkdir(&self, path: &NullFsPath) -> eyre::Result<()> {
+        self.inner.mkdir(path).await
+    }
+
+    async fn copy(&self, o: &NullFsPath, d: &NullFsPath) -> eyre::Result<()> {
+        self.inner.copy(o, d).await
+    }
+
+    async fn rename(&self, o: &NullFsPath, d: &NullFsPath) -> eyre::Result<()> {
+        self.inner.rename(o, d).await
+    }
+
+    async fn stats(&self, path: &NullFsPath) -> eyre::Result<FileStat> {
+        self.inner.stats(path).await
+    }
+
+    async fn exists(&self, path: &NullFsPath) -> eyre::Result<bool> {
+        self.inner.exists(path).await
+    }
+
+    async fn read(&self, path: &NullFsPath) -> eyre::Result<Vec<u8>> {
+        self.inner.read(path).await
+    }
+
+    async fn write(&self, file: &File, _: &[u8]) -> eyre::Result<()> {
+        eyre::bail!("Buffered write of {}", file.path)
+    }
+
+    async fn write_stream(&self, file: &File, chunks: ByteStream) -> eyre::Result<()> {
+        let counted = self.chunks.clone();
+        let chunks = chunks.inspect_ok(move |chunk| {
+            let mut counted = counted.lock().unwrap();
+            counted.0 += 1;
+            counted.1 = counted.1.max(chunk.len());
+        });
+        self.inner.write_stream(file, Box::pin(chunks)).await
+    }
+
+    async fn delete(&self, file: &File) -> eyre::Result<()> {
+        self.inner.delete(file).await
+    }
+
+    async fn hash(&self, path: &NullFsPath) -> eyre::Result<String> {
+        self.inner.hash(path).await
+    }
+
+    async fn shallow_hash(&self, file: &File) -> eyre::Result<String> {
+        self.inner.shallow_hash(file).await
+    }
+}
+
+#[actix_web::test]
+async fn test_streamed_download() -> eyre::Result<()> {
+    const SIZE: usize = 16 * 1024 * 1024;
+    let content = Bytes::from((0..SIZE).map(|i| (i % 251) as u8).collect::<Vec<_>>());
+    let serving = |content: Bytes, hash: String| {
+        spawn_mock_relay(move |cfg| {
+            let (content, hash) = (content.clone(), hash.clone());
+            cfg.route(
+                "/v1/exists",
+                web::get().to(|| async { HttpResponse::Ok().json(true) }),
+            )
+            .route(
+                "/v1/hash",
+                web::get().to(move || {
+                    let hash = hash.clone();
+                    async move { HttpResponse::Ok().json(hash) }
+                }),
+            )
+            .route(
+                "/v1/download",
+                web::get().to(move || {
+                    let content = content.clone();
+                    async move { HttpResponse::Ok().body(content) }
+                }),
+            );
+        })
+    };
+
+    let root = temp_path("streamed");
+    tokio::fs::create_dir_all(&root).await?;
+    let mut inner = AnyFs::from_volume_item("vol", &local_volume(&root))?;
+    inner.init().await?;
+    let chunks = Arc::new(std::sync::Mutex::new((0, 0)));
+    let fs = AnyFs {
+        volume_name: "vol".to_owned(),
+        fs_instance: Arc::new(tokio::sync::RwLock::new(ChunkCounter {
+            inner,
+            chunks: chunks.clone(),
+        })),
+    };
+
+    let path = NullFsPath::from_to_str("@/vol/large.bin")?;
+    let write = Command::Write {
+        file: File {
+            file_type: FileType::infer_from_path(&path),
+            path: path.clone(),
+            stat: FileStat {
+                node: NodeKind::File { size: SIZE as u64 },
+                modified: systime_to_millis(SystemTime::now()),
+                created: None,
+                accessed: None,
+            },
+        },
+    };
+
+    let hash = format!("{:x}", Sha256::digest(&content));
+    let mut share_node = mock_share_node(serving(content.clone(), hash)?).await?;
+    share_node.stream_threshold = 1024 * 1024;
+    share_node
+        .run_command(&write, &fs, &[], &Prefetched::default())
+        .await?;
+    assert!(tokio::fs::read(root.join("large.bin")).await? == content);
+    let (count, largest) = *chunks.lock().unwrap();
+    assert!(count > 1, "{count} chunk(s)");
+    assert!(largest <= 1024 * 1024, "largest chunk of {largest} bytes");
+
+    // a corrupted download leaves the previous content in place
+    let mut corrupted = content.to_vec();
+    corrupted[SIZE / 2] ^= 0xff;
+    let expected = format!("{:x}", Sha256::digest(&content));
+    let mut share_node = mock_share_node(serving(Bytes::from(corrupted), expected)?).await?;
+    share_node.stream_threshold = 1024 * 1024;
+    tokio::fs::write(root.join("large.bin"), b"previous").await?;
+    let err = share_node
+        .run_command(&write, &fs, &[], &Prefetched::default())
+        .await
+        .unwrap_err();
+    assert!(format!("{err:#}").contains("Refusing"), "{err:#}");
+    assert_eq!(tokio::fs::read(root.join("large.bin")).await?, b"previous");
+    let mut entries = tokio::fs::read_dir(&root).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        assert_eq!(entry.file_name(), "large.bin");
+    }
+
+    tokio::fs::remove_dir_all(&root).await.ok();
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_download_throttle() -> eyre::Result<()> {
+    const RATE: u64 = 1024 * 1024;
+    let content = Bytes::from(vec![7u8; RATE as usize]);
+    let hash = format!("{:x}", Sha256::digest(&content));
+    let relay = spawn_mock_relay(move |cfg| {
+        let content = content.clone();
+        cfg.route(
+            "/v1/download",
+            web::get().to(move || {
+                let content = content.clone();
+                async move { HttpResponse::Ok().body(content) }
+            }),
+        );
+    })?;
+
+    let mut share_node = mock_share_node(relay).await?;
+    share_node.throttle = Some(Arc::new(RateLimiter::new(RATE)));
+    let path = NullFsPath::from_to_str("@/vol/a.bin")?;
+
+    // each transfer alone fits in the one second burst, the bucket they share does not
+    let start = Instant::now();
+    let (a, b) = tokio::join!(
+        share_node.download_verified(&path, &hash, None),
+        share_node.download_verified(&path, &hash, None)
+    );
+    assert_eq!(
+        a?.unwrap_or_default().len() + b?.unwrap_or_default().len(),
+        2 * RATE as usize
+    );
+    assert!(
+        start.elapsed() >= Duration::from_millis(950),
+        "{:?}",
+        start.elapsed()
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_throttle_priority() -> eyre::Result<()> {
+    const RATE: u64 = 256 * 1024;
+    const CHUNK: u64 = 8 * 1024;
+    let throttle = Arc::new(RateLimiter::new(RATE));
+    let deadline = Instant::now() + Duration::from_secs(2);
+
+    let transfer = |priority: u32| {
+        let throttle = throttle.clone();
+        tokio::spawn(async move {
+            let mut bytes = 0;
+            while Instant::now() < deadline {
+                throttle.acquire(CHUNK, priority).await;
+                bytes += CHUNK;
+            }
+            bytes
+        })
+    };
+    let (high, low) = (transfer(1), transfer(0));
+    let (high, low) = (high.await?, low.await?);
+
+    // twice the weight, the low priority transfer still goes on
+    assert!(low > 0);
+    assert!(high > low * 3 / 2, "high {high}, low {low}");
+    assert!(high + low <= 4 * RATE, "high {high}, low {low}");
+
+    // alone, a low priority transfer gets the whole rate once the high one is gone
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    let start = Instant::now();
+    throttle.acquire(RATE, 0).await;
+    throttle.acquire(RATE / 2, 0).await;
+    assert!(
+        start.elapsed() < Duration::from_millis(900),
+        "{:?}",
+        start.elapsed()
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_concurrent_reads() -> eyre::Result<()> {
+    let fs = AnyFs {
+        volume_name: "Mem".to_owned(),
+        fs_instance: Arc::new(tokio::sync::RwLock::new(ListingSpy {
+            inner: AnyFs {
+                volume_name: "Mem".to_owned(),
+                fs_instance: Arc::new(tokio::sync::RwLock::new(MemVolume::new("Mem"))),
+            },
+            listed: Default::default(),
+            rendezvous: Some(tokio::sync::Barrier::new(2)),
+        })),
+    };
+
+    // each listing only returns once the other one started
+    let root = fs.volume_root()?;
+    let listings = futures::future::try_join(fs.dir(&root), fs.dir(&root));
+    tokio::time::timeout(Duration::from_secs(5), listings)
+        .await
+        .map_err(|_| eyre::eyre!("Listings were serialized"))??;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_snapshot_skips_unchanged_dirs() -> eyre::Result<()> {
+    let inner = AnyFs {
+        volume_name: "Mem".to_owned(),
+        fs_instance: Arc::new(tokio::sync::RwLock::new(MemVolume::new("Mem"))),
+    };
+    let file = |rel: &str| -> eyre::Result<File> {
+        let path = NullFsPath::from_to_str(format!("@/Mem/{rel}"))?;
+        Ok(File {
+            file_type: FileType::infer_from_path(&path),
+            path,
+            stat: FileStat {
+                node: NodeKind::File { size: 0 },
+                modified: 0,
+                created: None,
+                accessed: None,
+            },
+        })
+    };
+    for rel in ["a/b/c/deep.txt", "a/b/other.txt", "x/y.txt"] {
+        inner.write(&file(rel)?, rel.as_bytes()).await?;
+    }
+
+    let spy = Arc::new(tokio::sync::RwLock::new(ListingSpy {
+        inner: inner.clone(),
+        listed: Default::default(),
+        rendezvous: None,
+    }));
+    let fs = AnyFs {
+        volume_name: "Mem".to_owned(),
+        fs_instance: spy.clone(),
+    };
+    let listed = async || std::mem::take(&mut *spy.read().await.listed.lock().unwrap());
+
+    let dirs = ["@/Mem", "@/Mem/a", "@/Mem/a/b", "@/Mem/a/b/c", "@/Mem/x"]
+        .into_iter()
+        .map(NullFsPath::from_to_str)
+        .collect::<eyre::Result<Vec<_>>>()?;
+    let sorted = async || {
+        let mut listed = listed().await;
+        listed.sort();
+        listed
+    };
+
+    // each directory is listed by its shallow hash then by the capture
+    let state_file = temp_path("state.db");
+    Snapshot::new(fs.clone()).capture(&state_file).await?;
+    let twice = dirs.iter().flat_map(|dir| [dir.clone(), dir.clone()]);
+    assert_eq!(sorted().await, twice.collect::<Vec<_>>());
+
+    // nothing changed, each directory is only listed by its shallow hash
+    let commands = Snapshot::new(fs.clone()).capture(&state_file).await?;
+    assert!(commands.is_empty());
+    assert_eq!(sorted().await, dirs);
+
+    // a change deep down is still found, only its directory is listed again
+    inner
+        .write(&file("a/b/c/deep.txt")?, b"deeply changed")
+        .await?;
+    let commands = Snapshot::new(fs.clone()).capture(&state_file).await?;
+    assert_eq!(commands.len(), 1);
+    assert!(commands[0].to_string().contains("@/Mem/a/b/c/deep.txt"));
+    let mut expected = dirs.clone();
+    expected.insert(4, NullFsPath::from_to_str("@/Mem/a/b/c")?);
+    assert_eq!(sorted().await, expected);
+
+    tokio::fs::remove_file(&state_file).await.ok();
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_incremental_scan() -> eyre::Result<()> {
+    let root = temp_path("incremental");
+    tokio::fs::create_dir_all(root.join("sub")).await?;
+    tokio::fs::write(root.join("sub/a.txt"), b"a").await?;
+    // directory mtimes too recent to be trusted are listed again anyway
+    let long_ago = SystemTime::now() - Duration::from_secs(3600);
+    for dir in [root.clone(), root.join("sub")] {
+        std::fs::File::open(dir)?.set_modified(long_ago)?;
+    }
+
+    let mut inner = AnyFs::from_volume_item("Vol", &local_volume(&root))?;
+    inner.init().await?;
+    let spy = Arc::new(tokio::sync::RwLock::new(ListingSpy {
+        inner,
+        listed: Default::default(),
+        rendezvous: None,
+    }));
+    let fs = AnyFs {
+        volume_name: "Vol".to_owned(),
+        fs_instance: spy.clone(),
+    };
+    let listed = async || std::mem::take(&mut *spy.read().await.listed.lock().unwrap());
+    let state_file = temp_path("incremental.db");
+    let capture = async || {
+        Snapshot::new(fs.clone())
+            .incremental(true)
+            .capture(&state_file)
+            .await
+    };
+
+    // each directory is listed by its shallow hash then by the capture
+    capture().await?;
+    assert_eq!(listed().await.len(), 4);
+
+    // an edit in place leaves the directory mtime alone, the file is still checked
+    tokio::fs::write(root.join("sub/a.txt"), b"edited").await?;
+    let commands = capture().await?;
+    assert_eq!(commands.len(), 1);
+    assert!(
+        matches!(&commands[0], Command::Touch { file } if file.path.to_string() == "@/Vol/sub/a.txt")
+    );
+    assert!(listed().await.is_empty());
+
+    // an added entry bumps the mtime of its directory only, listed by its shallow hash
+    // then by the capture
+    tokio::fs::write(root.join("sub/b.txt"), b"b").await?;
+    let commands = capture().await?;
+    assert_eq!(commands.len(), 1);
+    assert!(
+        matches!(&commands[0], Command::Write { file } if file.path.to_string() == "@/Vol/sub/b.txt")
+    );
+    let sub = NullFsPath::from_to_str("@/Vol/sub")?;
+    assert_eq!(listed().await, vec![sub.clone(), sub]);
+
+    tokio::fs::remove_file(&state_file).await.ok();
+    tokio::fs::remove_dir_all(&root).await.ok();
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_apply_report() -> eyre::Result<()> {
+    let relay = spawn_mock_relay(|cfg| {
+        cfg.route(
+            "/v1/exists",
+            web::get().to(|params: web::Query<WithPath>| async move {
+                HttpResponse::Ok().json(!params.path.to_string().ends_with("gone.txt"))
+            }),
+        )
+        .route(
+            "/v1/download",
+            web::get().to(|params: web::Query<WithPath>| async move {
+                if params.path.to_string().ends_with("broken.txt") {
+                    return HttpResponse::InternalServerError().finish();
+                }
+                HttpResponse::Ok().body("content")
+            }),
+        )
+        .route(
+            "/v1/hash",
+            web::get().to(|| async {
+                HttpResponse::Ok().json(format!("{:x}", Sha256::digest(b"content")))
+            }),
+        );
+    })?;
+
+    let root = temp_path("apply");
+    tokio::fs::create_dir_all(&root).await?;
+    let mut fs = AnyFs::from_volume_item("Apply", &local_volume(&root))?;
+    fs.init().await?;
+
+    let file = |name: &str| -> eyre::Result<File> {
+        let path = NullFsPath::from_to_str(format!("@/Apply/{name}"))?;
+        Ok(File {
+            file_type: FileType::infer_from_path(&path),
+            path,
+            stat: FileStat {
+                node: NodeKind::File { size: 7 },
+                modified: 1000,
+                created: None,
+                accessed: None,
+            },
+        })
+    };
+
+    let share_node = mock_share_node(relay).await?;
+    share_node
+        .store
+        .stash(
+            vec![
+                Command::Write {
+                    file: file("ok.txt")?,
+                },
+                Command::Write {
+                    file: file("gone.txt")?,
+                },
+                Command::Touch {
+                    file: file("broken.txt")?,
+                },
+            ],
+            &fs,
+            None,
+        )
+        .await?;
+
+    let report = share_node.apply_commands(&fs, &[]).await?;
+    assert_eq!(report.applied, 1);
+    assert_eq!(report.skipped, 1);
+    assert_eq!(report.bytes, 7);
+    assert_eq!(report.failures.len(), 1);
+    assert!(
+        matches!(&report.failures[0].command, Command::Touch { file } if file.path.to_string().ends_with("broken.txt"))
+    );
+    assert_eq!(tokio::fs::read(root.join("ok.txt")).await?, b"content");
+    // the failed command waits for its retry
+    assert!(share_node.store.unstash("Apply").await?.is_empty());
+
+    tokio::fs::remove_dir_all(&root).await.ok();
+    Ok(())
+}
+
+#[test]
+fn test_quarantine_names() -> eyre::Result<()> {
+    let namer = QuarantineNamer::new("node:1/a");
+    let originals = [
+        NullFsPath::from_to_str("@/Vol/docs/report.final.pdf")?,
+        NullFsPath::from_to_str("@/Vol/CON.txt")?,
+        NullFsPath::from_to_str("@/Vol/what?.md ")?,
+        NullFsPath::from_to_str("@/Vol/.hidden")?,
+    ];
+
+    let mut seen = HashSet::new();
+    for i in 0..10_000u32 {
+        let original = &originals[i as usize % originals.len()];
+        let name = namer.name_for(original, b"same content", 1000);
+        assert!(
+            !name.contains(['<', '>', ':', '"', '/', '\\', '|', '?', '*']),
+            "{name}"
+        );
+        assert!(!name.ends_with(['.', ' ']), "{name}");
+        assert!(!name.to_uppercase().starts_with("CON."), "{name}");
+        assert!(seen.insert(name), "Duplicate quarantine name");
+    }
+
+    let path = namer.path_for(DEFAULT_QUARANTINE_DIR, &originals[0], b"x", 5)?;
+    let comps = path.components();
+    assert_eq!(comps[..3], ["Vol", DEFAULT_QUARANTINE_DIR, "docs"]);
+    assert!(comps[3].starts_with("report.final.conflict-node_1_a-5-"));
+    assert!(comps[3].ends_with(".pdf"));
+
+    Ok(())
+}
+
+#[test]
+fn test_audio_file_type() -> eyre::Result<()> {
+    let song = NullFsPath::from_to_str("@/music/song.flac")?;
+    assert_eq!(FileType::infer_from_path(&song), FileType::Audio);
+    assert_eq!(
+        FileType::mime_from_path(&song, &IndexMap::new()),
+        "audio/flac"
+    );
+    assert_eq!(serde_json::to_string(&FileType::Audio)?, "\"audio\"");
+
+    // state written before, or by newer versions, still reads
+    let parsed = serde_json::from_str::<Vec<FileType>>(r#"["image","unkown","hologram"]"#)?;
+    assert_eq!(
+        parsed,
+        [FileType::Image, FileType::Unkown, FileType::Unkown]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_file_type_sniffing() -> eyre::Result<()> {
+    let mut tar = vec![0u8; 512];
+    tar[257..262].copy_from_slice(b"ustar");
+    let mut bmp = b"BM\x36\0\0\0\0\0\0\0\x36\0\0\0\x28\0\0\0".to_vec();
+    bmp.resize(54, 0);
+    let mut exe = vec![0u8; 0x88];
+    exe[..2].copy_from_slice(b"MZ");
+    exe[0x3c] = 0x80;
+    exe[0x80..0x84].copy_from_slice(b"PE\0\0");
+    let samples: [(&[u8], FileType); 14] = [
+        (b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR", FileType::Image),
+        (b"\xff\xd8\xff\xe0\0\x10JFIF", FileType::Image),
+        (b"RIFF\0\0\0\0WEBPVP8 ", FileType::Image),
+        (b"\0\0\0\x18ftypmp42", FileType::Video),
+        (b"%PDF-1.7\n", FileType::Document),
+        (b"PK\x03\x04\x14\0", FileType::Archive),
+        (&tar, FileType::Archive),
+        (b"\x7fELF\x02\x01\x01", FileType::Executable),
+        (&bmp, FileType::Image),
+        (&exe, FileType::Executable),
+        // text starting with the same two letters as a bitmap or a windows program
+        (b"BMW owners club, minutes of the meeting", FileType::Text),
+        (
+            b"MZ-80 programs listing\n10 PRINT \"HELLO\"\n",
+            FileType::Text,
+        ),
+        ("plain text, caf\u{e9}".as_bytes(), FileType::Text),
+        (b"\0\x01\x02\x03", FileType::Unkown),
+    ];
+    assert_eq!(
+        FileType::infer_from_bytes(b"fLaC\0\0\0\x22"),
+        FileType::Audio
+    );
+    for (bytes, expected) in samples {
+        assert_eq!(FileType::infer_from_bytes(bytes), expected, "{bytes:?}");
+    }
+    // cut in the middle of a character
+    assert_eq!(
+        FileType::infer_from_bytes(&"caf\u{e9}".as_bytes()[..4]),
+        FileType::Text
+    );
+
+    let root = temp_path("sniffing");
+    tokio::fs::create_dir_all(&root).await?;
+    tokio::fs::write(root.join("picture"), b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR").await?;
+    // the extension wins over the content
+    tokio::fs::write(root.join("notes.txt"), b"%PDF-1.7\n").await?;
+
+    let mut fs = AnyFs::from_volume_item("Sniff", &local_volume(&root))?;
+    fs.init().await?;
+    let root_path = NullFsPath::from_to_str("@/Sniff")?;
+    for listing in [
+        fs.dir(&root_path).await?,
+        fs.dir_page(&root_path, &DirPage::default()).await?.entries,
+    ] {
+        let types = listing
+            .into_iter()
+            .map(|file| {
+                (
+                    file.path.file_name().unwrap_or_default().to_owned(),
+                    file.file_type,
+                )
+            })
+            .collect::<HashMap<_, _>>();
+        assert_eq!(types["picture"], FileType::Image);
+        assert_eq!(types["notes.txt"], FileType::Text);
+    }
+
+    tokio::fs::remove_dir_all(&root).await.ok();
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_snapshot_excludes_quarantine() -> eyre::Result<()> {
+    let root = temp_path("quarantine");
+    tokio::fs::create_dir_all(root.join("conflicts/sub")).await?;
+    tokio::fs::write(root.join("conflicts/sub/a.conflict-x.txt"), b"a").await?;
+    tokio::fs::write(root.join("kept.txt"), b"b").await?;
+
+    let mut fs = AnyFs::from_volume_item("Quarantine", &local_volume(&root))?;
+    fs.init().await?;
+    let state_file = root.with_extension("state.db");
+    let commands = Snapshot::new(fs)
+        .quarantine_dir("conflicts")
+        .capture(&state_file)
+        .await?;
+
+    let paths = commands
+        .iter()
+        .map(|command| match command {
+            Command::Write { file }
+            | Command::Touch { file }
+            | Command::Delete { file }
+            | Command::Rename { to: file, .. } => file.path.to_string(),
+        })
+        .collect::<HashSet<_>>();
+    assert!(paths.contains("@/Quarantine/kept.txt"));
+    assert!(paths.iter().all(|path| !path.contains("conflict")));
+
+    tokio::fs::remove_dir_all(&root).await.ok();
+    tokio::fs::remove_file(&state_file).await.ok();
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_conflicting_edits() -> eyre::Result<()> {
+    let serving = |content: &'static [u8]| {
+        spawn_mock_relay(move |cfg| {
+            cfg.route(
+                "/v1/exists",
+                web::get().to(|| async { HttpResponse::Ok().json(true) }),
+            )
+            .route(
+                "/v1/hash",
+                web::get().to(move || async move {
+                    HttpResponse::Ok().json(format!("{:x}", Sha256::digest(content)))
+                }),
+            )
+            .route(
+                "/v1/download",
+                web::get().to(move || async move { HttpResponse::Ok().body(content) }),
+            );
+        })
+    };
+
+    let root = temp_path("conflicts");
+    tokio::fs::create_dir_all(&root).await?;
+    let mut fs = AnyFs::from_volume_item("vol", &local_volume(&root))?;
+    fs.init().await?;
+
+    let path = NullFsPath::from_to_str("@/vol/notes.txt")?;
+    let write = |size: u64| Command::Write {
+        file: File {
+            file_type: FileType::infer_from_path(&path),
+            path: path.clone(),
+            stat: FileStat {
+                node: NodeKind::File { size },
+                modified: systime_to_millis(SystemTime::now()),
+                created: None,
+                accessed: None,
+            },
+        },
+    };
+    let conflict = conflict_sibling(&path, DEFAULT_CONFLICT_SUFFIX, "mock")?;
+    assert_eq!(conflict.to_string(), "@/vol/notes (conflict mock).txt");
+
+    let state_path = temp_path("conflicts-state.db");
+    let mut base = mock_share_node(serving(b"base")?).await?;
+    base.hashes_store = Some(StateStore::open(&state_path).await?);
+    base.run_command(&write(4), &fs, &[], &Prefetched::default())
+        .await?;
+    assert_eq!(tokio::fs::read(root.join("notes.txt")).await?, b"base");
+    drop(base);
+
+    // edited on both sides since the last sync, the node restarted in between
+    tokio::fs::write(root.join("notes.txt"), b"local edit").await?;
+    let store = StateStore::open(&state_path).await?;
+    let mut remote = mock_share_node(serving(b"remote edit")?).await?;
+    remote.hashes = Arc::new(tokio::sync::Mutex::new(store.load_synced().await?));
+    remote.hashes_store = Some(store);
+    remote
+        .run_command(&write(11), &fs, &[], &Prefetched::default())
+        .await?;
+    assert_eq!(
+        tokio::fs::read(root.join("notes.txt")).await?,
+        b"local edit"
+    );
+    assert_eq!(
+        tokio::fs::read(root.join("notes (conflict mock).txt")).await?,
+        b"remote edit"
+    );
+
+    // the kept version is the new base, a later remote edit applies as usual
+    let mut next = mock_share_node(serving(b"next remote edit")?).await?;
+    next.hashes = remote.hashes.clone();
+    next.run_command(&write(16), &fs, &[], &Prefetched::default())
+        .await?;
+    assert_eq!(
+        tokio::fs::read(root.join("notes.txt")).await?,
+        b"next remote edit"
+    );
+
+    tokio::fs::remove_dir_all(&root).await.ok();
+    tokio::fs::remove_file(&state_path).await.ok();
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_keep_versions() -> eyre::Result<()> {
+    let serving = |content: &'static [u8]| {
+        spawn_mock_relay(move |cfg| {
+            cfg.route(
+                "/v1/exists",
+                web::get().to(|| async { HttpResponse::Ok().json(true) }),
+            )
+            .route(
+                "/v1/hash",
+                web::get().to(move || async move {
+                    HttpResponse::Ok().json(format!("{:x}", Sha256::digest(content)))
+                }),
+            )
+            .route(
+                "/v1/download",
+                web::get().to(move || async move { HttpResponse::Ok().body(content) }),
+            );
+        })
+    };
+
+    let root = temp_path("versions");
+    tokio::fs::create_dir_all(&root).await?;
+    let mut fs = AnyFs::from_volume_item("vol", &local_volume(&root))?;
+    fs.init().await?;
+
+    let path = NullFsPath::from_to_str("@/vol/docs/report.txt")?;
+    let contents: [&'static [u8]; 5] = [b"v1", b"v2", b"v3", b"v4", b"v5"];
+    for (i, content) in contents.iter().enumerate() {
+        let write = Command::Write {
+            file: File {
+                file_type: FileType::infer_from_path(&path),
+                path: path.clone(),
+                stat: FileStat {
+                    node: NodeKind::File { size: 2 },
+                    modified: 1_700_000_000_000 + i as u64 * 1000,
+                    created: None,
+                    accessed: None,
+                },
+            },
+        };
+        let mut share_node = mock_share_node(serving(content)?).await?;
+        share_node.keep_versions = 3;
+        share_node
+            .run_command(&write, &fs, &[], &Prefetched::default())
+            .await?;
+
+        // overwritten three times, three prior versions
+        if i == 3 {
+            let versions = root.join(VERSIONS_DIR).join("docs/report.txt");
+            for (j, content) in contents[..3].iter().enumerate() {
+                let version = versions.join(format!("{}.txt", 1_700_000_000_000 + j * 1000));
+                assert_eq!(tokio::fs::read(version).await?, *content);
+            }
+        }
+    }
+    assert_eq!(tokio::fs::read(root.join("docs/report.txt")).await?, b"v5");
+
+    // only the newest are kept
+    let versions = versions_dir(&path)?;
+    assert_eq!(
+        versions.to_string(),
+        "@/vol/.nullfs-versions/docs/report.txt"
+    );
+    let mut kept = fs
+        .dir(&versions)
+        .await?
+        .into_iter()
+        .map(|version| version.path.file_name().unwrap_or_default().to_owned())
+        .collect::<Vec<_>>();
+    kept.sort();
+    assert_eq!(
+        kept,
+        [
+            "1700000001000.txt",
+            "1700000002000.txt",
+            "1700000003000.txt"
+        ]
+    );
+
+    // and never sync
+    let state_file = temp_path("versions.db");
+    let commands = Snapshot::new(fs).capture(&state_file).await?;
+    assert!(
+        commands.iter().all(|command| match command {
+            Command::Write { file } | Command::Touch { file } | Command::Delete { file } =>
+                !file.path.to_string().contains(VERSIONS_DIR),
+            Command::Rename { .. } => true,
+        }),
+        "{commands:?}"
+    );
+
+    tokio::fs::remove_file(&state_file).await.ok();
+    tokio::fs::remove_dir_all(&root).await.ok();
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_delta_transfer() -> eyre::Result<()> {
+    let mut previous = vec![0u8; 4 * 1024 * 1024];
+    rand::fill(&mut previous[..]);
+    let mut edited = previous.clone();
+    rand::fill(&mut edited[2 * 1024 * 1024..2 * 1024 * 1024 + 100]);
+
+    let hash = format!("{:x}", Sha256::digest(&edited));
+    let mut chunker = Chunker::default();
+    chunker.update(&edited);
+    let chunks = chunker.finish();
+    let content = Bytes::from(edited.clone());
+    let served = Arc::new(AtomicU64::new(0));
+    let relay = {
+        let served = served.clone();
+        spawn_mock_relay(move |cfg| {
+            let (hash, chunks) = (hash.clone(), chunks.clone());
+            let (content, served) = (content.clone(), served.clone());
+            cfg.route(
+                "/v1/exists",
+                web::get().to(|| async { HttpResponse::Ok().json(true) }),
+            )
+            .route(
+                "/v1/hash",
+                web::get().to(move || {
+                    let hash = hash.clone();
+                    async move { HttpResponse::Ok().json(hash) }
+                }),
+            )
+            .route(
+                "/v1/chunks",
+                web::get().to(move || {
+                    let chunks = chunks.clone();
+                    async move { HttpResponse::Ok().json(chunks) }
+                }),
+            )
+            .route(
+                "/v1/download",
+                web::get().to(move |req: HttpRequest| {
+                    let (content, served) = (content.clone(), served.clone());
+                    async move {
+                        let range = req
+                            .headers()
+                            .get("range")
+                            .and_then(|value| value.to_str().ok())
+                            .and_then(|value| value.strip_prefix("bytes="))
+                            .and_then(|value| value.split_once('-'))
+                            .and_then(|(start, end)| {
+                                Some((start.parse::<usize>().ok()?, end.parse::<usize>().ok()?))
+                            });
+                        let (mut resp, body) = match range {
+                            Some((start, end)) => (
+                                HttpResponse::PartialContent(),
+                                content.slice(start..end + 1),
+                            ),
+                            None => (HttpResponse::Ok(), content),
+                        };
+                        served.fetch_add(body.len() as u64, Ordering::Relaxed);
+                        resp.body(body)
+                    }
+                }),
+            );
+        })?
+    };
+
+    let root = temp_path("delta");
+    tokio::fs::create_dir_all(&root).await?;
+    tokio::fs::write(root.join("disk.img"), &previous).await?;
+    let mut fs = AnyFs::from_volume_item("vol", &local_volume(&root))?;
+    fs.init().await?;
+
+    let path = NullFsPath::from_to_str("@/vol/disk.img")?;
+    let touch = Command::Touch {
+        file: File {
+            file_type: FileType::infer_from_path(&path),
+            path: path.clone(),
+            stat: FileStat {
+                node: NodeKind::File {
+                    size: edited.len() as u64,
+                },
+                modified: 1_700_000_000_000,
+                created: None,
+                accessed: None,
+            },
+        },
+    };
+    let outcome = mock_share_node(relay)
+        .await?
+        .run_command(&touch, &fs, &[], &Prefetched::default())
+        .await?;
+
+    // only the chunks around the edit went over the wire
+    let CommandOutcome::Applied { bytes } = outcome else {
+        eyre::bail!("Expected the touch to be applied, got {outcome:?}");
+    };
+    assert!(bytes > 0 && bytes <= 2 * MAX_CHUNK_SIZE as u64);
+    assert_eq!(served.load(Ordering::Relaxed), bytes);
+    assert_eq!(tokio::fs::read(root.join("disk.img")).await?, edited);
+
+    tokio::fs::remove_dir_all(&root).await.ok();
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_merkle_skips_unchanged_subtrees() -> eyre::Result<()> {
+    let remote_root = temp_path("merkle-remote");
+    let local_root = temp_path("merkle-local");
+    for root in [&remote_root, &local_root] {
+        tokio::fs::create_dir_all(root.join("same/nested")).await?;
+        tokio::fs::create_dir_all(root.join("diff")).await?;
+        tokio::fs::write(root.join("same/a.txt"), b"a").await?;
+        tokio::fs::write(root.join("same/nested/b.txt"), b"b").await?;
+    }
+    tokio::fs::write(remote_root.join("diff/c.txt"), b"remote").await?;
+    tokio::fs::write(local_root.join("diff/c.txt"), b"local").await?;
+
+    let mut remote = AnyFs::from_volume_item("Merkle", &local_volume(&remote_root))?;
+    remote.init().await?;
+    let state_file = remote_root.with_extension("merkle.db");
+
+    // the relay tree matches a plain recursive hash of the volume
+    let root = remote.volume_root()?;
+    let node = Snapshot::new(remote.clone())
+        .merkle_node(&state_file, &root)
+        .await?
+        .expect("root node");
+    assert_eq!(node.hash, remote.hash(&root).await?);
+    assert_eq!(node.children.len(), 2);
+
+    let relay = spawn_mock_relay(move |cfg| {
+        let remote = remote.clone();
+        let state_file = state_file.clone();
+        cfg.route(
+            "/v1/merkle",
+            web::get().to(move |params: web::Query<WithPath>| {
+                let (remote, state_file) = (remote.clone(), state_file.clone());
+                async move {
+                    match Snapshot::new(remote)
+                        .merkle_node(&state_file, &params.path)
+                        .await
+                    {
+                        Ok(Some(node)) => HttpResponse::Ok().json(node),
+                        _ => HttpResponse::NotFound().finish(),
+                    }
+                }
+            }),
+        )
+        .route(
+            "/v1/exists",
+            web::get().to(|| async { HttpResponse::Ok().json(true) }),
+        )
+        .route(
+            "/v1/hash",
+            web::get().to(|| async {
+                HttpResponse::Ok().json(format!("{:x}", Sha256::digest(b"remote")))
+            }),
+        )
+        .route(
+            "/v1/download",
+            web::get().to(|| async { HttpResponse::Ok().body("remote") }),
+        );
+    })?;
+
+    let mut local = AnyFs::from_volume_item("Merkle", &local_volume(&local_root))?;
+    local.init().await?;
+    let mut share_node = mock_share_node(relay).await?;
+    share_node.merkle = true;
+
+    let file = |rel: &str, size: u64| -> eyre::Result<File> {
+        let path = NullFsPath::from_to_str(format!("@/Merkle/{rel}"))?;
+        Ok(File {
+            file_type: FileType::infer_from_path(&path),
+            path,
+            stat: FileStat {
+                node: NodeKind::File { size },
+                modified: 1000,
+                created: None,
+                accessed: None,
+            },
+        })
+    };
+
+    let targets = [
+        NullFsPath::from_to_str("@/Merkle/same/nested/b.txt")?,
+        NullFsPath::from_to_str("@/Merkle/diff/c.txt")?,
+    ];
+    let unchanged = share_node.unchanged_paths(&local, &targets).await?;
+    assert_eq!(unchanged, [NullFsPath::from_to_str("@/Merkle/same")?]);
+
+    share_node
+        .store
+        .stash(
+            vec![
+                Command::Touch {
+                    file: file("same/nested/b.txt", 1)?,
+                },
+                Command::Touch {
+                    file: file("diff/c.txt", 6)?,
+                },
+            ],
+            &local,
+            None,
+        )
+        .await?;
+
+    let report = share_node.apply_commands(&local, &[]).await?;
+    assert_eq!((report.applied, report.skipped), (1, 1));
+    assert_eq!(
+        tokio::fs::read(local_root.join("diff/c.txt")).await?,
+        b"remote"
+    );
+
+    tokio::fs::remove_dir_all(&remote_root).await.ok();
+    tokio::fs::remove_dir_all(&local_root).await.ok();
+    tokio::fs::remove_file(remote_root.with_extension("merkle.db"))
+        .await
+        .ok();
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_s3_volume() -> eyre::Result<()> {
+    let store = Arc::new(object_store::memory::InMemory::new());
+    let mut fs = S3Volume::with_store("Bucket", Some("backups/node".to_owned()), store.clone());
+    fs.init().await?;
+
+    let path = NullFsPath::from_to_str("@/Bucket/media/a/b.txt")?;
+    assert_eq!(fs.resolve(&path)?.as_ref(), "backups/node/media/a/b.txt");
+    assert_eq!(fs.to_virtual(&fs.resolve(&path)?)?, path);
+
+    let file = |rel: &str| -> eyre::Result<File> {
+        let path = NullFsPath::from_to_str(format!("@/Bucket/{rel}"))?;
+        Ok(File {
+            file_type: FileType::infer_from_path(&path),
+            path,
+            stat: FileStat {
+                node: NodeKind::File { size: 0 },
+                modified: 0,
+                created: None,
+                accessed: None,
+            },
+        })
+    };
+    fs.write(&file("media/a/b.txt")?, b"content").await?;
+    fs.write(&file("media/c.txt")?, b"other").await?;
+
+    let media = NullFsPath::from_to_str("@/Bucket/media")?;
+    let mut listing = fs
+        .dir(&media)
+        .await?
+        .into_iter()
+        .map(|entry| (entry.path.to_string(), entry.stat.node))
+        .collect::<Vec<_>>();
+    listing.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(
+        listing,
+        [
+            ("@/Bucket/media/a".to_owned(), NodeKind::Dir),
+            (
+                "@/Bucket/media/c.txt".to_owned(),
+                NodeKind::File { size: 5 }
+            ),
+        ]
+    );
+
+    assert!(fs.stats(&media).await?.is_dir());
+    assert!(fs.exists(&path).await?);
+    assert_eq!(fs.read(&path).await?, b"content");
+    // the in-memory store etags are not md5 digests, the content gets hashed
+    assert_eq!(
+        fs.hash(&path).await?,
+        format!("{:x}", Sha256::digest(b"content"))
+    );
+
+    let mut dir = file("media")?;
+    dir.stat.node = NodeKind::Dir;
+    fs.delete(&dir).await?;
+    assert!(!fs.exists(&path).await?);
+    assert!(!fs.exists(&media).await?);
+
+    assert!(is_plain_md5("\"9a0364b9e99bb480dd25e1f0284c8555\""));
+    assert!(!is_plain_md5("\"9a0364b9e99bb480dd25e1f0284c8555-2\""));
+
+    Ok(())
+}
+
+/// Filesystem model where every command assigns a fixed value to a region, which is what the
+/// relay commands amount to: writes fetch the relay version, deletes remove a whole subtree
+fn apply_model(fs: &mut BTreeMap<String, String>, command: &(bool, &str)) {
+    match command {
+        (true, path) => {
+            fs.insert(path.to_string(), format!("remote:{path}"));
+        }
+        (false, path) => {
+            fs.retain(|p, _| p != path && !p.starts_with(&format!("{path}/")));
+        }
+    }
+}
+
+#[test]
+fn test_collapsing_preserves_net_effect() {
+    let pool = [
+        (true, "a"),
+        (true, "a/b"),
+        (false, "a"),
+        (true, "c"),
+        (false, "c"),
+        (true, "a/d"),
+    ];
+    let mut rng = rand::rng();
+
+    for _ in 0..5_000 {
+        // random sequences made of random blocks, blocks are sometimes repeated back to back
+        let mut seq = vec![];
+        while seq.len() < 24 {
+            let len = rng.random_range(1..=4);
+            let block = (0..len)
+                .map(|_| pool[rng.random_range(0..pool.len())])
+                .collect::<Vec<_>>();
+            for _ in 0..rng.random_range(1..=3) {
+                seq.extend(block.iter().copied());
+            }
+        }
+
+        let initial = BTreeMap::from([("a/e".to_owned(), "local".to_owned())]);
+        let mut expected = initial.clone();
+        let mut actual = initial;
+        seq.iter().for_each(|cmd| apply_model(&mut expected, cmd));
+        reduce_contiguous_subsequences(&seq)
+            .iter()
+            .for_each(|cmd| apply_model(&mut actual, cmd));
+
+        assert_eq!(expected, actual, "{seq:?}");
+    }
+}
+
+/// Quadratic reference of [`reduce_contiguous_subsequences`]
+fn reduce_contiguous_subsequences_naive<T: Eq + Clone>(seq: &[T]) -> Vec<T> {
+    let mut out = vec![];
+    let mut i = 0;
+
+    while i < seq.len() {
+        out.push(seq[i].clone());
+
+        let mut skip = 0;
+        for len in (1..=out.len().min(seq.len() - i - 1)).rev() {
+            if out[out.len() - len..] == seq[i + 1..i + 1 + len] {
+                skip = len;
+                break;
+            }
+        }
+
+        i += 1 + skip;
+    }
+
+    out
+}
+
+#[test]
+fn test_reduce_matches_naive() {
+    let seq = [1, 2, 3, 1, 2, 3, 4, 5, 4, 5, 1, 2];
+    assert_eq!(reduce_contiguous_subsequences(&seq), [1, 2, 3, 4, 5, 1, 2]);
+    assert!(reduce_contiguous_subsequences::<u8>(&[]).is_empty());
+
+    let mut rng = rand::rng();
+    for _ in 0..10_000 {
+        let alphabet = rng.random_range(1..=5);
+        let seq = (0..rng.random_range(0..64))
+            .map(|_| rng.random_range(0..alphabet))
+            .collect::<Vec<u8>>();
+        assert_eq!(
+            reduce_contiguous_subsequences(&seq),
+            reduce_contiguous_subsequences_naive(&seq),
+            "{seq:?}"
+        );
+    }
+
+    // a big change: thousands of distinct commands, some of them pulled twice
+    let hashes = (0..5_000)
+        .map(|i| format!("{:x}", i % 4_000))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        reduce_contiguous_subsequences(&hashes),
+        reduce_contiguous_subsequences_naive(&hashes)
+    );
+}
+
+/// Net effect of the relay commands on the entries of a volume, a file replaces whatever
+/// was below its path and a delete removes a whole subtree
+fn apply_command_model(fs: &mut HashMap<NullFsPath, FileStat>, command: &Command) {
+    match command {
+        Command::Write { file } | Command::Touch { file } => {
+            if file.stat.is_file() {
+                fs.retain(|path, _| !path.is_within(&file.path));
+            }
+            fs.insert(file.path.clone(), file.stat.clone());
+        }
+        Command::Delete { file } => fs.retain(|path, _| !path.is_within(&file.path)),
+        Command::Rename { from, to } => {
+            let (moved, rest) = std::mem::take(fs)
+                .into_iter()
+                .partition::<HashMap<_, _>, _>(|(path, _)| path.is_within(&from.path));
+            *fs = rest;
+            fs.retain(|path, _| !path.is_within(&to.path));
+            let depth = from.path.components().len();
+            for (path, stat) in moved {
+                let below = path.components()[depth..].to_vec();
+                fs.insert(to.path.extend(below).unwrap(), stat);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_needed_commands() -> eyre::Result<()> {
+    let file = |path: &str, size: u64| -> eyre::Result<File> {
+        let path = NullFsPath::from_to_str(format!("@/vol/{path}"))?;
+        Ok(File {
+            file_type: FileType::infer_from_path(&path),
+            path,
+            stat: FileStat {
+                node: match size {
+                    0 => NodeKind::Dir,
+                    size => NodeKind::File { size },
+                },
+                modified: 1_700_000_000_000 + size,
+                created: None,
+                accessed: None,
+            },
+        })
+    };
+    let write = |path, size| file(path, size).map(|file| Command::Write { file });
+    let delete = |path, size| file(path, size).map(|file| Command::Delete { file });
+
+    // written then deleted, never needed to land
+    let seq = [write("a/b", 1)?, write("c", 2)?, delete("a", 0)?];
+    assert_eq!(needed_commands(&seq), [false, true, true]);
+
+    // moved elsewhere before the delete
+    let seq = [
+        write("a", 1)?,
+        Command::Rename {
+            from: file("a", 1)?,
+            to: file("b", 1)?,
+        },
+        delete("a", 1)?,
+    ];
+    assert_eq!(needed_commands(&seq), [true, true, true]);
+
+    // deleted then written back as it was, or not
+    let seq = [delete("a", 1)?, write("a", 1)?];
+    assert_eq!(needed_commands(&seq), [false, true]);
+    let seq = [delete("a", 1)?, write("a", 2)?];
+    assert_eq!(needed_commands(&seq), [true, true]);
+
+    let pool = [
+        write("a", 1)?,
+        write("a", 2)?,
+        delete("a", 1)?,
+        delete("a", 0)?,
+        write("a", 0)?,
+        write("a/b", 3)?,
+        delete("a/b", 3)?,
+        Command::Touch {
+            file: file("c", 4)?,
+        },
+        delete("c", 4)?,
+        Command::Rename {
+            from: file("a", 0)?,
+            to: file("c", 0)?,
+        },
+        Command::Rename {
+            from: file("c", 4)?,
+            to: file("a", 4)?,
+        },
+    ];
+    let mut rng = rand::rng();
+    for _ in 0..5_000 {
+        let seq = (0..rng.random_range(1..12))
+            .map(|_| pool[rng.random_range(0..pool.len())].clone())
+            .collect::<Vec<_>>();
+
+        let initial =
+            HashMap::from([(NullFsPath::from_to_str("@/vol/a/e")?, file("a/e", 5)?.stat)]);
+        let mut expected = initial.clone();
+        let mut actual = initial;
+        seq.iter()
+            .for_each(|cmd| apply_command_model(&mut expected, cmd));
+        seq.iter()
+            .zip(needed_commands(&seq))
+            .filter(|(_, needed)| *needed)
+            .for_each(|(cmd, _)| apply_command_model(&mut actual, cmd));
+
+        assert_eq!(expected, actual, "{seq:#?}");
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_unstash_collapsing() -> eyre::Result<()> {
+    let root = temp_path("collapse");
+    tokio::fs::create_dir_all(&root).await?;
+    let mut fs = AnyFs::from_volume_item("Collapse", &local_volume(&root))?;
+    fs.init().await?;
+
+    let commands = sample_commands(2)?;
+    let repeated = [commands.clone(), commands].concat();
+
+    let stash_file = temp_path("stash.db");
+    let stash = CommandStash::open(&stash_file).await?;
+    stash.stash(repeated.clone(), &fs, Some("peer-b")).await?;
+    assert!(stash_file.with_extension("db-wal").exists());
+    let ops = stash.unstash("Collapse").await?;
+    assert_eq!(ops.len(), 2);
+
+    // the repetitions left out are retired without counting as applied
+    let counts = stash.counts("Collapse").await?;
+    assert_eq!((counts.done, counts.collapsed), (0, 2));
+    let kept = stash
+        .suppress_echoes("Collapse", "peer-b", sample_commands(2)?)
+        .await?;
+    assert_eq!(kept.len(), 2);
+
+    for op in &ops {
+        stash.mark_done(op).await?;
+    }
+    assert!(stash.unstash("Collapse").await?.is_empty());
+    assert_eq!(stash.counts("Collapse").await?.done, 2);
+    assert_eq!(stash.vacuum().await?, 2);
+    assert_eq!(stash.counts("Collapse").await?.collapsed, 0);
+
+    let stash = CommandStash::open(&temp_path("stash.db"))
+        .await?
+        .collapsing(false);
+    stash.stash(repeated, &fs, None).await?;
+    assert_eq!(stash.unstash("Collapse").await?.len(), 4);
+
+    tokio::fs::remove_dir_all(&root).await.ok();
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_failed_commands_dead_letter() -> eyre::Result<()> {
+    let fs = AnyFs {
+        volume_name: "vol".to_owned(),
+        fs_instance: Arc::new(tokio::sync::RwLock::new(MemVolume::new("vol"))),
+    };
+    let stash_file = temp_path("stash.db");
+    let stash = CommandStash::open(&stash_file).await?.max_attempts(2);
+    stash.stash(sample_commands(1)?, &fs, None).await?;
+
+    let mut op = stash.unstash("vol").await?.remove(0);
+    stash.mark_failed(&op, "relay down").await?;
+    assert!(stash.unstash("vol").await?.is_empty());
+
+    // pretend the retry time came, the second failure is the last one
+    op.attempts = 1;
+    stash.mark_failed(&op, "relay down").await?;
+    assert!(stash.unstash("vol").await?.is_empty());
+
+    let pool = sqlx::SqlitePool::connect(&format!("sqlite://{}", stash_file.display())).await?;
+    let (state, attempts): (i32, u32) = sqlx::query_as("SELECT state, attempts FROM Command")
+        .fetch_one(&pool)
+        .await?;
+    assert_eq!((state, attempts), (-1, 2));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_retry_busy_stash() -> eyre::Result<()> {
+    let stash_file = temp_path("stash.db");
+    CommandStash::open(&stash_file).await?;
+
+    let options = SqliteConnectOptions::from_str(&format!("sqlite://{}", stash_file.display()))?;
+    let holder = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect_with(options.clone())
+        .await?;
+    // no busy timeout, a locked database is reported right away
+    let writer = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect_with(options.busy_timeout(Duration::ZERO))
+        .await?;
+
+    let mut lock = holder.begin().await?;
+    sqlx::query("UPDATE Command SET state = 5")
+        .execute(&mut *lock)
+        .await?;
+    let update = "UPDATE Command SET state = 0";
+    let refused = sqlx::query(update).execute(&writer).await.unwrap_err();
+    assert!(is_busy(&refused), "{refused}");
+
+    let release = tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        lock.commit().await
+    });
+    let attempts = AtomicU32::new(0);
+    retry_busy(10, || {
+        attempts.fetch_add(1, Ordering::Relaxed);
+        sqlx::query(update).execute(&writer)
+    })
+    .await?;
+    assert!(attempts.load(Ordering::Relaxed) > 1);
+    release.await??;
+
+    // other errors are not retried
+    attempts.store(0, Ordering::Relaxed);
+    let missing = retry_busy(10, || {
+        attempts.fetch_add(1, Ordering::Relaxed);
+        sqlx::query("SELECT * FROM Missing").execute(&writer)
+    })
+    .await;
+    assert!(missing.is_err());
+    assert_eq!(attempts.load(Ordering::Relaxed), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_suppress_echoes() -> eyre::Result<()> {
+    let fs = AnyFs {
+        volume_name: "vol".to_owned(),
+        fs_instance: Arc::new(tokio::sync::RwLock::new(MemVolume::new("vol"))),
+    };
+    let stash = CommandStash::open(&temp_path("stash.db")).await?;
+    stash
+        .stash(sample_commands(3)?, &fs, Some("peer-b"))
+        .await?;
+    for op in stash.unstash("vol").await? {
+        stash.mark_done(&op).await?;
+    }
+
+    // captured back after being applied, peer-b already has them
+    let echoes = stash
+        .suppress_echoes("vol", "peer-b", sample_commands(3)?)
+        .await?;
+    assert!(echoes.is_empty());
+    let kept = stash
+        .suppress_echoes("vol", "peer-c", sample_commands(3)?)
+        .await?;
+    assert_eq!(kept.len(), 3);
+
+    // changed locally since
+    let Command::Write { file } = &sample_commands(1)?[0] else {
+        unreachable!()
+    };
+    let mut edited = file.clone();
+    edited.stat.modified = systime_to_millis(SystemTime::now()) + 60_000;
+    let local = vec![
+        Command::Write { file: edited },
+        Command::Delete { file: file.clone() },
+    ];
+    let kept = stash
+        .suppress_echoes("vol", "peer-b", local.clone())
+        .await?;
+    assert_eq!(kept, local);
+
+    // matched through the destination of a rename, the other paths are left alone
+    let moved = File {
+        path: NullFsPath::from_to_str("@/vol/moved.txt")?,
+        ..file.clone()
+    };
+    let rename = Command::Rename {
+        from: file.clone(),
+        to: moved.clone(),
+    };
+    stash
+        .stash(vec![rename.clone()], &fs, Some("peer-b"))
+        .await?;
+    for op in stash.unstash("vol").await? {
+        stash.mark_done(&op).await?;
+    }
+    let untouched = Command::Write {
+        file: File {
+            path: NullFsPath::from_to_str("@/vol/untouched.txt")?,
+            ..file.clone()
+        },
+    };
+    let kept = stash
+        .suppress_echoes("vol", "peer-b", vec![rename, untouched.clone()])
+        .await?;
+    assert_eq!(kept, vec![untouched]);
+    assert!(
+        stash
+            .suppress_echoes("vol", "peer-b", vec![])
+            .await?
+            .is_empty()
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_vacuum_keeps_echo_suppression() -> eyre::Result<()> {
+    let fs = AnyFs {
+        volume_name: "vol".to_owned(),
+        fs_instance: Arc::new(tokio::sync::RwLock::new(MemVolume::new("vol"))),
+    };
+    let stash = CommandStash::open(&temp_path("stash.db")).await?;
+    let commands = sample_commands(3)?;
+    stash.stash(commands.clone(), &fs, Some("peer-b")).await?;
+    for op in stash.unstash("vol").await? {
+        stash.mark_done(&op).await?;
+    }
+
+    // applied again since, only the last command of a path is kept
+    let Command::Write { file } = &commands[0] else {
+        unreachable!()
+    };
+    let mut touched = file.clone();
+    touched.stat.modified += 1;
+    let again = vec![Command::Touch { file: touched }];
+    stash.stash(again.clone(), &fs, Some("peer-b")).await?;
+    for op in stash.unstash("vol").await? {
+        stash.mark_done(&op).await?;
+    }
+
+    assert_eq!(stash.vacuum().await?, 1);
+    assert!(
+        stash
+            .suppress_echoes("vol", "peer-b", commands)
+            .await?
+            .is_empty()
+    );
+    assert!(
+        stash
+            .suppress_echoes("vol", "peer-b", again)
+            .await?
+            .is_empty()
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_reload_keeps_previous_config() -> eyre::Result<()> {
+    let root = temp_path("reload");
+    tokio::fs::create_dir_all(&root).await?;
+    let config_file = root.with_extension("yaml");
+    let config_with = |node: &str, root: &Path| {
+        format!(
+            "name: {node}\naddress: 127.0.0.1\nport: 5560\nusers: []\nrelayNodes: {{}}\n\
+             volumes:\n  Docs:\n    store:\n      type: local\n      root: {}\n    \
+             allow: []\n    pullFrom: []\n",
+            root.display()
+        )
+    };
+
+    tokio::fs::write(&config_file, config_with("before", &root)).await?;
+    let mut current = Arc::new(NodeConfig::load_checked(&config_file).await?);
+
+    // parses and validates, but the volume root does not exist
+    tokio::fs::write(&config_file, config_with("after", &root.join("missing"))).await?;
+    assert!(
+        NodeConfig::reload(&mut current, &config_file)
+            .await
+            .is_err()
+    );
+    assert_eq!(current.name, "before");
+
+    tokio::fs::write(&config_file, config_with("after", &root)).await?;
+    NodeConfig::reload(&mut current, &config_file).await?;
+    assert_eq!(current.name, "after");
+
+    tokio::fs::remove_dir_all(&root).await.ok();
+    tokio::fs::remove_file(&config_file).await.ok();
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_live_config_reload() -> eyre::Result<()> {
+    let root = temp_path("live");
+    tokio::fs::create_dir_all(&root).await?;
+    let config_file = root.with_extension("yaml");
+    let config_with = |node: &str, port: u16, root: &Path| {
+        format!(
+            "name: {node}\naddress: 127.0.0.1\nport: {port}\nusers: []\nrelayNodes: {{}}\n\
+             volumes:\n  Docs:\n    store:\n      type: local\n      root: {}\n    \
+             allow: []\n    pullFrom: []\n",
+            root.display()
+        )
+    };
+    tokio::fs::write(&config_file, config_with("before", 5574, &root)).await?;
+    let live = web::Data::new(LiveConfig::new(Arc::new(
+        NodeConfig::load_checked(&config_file).await?,
+    )));
+
+    let app = actix_web::test::init_service(
+        App::new()
+            .app_data(live.clone())
+            .app_data(web::Data::new(VolumeStates::default()))
+            .service(web::scope("/v1").configure(api_routes)),
+    )
+    .await;
+    let node_name = async || {
+        let req = actix_web::test::TestRequest::get()
+            .uri("/v1/health")
+            .to_request();
+        let health: serde_json::Value = actix_web::test::call_and_read_body_json(&app, req).await;
+        health["name"].clone()
+    };
+    assert_eq!(node_name().await, "before");
+
+    // rejected, the node keeps serving the previous configuration
+    tokio::fs::write(
+        &config_file,
+        config_with("after", 5574, &root.join("missing")),
+    )
+    .await?;
+    assert!(live.reload(&config_file).await.is_err());
+    assert_eq!(node_name().await, "before");
+
+    // swapped in, the server only binds the new port after a restart
+    tokio::fs::write(&config_file, config_with("after", 5575, &root)).await?;
+    live.reload(&config_file).await?;
+    assert_eq!(node_name().await, "after");
+    assert_eq!(live.current().port, 5575);
+
+    tokio::fs::remove_dir_all(&root).await.ok();
+    tokio::fs::remove_file(&config_file).await.ok();
+    Ok(())
+}
+
+#[test]
+fn test_obsolete_config_keys() -> eyre::Result<()> {
+    let config = "name: node\naddress: 127.0.0.1\nport: 5589\ncompressState: true\nusers: []\n\
+                  relayNodes: {}\nvolumes: {}\n";
+    // still accepted, only warned about
+    serde_yaml::from_str::<NodeConfig>(config)?;
+    let obsolete = obsolete_keys(config)
+        .into_iter()
+        .map(|(key, _)| key)
+        .collect::<Vec<_>>();
+    assert_eq!(obsolete, vec!["compressState"]);
+
+    assert!(obsolete_keys(&config.replace("compressState: true\n", "")).is_empty());
+    assert!(obsolete_keys("not: [a, mapping").is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn test_hashed_passwords() -> eyre::Result<()> {
+    let hash = hash_password("s3cr3t")?;
+    let config: NodeConfig = serde_yaml::from_str(&format!(
+        "name: node\naddress: 127.0.0.1\nport: 5576\nusers:\n  - name: hashed\n    \
+         password: \"{hash}\"\n  - name: plain\n    password: p\nrelayNodes: {{}}\n\
+         volumes:\n  Docs:\n    store:\n      type: memory\n    allow: [hashed, plain]\n    \
+         pullFrom: []\n"
+    ))?;
+    let user = |name: &str, password: &str| User {
+        name: name.to_owned(),
+        password: Some(password.to_owned()),
+    };
+
+    assert!(config.allow("Docs", &user("hashed", "s3cr3t")));
+    // served from the cache the second time
+    assert!(config.allow("Docs", &user("hashed", "s3cr3t")));
+    assert!(!config.allow("Docs", &user("hashed", "wrong")));
+    assert!(!config.allow("Docs", &user("hashed", &hash)));
+    assert!(config.allow("Docs", &user("plain", "p")));
+    assert!(!config.allow("Docs", &user("plain", "q")));
+    assert_eq!(
+        config.access_level("Docs", &user("plain", "p")),
+        Some(Access::Ro)
+    );
+
+    let plaintext = config
+        .users
+        .iter()
+        .filter(|user| user.has_plaintext_password())
+        .map(|user| user.name.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(plaintext, vec!["plain"]);
+
+    Ok(())
+}
+
+#[test]
+fn test_unknown_user_grant() -> eyre::Result<()> {
+    // not validated, as a reload slipping past validation would leave it
+    let config: NodeConfig = serde_yaml::from_str(
+        "name: node\naddress: 127.0.0.1\nport: 5576\nusers:\n  - name: u\n    password: p\n\
+         relayNodes: {}\nvolumes:\n  Docs:\n    store:\n      type: memory\n    \
+         allow: [gone, u]\n    pullFrom: []\n",
+    )?;
+    let user = |name: &str, password: &str| User {
+        name: name.to_owned(),
+        password: Some(password.to_owned()),
+    };
+
+    assert!(!config.allow("Docs", &user("gone", "p")));
+    assert_eq!(config.access_level("Docs", &user("gone", "p")), None);
+    // the grants after it still apply
+    assert!(config.allow("Docs", &user("u", "p")));
+
+    Ok(())
+}
+
+#[test]
+fn test_highest_grant_applies() -> eyre::Result<()> {
+    let config: NodeConfig = serde_yaml::from_str(
+        "name: node\naddress: 127.0.0.1\nport: 5576\nusers:\n  - name: alice\n    password: a\n\
+         \x20 - name: bob\n    password: b\nrelayNodes: {}\nvolumes:\n  Docs:\n    store:\n      \
+         type: memory\n    allow: [\"*\", {user: alice, access: rw}]\n    pullFrom: []\n",
+    )?;
+    let user = |name: &str, password: &str| User {
+        name: name.to_owned(),
+        password: Some(password.to_owned()),
+    };
+
+    // the wildcard comes first, the grant naming alice still applies
+    assert_eq!(
+        config.access_level("Docs", &user("alice", "a")),
+        Some(Access::Rw)
+    );
+    assert_eq!(
+        config.access_level("Docs", &user("bob", "b")),
+        Some(Access::Ro)
+    );
+    assert_eq!(config.access_level("Docs", &user("alice", "b")), None);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_config_env_vars() -> eyre::Result<()> {
+    let config_file = temp_path("env.yaml");
+    tokio::fs::write(
+        &config_file,
+        "name: ${NULLFS_NODE:-node}\naddress: 127.0.0.1\nport: 5573\n\
+         users:\n  - name: u\n    password: ${NULLFS_PW}\nrelayNodes: {}\nvolumes: {}\n",
+    )
+    .await?;
+
+    // only this test touches these variables
+    unsafe {
+        std::env::remove_var("NULLFS_PW");
+        std::env::remove_var("NULLFS_NODE");
+    }
+    let error = NodeConfig::load_from_file(&config_file).await.unwrap_err();
+    assert!(format!("{error:#}").contains("NULLFS_PW is not set"));
+
+    unsafe {
+        std::env::set_var("NULLFS_PW", "s3cr3t");
+    }
+    let config = NodeConfig::load_from_file(&config_file).await?;
+    assert_eq!(config.name, "node");
+    assert_eq!(config.users[0].password.as_deref(), Some("s3cr3t"));
+
+    let lookup = |name: &str| (name == "A").then(|| "a".to_owned());
+    assert_eq!(expand_env_vars("${A}-${B:-b}-${C:-}", lookup)?, "a-b-");
+    assert!(expand_env_vars("${A", lookup).is_err());
+    assert!(expand_env_vars("${A B}", lookup).is_err());
+
+    tokio::fs::remove_file(&config_file).await.ok();
+    Ok(())
+}
+
+#[test]
+fn test_refresh_jitter() -> eyre::Result<()> {
+    let config = |extra: &str| {
+        serde_yaml::from_str::<NodeConfig>(&format!(
+            "name: node\naddress: 127.0.0.1\nport: 5580\n{extra}\
+             users: []\nrelayNodes: {{}}\nvolumes: {{}}\n"
+        ))
+    };
+
+    assert_eq!(config("")?.refresh_delay(), Duration::from_secs(5));
+    assert_eq!(
+        config("refreshSecs: 0\n")?.refresh_delay(),
+        Duration::from_secs(1)
+    );
+
+    let jittered = config("refreshSecs: 2\nrefreshJitterSecs: 3\n")?;
+    let delays = (0..1000)
+        .map(|_| jittered.refresh_delay())
+        .collect::<Vec<_>>();
+    assert!(
+        delays
+            .iter()
+            .all(|delay| (Duration::from_secs(2)..=Duration::from_secs(5)).contains(delay))
+    );
+    assert!(delays.iter().any(|delay| *delay != delays[0]));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_data_dir() -> eyre::Result<()> {
+    let data_dir = temp_path("data-dir").join("nested");
+    let config: NodeConfig = serde_yaml::from_str(&format!(
+        "name: node\naddress: 127.0.0.1\nport: 5581\ndataDir: {}\n\
+         users: []\nrelayNodes: {{}}\nvolumes: {{}}\n",
+        data_dir.display()
+    ))?;
+    config.create_data_dir()?;
+
+    let identifier = NodeIdentifier::load_from_file(&config.data_dir().join(".id-node"))?;
+    CommandStash::new(&config, &identifier).await?;
+    assert!(data_dir.join(".id-node").exists());
+    assert!(
+        data_dir
+            .join(format!(".stash-{}.db", identifier.uuid))
+            .exists()
+    );
+
+    tokio::fs::remove_dir_all(data_dir.parent().unwrap())
+        .await
+        .ok();
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_stash_pool_settings() -> eyre::Result<()> {
+    let data_dir = temp_path("stash-pool");
+    let config_file = temp_path("stash-pool.yaml");
+    let yaml = |pool_size: u32| {
+        format!(
+            "name: node\naddress: 127.0.0.1\nport: 5585\ndataDir: {}\n\
+             stashPoolSize: {pool_size}\nstashCachePages: 2000\n\
+             users: []\nrelayNodes: {{}}\nvolumes: {{}}\n",
+            data_dir.display()
+        )
+    };
+
+    tokio::fs::write(&config_file, yaml(0)).await?;
+    let err = NodeConfig::load_from_file(&config_file).await.unwrap_err();
+    assert!(format!("{err:?}").contains("at least 1"));
+
+    // a single connection still serves concurrent queries, one after the other
+    tokio::fs::write(&config_file, yaml(1)).await?;
+    let config = NodeConfig::load_from_file(&config_file).await?;
+    config.create_data_dir()?;
+    let identifier = NodeIdentifier::load_from_file(&config.data_dir().join(".id-node"))?;
+    let stash = CommandStash::new(&config, &identifier).await?;
+    let (a, b) = tokio::join!(stash.counts("Docs"), stash.counts("Pics"));
+    assert_eq!(a?, b?);
+
+    tokio::fs::remove_dir_all(&data_dir).await.ok();
+    tokio::fs::remove_file(&config_file).await.ok();
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_config_problems() -> eyre::Result<()> {
+    let root = temp_path("problems");
+    tokio::fs::create_dir_all(&root).await?;
+    let config: NodeConfig = serde_yaml::from_str(&format!(
+        "name: node\naddress: 127.0.0.1\nport: 5571\nusers: []\n\
+         relayNodes:\n  relay-a:\n    address: http://127.0.0.1:5572\n    \
+         auth:\n      name: u\nvolumes:\n  Docs:\n    store:\n      type: local\n      \
+         root: {}\n    allow: []\n    pullFrom: [relay-a]\n  Missing:\n    store:\n      \
+         type: local\n      root: {}\n    allow: []\n    pullFrom: [relay-b]\n",
+        root.display(),
+        root.join("missing").display()
+    ))?;
+
+    let problems = config.problems().await;
+    assert_eq!(problems.len(), 2, "{problems:?}");
+    assert!(problems[0].starts_with("Volume \"Missing\": root"));
+    assert!(problems[1].contains("relay-b"));
+
+    tokio::fs::remove_dir_all(&root).await.ok();
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_snapshot_in_memory() -> eyre::Result<()> {
+    let mem = MemVolume::new("Mem");
+    let fs = AnyFs {
+        volume_name: "Mem".to_owned(),
+        fs_instance: Arc::new(tokio::sync::RwLock::new(mem.clone())),
+    };
+
+    let file = |rel: &str| -> eyre::Result<File> {
+        let path = NullFsPath::from_to_str(format!("@/Mem/{rel}"))?;
+        Ok(File {
+            file_type: FileType::infer_from_path(&path),
+            path,
+            stat: FileStat {
+                node: NodeKind::File { size: 0 },
+                modified: 0,
+                created: None,
+                accessed: None,
+            },
+        })
+    };
+    for rel in ["a.txt", "b.txt", "c/d.txt"] {
+        fs.write(&file(rel)?, rel.as_bytes()).await?;
+    }
+
+    let state_file = temp_path("state.db");
+    let snapshot = Snapshot::new(fs.clone());
+    let commands = snapshot.clone().capture(&state_file).await?;
+    assert_eq!(commands.len(), 4);
+
+    fs.copy(
+        &NullFsPath::from_to_str("@/Mem/c/d.txt")?,
+        &NullFsPath::from_to_str("@/Mem/new_dir/eee.txt")?,
+    )
+    .await?;
+    fs.write(&file("new_dir/fff.txt")?, b"fff").await?;
+    let commands = snapshot.clone().capture(&state_file).await?;
+    assert_eq!(commands.len(), 3);
+
+    let commands = snapshot.clone().capture(&state_file).await?;
+    assert_eq!(commands.len(), 0);
+
+    // mtimes move deterministically, no need to wait for the clock
+    mem.set_modified(&NullFsPath::from_to_str("@/Mem/a.txt")?, 1_000_000)?;
+    let commands = snapshot.clone().capture(&state_file).await?;
+    assert!(
+        matches!(&commands[..], [Command::Touch { file }] if file.path.to_string() == "@/Mem/a.txt")
+    );
+
+    fs.delete(&file("new_dir/fff.txt")?).await?;
+    let commands = snapshot.capture(&state_file).await?;
+    assert_eq!(commands.len(), 1);
+    assert!(matches!(commands[0], Command::Delete { .. }));
+
+    tokio::fs::remove_file(&state_file).await.ok();
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_schedule_by_priority() -> eyre::Result<()> {
+    let url = Url::parse("http://127.0.0.1:1")?;
+    let mut vol2relay: Vec<EdgeNodes> = vec![];
+    for (volume, priority) in [("Media", 0), ("Docs", 10), ("Photos", 5), ("Music", 0)] {
+        let fs = AnyFs::from_volume_item(volume, &local_volume(Path::new(".")))?;
+        let mut share_node = mock_share_node(url.clone()).await?;
+        share_node.volume_priority = priority;
+        vol2relay.push(vec![(fs, share_node)]);
+    }
+
+    for _ in 0..20 {
+        Synchronizer::schedule(&mut vol2relay);
+        let order = vol2relay
+            .iter()
+            .map(|edge_nodes| edge_nodes[0].0.get_volume_name())
+            .collect::<Vec<_>>();
+        assert_eq!(order[..2], ["Docs", "Photos"]);
+        assert!(order[2..].contains(&"Media".to_owned()));
+        assert!(order[2..].contains(&"Music".to_owned()));
+    }
+
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_rename_detection() -> eyre::Result<()> {
+    let mem = MemVolume::new("Mem");
+    let fs = AnyFs {
+        volume_name: "Mem".to_owned(),
+        fs_instance: Arc::new(tokio::sync::RwLock::new(mem)),
+    };
+    let path = |rel: &str| NullFsPath::from_to_str(format!("@/Mem/{rel}"));
+    let file = |rel: &str| -> eyre::Result<File> {
+        let path = path(rel)?;
+        Ok(File {
+            file_type: FileType::infer_from_path(&path),
+            path,
+            stat: FileStat {
+                node: NodeKind::File { size: 0 },
+                modified: 0,
+                created: None,
+                accessed: None,
+            },
+        })
+    };
+    fs.write(&file("a.txt")?, b"moved around").await?;
+    fs.write(&file("b.txt")?, b"same size!!!").await?;
+
+    let state_file = temp_path("state.db");
+    Snapshot::new(fs.clone()).capture(&state_file).await?;
+
+    fs.rename(&path("a.txt")?, &path("sub/a.txt")?).await?;
+    fs.delete(&file("b.txt")?).await?;
+    fs.write(&file("c.txt")?, b"other bytes!").await?;
+    let commands = Snapshot::new(fs.clone()).capture(&state_file).await?;
+
+    let rendered = commands.iter().map(|c| c.to_string()).collect::<Vec<_>>();
+    assert!(
+        rendered.contains(&"** @/Mem/a.txt -> @/Mem/sub/a.txt".to_owned()),
+        "{rendered:?}"
+    );
+    // same size, different content: not a rename
+    assert!(
+        rendered.iter().any(|c| c.starts_with("-- @/Mem/b.txt")),
+        "{rendered:?}"
+    );
+    assert!(
+        rendered.iter().any(|c| c.starts_with("++ @/Mem/c.txt")),
+        "{rendered:?}"
+    );
+    assert!(
+        !rendered.iter().any(|c| c.starts_with("-- @/Mem/a.txt")),
+        "{rendered:?}"
+    );
+    tokio::fs::remove_file(&state_file).await.ok();
+
+    // the receiving side moves its own copy instead of downloading it again
+    let content_hash = format!("{:x}", Sha256::digest(b"moved around"));
+    let relay = spawn_mock_relay(move |cfg| {
+        let content_hash = content_hash.clone();
+        cfg.route(
+            "/v1/exists",
+            web::get().to(|| async { HttpResponse::Ok().json(true) }),
+        )
+        .route(
+            "/v1/hash",
+            web::get().to(move || {
+                let content_hash = content_hash.clone();
+                async move { HttpResponse::Ok().json(content_hash) }
+            }),
+        );
+    })?;
+
+    let local = AnyFs {
+        volume_name: "Mem".to_owned(),
+        fs_instance: Arc::new(tokio::sync::RwLock::new(MemVolume::new("Mem"))),
+    };
+    local.write(&file("a.txt")?, b"moved around").await?;
+    let share_node = mock_share_node(relay).await?;
+    let rename = commands
+        .into_iter()
+        .find(|command| matches!(command, Command::Rename { .. }))
+        .unwrap();
+    share_node.store.stash(vec![rename], &local, None).await?;
+
+    let report = share_node.apply_commands(&local, &[]).await?;
+    assert_eq!((report.applied, report.bytes), (1, 0));
+    assert!(!local.exists(&path("a.txt")?).await?);
+    assert_eq!(local.read(&path("sub/a.txt")?).await?, b"moved around");
+
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_info_hides_store_options() -> eyre::Result<()> {
+    let config: NodeConfig = serde_yaml::from_str(
+        "name: node\naddress: 127.0.0.1\nport: 5590\nusers: []\nrelayNodes: {}\nvolumes:\n  \
+         Docs:\n    store:\n      type: vault\n      token: s3cr3t\n    allow: []\n    \
+         pullFrom: []\n",
+    )?;
+    let app = actix_web::test::init_service(
+        App::new()
+            .app_data(web::Data::new(Arc::new(config)))
+            .service(web::scope("/v1").configure(api_routes)),
+    )
+    .await;
+
+    let req = actix_web::test::TestRequest::get()
+        .uri("/v1/info")
+        .to_request();
+    let info: serde_json::Value = actix_web::test::call_and_read_body_json(&app, req).await;
+    assert_eq!(
+        info["volumes"]["Docs"]["store"],
+        serde_json::json!({ "type": "vault" })
+    );
+    assert!(!info.to_string().contains("s3cr3t"), "{info}");
+
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_liveness_cache() -> eyre::Result<()> {
+    let probes = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let relay_probes = probes.clone();
+    let relay = spawn_mock_relay(move |cfg| {
+        let probes = relay_probes.clone();
+        cfg.route(
+            "/v1/info",
+            web::get().to(move || {
+                probes.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                async { HttpResponse::Ok().json(serde_json::json!({})) }
+            }),
+        );
+    })?;
+
+    let share_node = mock_share_node(relay).await?;
+    assert!(share_node.is_alive().await?);
+    assert!(share_node.is_alive().await?);
+    assert_eq!(probes.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+    // a failed request means the relay has to be probed again
+    let mut down = mock_share_node(Url::parse("http://127.0.0.1:1")?).await?;
+    down.liveness_ttl = Duration::from_secs(3600);
+    assert!(!down.is_alive().await?);
+    assert_eq!(down.liveness(), Some(false));
+    assert!(
+        down.remote_exists(&NullFsPath::from_to_str("@/vol/a")?)
+            .await
+            .is_err()
+    );
+    assert_eq!(down.liveness(), None);
+
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_relay_priority_order() -> eyre::Result<()> {
+    let mock_relay = || -> eyre::Result<(Url, Arc<AtomicU32>)> {
+        let pulls = Arc::new(AtomicU32::new(0));
+        let relay_pulls = pulls.clone();
+        let relay = spawn_mock_relay(move |cfg| {
+            let pulls = relay_pulls.clone();
+            cfg.route(
+                "/v1/commands",
+                web::get().to(move || {
+                    pulls.fetch_add(1, Ordering::SeqCst);
+                    async { HttpResponse::Ok().json(Vec::<Command>::new()) }
+                }),
+            )
+            .default_service(web::to(|| async {
+                HttpResponse::Ok().json(serde_json::json!({}))
+            }));
+        })?;
+        Ok((relay, pulls))
+    };
+    let (primary, primary_pulls) = mock_relay()?;
+    let (backup, backup_pulls) = mock_relay()?;
+
+    let root = temp_path("relay-order");
+    tokio::fs::create_dir_all(&root).await?;
+    let fs = AnyFs::from_volume_item("Docs", &local_volume(&root))?;
+    let share_node = async |address: Url, name: &str, priority: usize| {
+        let mut share_node = mock_share_node(address).await?;
+        share_node.name = name.to_owned();
+        share_node.priority = priority;
+        share_node.relay_order = RelayOrder::Priority;
+        eyre::Ok(share_node)
+    };
+    let mut vol2relay: Vec<EdgeNodes> = vec![vec![
+        (fs.clone(), share_node(backup.clone(), "backup", 1).await?),
+        (fs.clone(), share_node(primary, "primary", 0).await?),
+    ]];
+    let identifier = Arc::new(NodeIdentifier {
+        uuid: "this-node".to_owned(),
+    });
+    let states = VolumeStates::default();
+
+    for _ in 0..10 {
+        Synchronizer::sync_once(&mut vol2relay, identifier.clone(), &states, 1).await?;
+    }
+    assert_eq!(primary_pulls.load(Ordering::SeqCst), 10);
+    assert_eq!(backup_pulls.load(Ordering::SeqCst), 0);
+    assert_eq!(
+        states.activity("Docs").last_relay.as_deref(),
+        Some("primary")
+    );
+
+    // the backup only serves while the primary is down
+    let mut vol2relay: Vec<EdgeNodes> = vec![vec![
+        (fs.clone(), share_node(backup, "backup", 1).await?),
+        (
+            fs,
+            share_node(Url::parse("http://127.0.0.1:9")?, "primary", 0).await?,
+        ),
+    ]];
+    Synchronizer::sync_once(&mut vol2relay, identifier, &states, 1).await?;
+    assert_eq!(backup_pulls.load(Ordering::SeqCst), 1);
+    assert_eq!(
+        states.activity("Docs").last_relay.as_deref(),
+        Some("backup")
+    );
+
+    tokio::fs::remove_dir_all(&root).await.ok();
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_paused_volume() -> eyre::Result<()> {
+    let hits = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let relay_hits = hits.clone();
+    let relay = spawn_mock_relay(move |cfg| {
+        let hits = relay_hits.clone();
+        cfg.default_service(web::to(move || {
+            hits.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            async { HttpResponse::ServiceUnavailable().finish() }
+        }));
+    })?;
+
+    let root = temp_path("paused");
+    tokio::fs::create_dir_all(&root).await?;
+    tokio::fs::write(root.join("a.txt"), b"a").await?;
+
+    let fs = AnyFs::from_volume_item("Docs", &local_volume(&root))?;
+    let mut vol2relay: Vec<EdgeNodes> = vec![vec![(fs, mock_share_node(relay).await?)]];
+    let identifier = Arc::new(NodeIdentifier {
+        uuid: "this-node".to_owned(),
+    });
+
+    // the sync loop leaves a paused volume alone
+    let states = web::Data::new(VolumeStates::default());
+    states.pause("Docs")?;
+    Synchronizer::sync_once(&mut vol2relay, identifier.clone(), &states, 1).await?;
+    assert_eq!(hits.load(std::sync::atomic::Ordering::SeqCst), 0);
+
+    states.resume("Docs")?;
+    Synchronizer::sync_once(&mut vol2relay, identifier.clone(), &states, 1).await?;
+    assert!(hits.load(std::sync::atomic::Ordering::SeqCst) > 0);
+
+    // while still being served
+    let config: NodeConfig = serde_yaml::from_str(&format!(
+        "name: node\naddress: 127.0.0.1\nport: 5561\nusers:\n  - name: u\n    password: p\n\
+         \x20 - name: w\n    password: q\nrelayNodes: {{}}\nvolumes:\n  Docs:\n    store:\n      \
+         type: local\n      root: {}\n    allow: [u, {{user: w, access: rw}}]\n    pullFrom: []\n",
+        root.display()
+    ))?;
+    let app = actix_web::test::init_service(
+        App::new()
+            .app_data(web::Data::new(Arc::new(config)))
+            .app_data(web::Data::new(identifier))
+            .app_data(web::Data::new(FsSnapshots::default()))
+            .app_data(states.clone())
+            .service(web::scope("/v1").configure(api_routes)),
+    )
+    .await;
+    let auth = ("Authorization", "Basic dTpw"); // u:p
+
+    // reading a volume is not enough to stop its sync
+    for action in ["pause", "resume"] {
+        let req = actix_web::test::TestRequest::post()
+            .uri(&format!("/v1/volume/Docs/{action}"))
+            .insert_header(auth)
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 403);
+    }
+    assert!(!states.is_paused("Docs"));
+
+    let req = actix_web::test::TestRequest::post()
+        .uri("/v1/volume/Docs/pause")
+        .insert_header(("Authorization", "Basic dzpx")) // w:q
+        .to_request();
+    let status: serde_json::Value = actix_web::test::call_and_read_body_json(&app, req).await;
+    assert_eq!(status["status"], "paused");
+    assert!(states.is_paused("Docs"));
+
+    let req = actix_web::test::TestRequest::get()
+        .uri("/v1/dir?path=@/Docs")
+        .insert_header(auth)
+        .to_request();
+    let listing: Vec<File> = actix_web::test::call_and_read_body_json(&app, req).await;
+    assert_eq!(listing.len(), 1);
+
+    states.report_error("Docs", "boom");
+    let req = actix_web::test::TestRequest::get()
+        .uri("/v1/health")
+        .to_request();
+    let health: serde_json::Value = actix_web::test::call_and_read_body_json(&app, req).await;
+    // deliberate pauses are not failures
+    assert_eq!(health["status"], "ok");
+    assert_eq!(health["volumes"]["Docs"]["status"], "paused");
+
+    states.resume("Docs")?;
+    assert_eq!(
+        states.status("Docs"),
+        VolumeStatus::Degraded {
+            error: "boom".to_owned()
+        }
+    );
+
+    tokio::fs::remove_dir_all(&root).await.ok();
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_dir_pagination() -> eyre::Result<()> {
+    let root = temp_path("paged");
+    tokio::fs::create_dir_all(root.join("z-dir")).await?;
+    tokio::fs::write(root.join("z-dir/d.txt"), b"d").await?;
+    tokio::fs::write(root.join("z-dir/.e.txt.nullfs-tmp"), b"e").await?;
+    tokio::fs::write(root.join("a.txt"), b"aaa").await?;
+    tokio::fs::write(root.join("b.txt"), b"b").await?;
+    tokio::fs::write(root.join("c.txt"), b"cc").await?;
+
+    let names = |entries: &[File]| {
+        entries
+            .iter()
+            .map(|f| f.path.file_name().unwrap_or_default().to_owned())
+            .collect::<Vec<_>>()
+    };
+
+    // directories first, then the window
+    let mut fs = AnyFs::from_volume_item("Docs", &local_volume(&root))?;
+    fs.init().await?;
+    let dir = NullFsPath::from_to_str("@/Docs")?;
+    let page = DirPage {
+        offset: 1,
+        limit: Some(2),
+        ..Default::default()
+    };
+    let listing = fs.dir_page(&dir, &page).await?;
+    assert_eq!(listing.total, 4);
+    assert_eq!(names(&listing.entries), ["a.txt", "b.txt"]);
+
+    let page = DirPage {
+        order: SortOrder::Desc,
+        ..Default::default()
+    };
+    let listing = fs.dir_page(&dir, &page).await?;
+    assert_eq!(
+        names(&listing.entries),
+        ["z-dir", "c.txt", "b.txt", "a.txt"]
+    );
+
+    // writes in progress are not counted
+    assert_eq!(fs.count_children(&dir).await?, 4);
+    assert_eq!(fs.count_children(&dir.join("z-dir")?).await?, 1);
+
+    let config: NodeConfig = serde_yaml::from_str(&format!(
+        "name: node\naddress: 127.0.0.1\nport: 5578\nusers:\n  - name: u\n    password: p\n\
+         relayNodes: {{}}\nvolumes:\n  Docs:\n    store:\n      type: local\n      \
+         root: {}\n    allow: [u]\n    pullFrom: []\n",
+        root.display()
+    ))?;
+    let app = actix_web::test::init_service(
+        App::new()
+            .app_data(web::Data::new(Arc::new(config)))
+            .app_data(web::Data::new(FsSnapshots::default()))
+            .service(web::scope("/v1").configure(api_routes)),
+    )
+    .await;
+
+    let req = actix_web::test::TestRequest::get()
+        .uri("/v1/dir?path=@/Docs&sort=size&order=desc&offset=1&limit=10")
+        .insert_header(("Authorization", "Basic dTpw")) // u:p
+        .to_request();
+    let resp = actix_web::test::call_service(&app, req).await;
+    assert_eq!(resp.headers().get(TOTAL_COUNT_HEADER).unwrap(), "4");
+    let listing: Vec<File> = actix_web::test::read_body_json(resp).await;
+    assert_eq!(names(&listing), ["a.txt", "c.txt", "b.txt"]);
+
+    let req = actix_web::test::TestRequest::get()
+        .uri("/v1/dir?path=@/Docs&limit=2")
+        .insert_header(("Authorization", "Basic dTpw")) // u:p
+        .to_request();
+    let resp = actix_web::test::call_service(&app, req).await;
+    let listing: serde_json::Value = actix_web::test::read_body_json(resp).await;
+    assert_eq!(listing[0]["path"], "@/Docs/z-dir");
+    assert_eq!(listing[0]["entries"], 1);
+    assert!(listing[1].get("entries").is_none());
+
+    tokio::fs::remove_dir_all(&root).await.ok();
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_mime_overrides() -> eyre::Result<()> {
+    let root = temp_path("mime");
+    tokio::fs::create_dir_all(&root).await?;
+    tokio::fs::write(root.join("logo.svg"), b"<svg/>").await?;
+    tokio::fs::write(root.join("font.woff2"), b"wOF2").await?;
+
+    let config: NodeConfig = serde_yaml::from_str(&format!(
+        "name: node\naddress: 127.0.0.1\nport: 5582\nusers:\n  - name: u\n    password: p\n\
+         mimeOverrides:\n  .SVG: image/x-custom+xml\n\
+         relayNodes: {{}}\nvolumes:\n  Assets:\n    store:\n      type: local\n      \
+         root: {}\n    allow: [u]\n    pullFrom: []\n",
+        root.display()
+    ))?;
+    let svg = NullFsPath::from_to_str("@/Assets/logo.svg")?;
+    assert_eq!(
+        FileType::mime_from_path(&svg, &IndexMap::new()),
+        "image/svg+xml"
+    );
+    assert_eq!(
+        FileType::mime_from_path(&svg, &config.mime_overrides),
+        "image/x-custom+xml"
+    );
+
+    let app = actix_web::test::init_service(
+        App::new()
+            .app_data(web::Data::new(Arc::new(config)))
+            .app_data(web::Data::new(FsSnapshots::default()))
+            .service(web::scope("/v1").configure(api_routes)),
+    )
+    .await;
+
+    for (path, expected) in [
+        ("@/Assets/logo.svg", "image/x-custom+xml"),
+        ("@/Assets/font.woff2", "font/woff2"),
+    ] {
+        let req = actix_web::test::TestRequest::get()
+            .uri(&format!("/v1/download?path={path}"))
+            .insert_header(("Authorization", "Basic dTpw")) // u:p
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        assert_eq!(resp.headers().get("content-type").unwrap(), expected);
+    }
+
+    tokio::fs::remove_dir_all(&root).await.ok();
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_search() -> eyre::Result<()> {
+    let root = temp_path("search");
+    tokio::fs::create_dir_all(root.join("2024/summer")).await?;
+    tokio::fs::write(root.join("2024/summer/Vacation-1.jpg"), b"1").await?;
+    tokio::fs::write(root.join("2024/summer/vacation-2.jpg"), b"2").await?;
+    tokio::fs::write(root.join("2024/notes.txt"), b"n").await?;
+
+    let config: NodeConfig = serde_yaml::from_str(&format!(
+        "name: node\naddress: 127.0.0.1\nport: 5579\nusers:\n  - name: u\n    password: p\n\
+         relayNodes: {{}}\nvolumes:\n  media:\n    store:\n      type: local\n      \
+         root: {}\n    allow: [u]\n    pullFrom: []\n",
+        root.display()
+    ))?;
+    let app = actix_web::test::init_service(
+        App::new()
+            .app_data(web::Data::new(Arc::new(config)))
+            .app_data(web::Data::new(FsSnapshots::default()))
+            .service(web::scope("/v1").configure(api_routes)),
+    )
+    .await;
+    let auth = ("Authorization", "Basic dTpw"); // u:p
+
+    let req = actix_web::test::TestRequest::get()
+        .uri("/v1/search?volume=media&q=VACATION")
+        .insert_header(auth)
+        .to_request();
+    let found: SearchResults = actix_web::test::call_and_read_body_json(&app, req).await;
+    let mut names = found
+        .files
+        .iter()
+        .map(|f| f.path.to_string())
+        .collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(
+        names,
+        [
+            "@/media/2024/summer/Vacation-1.jpg",
+            "@/media/2024/summer/vacation-2.jpg"
+        ]
+    );
+    assert!(!found.truncated);
+
+    let req = actix_web::test::TestRequest::get()
+        .uri("/v1/search?volume=media&q=vacation&limit=1")
+        .insert_header(auth)
+        .to_request();
+    let found: SearchResults = actix_web::test::call_and_read_body_json(&app, req).await;
+    assert_eq!(found.files.len(), 1);
+    assert!(found.truncated);
+
+    // same checks as the other volume routes
+    let req = actix_web::test::TestRequest::get()
+        .uri("/v1/search?volume=media&q=vacation")
+        .to_request();
+    let resp = actix_web::test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 401);
+
+    tokio::fs::remove_dir_all(&root).await.ok();
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_https_relay() -> eyre::Result<()> {
+    let dir = temp_path("tls");
+    tokio::fs::create_dir_all(&dir).await?;
+    let ca_key = rcgen::KeyPair::generate()?;
+    let mut ca_params = rcgen::CertificateParams::new(Vec::<String>::new())?;
+    ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+    // the leaf must not share the default name of the CA, it would pass for self-signed
+    ca_params
+        .distinguished_name
+        .push(rcgen::DnType::CommonName, "nullfs test CA");
+    let ca = ca_params.self_signed(&ca_key)?;
+    let key = rcgen::KeyPair::generate()?;
+    let cert = rcgen::CertificateParams::new(vec!["localhost".to_owned()])?
+        .signed_by(&key, &ca, &ca_key)?;
+    let tls = TlsConfig {
+        cert: dir.join("cert.pem"),
+        key: dir.join("key.pem"),
+    };
+    tokio::fs::write(&tls.cert, cert.pem()).await?;
+    tokio::fs::write(&tls.key, key.serialize_pem()).await?;
+    tokio::fs::write(dir.join("ca.pem"), ca.pem()).await?;
+
+    let server = HttpServer::new(|| {
+        App::new().route(
+            "/v1/commands",
+            web::get().to(|| async { HttpResponse::Ok().json(sample_commands(3).unwrap()) }),
+        )
+    })
+    .workers(1)
+    .bind_rustls_0_23(("127.0.0.1", 0), tls.server_config()?)?;
+    let port = server.addrs()[0].port();
+    actix_web::rt::spawn(server.run());
+
+    let fs = AnyFs {
+        volume_name: "vol".to_owned(),
+        fs_instance: Arc::new(tokio::sync::RwLock::new(MemVolume::new("vol"))),
+    };
+    let identifier = Arc::new(NodeIdentifier {
+        uuid: "this-node".to_owned(),
+    });
+    let mut share_node = mock_share_node(Url::parse(&format!("https://localhost:{port}"))?).await?;
+
+    // the relay certificate is not trusted by default
+    assert!(share_node.pull(&fs, identifier.clone()).await.is_err());
+
+    share_node.relay.ca_cert = Some(dir.join("ca.pem"));
+    share_node.client = ShareNode::client_for(&share_node.relay, true)?;
+    share_node.pull(&fs, identifier).await?;
+    assert_eq!(share_node.store.unstash("vol").await?.len(), 3);
+
+    // unreadable files are rejected when the configuration is validated
+    let missing = TlsConfig {
+        cert: dir.join("missing.pem"),
+        key: tls.key.clone(),
+    };
+    assert!(missing.server_config().is_err());
+
+    tokio::fs::remove_dir_all(&dir).await.ok();
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_sync_once_reports_unreachable_relays() -> eyre::Result<()> {
+    let root = temp_path("unreachable");
+    tokio::fs::create_dir_all(&root).await?;
+    let fs = AnyFs::from_volume_item("Docs", &local_volume(&root))?;
+    // nothing listens on the discard port
+    let share_node = mock_share_node(Url::parse("http://127.0.0.1:9")?).await?;
+    let mut vol2relay: Vec<EdgeNodes> = vec![vec![(fs, share_node)]];
+    let identifier = Arc::new(NodeIdentifier {
+        uuid: "this-node".to_owned(),
+    });
+
+    let states = VolumeStates::default();
+    let summary = Synchronizer::sync_once(&mut vol2relay, identifier, &states, 1).await?;
+    assert!(summary.errors.is_empty());
+    assert!(!summary.is_success());
+    assert_eq!(summary.unreachable.iter().collect::<Vec<_>>(), vec!["mock"]);
+    assert!(summary.to_string().ends_with("unreachable: mock"));
+
+    tokio::fs::remove_dir_all(&root).await.ok();
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_sync_once_summary() -> eyre::Result<()> {
+    // serves a write of `a.txt` and `b.txt`, downloads of `broken` fail
+    let relay = |broken: Option<&'static str>| {
+        spawn_mock_relay(move |cfg| {
+            let content = |path: &NullFsPath| path.to_string().into_bytes();
+            cfg.route("/v1/info", web::get().to(HttpResponse::Ok))
+                .route(
+                    "/v1/exists",
+                    web::get().to(|| async { HttpResponse::Ok().json(true) }),
+                )
+                .route(
+                    "/v1/hash",
+                    web::get().to(move |params: web::Query<WithPath>| async move {
+                        let hash = Sha256::digest(content(&params.path));
+                        HttpResponse::Ok().json(format!("{hash:x}"))
+                    }),
+                )
+                .route(
+                    "/v1/commands",
+                    web::get().to(move || async move {
+                        let write = |name: &str| -> Command {
+                            let path = NullFsPath::from_to_str(format!("@/Docs/{name}")).unwrap();
+                            Command::Write {
+                                file: File {
+                                    file_type: FileType::Text,
+                                    stat: FileStat {
+                                        node: NodeKind::File {
+                                            size: content(&path).len() as u64,
+                                        },
+                                        modified: systime_to_millis(SystemTime::now()),
+                                        created: None,
+                                        accessed: None,
+                                    },
+                                    path,
+                                },
+                            }
+                        };
+                        HttpResponse::Ok().json(vec![write("a.txt"), write("b.txt")])
+                    }),
+                )
+                .route(
+                    "/v1/download",
+                    web::get().to(move |params: web::Query<WithPath>| async move {
+                        match params.path.file_name() == broken {
+                            true => HttpResponse::InternalServerError().finish(),
+                            false => HttpResponse::Ok().body(content(&params.path)),
+                        }
+                    }),
+                );
+        })
+    };
+    let identifier = Arc::new(NodeIdentifier {
+        uuid: "this-node".to_owned(),
+    });
+    let states = VolumeStates::default();
+    let sync = async |relay: Url, name: &str| -> eyre::Result<SyncSummary> {
+        let root = temp_path(name);
+        tokio::fs::create_dir_all(&root).await?;
+        let mut fs = AnyFs::from_volume_item("Docs", &local_volume(&root))?;
+        fs.init().await?;
+        let mut vol2relay: Vec<EdgeNodes> = vec![vec![(fs, mock_share_node(relay).await?)]];
+        let summary = Synchronizer::sync_once(&mut vol2relay, identifier.clone(), &states, 1).await;
+        tokio::fs::remove_dir_all(&root).await.ok();
+        summary
+    };
+
+    let summary = sync(relay(None)?, "summary-ok").await?;
+    assert!(summary.is_success(), "{summary} {:?}", summary.errors);
+    assert_eq!((summary.applied, summary.failed), (2, 0));
+    assert_eq!(summary.bytes, 2 * "@/Docs/a.txt".len() as u64);
+    assert!(summary.errors.is_empty());
+    assert_eq!(
+        summary.to_string(),
+        "2 applied, 0 skipped, 0 failed, 24 bytes downloaded, 0 error(s)"
+    );
+
+    let summary = sync(relay(Some("b.txt"))?, "summary-failing").await?;
+    assert!(!summary.is_success(), "{summary}");
+    assert_eq!((summary.applied, summary.failed), (1, 1));
+    assert_eq!(summary.bytes, "@/Docs/a.txt".len() as u64);
+    assert_eq!(summary.errors.len(), 1);
+    assert!(
+        summary.errors[0].contains("@/Docs/b.txt"),
+        "{:?}",
+        summary.errors
+    );
+    assert!(summary.unreachable.is_empty());
+
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_parallel_volume_sync() -> eyre::Result<()> {
+    let relay = |delay: Duration| {
+        spawn_mock_relay(move |cfg| {
+            cfg.route("/v1/info", web::get().to(HttpResponse::Ok))
+                .route(
+                    "/v1/commands",
+                    web::get().to(move || async move {
+                        tokio::time::sleep(delay).await;
+                        HttpResponse::Ok().json(Vec::<Command>::new())
+                    }),
+                );
+        })
+    };
+    let slow = relay(Duration::from_millis(1500))?;
+    let fast = relay(Duration::ZERO)?;
+
+    let (slow_root, fast_root) = (temp_path("parallel-slow"), temp_path("parallel-fast"));
+    tokio::fs::create_dir_all(&slow_root).await?;
+    tokio::fs::create_dir_all(&fast_root).await?;
+
+    // both volumes stash into the same database
+    let slow_node = mock_share_node(slow).await?;
+    let mut fast_node = mock_share_node(fast).await?;
+    fast_node.store = slow_node.store.clone();
+
+    let mut vol2relay: Vec<EdgeNodes> = vec![
+        vec![(
+            AnyFs::from_volume_item("Slow", &local_volume(&slow_root))?,
+            slow_node,
+        )],
+        vec![(
+            AnyFs::from_volume_item("Fast", &local_volume(&fast_root))?,
+            fast_node,
+        )],
+    ];
+    let identifier = Arc::new(NodeIdentifier {
+        uuid: "this-node".to_owned(),
+    });
+
+    let states = VolumeStates::default();
+    let started = crate::nullfs::systime_to_millis(std::time::SystemTime::now());
+    let summary = Synchronizer::sync_once(&mut vol2relay, identifier, &states, 2).await?;
+    assert!(summary.is_success());
+
+    // the fast volume went through without waiting on the slow relay
+    let fast_applied = states.activity("Fast").last_apply.unwrap();
+    let slow_applied = states.activity("Slow").last_apply.unwrap();
+    assert!(fast_applied - started < 1000);
+    assert!(slow_applied - started >= 1500);
+
+    tokio::fs::remove_dir_all(&slow_root).await.ok();
+    tokio::fs::remove_dir_all(&fast_root).await.ok();
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_status_report() -> eyre::Result<()> {
+    let fs = AnyFs {
+        volume_name: "Docs".to_owned(),
+        fs_instance: Arc::new(tokio::sync::RwLock::new(MemVolume::new("Docs"))),
+    };
+    let stash = CommandStash::open(&temp_path("stash.db")).await?;
+    stash.stash(sample_commands(3)?, &fs, None).await?;
+    let op = stash.unstash("Docs").await?.remove(0);
+    stash.mark_done(&op).await?;
+
+    let config: NodeConfig = serde_yaml::from_str(
+        "name: node\naddress: 127.0.0.1\nport: 5570\nusers: []\n\
+         relayNodes: {}\nvolumes:\n  Docs:\n    store:\n      type: memory\n    \
+         allow: []\n    pullFrom: [relay-a, relay-b]\n",
+    )?;
+    let states = web::Data::new(VolumeStates::default());
+    states.report_liveness("Docs", "relay-a", false);
+    states.report_pull("Docs", "relay-b");
+    let app = actix_web::test::init_service(
+        App::new()
+            .app_data(web::Data::new(Arc::new(config)))
+            .app_data(web::Data::new(Arc::new(stash)))
+            .app_data(states.clone())
+            .service(web::scope("/v1").configure(api_routes)),
+    )
+    .await;
+
+    let req = actix_web::test::TestRequest::get()
+        .uri("/v1/status")
+        .to_request();
+    let status: serde_json::Value = actix_web::test::call_and_read_body_json(&app, req).await;
+    let docs = &status["volumes"]["Docs"];
+    assert_eq!(docs["status"], "active");
+    assert_eq!(docs["commands"]["pending"], 2);
+    assert_eq!(docs["commands"]["done"], 1);
+    assert_eq!(docs["commands"]["deadLetter"], 0);
+    assert!(docs["lastPull"].is_u64());
+    assert!(docs["lastApply"].is_null());
+    assert_eq!(docs["lastRelay"], "relay-b");
+    assert_eq!(
+        docs["relays"],
+        serde_json::json!({"relay-a": false, "relay-b": null})
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_metrics() -> eyre::Result<()> {
+    let fs = AnyFs {
+        volume_name: "Metered".to_owned(),
+        fs_instance: Arc::new(tokio::sync::RwLock::new(MemVolume::new("Metered"))),
+    };
+    let stash = CommandStash::open(&temp_path("stash.db"))
+        .await?
+        .max_attempts(1);
+    stash.stash(sample_commands(3)?, &fs, None).await?;
+    let mut ops = stash.unstash("Metered").await?;
+    stash.mark_done(&ops.remove(0)).await?;
+    stash.mark_failed(&ops.remove(0), "relay down").await?;
+    METRICS.sync_tick(Duration::from_millis(300));
+
+    let text = METRICS.render();
+    assert!(text.contains("nullfs_commands_stashed_total{volume=\"Metered\"} 3\n"));
+    assert!(text.contains("nullfs_commands_applied_total{volume=\"Metered\"} 1\n"));
+    assert!(text.contains("nullfs_command_failures_total{volume=\"Metered\"} 1\n"));
+    assert!(text.contains("# TYPE nullfs_sync_tick_duration_seconds histogram"));
+    assert!(text.contains("nullfs_sync_tick_duration_seconds_bucket{le=\"+Inf\"}"));
+
+    // buckets are cumulative
+    let buckets = text
+        .lines()
+        .filter(|line| line.starts_with("nullfs_sync_tick_duration_seconds_bucket"))
+        .map(|line| line.rsplit(' ').next().unwrap().parse::<u64>())
+        .collect::<Result<Vec<_>, _>>()?;
+    assert!(buckets.windows(2).all(|pair| pair[0] <= pair[1]));
+    assert!(*buckets.last().unwrap() >= 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_read_stream() -> eyre::Result<()> {
+    use futures::TryStreamExt;
+
+    let root = temp_path("stream");
+    tokio::fs::create_dir_all(&root).await?;
+    let content = (0..200_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    tokio::fs::write(root.join("big.bin"), &content).await?;
+
+    let mut local = AnyFs::from_volume_item("Local", &local_volume(&root))?;
+    local.init().await?;
+    let path = NullFsPath::from_to_str("@/Local/big.bin")?;
+    let chunks = local
+        .read_stream(&path)
+        .await?
+        .try_collect::<Vec<_>>()
+        .await?;
+    assert_eq!(chunks.concat(), content);
+
+    let mem = MemVolume::new("Mem");
+    let path = NullFsPath::from_to_str("@/Mem/big.bin")?;
+    let file = File {
+        file_type: FileType::infer_from_path(&path),
+        path: path.clone(),
+        stat: FileStat {
+            node: NodeKind::File { size: 0 },
+            modified: 0,
+            created: None,
+            accessed: None,
+        },
+    };
+    mem.write(&file, &content).await?;
+    let chunks = mem
+        .read_stream(&path)
+        .await?
+        .try_collect::<Vec<_>>()
+        .await?;
+    assert_eq!(chunks.len(), 4);
+    assert_eq!(chunks.concat(), content);
+
+    tokio::fs::remove_dir_all(&root).await.ok();
+
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_download_range() -> eyre::Result<()> {
+    let root = temp_path("range");
+    tokio::fs::create_dir_all(&root).await?;
+    tokio::fs::write(root.join("a.txt"), b"0123456789").await?;
+
+    let config: NodeConfig = serde_yaml::from_str(&format!(
+        "name: node\naddress: 127.0.0.1\nport: 5562\nusers:\n  - name: u\n    password: p\n\
+         relayNodes: {{}}\nvolumes:\n  Docs:\n    store:\n      type: local\n      \
+         root: {}\n    allow: [u]\n    pullFrom: []\n",
+        root.display()
+    ))?;
+    let app = actix_web::test::init_service(
+        App::new()
+            .app_data(web::Data::new(Arc::new(config)))
+            .app_data(web::Data::new(FsSnapshots::default()))
+            .service(web::scope("/v1").configure(api_routes)),
+    )
+    .await;
+    let download = |range: Option<&str>| {
+        let mut req = actix_web::test::TestRequest::get()
+            .uri("/v1/download?path=@/Docs/a.txt")
+            .insert_header(("Authorization", "Basic dTpw")); // u:p
+        if let Some(range) = range {
+            req = req.insert_header(("Range", range));
+        }
+        req.to_request()
+    };
+
+    let resp = actix_web::test::call_service(&app, download(None)).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers().get("Accept-Ranges").unwrap(), "bytes");
+    assert_eq!(actix_web::test::read_body(resp).await, "0123456789");
+
+    let resp = actix_web::test::call_service(&app, download(Some("bytes=2-5"))).await;
+    assert_eq!(resp.status(), 206);
+    assert_eq!(resp.headers().get("Content-Range").unwrap(), "bytes 2-5/10");
+    assert_eq!(actix_web::test::read_body(resp).await, "2345");
+
+    let resp = actix_web::test::call_service(&app, download(Some("bytes=-3"))).await;
+    assert_eq!(resp.status(), 206);
+    assert_eq!(actix_web::test::read_body(resp).await, "789");
+
+    let resp = actix_web::test::call_service(&app, download(Some("bytes=20-"))).await;
+    assert_eq!(resp.status(), 416);
+    assert_eq!(resp.headers().get("Content-Range").unwrap(), "bytes */10");
+
+    tokio::fs::remove_dir_all(&root).await.ok();
+
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_download_not_modified() -> eyre::Result<()> {
+    let root = temp_path("etag");
+    tokio::fs::create_dir_all(&root).await?;
+    tokio::fs::write(root.join("a.txt"), b"unchanged").await?;
+    let hash = format!("{:x}", Sha256::digest(b"unchanged"));
+
+    let config: NodeConfig = serde_yaml::from_str(&format!(
+        "name: node\naddress: 127.0.0.1\nport: 5563\nusers:\n  - name: u\n    password: p\n\
+         relayNodes: {{}}\nvolumes:\n  Docs:\n    store:\n      type: local\n      \
+         root: {}\n    allow: [u]\n    pullFrom: []\n",
+        root.display()
+    ))?;
+    let config = web::Data::new(Arc::new(config));
+    let app = actix_web::test::init_service(
+        App::new()
+            .app_data(config.clone())
+            .app_data(web::Data::new(FsSnapshots::default()))
+            .service(web::scope("/v1").configure(api_routes)),
+    )
+    .await;
+    let get = |endpoint: &str, etag: Option<&str>| {
+        let mut req = actix_web::test::TestRequest::get()
+            .uri(&format!("/v1/{endpoint}?path=@/Docs/a.txt"))
+            .insert_header(("Authorization", "Basic dTpw")); // u:p
+        if let Some(etag) = etag {
+            req = req.insert_header(("If-None-Match", etag));
+        }
+        req.to_request()
+    };
+
+    for endpoint in ["download", "hash"] {
+        let resp = actix_web::test::call_service(&app, get(endpoint, None)).await;
+        assert_eq!(resp.status(), 200);
+        let etag = resp.headers().get("ETag").unwrap().to_str()?.to_owned();
+        assert_eq!(etag, format!("\"{hash}\""));
+
+        // the copy of the client is current, the body is skipped
+        let resp = actix_web::test::call_service(&app, get(endpoint, Some(&etag))).await;
+        assert_eq!(resp.status(), 304);
+        assert!(actix_web::test::read_body(resp).await.is_empty());
+
+        let resp = actix_web::test::call_service(&app, get(endpoint, Some("\"stale\""))).await;
+        assert_eq!(resp.status(), 200);
+    }
+
+    // a puller sends the hash it last synced
+    let relay = spawn_mock_relay(move |cfg| {
+        cfg.app_data(config.clone())
+            .app_data(web::Data::new(FsSnapshots::default()))
+            .service(web::scope("/v1").configure(api_routes));
+    })?;
+    let mut share_node = mock_share_node(relay).await?;
+    share_node.relay.auth = User {
+        name: "u".to_owned(),
+        password: Some("p".to_owned()),
+    };
+    let path = NullFsPath::from_to_str("@/Docs/a.txt")?;
+    assert_eq!(
+        share_node
+            .download_verified(&path, &hash, None)
+            .await?
+            .as_deref(),
+        Some(&b"unchanged"[..])
+    );
+    assert_eq!(
+        share_node
+            .download_verified(&path, &hash, Some(&hash))
+            .await?,
+        None
+    );
+
+    // a file edited locally only is left as is without fetching the relay copy again
+    let bodies = Arc::new(AtomicU32::new(0));
+    let served = bodies.clone();
+    let relay = spawn_mock_relay(move |cfg| {
+        let served = served.clone();
+        cfg.route(
+            "/v1/exists",
+            web::get().to(|| async { HttpResponse::Ok().json(true) }),
+        )
+        .route(
+            "/v1/hash",
+            web::get().to(|| async {
+                HttpResponse::Ok().json(format!("{:x}", Sha256::digest(b"unchanged")))
+            }),
+        )
+        .route(
+            "/v1/download",
+            web::get().to(move |req: HttpRequest| {
+                let served = served.clone();
+                async move {
+                    let etag = format!("\"{:x}\"", Sha256::digest(b"unchanged"));
+                    if req
+                        .headers()
+                        .get("If-None-Match")
+                        .and_then(|v| v.to_str().ok())
+                        == Some(etag.as_str())
+                    {
+                        return HttpResponse::NotModified().finish();
+                    }
+                    served.fetch_add(1, Ordering::SeqCst);
+                    HttpResponse::Ok().body(&b"unchanged"[..])
+                }
+            }),
+        );
+    })?;
+
+    let local_root = temp_path("etag-local");
+    tokio::fs::create_dir_all(&local_root).await?;
+    tokio::fs::write(local_root.join("a.txt"), b"local edit").await?;
+    let mut local = AnyFs::from_volume_item("Docs", &local_volume(&local_root))?;
+    local.init().await?;
+
+    let share_node = mock_share_node(relay).await?;
+    share_node
+        .hashes
+        .lock()
+        .await
+        .mark_synced(&path, hash.clone());
+    let file = File {
+        file_type: FileType::infer_from_path(&path),
+        path: path.clone(),
+        stat: FileStat {
+            node: NodeKind::File { size: 9 },
+            modified: systime_to_millis(SystemTime::now()),
+            created: None,
+            accessed: None,
+        },
+    };
+    share_node
+        .store
+        .stash(vec![Command::Write { file }], &local, None)
+        .await?;
+
+    let report = share_node.apply_commands(&local, &[]).await?;
+    assert_eq!((report.applied, report.skipped), (0, 1));
+    assert_eq!(bodies.load(Ordering::SeqCst), 0);
+    assert_eq!(
+        tokio::fs::read(local_root.join("a.txt")).await?,
+        b"local edit"
+    );
+    let mut entries = tokio::fs::read_dir(&local_root).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        assert_eq!(entry.file_name(), "a.txt");
+    }
+
+    tokio::fs::remove_dir_all(&root).await.ok();
+    tokio::fs::remove_dir_all(&local_root).await.ok();
+
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_expired_commands_revalidated() -> eyre::Result<()> {
+    let mem_fs = |mem: &MemVolume| AnyFs {
+        volume_name: "Mem".to_owned(),
+        fs_instance: Arc::new(tokio::sync::RwLock::new(mem.clone())),
+    };
+    let remote = mem_fs(&MemVolume::new("Mem"));
+    let local = mem_fs(&MemVolume::new("Mem"));
+    let file = |rel: &str| -> eyre::Result<File> {
+        let path = NullFsPath::from_to_str(format!("@/Mem/{rel}"))?;
+        Ok(File {
+            file_type: FileType::infer_from_path(&path),
+            path,
+            stat: FileStat {
+                node: NodeKind::File { size: 0 },
+                modified: 0,
+                created: None,
+                accessed: None,
+            },
+        })
+    };
+    let stated = async |fs: &AnyFs, rel: &str| -> eyre::Result<File> {
+        let mut file = file(rel)?;
+        file.stat = fs.stats(&file.path).await?;
+        Ok(file)
+    };
+
+    for rel in ["same.txt", "gone.txt", "rewritten.txt"] {
+        remote.write(&file(rel)?, b"v1").await?;
+    }
+    local.write(&file("recreated.txt")?, b"v1").await?;
+    let commands = vec![
+        Command::Write {
+            file: stated(&remote, "same.txt").await?,
+        },
+        Command::Write {
+            file: stated(&remote, "gone.txt").await?,
+        },
+        Command::Write {
+            file: stated(&remote, "rewritten.txt").await?,
+        },
+        Command::Delete {
+            file: file("recreated.txt")?,
+        },
+    ];
+
+    // the relay moved on while the commands were pending
+    remote.delete(&file("gone.txt")?).await?;
+    remote.write(&file("rewritten.txt")?, b"v2").await?;
+    remote.write(&file("recreated.txt")?, b"v2").await?;
+
+    let relay = spawn_mock_relay(move |cfg| {
+        let remote = remote.clone();
+        let (stat, exists, hash, download) = (
+            remote.clone(),
+            remote.clone(),
+            remote.clone(),
+            remote.clone(),
+        );
+        cfg.route(
+            "/v1/stat",
+            web::get().to(move |params: web::Query<WithPath>| {
+                let fs = stat.clone();
+                async move {
+                    match fs.exists(&params.path).await.unwrap() {
+                        true => HttpResponse::Ok().json(fs.stats(&params.path).await.unwrap()),
+                        false => HttpResponse::NotFound().finish(),
+                    }
+                }
+            }),
+        )
+        .route(
+            "/v1/exists",
+            web::get().to(move |params: web::Query<WithPath>| {
+                let fs = exists.clone();
+                async move { HttpResponse::Ok().json(fs.exists(&params.path).await.unwrap()) }
+            }),
+        )
+        .route(
+            "/v1/hash",
+            web::get().to(move |params: web::Query<WithPath>| {
+                let fs = hash.clone();
+                async move { HttpResponse::Ok().json(fs.hash(&params.path).await.unwrap()) }
+            }),
+        )
+        .route(
+            "/v1/download",
+            web::get().to(move |params: web::Query<WithPath>| {
+                let fs = download.clone();
+                async move { HttpResponse::Ok().body(fs.read(&params.path).await.unwrap()) }
+            }),
+        );
+    })?;
+
+    let mut share_node = mock_share_node(relay).await?;
+    share_node.command_ttl = Some(Duration::ZERO);
+    share_node.store.stash(commands, &local, None).await?;
+    let report = share_node
+        .apply_commands(&local, std::slice::from_ref(&share_node))
+        .await?;
+
+    // only the command still matching the relay went through
+    assert_eq!((report.applied, report.skipped), (1, 3));
+    assert!(report.failures.is_empty());
+    assert_eq!(local.read(&file("same.txt")?.path).await?, b"v1");
+    assert!(!local.exists(&file("rewritten.txt")?.path).await?);
+    assert!(local.exists(&file("recreated.txt")?.path).await?);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_custom_backend() -> eyre::Result<()> {
+    use crate::nullfs::{
+        backend::{SharedFs, register_backend},
+        local_fs::LocalVolume,
+    };
+
+    // a local volume under a scratch directory chosen by the `dir` option
+    register_backend(
+        "scratch",
+        |name: &str, store: &StoreKind, _: Option<PathBuf>| -> eyre::Result<SharedFs> {
+            let StoreKind::Custom { options, .. } = store else {
+                eyre::bail!("Expected a custom store");
+            };
+            let dir = options["dir"].as_str().unwrap_or_default();
+
+            Ok(Arc::new(tokio::sync::RwLock::new(LocalVolume {
+                name: name.to_owned(),
+                root: std::env::temp_dir().join(dir),
+                snapshot_root: None,
+                follow_symlinks: false,
+                trash: false,
+                trash_retention_days: None,
+            })))
+        },
+    );
+
+    let dir = temp_path("scratch");
+    tokio::fs::create_dir_all(&dir).await?;
+    tokio::fs::write(dir.join("a.txt"), b"hello").await?;
+    let yaml = |kind: &str| {
+        format!(
+            "name: node\naddress: 127.0.0.1\nport: 5563\nusers: []\nrelayNodes: {{}}\n\
+             volumes:\n  Scratch:\n    store:\n      type: {kind}\n      dir: {}\n    \
+             allow: []\n    pullFrom: []\n",
+            dir.file_name().unwrap().to_string_lossy()
+        )
+    };
+
+    let path = temp_path("custom.yaml");
+    tokio::fs::write(&path, yaml("scratch")).await?;
+    let config = NodeConfig::load_from_file(&path).await?;
+    let volume = &config.volumes["Scratch"];
+    assert_eq!(volume.store.kind(), "scratch");
+    assert!(serde_yaml::to_string(volume)?.contains("type: scratch"));
+
+    let fs = config
+        .get_initialized_fs_volume("Scratch")
+        .await?
+        .expect("volume");
+    let file = NullFsPath::from_to_str("@/Scratch/a.txt")?;
+    assert_eq!(fs.read(&file).await?, b"hello");
+
+    tokio::fs::write(&path, yaml("unknown")).await?;
+    let err = NodeConfig::load_from_file(&path).await.unwrap_err();
+    assert!(format!("{err:?}").contains("unknown store type"));
+
+    tokio::fs::remove_file(&path).await.ok();
+    tokio::fs::remove_dir_all(&dir).await.ok();
+
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_upload_and_delete() -> eyre::Result<()> {
+    let root = temp_path("upload");
+    tokio::fs::create_dir_all(&root).await?;
+
+    let config: NodeConfig = serde_yaml::from_str(&format!(
+        "name: node\naddress: 127.0.0.1\nport: 5564\nusers:\n  - name: u\n    password: p\n\
+         \x20 - name: r\n    password: p\nrelayNodes: {{}}\nvolumes:\n  Docs:\n    store:\n      \
+         type: local\n      root: {root}\n    allow: [{{user: u, access: rw}}, r]\n    \
+         pullFrom: []\n    writable: true\n  \
+         Archive:\n    store:\n      type: local\n      root: {root}\n    allow: [u]\n    \
+         pullFrom: []\n",
+        root = root.display()
+    ))?;
+    let app = actix_web::test::init_service(
+        App::new()
+            .app_data(web::Data::new(Arc::new(config)))
+            .app_data(web::Data::new(FsSnapshots::default()))
+            .service(web::scope("/v1").configure(api_routes)),
+    )
+    .await;
+    let auth = ("Authorization", "Basic dTpw"); // u:p
+
+    let req = actix_web::test::TestRequest::post()
+        .uri("/v1/upload?path=@/Docs/sub/a.txt")
+        .insert_header(auth)
+        .set_payload("uploaded")
+        .to_request();
+    let resp = actix_web::test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(tokio::fs::read(root.join("sub/a.txt")).await?, b"uploaded");
+
+    // bare names only get to read
+    let req = actix_web::test::TestRequest::post()
+        .uri("/v1/upload?path=@/Docs/sub/a.txt")
+        .insert_header(("Authorization", "Basic cjpw")) // r:p
+        .set_payload("overwritten")
+        .to_request();
+    let resp = actix_web::test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 403);
+    assert_eq!(tokio::fs::read(root.join("sub/a.txt")).await?, b"uploaded");
+    let req = actix_web::test::TestRequest::get()
+        .uri("/v1/exists?path=@/Docs/sub/a.txt")
+        .insert_header(("Authorization", "Basic cjpw"))
+        .to_request();
+    let resp = actix_web::test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+
+    // read-only volumes reject writes even from allowed users
+    let req = actix_web::test::TestRequest::post()
+        .uri("/v1/upload?path=@/Archive/b.txt")
+        .insert_header(auth)
+        .set_payload("nope")
+        .to_request();
+    let resp = actix_web::test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 403);
+    assert!(!root.join("b.txt").exists());
+
+    let req = actix_web::test::TestRequest::delete()
+        .uri("/v1/file?path=@/Archive/sub/a.txt")
+        .insert_header(auth)
+        .to_request();
+    let resp = actix_web::test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 403);
+
+    let req = actix_web::test::TestRequest::delete()
+        .uri("/v1/file?path=@/Docs/sub/a.txt")
+        .insert_header(auth)
+        .to_request();
+    let resp = actix_web::test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+    assert!(!root.join("sub/a.txt").exists());
+
+    let req = actix_web::test::TestRequest::delete()
+        .uri("/v1/file?path=@/Docs/sub/a.txt")
+        .insert_header(auth)
+        .to_request();
+    let resp = actix_web::test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 404);
+
+    tokio::fs::remove_dir_all(&root).await.ok();
+
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_upload_streamed() -> eyre::Result<()> {
+    use crate::nullfs::backend::{SharedFs, register_backend};
+
+    // a local volume refusing buffered writes
+    let chunks = Arc::new(std::sync::Mutex::new((0, 0)));
+    let counted = chunks.clone();
+    let root = temp_path("upload-streamed");
+    tokio::fs::create_dir_all(&root).await?;
+    let volume_root = root.clone();
+    register_backend(
+        "chunk-counter",
+        move |name: &str, _: &StoreKind, _: Option<PathBuf>| -> eyre::Result<SharedFs> {
+            Ok(Arc::new(tokio::sync::RwLock::new(ChunkCounter {
+                inner: AnyFs::from_volume_item(name, &local_volume(&volume_root))?,
+                chunks: counted.clone(),
+            })))
+        },
+    );
+
+    let config: NodeConfig = serde_yaml::from_str(
+        "name: node\naddress: 127.0.0.1\nport: 5591\nusers:\n  - name: u\n    password: p\n\
+         relayNodes: {}\nvolumes:\n  Docs:\n    store:\n      type: chunk-counter\n    \
+         allow: [{user: u, access: rw}]\n    pullFrom: []\n    writable: true\n",
+    )?;
+    let app = actix_web::test::init_service(
+        App::new()
+            .app_data(web::Data::new(Arc::new(config)))
+            .app_data(web::Data::new(FsSnapshots::default()))
+            .service(web::scope("/v1").configure(api_routes)),
+    )
+    .await;
+
+    let content = "x".repeat(64 * 1024);
+    let req = actix_web::test::TestRequest::post()
+        .uri("/v1/upload?path=@/Docs/large.txt")
+        .insert_header(("Authorization", "Basic dTpw")) // u:p
+        .set_payload(content.clone())
+        .to_request();
+    let resp = actix_web::test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+    let body: serde_json::Value = actix_web::test::read_body_json(resp).await;
+    assert_eq!(body["written"], content.len());
+    assert_eq!(
+        tokio::fs::read(root.join("large.txt")).await?,
+        content.as_bytes()
+    );
+    assert!(chunks.lock().unwrap().0 > 0);
+
+    tokio::fs::remove_dir_all(&root).await.ok();
+
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_anonymous_volume() -> eyre::Result<()> {
+    let root = temp_path("anonymous");
+    tokio::fs::create_dir_all(&root).await?;
+    tokio::fs::write(root.join("a.txt"), b"public").await?;
+
+    let config: NodeConfig = serde_yaml::from_str(&format!(
+        "name: node\naddress: 127.0.0.1\nport: 5577\nusers:\n  - name: u\n    password: p\n\
+         relayNodes: {{}}\nvolumes:\n  Public:\n    store:\n      type: local\n      \
+         root: {root}\n    allow: [{{user: \"*\", access: rw}}]\n    pullFrom: []\n    \
+         writable: true\n    anonymous: true\n  Private:\n    store:\n      type: local\n      \
+         root: {root}\n    allow: [\"*\"]\n    pullFrom: []\n",
+        root = root.display()
+    ))?;
+    let app = actix_web::test::init_service(
+        App::new()
+            .app_data(web::Data::new(Arc::new(config)))
+            .app_data(web::Data::new(FsSnapshots::default()))
+            .service(web::scope("/v1").configure(api_routes)),
+    )
+    .await;
+
+    let req = actix_web::test::TestRequest::get()
+        .uri("/v1/download?path=@/Public/a.txt")
+        .to_request();
+    let resp = actix_web::test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(actix_web::test::read_body(resp).await, "public");
+
+    let req = actix_web::test::TestRequest::post()
+        .uri("/v1/upload?path=@/Public/b.txt")
+        .set_payload("nope")
+        .to_request();
+    let resp = actix_web::test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 401);
+    assert!(!root.join("b.txt").exists());
+
+    // `*` lets any configured user in, with the access of the entry
+    let req = actix_web::test::TestRequest::post()
+        .uri("/v1/upload?path=@/Public/b.txt")
+        .insert_header(("Authorization", "Basic dTpw")) // u:p
+        .set_payload("written")
+        .to_request();
+    let resp = actix_web::test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+
+    let req = actix_web::test::TestRequest::get()
+        .uri("/v1/exists?path=@/Private/a.txt")
+        .to_request();
+    let resp = actix_web::test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 401);
+    let req = actix_web::test::TestRequest::get()
+        .uri("/v1/exists?path=@/Private/a.txt")
+        .insert_header(("Authorization", "Basic dTpx")) // u:q
+        .to_request();
+    let resp = actix_web::test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 401);
+
+    tokio::fs::remove_dir_all(&root).await.ok();
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_sync_events() -> eyre::Result<()> {
+    use crate::nullfs::events::SyncEvent;
+    use futures::{SinkExt, StreamExt};
+    use tokio_tungstenite::tungstenite::{self, client::IntoClientRequest};
+
+    let config: NodeConfig = serde_yaml::from_str(
+        "name: node\naddress: 127.0.0.1\nport: 5581\nusers:\n  - name: u\n    password: p\n  \
+         - name: w\n    password: p\nrelayNodes: {}\nvolumes:\n  Docs:\n    store:\n      \
+         type: memory\n    allow: [u]\n    pullFrom: []\n  Other:\n    store:\n      \
+         type: memory\n    allow: [w]\n    pullFrom: []\n",
+    )?;
+    let config = web::Data::new(Arc::new(config));
+    let states = web::Data::new(VolumeStates::default());
+    let server_states = states.clone();
+    let address = spawn_mock_relay(move |cfg| {
+        cfg.app_data(config.clone())
+            .app_data(server_states.clone())
+            .service(web::scope("/v1").configure(api_routes));
+    })?;
+
+    let events_url = format!("ws://{}/v1/events", address.authority());
+    let mut request = events_url.as_str().into_client_request()?;
+    request
+        .headers_mut()
+        .insert("Authorization", "Basic dTpw".parse()?); // u:p
+    let (mut socket, _) = tokio_tungstenite::connect_async(request).await?;
+
+    let refused = tokio_tungstenite::connect_async(events_url.as_str()).await;
+    assert!(matches!(
+        refused,
+        Err(tungstenite::Error::Http(resp)) if resp.status() == 401
+    ));
+
+    let stashed = |volume: &str, command: &str| SyncEvent::CommandStashed {
+        volume: volume.to_owned(),
+        command: command.to_owned(),
+    };
+    states.publish(stashed("Other", "Write @/Other/secret.txt"));
+    states.publish(stashed("Docs", "Write @/Docs/a.txt"));
+    states.report_liveness("Docs", "relay", false);
+    // unchanged, nothing published
+    states.report_liveness("Docs", "relay", false);
+    states.report_liveness("Docs", "relay", true);
+
+    let mut received = vec![];
+    while received.len() < 3 {
+        let message = tokio::time::timeout(Duration::from_secs(5), socket.next())
+            .await?
+            .expect("event")?;
+        if let tungstenite::Message::Text(text) = message {
+            received.push(serde_json::from_str::<serde_json::Value>(&text)?);
+        }
+    }
+    assert_eq!(
+        received,
+        vec![
+            serde_json::json!({
+                "type": "commandStashed",
+                "volume": "Docs",
+                "command": "Write @/Docs/a.txt"
+            }),
+            serde_json::json!({ "type": "relayDown", "volume": "Docs", "relay": "relay" }),
+            serde_json::json!({ "type": "relayUp", "volume": "Docs", "relay": "relay" }),
+        ]
+    );
+
+    socket.close(None).await?;
+    socket.flush().await.ok();
+
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_api_cors() -> eyre::Result<()> {
+    use crate::server::api_cors;
+
+    let config: NodeConfig = serde_yaml::from_str(
+        "name: node\naddress: 127.0.0.1\nport: 5580\ncorsAllowedOrigins: [\"https://app.example.com\"]\n\
+         users: []\nrelayNodes: {}\nvolumes: {}\n",
+    )?;
+    let app = actix_web::test::init_service(
+        App::new()
+            .app_data(web::Data::new(Arc::new(config.clone())))
+            .app_data(web::Data::new(VolumeStates::default()))
+            .service(
+                web::scope("/v1")
+                    .wrap(api_cors(&config))
+                    .configure(api_routes),
+            ),
+    )
+    .await;
+
+    let req = actix_web::test::TestRequest::default()
+        .method(actix_web::http::Method::OPTIONS)
+        .uri("/v1/dir?path=@/Docs")
+        .insert_header(("Origin", "https://app.example.com"))
+        .insert_header(("Access-Control-Request-Method", "GET"))
+        .insert_header(("Access-Control-Request-Headers", "authorization"))
+        .to_request();
+    let resp = actix_web::test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(
+        resp.headers().get("access-control-allow-origin").unwrap(),
+        "https://app.example.com"
+    );
+    assert!(
+        resp.headers()
+            .get("access-control-allow-headers")
+            .unwrap()
+            .to_str()?
+            .contains("authorization")
+    );
+
+    let req = actix_web::test::TestRequest::get()
+        .uri("/v1/health")
+        .insert_header(("Origin", "https://app.example.com"))
+        .to_request();
+    let resp = actix_web::test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(
+        resp.headers().get("access-control-allow-origin").unwrap(),
+        "https://app.example.com"
+    );
+
+    let req = actix_web::test::TestRequest::get()
+        .uri("/v1/health")
+        .insert_header(("Origin", "https://other.example.com"))
+        .to_request();
+    let resp = actix_web::test::call_service(&app, req).await;
+    assert!(resp.headers().get("access-control-allow-origin").is_none());
+
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_request_limits() -> eyre::Result<()> {
+    use crate::server::limits::{limit_body, limit_download_time};
+    use actix_web::middleware::from_fn;
+
+    let root = temp_path("limits");
+    tokio::fs::create_dir_all(&root).await?;
+    tokio::fs::write(root.join("a.txt"), b"content").await?;
+
+    let config: NodeConfig = serde_yaml::from_str(&format!(
+        "name: node\naddress: 127.0.0.1\nport: 5583\nmaxBodyBytes: 16\ndownloadTimeoutSecs: 0\n\
+         users:\n  - name: u\n    password: p\nrelayNodes: {{}}\nvolumes:\n  Docs:\n    \
+         store:\n      type: local\n      root: {root}\n    \
+         allow: [{{user: u, access: rw}}]\n    pullFrom: []\n    writable: true\n",
+        root = root.display()
+    ))?;
+    let app = actix_web::test::init_service(
+        App::new()
+            .app_data(web::Data::new(Arc::new(config)))
+            .app_data(web::Data::new(FsSnapshots::default()))
+            .service(
+                web::scope("/v1")
+                    .wrap(from_fn(limit_body))
+                    .configure(api_routes),
+            )
+            .service(
+                web::scope("/slow")
+                    .wrap(from_fn(limit_download_time))
+                    .route(
+                        "",
+                        web::get().to(|| async {
+                            tokio::time::sleep(Duration::from_millis(100)).await;
+                            "late"
+                        }),
+                    ),
+            ),
+    )
+    .await;
+    let auth = ("Authorization", "Basic dTpw"); // u:p
+
+    let req = actix_web::test::TestRequest::post()
+        .uri("/v1/upload?path=@/Docs/declared.txt")
+        .insert_header(auth)
+        .insert_header(("Content-Length", "17"))
+        .set_payload("x".repeat(17))
+        .to_request();
+    let resp = actix_web::test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 413);
+    let body: serde_json::Value = actix_web::test::read_body_json(resp).await;
+    assert_eq!(body["error"]["code"], "payload_too_large");
+
+    // without a declared length the body is counted as it arrives
+    let req = actix_web::test::TestRequest::post()
+        .uri("/v1/upload?path=@/Docs/chunked.txt")
+        .insert_header(auth)
+        .set_payload("x".repeat(17))
+        .to_request();
+    let resp = actix_web::test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 413);
+    assert!(!root.join("declared.txt").exists());
+    assert!(!root.join("chunked.txt").exists());
+
+    let req = actix_web::test::TestRequest::post()
+        .uri("/v1/upload?path=@/Docs/small.txt")
+        .insert_header(auth)
+        .set_payload("x".repeat(16))
+        .to_request();
+    let resp = actix_web::test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+
+    let req = actix_web::test::TestRequest::get()
+        .uri("/slow")
+        .to_request();
+    let err = actix_web::test::try_call_service(&app, req)
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(err.as_response_error().status_code(), 503);
+
+    tokio::fs::remove_dir_all(&root).await.ok();
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_list_volumes() -> eyre::Result<()> {
+    let config: NodeConfig = serde_yaml::from_str(
+        "name: node\naddress: 127.0.0.1\nport: 5584\nusers:\n  - name: u\n    password: p\n  \
+         - name: v\n    password: w\nrelayNodes: {}\nvolumes:\n  Docs:\n    store:\n      \
+         type: memory\n    allow: [{user: u, access: rw}, {user: v, access: ro}]\n    \
+         pullFrom: []\n    writable: true\n  Pics:\n    store:\n      type: memory\n    \
+         allow: [v]\n    pullFrom: []\n",
+    )?;
+    let app = actix_web::test::init_service(
+        App::new()
+            .app_data(web::Data::new(Arc::new(config)))
+            .app_data(web::Data::new(FsSnapshots::default()))
+            .service(web::scope("/v1").configure(api_routes)),
+    )
+    .await;
+
+    let list = |auth: &'static str| {
+        actix_web::test::TestRequest::get()
+            .uri("/v1/volumes")
+            .insert_header(("Authorization", auth))
+            .to_request()
+    };
+
+    let body: serde_json::Value =
+        actix_web::test::call_and_read_body_json(&app, list("Basic dTpw")).await; // u:p
+    assert_eq!(
+        body["volumes"],
+        serde_json::json!([{ "name": "Docs", "kind": "memory", "writable": true }])
+    );
+
+    let body: serde_json::Value =
+        actix_web::test::call_and_read_body_json(&app, list("Basic djp3")).await; // v:w
+    assert_eq!(
+        body["volumes"],
+        serde_json::json!([
+            { "name": "Docs", "kind": "memory", "writable": false },
+            { "name": "Pics", "kind": "memory", "writable": false },
+        ])
+    );
+
+    let resp = actix_web::test::call_service(&app, list("Basic dTpx")).await; // u:q
+    assert_eq!(resp.status(), 401);
+
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_api_errors() -> eyre::Result<()> {
+    let root = temp_path("api-errors");
+    tokio::fs::create_dir_all(&root).await?;
+
+    let config: NodeConfig = serde_yaml::from_str(&format!(
+        "name: node\naddress: 127.0.0.1\nport: 5578\nusers:\n  - name: u\n    password: p\n\
+         relayNodes: {{}}\nvolumes:\n  Docs:\n    store:\n      type: local\n      \
+         root: {root}\n    allow: [u]\n    pullFrom: []\n",
+        root = root.display()
+    ))?;
+    let app = actix_web::test::init_service(
+        App::new()
+            .app_data(web::Data::new(Arc::new(config)))
+            .app_data(web::Data::new(FsSnapshots::default()))
+            .service(web::scope("/v1").configure(api_routes)),
+    )
+    .await;
+    let auth = ("Authorization", "Basic dTpw"); // u:p
+
+    let cases = [
+        (
+            "/v1/stat?path=@/Docs/a.txt",
+            "Basic dTpx",
+            401,
+            "unauthorized",
+        ), // u:q
+        ("/v1/stat?path=@/Docs/a.txt", auth.1, 404, "not_found"),
+        ("/v1/search?volume=Docs&q=%20", auth.1, 400, "bad_request"),
+    ];
+    for (uri, credentials, status, code) in cases {
+        let req = actix_web::test::TestRequest::get()
+            .uri(uri)
+            .insert_header(("Authorization", credentials))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), status, "{uri}");
+        if status == 401 {
+            assert_eq!(
+                resp.headers().get("www-authenticate").unwrap(),
+                "Basic realm=\"nullfs\""
+            );
+        }
+
+        let body: serde_json::Value = actix_web::test::read_body_json(resp).await;
+        assert_eq!(body["error"]["code"], code, "{uri}");
+        assert!(body["error"]["message"].is_string(), "{uri}");
+    }
+
+    let req = actix_web::test::TestRequest::post()
+        .uri("/v1/upload?path=@/Docs/a.txt")
+        .insert_header(auth)
+        .set_payload("nope")
+        .to_request();
+    let resp = actix_web::test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 403);
+    let body: serde_json::Value = actix_web::test::read_body_json(resp).await;
+    assert_eq!(body["error"]["code"], "forbidden");
+
+    tokio::fs::remove_dir_all(&root).await.ok();
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_snapshot_nullfsignore() -> eyre::Result<()> {
+    let fs = AnyFs {
+        volume_name: "Mem".to_owned(),
+        fs_instance: Arc::new(tokio::sync::RwLock::new(MemVolume::new("Mem"))),
+    };
+    let file = |rel: &str| -> eyre::Result<File> {
+        let path = NullFsPath::from_to_str(format!("@/Mem/{rel}"))?;
+        Ok(File {
+            file_type: FileType::infer_from_path(&path),
+            path,
+            stat: FileStat {
+                node: NodeKind::File { size: 0 },
+                modified: 0,
+                created: None,
+                accessed: None,
+            },
+        })
+    };
+    let written = |commands: &[Command]| {
+        let mut paths = commands
+            .iter()
+            .map(|command| match command {
+                Command::Write { file } | Command::Touch { file } | Command::Delete { file } => {
+                    file.path.to_string()
+                }
+                Command::Rename { to, .. } => to.path.to_string(),
+            })
+            .collect::<Vec<_>>();
+        paths.sort();
+        paths
+    };
+
+    for rel in ["a.txt", "notes.txt", "x.tmp", "keep.tmp", "build/out.o"] {
+        fs.write(&file(rel)?, rel.as_bytes()).await?;
+    }
+    fs.write(&file(".nullfsignore")?, b"*.tmp\n!keep.tmp\nbuild/\n")
+        .await?;
+
+    let state_file = temp_path("ignore.db");
+    let snapshot = Snapshot::new(fs.clone());
+    let commands = snapshot.clone().capture(&state_file).await?;
+    assert_eq!(
+        written(&commands),
+        [
+            "@/Mem/.nullfsignore",
+            "@/Mem/a.txt",
+            "@/Mem/keep.tmp",
+            "@/Mem/notes.txt"
+        ]
+    );
+
+    // a path ignored after being synced is left alone rather than deleted
+    fs.write(
+        &file(".nullfsignore")?,
+        b"*.tmp\n!keep.tmp\nbuild/\nnotes.txt\n",
+    )
+    .await?;
+    let commands = snapshot.clone().capture(&state_file).await?;
+    assert_eq!(written(&commands), ["@/Mem/.nullfsignore"]);
+
+    tokio::fs::remove_file(&state_file).await.ok();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_atomic_local_write() -> eyre::Result<()> {
+    let root = temp_path("atomic");
+    tokio::fs::create_dir_all(root.join("taken/inner")).await?;
+    let mut fs = AnyFs::from_volume_item("Atomic", &local_volume(&root))?;
+    fs.init().await?;
+
+    let file = |rel: &str| -> eyre::Result<File> {
+        let path = NullFsPath::from_to_str(format!("@/Atomic/{rel}"))?;
+        Ok(File {
+            file_type: FileType::infer_from_path(&path),
+            path,
+            stat: FileStat {
+                node: NodeKind::File { size: 3 },
+                modified: 0,
+                created: None,
+                accessed: None,
+            },
+        })
+    };
+
+    fs.write(&file("a.txt")?, b"old").await?;
+    fs.write(&file("a.txt")?, b"new").await?;
+    assert_eq!(tokio::fs::read(root.join("a.txt")).await?, b"new");
+
+    // a write in progress is never listed
+    tokio::fs::write(root.join(".b.txt.nullfs-tmp"), b"partial").await?;
+    let listed = fs.dir(&fs.volume_root()?).await?;
+    assert_eq!(listed.len(), 2, "{listed:?}");
+
+    // the directory in the way makes the rename fail, its temp file goes away
+    assert!(fs.write(&file("taken")?, b"abc").await.is_err());
+    assert!(root.join("taken/inner").is_dir());
+    assert!(!root.join(".taken.nullfs-tmp").exists());
+
+    tokio::fs::remove_dir_all(&root).await.ok();
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_write_keeps_source_mtime() -> eyre::Result<()> {
+    let source_root = temp_path("mtime-source");
+    let target_root = temp_path("mtime-target");
+    tokio::fs::create_dir_all(&source_root).await?;
+    tokio::fs::create_dir_all(&target_root).await?;
+    tokio::fs::write(source_root.join("a.txt"), b"content").await?;
+    let mut source = AnyFs::from_volume_item("Docs", &local_volume(&source_root))?;
+    let mut target = AnyFs::from_volume_item("Docs", &local_volume(&target_root))?;
+    source.init().await?;
+    target.init().await?;
+
+    // written a while after the source, as a sync would
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    let path = NullFsPath::from_to_str("@/Docs/a.txt")?;
+    let file = File {
+        file_type: FileType::infer_from_path(&path),
+        stat: source.stats(&path).await?,
+        path: path.clone(),
+    };
+    target.write(&file, &source.read(&path).await?).await?;
+
+    assert_eq!(target.stats(&path).await?.modified, file.stat.modified);
+
+    tokio::fs::remove_dir_all(&source_root).await.ok();
+    tokio::fs::remove_dir_all(&target_root).await.ok();
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_path_traversal() -> eyre::Result<()> {
+    assert!(NullFsPath::from_to_str("@/vol/../../etc/passwd").is_err());
+    assert!(NullFsPath::from_to_str("@/vol/a\\..\\b").is_err());
+    assert!(NullFsPath::from(Path::new("vol/../etc")).is_err());
+    assert!(
+        NullFsPath::from_to_str("@/vol")?
+            .extend(vec!["..".to_owned()])
+            .is_err()
+    );
+    assert_eq!(
+        NullFsPath::from_to_str("@/vol/./a/./b.txt")?,
+        NullFsPath::from_to_str("@/vol/a/b.txt")?
+    );
+
+    // a symlink cannot lead the resolver out of the volume root
+    let root = temp_path("traversal");
+    let outside = temp_path("outside");
+    tokio::fs::create_dir_all(&root).await?;
+    tokio::fs::create_dir_all(&outside).await?;
+    tokio::fs::write(outside.join("secret.txt"), b"secret").await?;
+    tokio::fs::write(root.join("a.txt"), b"a").await?;
+    #[cfg(unix)]
+    std::os::unix::fs::symlink(&outside, root.join("escape"))?;
+    #[cfg(windows)]
+    std::os::windows::fs::symlink_dir(&outside, root.join("escape"))?;
+
+    let mut fs = AnyFs::from_volume_item("Vol", &local_volume(&root))?;
+    fs.init().await?;
+    assert_eq!(
+        fs.read(&NullFsPath::from_to_str("@/Vol/a.txt")?).await?,
+        b"a"
+    );
+
+    let secret = NullFsPath::from_to_str("@/Vol/escape/secret.txt")?;
+    let err = fs.read(&secret).await.unwrap_err();
+    assert!(err.to_string().contains("outside of the volume root"));
+    let file = File {
+        file_type: FileType::infer_from_path(&secret),
+        path: NullFsPath::from_to_str("@/Vol/escape/new.txt")?,
+        stat: FileStat {
+            node: NodeKind::File { size: 0 },
+            modified: 0,
+            created: None,
+            accessed: None,
+        },
+    };
+    assert!(fs.write(&file, b"x").await.is_err());
+    assert!(!outside.join("new.txt").exists());
+
+    tokio::fs::remove_dir_all(&root).await.ok();
+    tokio::fs::remove_dir_all(&outside).await.ok();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_trash() -> eyre::Result<()> {
+    let root = temp_path("trash");
+    tokio::fs::create_dir_all(root.join("sub")).await?;
+    tokio::fs::write(root.join("sub/a.txt"), b"a").await?;
+    tokio::fs::write(root.join("b.txt"), b"b").await?;
+
+    let mut volume = local_volume(&root);
+    volume.store = StoreKind::Local {
+        root: root.clone(),
+        follow_symlinks: false,
+        trash: true,
+        trash_retention_days: Some(30),
+    };
+    let mut fs = AnyFs::from_volume_item("Vol", &volume)?;
+    fs.init().await?;
+    let state_file = temp_path("trash.db");
+    Snapshot::new(fs.clone()).capture(&state_file).await?;
+
+    // the deleted file can be put back from the trash
+    let deleted = NullFsPath::from_to_str("@/Vol/sub/a.txt")?;
+    fs.delete(&File {
+        file_type: FileType::infer_from_path(&deleted),
+        stat: fs.stats(&deleted).await?,
+        path: deleted.clone(),
+    })
+    .await?;
+    assert!(!fs.exists(&deleted).await?);
+    let mut stamps = tokio::fs::read_dir(root.join(TRASH_DIR)).await?;
+    let stamp = stamps.next_entry().await?.unwrap().path();
+    assert_eq!(tokio::fs::read(stamp.join("sub/a.txt")).await?, b"a");
+
+    // only the deletion syncs, not the trash
+    let commands = Snapshot::new(fs.clone()).capture(&state_file).await?;
+    assert_eq!(commands.len(), 1);
+    assert!(
+        matches!(&commands[0], Command::Delete { file } if file.path == deleted),
+        "{commands:?}"
+    );
+    assert!(
+        Snapshot::new(fs.clone())
+            .capture(&state_file)
+            .await?
+            .is_empty()
+    );
+
+    // the next deletion prunes what is past the retention
+    let expired = root.join(TRASH_DIR).join("expired");
+    tokio::fs::create_dir_all(&expired).await?;
+    std::fs::File::open(&expired)?
+        .set_modified(SystemTime::now() - Duration::from_secs(31 * 24 * 3600))?;
+    let deleted = NullFsPath::from_to_str("@/Vol/b.txt")?;
+    fs.delete(&File {
+        file_type: FileType::infer_from_path(&deleted),
+        stat: fs.stats(&deleted).await?,
+        path: deleted,
+    })
+    .await?;
+    assert!(!expired.exists());
+    assert!(stamp.join("sub/a.txt").exists());
+
+    tokio::fs::remove_file(&state_file).await.ok();
+    tokio::fs::remove_dir_all(&root).await.ok();
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_self_referential_symlink() -> eyre::Result<()> {
+    let root = temp_path("symlink-loop");
+    tokio::fs::create_dir_all(root.join("sub")).await?;
+    tokio::fs::write(root.join("a.txt"), b"a").await?;
+    tokio::fs::write(root.join("sub/b.txt"), b"b").await?;
+    std::os::unix::fs::symlink("..", root.join("sub/loop"))?;
+
+    // recorded as is by default
+    let mut fs = AnyFs::from_volume_item("Vol", &local_volume(&root))?;
+    fs.init().await?;
+    let link = NullFsPath::from_to_str("@/Vol/sub/loop")?;
+    assert_eq!(
+        fs.stats(&link).await?.node,
+        NodeKind::Symlink {
+            target: "..".to_owned()
+        }
+    );
+    assert_eq!(fs.hash(&link).await?, crate::nullfs::hashing::digest(".."));
+    fs.hash(&fs.volume_root()?).await?;
+
+    // followed, the link back to the root is not walked again
+    let mut volume = local_volume(&root);
+    volume.store = StoreKind::Local {
+        root: root.clone(),
+        follow_symlinks: true,
+        trash: false,
+        trash_retention_days: None,
+    };
+    let mut fs = AnyFs::from_volume_item("Vol", &volume)?;
+    fs.init().await?;
+    assert!(fs.stats(&link).await?.is_dir());
+    assert_eq!(fs.real_path(&link).await?, fs.volume_root()?);
+    fs.hash(&fs.volume_root()?).await?;
+
+    let state_file = temp_path("symlink-loop.db");
+    let commands = Snapshot::new(fs.clone()).capture(&state_file).await?;
+    let written = commands
+        .iter()
+        .filter_map(|command| match command {
+            Command::Write { file } => Some(file.path.to_string()),
+            _ => None,
+        })
+        .collect::<HashSet<_>>();
+    assert!(written.contains("@/Vol/sub/loop"));
+    assert!(!written.iter().any(|path| path.contains("loop/")));
+    assert!(Snapshot::new(fs).capture(&state_file).await?.is_empty());
+
+    tokio::fs::remove_file(&state_file).await.ok();
+    tokio::fs::remove_dir_all(&root).await.ok();
+    Ok(())
+}
+
+#[cfg(unix)]
+#[actix_web::test]
+async fn test_cross_directory_symlink() -> eyre::Result<()> {
+    let root = temp_path("symlink-cross");
+    tokio::fs::create_dir_all(root.join("a")).await?;
+    tokio::fs::create_dir_all(root.join("b")).await?;
+    tokio::fs::write(root.join("b/file.txt"), b"content").await?;
+    std::os::unix::fs::symlink("../b/file.txt", root.join("a/link"))?;
+
+    let mut fs = AnyFs::from_volume_item("Vol", &local_volume(&root))?;
+    fs.init().await?;
+    let state_file = temp_path("symlink-cross.db");
+    let commands = Snapshot::new(fs.clone()).capture(&state_file).await?;
+    let link = commands
+        .iter()
+        .find_map(|command| match command {
+            Command::Write { file } if file.path.to_string() == "@/Vol/a/link" => {
+                Some(file.clone())
+            }
+            _ => None,
+        })
+        .unwrap();
+    assert_eq!(
+        link.stat.node,
+        NodeKind::Symlink {
+            target: "../b/file.txt".to_owned()
+        }
+    );
+    assert_ne!(
+        fs.hash(&link.path).await?,
+        fs.hash(&NullFsPath::from_to_str("@/Vol/b/file.txt")?)
+            .await?
+    );
+
+    // the other node recreates the link instead of downloading the content
+    let command: Command = serde_json::from_str(&serde_json::to_string(&Command::Write {
+        file: File {
+            path: NullFsPath::from_to_str("@/Copy/a/link")?,
+            ..link.clone()
+        },
+    })?)?;
+    let downloads = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let relay_downloads = downloads.clone();
+    let relay = spawn_mock_relay(move |cfg| {
+        let downloads = relay_downloads.clone();
+        cfg.route(
+            "/v1/exists",
+            web::get().to(|| async { HttpResponse::Ok().json(true) }),
+        )
+        .default_service(web::to(move || {
+            downloads.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            async { HttpResponse::NotFound().finish() }
+        }));
+    })?;
+
+    let copy_root = temp_path("symlink-copy");
+    tokio::fs::create_dir_all(copy_root.join("b")).await?;
+    tokio::fs::write(copy_root.join("b/file.txt"), b"content").await?;
+    let mut copy = AnyFs::from_volume_item("Copy", &local_volume(&copy_root))?;
+    copy.init().await?;
+    mock_share_node(relay)
+        .await?
+        .run_command(&command, &copy, &[], &Prefetched::default())
+        .await?;
+    assert_eq!(downloads.load(std::sync::atomic::Ordering::SeqCst), 0);
+    assert_eq!(
+        tokio::fs::read_link(copy_root.join("a/link")).await?,
+        PathBuf::from("../b/file.txt")
+    );
+    assert_eq!(
+        copy.read(&NullFsPath::from_to_str("@/Copy/a/link")?)
+            .await?,
+        b"content"
+    );
+
+    // nor can a link lead out of the volume
+    let escape = File {
+        path: NullFsPath::from_to_str("@/Copy/a/escape")?,
+        stat: FileStat {
+            node: NodeKind::Symlink {
+                target: "../../outside".to_owned(),
+            },
+            ..link.stat.clone()
+        },
+        ..link
+    };
+    let err = copy.write(&escape, &[]).await.unwrap_err();
+    assert!(format!("{err:#}").contains("outside of the volume root"));
+
+    tokio::fs::remove_file(&state_file).await.ok();
+    tokio::fs::remove_dir_all(&root).await.ok();
+    tokio::fs::remove_dir_all(&copy_root).await.ok();
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_remote_stat() -> eyre::Result<()> {
+    let config: NodeConfig = serde_yaml::from_str(
+        "name: relay\naddress: 127.0.0.1\nport: 5566\nusers:\n  - name: user\n\
+         relayNodes: {}\nvolumes:\n  Docs:\n    store:\n      type: local\n      \
+         root: src/tests/test_dir\n    allow: [user]\n    pullFrom: []\n",
+    )?;
+    let config = Arc::new(config);
+    let relay = spawn_mock_relay(move |cfg| {
+        cfg.app_data(web::Data::new(config.clone()))
+            .app_data(web::Data::new(FsSnapshots::default()))
+            .service(web::scope("/v1").configure(api_routes));
+    })?;
+    let share_node = mock_share_node(relay).await?;
+
+    let mut fs = AnyFs::from_volume_item("Docs", &local_volume(Path::new("src/tests/test_dir")))?;
+    fs.init().await?;
+    let path = NullFsPath::from_to_str("@/Docs/c/d.txt")?;
+    assert_eq!(
+        share_node.remote_stat(&path).await?,
+        Some(fs.stats(&path).await?)
+    );
+
+    let dir = share_node.remote_stat(&path.parent().unwrap()).await?;
+    assert!(dir.is_some_and(|stat| stat.is_dir()));
+    let missing = NullFsPath::from_to_str("@/Docs/c/missing.txt")?;
+    assert_eq!(share_node.remote_stat(&missing).await?, None);
+
+    // the paths that cannot be hashed are left out
+    let hashes = share_node.remote_hashes(&[path.clone(), missing]).await?;
+    assert_eq!(
+        hashes.into_iter().collect::<Vec<_>>(),
+        vec![(path.clone(), fs.hash(&path).await?)]
+    );
+
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_tree_diff() -> eyre::Result<()> {
+    let remote_root = temp_path("tree");
+    tokio::fs::create_dir_all(remote_root.join("c/e")).await?;
+    for (rel, content) in [
+        ("a.txt", "a"),
+        ("b.txt", "b"),
+        ("c/d.txt", "d"),
+        ("c/e/f.txt", "f"),
+    ] {
+        tokio::fs::write(remote_root.join(rel), content).await?;
+    }
+
+    let config: NodeConfig = serde_yaml::from_str(&format!(
+        "name: relay\naddress: 127.0.0.1\nport: 5569\nusers:\n  - name: user\n\
+         relayNodes: {{}}\nvolumes:\n  Docs:\n    store:\n      type: local\n      \
+         root: {}\n    allow: [user]\n    pullFrom: []\n",
+        remote_root.display()
+    ))?;
+    let config = Arc::new(config);
+    let relay = spawn_mock_relay(move |cfg| {
+        cfg.app_data(web::Data::new(config.clone()))
+            .app_data(web::Data::new(FsSnapshots::default()))
+            .service(web::scope("/v1").configure(api_routes));
+    })?;
+    let share_node = mock_share_node(relay).await?;
+
+    let local = AnyFs {
+        volume_name: "Docs".to_owned(),
+        fs_instance: Arc::new(tokio::sync::RwLock::new(MemVolume::new("Docs"))),
+    };
+    let path = |rel: &str| NullFsPath::from_to_str(format!("@/Docs/{rel}"));
+    let file = |rel: &str| -> eyre::Result<File> {
+        let path = path(rel)?;
+        Ok(File {
+            file_type: FileType::infer_from_path(&path),
+            path,
+            stat: FileStat {
+                node: NodeKind::File { size: 1 },
+                modified: 0,
+                created: None,
+                accessed: None,
+            },
+        })
+    };
+    for (rel, content) in [
+        ("a.txt", "a"),
+        ("b.txt", "B"),
+        ("c/d.txt", "d"),
+        ("g.txt", "g"),
+    ] {
+        local.write(&file(rel)?, content.as_bytes()).await?;
+    }
+
+    // directories hash the same through the tree and through NullFs::hash
+    let mut remote_fs = AnyFs::from_volume_item("Docs", &local_volume(&remote_root))?;
+    remote_fs.init().await?;
+    let root = local.volume_root()?;
+    let remote = share_node.fetch_tree(&root).await?;
+    assert_eq!(remote.hash, remote_fs.hash(&root).await?);
+
+    let mut diverging = share_node.diverging_paths(&local, &root).await?;
+    diverging.sort_by_key(|path| path.to_string());
+    assert_eq!(
+        diverging,
+        vec![path("b.txt")?, path("c/e")?, path("g.txt")?]
+    );
+
+    let identical = share_node.diverging_paths(&local, &path("a.txt")?).await?;
+    assert!(identical.is_empty());
+
+    tokio::fs::remove_dir_all(&remote_root).await.ok();
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_tie_break() -> eyre::Result<()> {
+    // relays listed in `pullFrom` order, the last two agree on the newest version
+    let versions: [(&'static [u8], u64); 3] = [(b"one", 1000), (b"two", 2000), (b"two", 3000)];
+    let downloads = Arc::new([const { AtomicU32::new(0) }; 3]);
+
+    let mut addresses = vec![];
+    for (at, (content, modified)) in versions.into_iter().enumerate() {
+        let downloads = downloads.clone();
+        addresses.push(spawn_mock_relay(move |cfg| {
+            let downloads = downloads.clone();
+            cfg.route("/v1/info", web::get().to(HttpResponse::Ok))
+                .route(
+                    "/v1/exists",
+                    web::get().to(|| async { HttpResponse::Ok().json(true) }),
+                )
+                .route(
+                    "/v1/hashes",
+                    web::post().to(move |paths: web::Json<Vec<NullFsPath>>| async move {
+                        let hash = format!("{:x}", Sha256::digest(content));
+                        let hashes = paths
+                            .iter()
+                            .map(|path| (path.clone(), hash.clone()))
+                            .collect::<IndexMap<_, _>>();
+                        HttpResponse::Ok().json(hashes)
+                    }),
+                )
+                .route(
+                    "/v1/stats",
+                    web::post().to(move |paths: web::Json<Vec<NullFsPath>>| async move {
+                        let stat = FileStat {
+                            node: NodeKind::File {
+                                size: content.len() as u64,
+                            },
+                            modified,
+                            created: None,
+                            accessed: None,
+                        };
+                        let stats = paths
+                            .iter()
+                            .map(|path| (path.clone(), stat.clone()))
+                            .collect::<IndexMap<_, _>>();
+                        HttpResponse::Ok().json(stats)
+                    }),
+                )
+                // the prefetched hashes and times are enough
+                .route(
+                    "/v1/hash",
+                    web::get().to(|| async { HttpResponse::InternalServerError().finish() }),
+                )
+                .route(
+                    "/v1/stat",
+                    web::get().to(|| async { HttpResponse::InternalServerError().finish() }),
+                )
+                .route(
+                    "/v1/download",
+                    web::get().to(move || {
+                        let downloads = downloads.clone();
+                        async move {
+                            downloads[at].fetch_add(1, Ordering::SeqCst);
+                            HttpResponse::Ok().body(content)
+                        }
+                    }),
+                );
+        })?);
+    }
+
+    let path = NullFsPath::from_to_str("@/vol/a.txt")?;
+    let write = Command::Write {
+        file: File {
+            file_type: FileType::infer_from_path(&path),
+            path: path.clone(),
+            stat: FileStat {
+                node: NodeKind::File { size: 3 },
+                modified: 3000,
+                created: None,
+                accessed: None,
+            },
+        },
+    };
+
+    // a local copy of the version pulled from the first relay is no reason to skip the others
+    for (tie_break, served_by, content, local) in [
+        (TieBreak::Priority, 0, &b"one"[..], None),
+        (TieBreak::Newest, 2, b"two", None),
+        (TieBreak::Quorum, 1, b"two", None),
+        (TieBreak::Newest, 2, b"two", Some(b"one")),
+    ] {
+        let root = temp_path("tie-break");
+        tokio::fs::create_dir_all(&root).await?;
+        if let Some(local) = local {
+            tokio::fs::write(root.join("a.txt"), local).await?;
+        }
+        let mut fs = AnyFs::from_volume_item("vol", &local_volume(&root))?;
+        fs.init().await?;
+
+        let mut relays = vec![];
+        for (priority, address) in addresses.iter().enumerate() {
+            let mut relay = mock_share_node(address.clone()).await?;
+            relay.name = format!("relay-{priority}");
+            relay.priority = priority;
+            relay.tie_break = Some(tie_break.clone());
+            relays.push(relay);
+        }
+        relays[0]
+            .store
+            .stash(vec![write.clone()], &fs, None)
+            .await?;
+        for count in downloads.iter() {
+            count.store(0, Ordering::SeqCst);
+        }
+
+        let report = relays[0].apply_commands(&fs, &relays).await?;
+        assert_eq!(report.applied, 1, "{tie_break:?}: {:?}", report.failures);
+        assert_eq!(tokio::fs::read(root.join("a.txt")).await?, content);
+        let served = downloads
+            .iter()
+            .map(|count| count.load(Ordering::SeqCst))
+            .collect::<Vec<_>>();
+        let mut expected = vec![0; 3];
+        expected[served_by] = 1;
+        assert_eq!(served, expected, "{tie_break:?}");
+
+        tokio::fs::remove_dir_all(&root).await.ok();
+    }
+
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_prefetched_hashes() -> eyre::Result<()> {
+    let relay = spawn_mock_relay(|cfg| {
+        cfg.route(
+            "/v1/hashes",
+            web::post().to(|paths: web::Json<Vec<NullFsPath>>| async move {
+                let hash = format!("{:x}", Sha256::digest(b"same"));
+                let hashes = paths
+                    .iter()
+                    .map(|path| (path.clone(), hash.clone()))
+                    .collect::<IndexMap<_, _>>();
+                HttpResponse::Ok().json(hashes)
+            }),
+        )
+        .route(
+            "/v1/hash",
+            web::get().to(|| async { HttpResponse::InternalServerError().finish() }),
+        );
+    })?;
+
+    let local = AnyFs {
+        volume_name: "Mem".to_owned(),
+        fs_instance: Arc::new(tokio::sync::RwLock::new(MemVolume::new("Mem"))),
+    };
+    let path = NullFsPath::from_to_str("@/Mem/a.txt")?;
+    let file = File {
+        file_type: FileType::infer_from_path(&path),
+        path: path.clone(),
+        stat: FileStat {
+            node: NodeKind::File { size: 4 },
+            modified: 0,
+            created: None,
+            accessed: None,
+        },
+    };
+    local.write(&file, b"same").await?;
+
+    let share_node = mock_share_node(relay).await?;
+    let hashes = share_node
+        .remote_hashes(std::slice::from_ref(&path))
+        .await?;
+    assert_eq!(hashes.len(), 1);
+
+    // answered by the batch, the single path endpoint is never reached
+    share_node
+        .store
+        .stash(vec![Command::Touch { file }], &local, None)
+        .await?;
+    let report = share_node.apply_commands(&local, &[]).await?;
+    assert!(report.failures.is_empty(), "{:?}", report.failures);
+    assert_eq!(report.skipped, 1);
+
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_refuses_corrupted_download() -> eyre::Result<()> {
+    let relay = spawn_mock_relay(|cfg| {
+        cfg.route(
+            "/v1/exists",
+            web::get().to(|| async { HttpResponse::Ok().json(true) }),
+        )
+        .route(
+            "/v1/hash",
+            web::get().to(|| async {
+                HttpResponse::Ok().json(format!("{:x}", Sha256::digest(b"expected")))
+            }),
+        )
+        .route(
+            "/v1/download",
+            web::get().to(|| async { HttpResponse::Ok().body("tampered") }),
+        );
+    })?;
+
+    let local = AnyFs {
+        volume_name: "Mem".to_owned(),
+        fs_instance: Arc::new(tokio::sync::RwLock::new(MemVolume::new("Mem"))),
+    };
+    let path = NullFsPath::from_to_str("@/Mem/a.txt")?;
+    let file = File {
+        file_type: FileType::infer_from_path(&path),
+        path: path.clone(),
+        stat: FileStat {
+            node: NodeKind::File { size: 8 },
+            modified: 0,
+            created: None,
+            accessed: None,
+        },
+    };
+
+    let share_node = mock_share_node(relay).await?;
+    share_node
+        .store
+        .stash(vec![Command::Write { file }], &local, None)
+        .await?;
+    let report = share_node.apply_commands(&local, &[]).await?;
+    assert_eq!(report.failures.len(), 1);
+    assert!(report.failures[0].error.contains("content hash"));
+    assert!(!local.exists(&path).await?);
+
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_copy_duplicates() -> eyre::Result<()> {
+    let downloads = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let relay_downloads = downloads.clone();
+    let relay = spawn_mock_relay(move |cfg| {
+        let downloads = relay_downloads.clone();
+        cfg.route(
+            "/v1/exists",
+            web::get().to(|| async { HttpResponse::Ok().json(true) }),
+        )
+        .route(
+            "/v1/hashes",
+            web::post().to(|paths: web::Json<Vec<NullFsPath>>| async move {
+                let hash = format!("{:x}", Sha256::digest(b"same"));
+                let hashes = paths
+                    .iter()
+                    .map(|path| (path.clone(), hash.clone()))
+                    .collect::<IndexMap<_, _>>();
+                HttpResponse::Ok().json(hashes)
+            }),
+        )
+        .route(
+            "/v1/download",
+            web::get().to(move || {
+                downloads.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                async { HttpResponse::Ok().body("same") }
+            }),
+        );
+    })?;
+
+    let local = AnyFs {
+        volume_name: "Mem".to_owned(),
+        fs_instance: Arc::new(tokio::sync::RwLock::new(MemVolume::new("Mem"))),
+    };
+    let file = |rel: &str| -> eyre::Result<File> {
+        let path = NullFsPath::from_to_str(format!("@/Mem/{rel}"))?;
+        Ok(File {
+            file_type: FileType::infer_from_path(&path),
+            path,
+            stat: FileStat {
+                node: NodeKind::File { size: 4 },
+                modified: 0,
+                created: None,
+                accessed: None,
+            },
+        })
+    };
+
+    let state_path = temp_path("duplicates-state.db");
+    let mut share_node = mock_share_node(relay).await?;
+    share_node.hashes_store = Some(StateStore::open(&state_path).await?);
+    share_node
+        .store
+        .stash(
+            vec![
+                Command::Write {
+                    file: file("a.txt")?,
+                },
+                Command::Write {
+                    file: file("copies/b.txt")?,
+                },
+            ],
+            &local,
+            None,
+        )
+        .await?;
+    let report = share_node.apply_commands(&local, &[]).await?;
+    assert!(report.failures.is_empty(), "{:?}", report.failures);
+    assert_eq!(report.applied, 2);
+    assert_eq!(downloads.load(std::sync::atomic::Ordering::SeqCst), 1);
+    assert_eq!(local.read(&file("copies/b.txt")?.path).await?, b"same");
+
+    // the duplicates are still known once the hashes are loaded back
+    let store = StateStore::open(&state_path).await?;
+    share_node.hashes = Arc::new(tokio::sync::Mutex::new(store.load_synced().await?));
+    share_node.hashes_store = Some(store);
+    share_node
+        .store
+        .stash(
+            vec![Command::Write {
+                file: file("copies/c.txt")?,
+            }],
+            &local,
+            None,
+        )
+        .await?;
+    let report = share_node.apply_commands(&local, &[]).await?;
+    assert_eq!(report.applied, 1, "{:?}", report.failures);
+    assert_eq!(downloads.load(std::sync::atomic::Ordering::SeqCst), 1);
+    assert_eq!(local.read(&file("copies/c.txt")?.path).await?, b"same");
+
+    tokio::fs::remove_file(&state_path).await.ok();
+
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_peer_identities() -> eyre::Result<()> {
+    let root = temp_path("peer-identities");
+    let data_dir = root.join("data");
+    tokio::fs::create_dir_all(&data_dir).await?;
+    tokio::fs::create_dir_all(root.join("docs")).await?;
+
+    let config: NodeConfig = serde_yaml::from_str(&format!(
+        "name: relay\naddress: 127.0.0.1\nport: 5592\ndataDir: {}\nusers:\n  - name: u\n    \
+         password: p\n  - name: w\n    password: q\nrelayNodes: {{}}\nvolumes:\n  Docs:\n    \
+         store:\n      type: local\n      root: {}\n    allow: [u, w]\n    pullFrom: []\n",
+        data_dir.display(),
+        root.join("docs").display()
+    ))?;
+    let relay_id = Arc::new(NodeIdentifier {
+        uuid: uuid::Uuid::new_v4().to_string(),
+    });
+    let claims = data_dir.join(".peer-claims.json");
+    let app = actix_web::test::init_service(
+        App::new()
+            .app_data(web::Data::new(Arc::new(config)))
+            .app_data(web::Data::new(relay_id.clone()))
+            .app_data(web::Data::new(PeerRegistry::load(Some(claims.clone()))?))
+            .app_data(web::Data::new(FsSnapshots::default()))
+            .service(web::scope("/v1").configure(api_routes)),
+    )
+    .await;
+    let pull = async |auth: &str, node_id: &str, realm: Option<&str>| {
+        let realm = realm.map(|r| format!("&realm={r}")).unwrap_or_default();
+        let req = actix_web::test::TestRequest::get()
+            .uri(&format!(
+                "/v1/commands?volume=Docs&node_id={node_id}{realm}"
+            ))
+            .insert_header(("Authorization", format!("Basic {auth}")))
+            .to_request();
+        actix_web::test::call_service(&app, req).await.status()
+    };
+
+    // the realm is part of the peer state file name
+    assert_eq!(pull("dTpw", "peer-a", Some("acme")).await, 200); // u:p
+    assert_eq!(pull("dTpw", "peer-b", None).await, 200);
+    let relay_uuid = &relay_id.uuid;
+    assert!(
+        data_dir
+            .join(format!(".ext-state-Docs-{relay_uuid}-acme-peer-a.db"))
+            .exists()
+    );
+    assert!(
+        data_dir
+            .join(format!(".ext-state-Docs-{relay_uuid}-peer-b.db"))
+            .exists()
+    );
+
+    // another user, another realm, or the relay's own id collide
+    assert_eq!(pull("dzpx", "peer-a", Some("acme")).await, 409); // w:q
+    assert_eq!(pull("dTpw", "peer-a", Some("other")).await, 409);
+    assert_eq!(pull("dTpw", "peer-a", None).await, 409);
+    assert_eq!(pull("dTpw", relay_uuid, None).await, 409);
+    assert_eq!(pull("dTpw", "peer-a", Some("acme")).await, 200);
+
+    // the claims survive a restart
+    let reloaded = PeerRegistry::load(Some(claims))?;
+    assert!(!reloaded.claim("peer-a", "w", Some("acme"))?);
+    assert!(!reloaded.claim("peer-b", "u", Some("acme"))?);
+    assert!(reloaded.claim("peer-a", "u", Some("acme"))?);
+
+    // the least recently seen node id is forgotten past the bound
+    let bounded = PeerRegistry::default();
+    for i in 0..MAX_PEER_CLAIMS {
+        assert!(bounded.claim(&format!("node-{i}"), "u", None)?);
+    }
+    assert!(bounded.claim("node-0", "u", None)?);
+    assert!(bounded.claim("extra", "u", None)?);
+    assert!(bounded.claim("node-1", "w", None)?);
+    assert!(!bounded.claim("node-0", "w", None)?);
+
+    tokio::fs::remove_dir_all(&root).await.ok();
+
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_sync_weird_file_name() -> eyre::Result<()> {
+    const NAME: &str = "weird &name #1.txt";
+
+    let remote_root = temp_path("weird-remote");
+    let local_root = temp_path("weird-local");
+    tokio::fs::create_dir_all(remote_root.join("a+b c")).await?;
+    tokio::fs::create_dir_all(&local_root).await?;
+    tokio::fs::write(remote_root.join("a+b c").join(NAME), b"weird").await?;
+
+    let config: NodeConfig = serde_yaml::from_str(&format!(
+        "name: relay\naddress: 127.0.0.1\nport: 5565\nusers:\n  - name: user\n\
+         relayNodes: {{}}\nvolumes:\n  Weird:\n    store:\n      type: local\n      \
+         root: {}\n    allow: [user]\n    pullFrom: []\n",
+        remote_root.display()
+    ))?;
+    let (config, relay_id) = (
+        Arc::new(config),
+        Arc::new(NodeIdentifier {
+            uuid: uuid::Uuid::new_v4().to_string(),
+        }),
+    );
+    let relay_uuid = relay_id.uuid.clone();
+    let relay = spawn_mock_relay(move |cfg| {
+        cfg.app_data(web::Data::new(config.clone()))
+            .app_data(web::Data::new(relay_id.clone()))
+            .app_data(web::Data::new(PeerRegistry::default()))
+            .app_data(web::Data::new(FsSnapshots::default()))
+            .service(web::scope("/v1").configure(api_routes));
+    })?;
+
+    let mut local = AnyFs::from_volume_item("Weird", &local_volume(&local_root))?;
+    local.init().await?;
+    let share_node = mock_share_node(relay).await?;
+    let identifier = Arc::new(NodeIdentifier {
+        uuid: uuid::Uuid::new_v4().to_string(),
+    });
+    share_node.pull(&local, identifier.clone()).await?;
+    let report = share_node
+        .apply_commands(&local, std::slice::from_ref(&share_node))
+        .await?;
+    assert!(report.failures.is_empty(), "{:?}", report.failures);
+
+    // the relay saw the very same path the peer asked for
+    let path = NullFsPath::from_to_str(format!("@/Weird/a+b c/{NAME}"))?;
+    assert_eq!(local.read(&path).await?, b"weird");
+    assert_eq!(
+        path.url_encoded(),
+        "%40/Weird/a%2Bb%20c/weird%20%26name%20%231.txt"
+    );
+
+    tokio::fs::remove_dir_all(&remote_root).await.ok();
+    tokio::fs::remove_dir_all(&local_root).await.ok();
+    tokio::fs::remove_file(format!(
+        ".ext-state-Weird-{relay_uuid}-{}.db",
+        identifier.uuid
+    ))
+    .await
+    .ok();
+
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_dry_run() -> eyre::Result<()> {
+    let remote_root = temp_path("dry-remote");
+    let local_root = temp_path("dry-local");
+    tokio::fs::create_dir_all(remote_root.join("sub")).await?;
+    tokio::fs::create_dir_all(&local_root).await?;
+    tokio::fs::write(remote_root.join("a.txt"), b"a").await?;
+    tokio::fs::write(remote_root.join("sub/b.txt"), b"b").await?;
+    tokio::fs::write(local_root.join("a.txt"), b"local").await?;
+
+    let config: NodeConfig = serde_yaml::from_str(&format!(
+        "name: relay\naddress: 127.0.0.1\nport: 5582\nusers:\n  - name: user\n\
+         relayNodes: {{}}\nvolumes:\n  Dry:\n    store:\n      type: local\n      \
+         root: {}\n    allow: [user]\n    pullFrom: []\n",
+        remote_root.display()
+    ))?;
+    let (config, relay_id) = (
+        Arc::new(config),
+        Arc::new(NodeIdentifier {
+            uuid: uuid::Uuid::new_v4().to_string(),
+        }),
+    );
+    let relay_uuid = relay_id.uuid.clone();
+    let relay = spawn_mock_relay(move |cfg| {
+        cfg.app_data(web::Data::new(config.clone()))
+            .app_data(web::Data::new(relay_id.clone()))
+            .app_data(web::Data::new(PeerRegistry::default()))
+            .app_data(web::Data::new(FsSnapshots::default()))
+            .service(web::scope("/v1").configure(api_routes));
+    })?;
+
+    let mut local = AnyFs::from_volume_item("Dry", &local_volume(&local_root))?;
+    local.init().await?;
+    let mut share_node = mock_share_node(relay).await?;
+    share_node.dry_run = true;
+    let identifier = Arc::new(NodeIdentifier {
+        uuid: uuid::Uuid::new_v4().to_string(),
+    });
+    let pulled = share_node.pull(&local, identifier.clone()).await?;
+    assert!(!pulled.is_empty());
+
+    let report = share_node.apply_commands(&local, &[]).await?;
+    assert!(report.failures.is_empty(), "{:?}", report.failures);
+    assert_eq!(report.applied, 0);
+    assert_eq!(report.skipped, pulled.len());
+
+    // nothing written, the commands are consumed all the same
+    assert_eq!(tokio::fs::read(local_root.join("a.txt")).await?, b"local");
+    assert!(!local_root.join("sub").exists());
+    let counts = share_node.store.counts("Dry").await?;
+    assert_eq!(counts.pending + counts.retrying, 0);
+    assert_eq!(counts.done, pulled.len() as u64);
+    assert!(share_node.store.unstash("Dry").await?.is_empty());
+
+    tokio::fs::remove_dir_all(&remote_root).await.ok();
+    tokio::fs::remove_dir_all(&local_root).await.ok();
+    tokio::fs::remove_file(format!(
+        ".ext-state-Dry-{relay_uuid}-{}.db",
+        identifier.uuid
+    ))
+    .await
+    .ok();
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_commands_pages() -> eyre::Result<()> {
+    let root = temp_path("pages");
+    tokio::fs::create_dir_all(&root).await?;
+    for i in 0..5 {
+        tokio::fs::write(root.join(format!("{i}.txt")), b"page").await?;
+    }
+    let mut fs = AnyFs::from_volume_item("Pages", &local_volume(&root))?;
+    fs.init().await?;
+    let state_file = temp_path("pages-state.db");
+    let page = |since: Option<Cursor>| {
+        let (fs, state_file) = (fs.clone(), state_file.clone());
+        async move {
+            Snapshot::new(fs)
+                .capture_page(&state_file, since.as_ref(), 2)
+                .await
+        }
+    };
+
+    let first = page(None).await?;
+    assert_eq!(first.commands.len(), 2);
+    let total = first.remaining + 2;
+
+    // lost before being stashed, the page is answered again
+    let again = page(None).await?;
+    assert_eq!(again.commands, first.commands);
+    assert_eq!(again.cursor, first.cursor);
+
+    let mut cursor = first.cursor;
+    let mut seen = 2;
+    loop {
+        let next = page(Some(cursor.clone())).await?;
+        seen += next.commands.len() as u64;
+        cursor = next.cursor;
+        if next.remaining == 0 {
+            break;
+        }
+    }
+    assert_eq!(seen, total);
+
+    // acknowledged, nothing new to capture
+    let idle = page(Some(cursor.clone())).await?;
+    assert!(idle.commands.is_empty());
+    assert_eq!(idle.cursor, cursor);
+
+    tokio::fs::write(root.join("new.txt"), b"page").await?;
+    let fresh = page(Some(cursor.clone())).await?;
+    assert_eq!(fresh.commands.len(), 1);
+    assert_eq!(fresh.remaining, 0);
+
+    // the cursor of another state database starts from the queue head
+    let other = "other.99".parse::<Cursor>()?;
+    let restarted = page(Some(other)).await?;
+    assert_eq!(restarted.commands, fresh.commands);
+
+    // a peer going back to unpaged pulls gets what was still queued
+    let commands = Snapshot::new(fs.clone()).capture(&state_file).await?;
+    assert_eq!(commands, fresh.commands);
+    assert!(page(None).await?.commands.is_empty());
+
+    assert!("no-seq".parse::<Cursor>().is_err());
+
+    tokio::fs::remove_dir_all(&root).await.ok();
+    tokio::fs::remove_file(&state_file).await.ok();
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_pull_resumes_from_cursor() -> eyre::Result<()> {
+    let remote_root = temp_path("cursor-remote");
+    let local_root = temp_path("cursor-local");
+    tokio::fs::create_dir_all(&remote_root).await?;
+    tokio::fs::create_dir_all(&local_root).await?;
+    tokio::fs::write(remote_root.join("a.txt"), b"a").await?;
+
+    let config: NodeConfig = serde_yaml::from_str(&format!(
+        "name: relay\naddress: 127.0.0.1\nport: 5586\nusers:\n  - name: user\n\
+         relayNodes: {{}}\nvolumes:\n  Cursor:\n    store:\n      type: local\n      \
+         root: {}\n    allow: [user]\n    pullFrom: []\n",
+        remote_root.display()
+    ))?;
+    let (config, relay_id) = (
+        Arc::new(config),
+        Arc::new(NodeIdentifier {
+            uuid: uuid::Uuid::new_v4().to_string(),
+        }),
+    );
+    let relay_uuid = relay_id.uuid.clone();
+    let relay = spawn_mock_relay(move |cfg| {
+        cfg.app_data(web::Data::new(config.clone()))
+            .app_data(web::Data::new(relay_id.clone()))
+            .app_data(web::Data::new(PeerRegistry::default()))
+            .app_data(web::Data::new(FsSnapshots::default()))
+            .service(web::scope("/v1").configure(api_routes));
+    })?;
+
+    let mut local = AnyFs::from_volume_item("Cursor", &local_volume(&local_root))?;
+    local.init().await?;
+    let share_node = mock_share_node(relay).await?;
+    let identifier = Arc::new(NodeIdentifier {
+        uuid: uuid::Uuid::new_v4().to_string(),
+    });
+
+    let pulled = share_node.pull(&local, identifier.clone()).await?;
+    assert!(!pulled.is_empty());
+    let cursor = share_node.store.cursor("mock", "Cursor").await?;
+    assert!(cursor.is_some());
+
+    // acknowledged by the cursor, not sent twice
+    assert!(
+        share_node
+            .pull(&local, identifier.clone())
+            .await?
+            .is_empty()
+    );
+
+    tokio::fs::write(remote_root.join("b.txt"), b"b").await?;
+    let pulled = share_node.pull(&local, identifier.clone()).await?;
+    assert_eq!(pulled.len(), 1);
+    assert_ne!(share_node.store.cursor("mock", "Cursor").await?, cursor);
+
+    tokio::fs::remove_dir_all(&remote_root).await.ok();
+    tokio::fs::remove_dir_all(&local_root).await.ok();
+    tokio::fs::remove_file(format!(
+        ".ext-state-Cursor-{relay_uuid}-{}.db",
+        identifier.uuid
+    ))
+    .await
+    .ok();
+
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_shutdown_between_commands() -> eyre::Result<()> {
+    use actix_web::dev::Service as _;
+
+    let remote_root = temp_path("shutdown-remote");
+    let local_root = temp_path("shutdown-local");
+    tokio::fs::create_dir_all(&remote_root).await?;
+    tokio::fs::create_dir_all(&local_root).await?;
+    for name in ["a.txt", "b.txt", "c.txt"] {
+        tokio::fs::write(remote_root.join(name), name).await?;
+    }
+
+    let config: NodeConfig = serde_yaml::from_str(&format!(
+        "name: relay\naddress: 127.0.0.1\nport: 5587\nusers:\n  - name: user\n\
+         relayNodes: {{}}\nvolumes:\n  Halt:\n    store:\n      type: local\n      \
+         root: {}\n    allow: [user]\n    pullFrom: []\n",
+        remote_root.display()
+    ))?;
+    let (config, relay_id) = (
+        Arc::new(config),
+        Arc::new(NodeIdentifier {
+            uuid: uuid::Uuid::new_v4().to_string(),
+        }),
+    );
+    let relay_uuid = relay_id.uuid.clone();
+    let shutdown = CancellationToken::new();
+    let relay_shutdown = shutdown.clone();
+    let relay = spawn_mock_relay(move |cfg| {
+        let shutdown = relay_shutdown.clone();
+        cfg.app_data(web::Data::new(config.clone()))
+            .app_data(web::Data::new(relay_id.clone()))
+            .app_data(web::Data::new(PeerRegistry::default()))
+            .app_data(web::Data::new(FsSnapshots::default()))
+            .service(
+                web::scope("/v1")
+                    // shutting down while the first file downloads
+                    .wrap_fn(move |req, srv| {
+                        if req.path() == "/v1/download" {
+                            shutdown.cancel();
+                        }
+                        srv.call(req)
+                    })
+                    .configure(api_routes),
+            );
+    })?;
+
+    let mut local = AnyFs::from_volume_item("Halt", &local_volume(&local_root))?;
+    local.init().await?;
+    let mut share_node = mock_share_node(relay).await?;
+    let identifier = Arc::new(NodeIdentifier {
+        uuid: uuid::Uuid::new_v4().to_string(),
+    });
+    let pulled = share_node.pull(&local, identifier.clone()).await?;
+    let writes = pulled
+        .iter()
+        .filter(|command| matches!(command, Command::Write { .. }))
+        .count();
+    assert_eq!(writes, 3);
+
+    share_node.shutdown = shutdown;
+    let report = share_node.apply_commands(&local, &[]).await?;
+    assert!(report.failures.is_empty(), "{:?}", report.failures);
+    assert_eq!(report.applied, 1);
+
+    // the command in progress completed and was marked, the others are still pending
+    let counts = share_node.store.counts("Halt").await?;
+    assert_eq!(counts.done, 1);
+    assert_eq!(counts.retrying + counts.dead_letter, 0);
+    assert_eq!(counts.pending, pulled.len() as u64 - 1);
+
+    share_node.shutdown = CancellationToken::new();
+    let report = share_node.apply_commands(&local, &[]).await?;
+    assert!(report.failures.is_empty(), "{:?}", report.failures);
+    for name in ["a.txt", "b.txt", "c.txt"] {
+        assert_eq!(
+            tokio::fs::read(local_root.join(name)).await?,
+            name.as_bytes()
+        );
+    }
+    assert!(share_node.store.unstash("Halt").await?.is_empty());
+
+    tokio::fs::remove_dir_all(&remote_root).await.ok();
+    tokio::fs::remove_dir_all(&local_root).await.ok();
+    tokio::fs::remove_file(format!(
+        ".ext-state-Halt-{relay_uuid}-{}.db",
+        identifier.uuid
+    ))
+    .await
+    .ok();
+
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_sync_empty_dirs() -> eyre::Result<()> {
+    let remote_root = temp_path("empty-remote");
+    let local_root = temp_path("empty-local");
+    tokio::fs::create_dir_all(remote_root.join("new_dir/empty")).await?;
+    tokio::fs::create_dir_all(&local_root).await?;
+
+    let config: NodeConfig = serde_yaml::from_str(&format!(
+        "name: relay\naddress: 127.0.0.1\nport: 5568\nusers:\n  - name: user\n\
+         relayNodes: {{}}\nvolumes:\n  Empty:\n    store:\n      type: local\n      \
+         root: {}\n    allow: [user]\n    pullFrom: []\n",
+        remote_root.display()
+    ))?;
+    let config = Arc::new(config);
+    let relay_id = Arc::new(NodeIdentifier {
+        uuid: uuid::Uuid::new_v4().to_string(),
+    });
+    let relay_uuid = relay_id.uuid.clone();
+    let relay = spawn_mock_relay(move |cfg| {
+        cfg.app_data(web::Data::new(config.clone()))
+            .app_data(web::Data::new(relay_id.clone()))
+            .app_data(web::Data::new(PeerRegistry::default()))
+            .app_data(web::Data::new(FsSnapshots::default()))
+            .service(web::scope("/v1").configure(api_routes));
+    })?;
+
+    let mut local = AnyFs::from_volume_item("Empty", &local_volume(&local_root))?;
+    local.init().await?;
+    let share_node = mock_share_node(relay).await?;
+    let identifier = Arc::new(NodeIdentifier {
+        uuid: uuid::Uuid::new_v4().to_string(),
+    });
+    share_node.pull(&local, identifier.clone()).await?;
+    let report = share_node.apply_commands(&local, &[]).await?;
+    assert!(report.failures.is_empty(), "{:?}", report.failures);
+    assert!(local_root.join("new_dir/empty").is_dir());
+
+    // created later below a directory the peer already has
+    tokio::fs::create_dir_all(remote_root.join("new_dir/later")).await?;
+    share_node.pull(&local, identifier.clone()).await?;
+    share_node.apply_commands(&local, &[]).await?;
+    assert!(local_root.join("new_dir/later").is_dir());
+
+    tokio::fs::remove_dir_all(&remote_root).await.ok();
+    tokio::fs::remove_dir_all(&local_root).await.ok();
+    tokio::fs::remove_file(format!(
+        ".ext-state-Empty-{relay_uuid}-{}.db",
+        identifier.uuid
+    ))
+    .await
+    .ok();
+
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_relay_timeout() -> eyre::Result<()> {
+    let relay = spawn_mock_relay(|cfg| {
+        cfg.route(
+            "/v1/hash",
+            web::get().to(|| async {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                HttpResponse::Ok().json("late")
+            }),
+        );
+    })?;
+
+    let mut share_node = mock_share_node(relay).await?;
+    share_node.relay.timeout_secs = Some(1);
+    share_node.client = ShareNode::client_for(&share_node.relay, true)?;
+
+    let start = Instant::now();
+    let path = NullFsPath::from_to_str("@/vol/a.txt")?;
+    assert!(share_node.remote_hash(&path).await.is_err());
+    assert!(start.elapsed() < Duration::from_secs(4));
+
+    Ok(())
+}
+
+#[test]
+fn test_session_key_persisted() -> eyre::Result<()> {
+    use crate::server::load_session_key;
+
+    let path = temp_path("session-key");
+    let key = load_session_key(&path)?;
+    assert_eq!(load_session_key(&path)?.master(), key.master());
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(&path)?.permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    std::fs::write(&path, b"too short")?;
+    let regenerated = load_session_key(&path)?;
+    assert_ne!(regenerated.master(), key.master());
+    assert_eq!(load_session_key(&path)?.master(), regenerated.master());
+
+    std::fs::remove_file(&path).ok();
+
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_web_logout() -> eyre::Result<()> {
+    use crate::server::{session_middleware, web_routes};
+    use actix_web::cookie::{Cookie, Key};
+
+    let config: NodeConfig = serde_yaml::from_str(
+        "name: node\naddress: 127.0.0.1\nport: 5579\nusers:\n  - name: u\n    password: p\n\
+         relayNodes: {}\nvolumes: {}\n",
+    )?;
+    let app = actix_web::test::init_service(
+        App::new()
+            .app_data(web::Data::new(Arc::new(config)))
+            .app_data(web::Data::new(Arc::new(NodeIdentifier {
+                uuid: "node-id".to_owned(),
+            })))
+            .app_data(web::Data::new(FsSnapshots::default()))
+            .service(
+                web::scope("/web")
+                    .wrap(session_middleware(Key::generate(), false))
+                    .configure(web_routes),
+            ),
+    )
+    .await;
+    let session_cookie = |resp: &actix_web::dev::ServiceResponse| {
+        resp.response()
+            .cookies()
+            .find(|cookie| cookie.name() == "nullfs")
+            .map(|cookie| cookie.into_owned())
+    };
+
+    let req = actix_web::test::TestRequest::post()
+        .uri("/web/login")
+        .set_form([("username", "u"), ("password", "p")])
+        .to_request();
+    let resp = actix_web::test::call_service(&app, req).await;
+    assert_eq!(resp.headers().get("location").unwrap(), "/web/browser");
+    let cookie = session_cookie(&resp).expect("session cookie");
+
+    // browsing extends the session
+    let req = actix_web::test::TestRequest::get()
+        .uri("/web/browser")
+        .cookie(cookie.clone())
+        .to_request();
+    let resp = actix_web::test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+    let extended = session_cookie(&resp).expect("extended session cookie");
+    assert!(
+        extended
+            .max_age()
+            .is_some_and(|max_age| max_age.whole_hours() == 2)
+    );
+
+    let req = actix_web::test::TestRequest::get()
+        .uri("/web/logout")
+        .cookie(cookie.clone())
+        .to_request();
+    let resp = actix_web::test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 303);
+    assert_eq!(resp.headers().get("location").unwrap(), "/web/login");
+    let removed = session_cookie(&resp).expect("removal cookie");
+    assert_eq!(removed.value(), "");
+    assert!(removed.max_age().is_some_and(|max_age| max_age.is_zero()));
+
+    // what the browser sends once it dropped the cookie
+    let req = actix_web::test::TestRequest::get()
+        .uri("/web/browser")
+        .cookie(Cookie::new("nullfs", removed.value().to_owned()))
+        .to_request();
+    let resp = actix_web::test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 303);
+    assert!(
+        resp.headers()
+            .get("location")
+            .unwrap()
+            .to_str()?
+            .starts_with("/web/login?error=")
+    );
+
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_markdown_preview() -> eyre::Result<()> {
+    use crate::server::{session_middleware, web_routes};
+    use actix_web::cookie::Key;
+
+    let root = temp_path("preview");
+    tokio::fs::create_dir_all(&root).await?;
+    tokio::fs::write(
+        root.join("a.md"),
+        "[run](javascript:alert(1)) [again](JavaScript:alert(2)) ![img](data:text/html,x)\n\
+         [site](https://example.com) [sibling](b.md) <script>alert(3)</script>\n",
+    )
+    .await?;
+
+    let config: NodeConfig = serde_yaml::from_str(&format!(
+        "name: node\naddress: 127.0.0.1\nport: 5588\nusers:\n  - name: u\n    password: p\n\
+         relayNodes: {{}}\nvolumes:\n  Docs:\n    store:\n      type: local\n      \
+         root: {}\n    allow: [u]\n    pullFrom: []\n",
+        root.display()
+    ))?;
+    let app = actix_web::test::init_service(
+        App::new()
+            .app_data(web::Data::new(Arc::new(config)))
+            .app_data(web::Data::new(Arc::new(NodeIdentifier {
+                uuid: "node-id".to_owned(),
+            })))
+            .app_data(web::Data::new(FsSnapshots::default()))
+            .service(
+                web::scope("/web")
+                    .wrap(session_middleware(Key::generate(), false))
+                    .configure(web_routes),
+            ),
+    )
+    .await;
+
+    let req = actix_web::test::TestRequest::post()
+        .uri("/web/login")
+        .set_form([("username", "u"), ("password", "p")])
+        .to_request();
+    let resp = actix_web::test::call_service(&app, req).await;
+    let cookie = resp
+        .response()
+        .cookies()
+        .find(|cookie| cookie.name() == "nullfs")
+        .map(|cookie| cookie.into_owned())
+        .expect("session cookie");
+
+    let req = actix_web::test::TestRequest::get()
+        .uri("/web/preview?path=@/Docs/a.md")
+        .cookie(cookie)
+        .to_request();
+    let resp = actix_web::test::call_service(&app, req).await;
+    assert_eq!(resp.status(), 200);
+    assert!(
+        resp.headers()
+            .get("Content-Security-Policy")
+            .unwrap()
+            .to_str()?
+            .contains("script-src 'none'")
+    );
+
+    let body = String::from_utf8(actix_web::test::read_body(resp).await.to_vec())?;
+    let lowercase = body.to_lowercase();
+    assert!(!lowercase.contains("javascript:"), "{body}");
+    assert!(!lowercase.contains("data:text"), "{body}");
+    assert!(!body.contains("<script>"), "{body}");
+    assert!(body.contains("href=\"https://example.com\""), "{body}");
+    assert!(body.contains("href=\"b.md\""), "{body}");
+
+    tokio::fs::remove_dir_all(&root).await.ok();
+
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_image_and_large_preview() -> eyre::Result<()> {
+    use crate::server::{session_middleware, web_routes};
+    use actix_web::cookie::Key;
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+    let root = temp_path("image-preview");
+    tokio::fs::create_dir_all(&root).await?;
+    tokio::fs::write(root.join("cat & dog.png"), PNG).await?;
+    tokio::fs::write(root.join("big.txt"), "x".repeat(512 * 1024 + 1)).await?;
+    tokio::fs::write(root.join("small.txt"), "x".repeat(512 * 1024)).await?;
+
+    let config: NodeConfig = serde_yaml::from_str(&format!(
+        "name: node\naddress: 127.0.0.1\nport: 5593\nusers:\n  - name: u\n    password: p\n\
+         relayNodes: {{}}\nvolumes:\n  Docs:\n    store:\n      type: local\n      \
+         root: {}\n    allow: [u]\n    pullFrom: []\n",
+        root.display()
+    ))?;
+    let app = actix_web::test::init_service(
+        App::new()
+            .app_data(web::Data::new(Arc::new(config)))
+            .app_data(web::Data::new(Arc::new(NodeIdentifier {
+                uuid: "node-id".to_owned(),
+            })))
+            .app_data(web::Data::new(FsSnapshots::default()))
+            .service(
+                web::scope("/web")
+                    .wrap(session_middleware(Key::generate(), false))
+                    .configure(web_routes),
+            ),
+    )
+    .await;
+
+    let req = actix_web::test::TestRequest::post()
+        .uri("/web/login")
+        .set_form([("username", "u"), ("password", "p")])
+        .to_request();
+    let resp = actix_web::test::call_service(&app, req).await;
+    let cookie = resp
+        .response()
+        .cookies()
+        .find(|cookie| cookie.name() == "nullfs")
+        .map(|cookie| cookie.into_owned())
+        .expect("session cookie");
+    let get = async |uri: &str| {
+        let req = actix_web::test::TestRequest::get()
+            .uri(uri)
+            .cookie(cookie.clone())
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200, "{uri}");
+        let disposition = resp
+            .headers()
+            .get("Content-Disposition")
+            .map(|value| value.to_str().unwrap_or_default().to_owned());
+        let body = actix_web::test::read_body(resp).await.to_vec();
+        (disposition, body)
+    };
+
+    // images are shown inline from the browser route, their name escaped
+    let (_, body) = get("/web/preview?path=@/Docs/cat%20%26%20dog.png").await;
+    let body = String::from_utf8(body)?;
+    let src = "/web/browser?path=%40/Docs/cat%20%26%20dog.png&inline=true";
+    assert!(
+        body.contains(&format!("<img src=\"{src}\" alt=\"cat &amp; dog.png\">")),
+        "{body}"
+    );
+    let (disposition, image) = get(src).await;
+    assert!(disposition.is_some_and(|d| d.starts_with("inline")));
+    assert_eq!(image, PNG);
+
+    // past the cap the content is not read, a download link is offered instead
+    let (_, body) = get("/web/preview?path=@/Docs/big.txt").await;
+    let body = String::from_utf8(body)?;
+    assert!(
+        body.contains("Too large to preview (524289 bytes)"),
+        "{body}"
+    );
+    assert!(body.contains("href=\"/web/browser?path=%40/Docs/big.txt\""));
+    assert!(!body.contains("xxxx"));
+
+    let (_, body) = get("/web/preview?path=@/Docs/small.txt").await;
+    let body = String::from_utf8(body)?;
+    assert!(!body.contains("Too large to preview"));
+    assert!(body.contains(&"x".repeat(512 * 1024)));
+
+    tokio::fs::remove_dir_all(&root).await.ok();
+
+    Ok(())
+}
+
+#[actix_web::test]
+async fn test_download_compression() -> eyre::Result<()> {
+    let root = temp_path("compression");
+    tokio::fs::create_dir_all(&root).await?;
+    tokio::fs::write(root.join("a.txt"), "text ".repeat(1000)).await?;
+    tokio::fs::write(root.join("b.zip"), "zip ".repeat(1000)).await?;
+
+    let encoding_of = async |compression: bool, file: &str| -> eyre::Result<Option<String>> {
+        let config: NodeConfig = serde_yaml::from_str(&format!(
+            "name: node\naddress: 127.0.0.1\nport: 5566\ncompression: {compression}\nusers:\n  \
+             - name: u\n    password: p\nrelayNodes: {{}}\nvolumes:\n  Docs:\n    store:\n      \
+             type: local\n      root: {}\n    allow: [u]\n    pullFrom: []\n",
+            root.display()
+        ))?;
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::Data::new(Arc::new(config)))
+                .app_data(web::Data::new(FsSnapshots::default()))
+                .service(web::scope("/v1").configure(api_routes)),
+        )
+        .await;
+
+        let req = actix_web::test::TestRequest::get()
+            .uri(&format!("/v1/download?path=@/Docs/{file}"))
+            .insert_header(("Authorization", "Basic dTpw")) // u:p
+            .insert_header(("Accept-Encoding", "zstd"))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+
+        Ok(resp
+            .headers()
+            .get("Content-Encoding")
+            .map(|value| value.to_str().unwrap_or_default().to_owned()))
+    };
+
+    assert_eq!(encoding_of(true, "a.txt").await?.as_deref(), Some("zstd"));
+    assert_ne!(encoding_of(true, "b.zip").await?.as_deref(), Some("zstd"));
+    assert_ne!(encoding_of(false, "a.txt").await?.as_deref(), Some("zstd"));
+
+    tokio::fs::remove_dir_all(&root).await.ok();
+
     Ok(())
 }